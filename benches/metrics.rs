@@ -0,0 +1,61 @@
+//! Gather cost as the number of devices grows.
+//!
+//! All devices share one registry, so rendering should scale linearly with the
+//! number of series: compare the per-device time across the input sizes.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use homewizard_water_exporter::homewizard::HomeWizardWaterData;
+use homewizard_water_exporter::metrics::Metrics;
+use std::hint::black_box;
+
+fn metrics_with_devices(count: usize) -> Metrics {
+    let metrics = Metrics::new().unwrap();
+    for i in 0..count {
+        let data = HomeWizardWaterData {
+            wifi_ssid: "BenchNetwork".to_string(),
+            wifi_strength: Some(80.0),
+            total_liter_m3: 1000.0 + i as f64,
+            active_liter_lpm: Some(5.5),
+            total_liter_offset_m3: 0.0,
+            wifi_rssi_db: None,
+            ..Default::default()
+        };
+        metrics.update(&format!("meter-{i}"), &data).unwrap();
+    }
+    metrics
+}
+
+fn bench_gather(c: &mut Criterion) {
+    let mut group = c.benchmark_group("gather");
+    for devices in [1, 10, 100, 1000] {
+        let metrics = metrics_with_devices(devices);
+        group.throughput(Throughput::Elements(devices as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(devices), &metrics, |b, m| {
+            b.iter(|| black_box(m.gather().unwrap()))
+        });
+    }
+    group.finish();
+}
+
+fn bench_update(c: &mut Criterion) {
+    let metrics = metrics_with_devices(100);
+    let data = HomeWizardWaterData {
+        wifi_ssid: "BenchNetwork".to_string(),
+        wifi_strength: Some(80.0),
+        total_liter_m3: 1234.5,
+        active_liter_lpm: Some(7.0),
+        total_liter_offset_m3: 0.0,
+        wifi_rssi_db: None,
+        ..Default::default()
+    };
+    c.bench_function("update/100", |b| {
+        b.iter(|| {
+            metrics
+                .update(black_box("meter-50"), black_box(&data))
+                .unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, bench_gather, bench_update);
+criterion_main!(benches);
@@ -0,0 +1,160 @@
+use axum::extract::connect_info::ConnectInfo;
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::debug;
+
+/// An IPv4 or IPv6 network in CIDR notation; a bare address is a single host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6 addresses
+        match (self.address, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let address: IpAddr = address
+            .parse()
+            .map_err(|_| format!("invalid network '{s}', expected an address or CIDR"))?;
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("invalid prefix length in '{s}', expected 0 to {max}"))?,
+            None => max,
+        };
+        Ok(Self { address, prefix })
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+/// Networks allowed to reach the HTTP endpoints (`--allowed-networks`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Allowlist {
+    networks: Vec<IpNetwork>,
+}
+
+impl Allowlist {
+    /// `None` when no networks are configured, leaving the endpoints open to all.
+    pub fn new(networks: &[IpNetwork]) -> Option<Self> {
+        (!networks.is_empty()).then(|| Self {
+            networks: networks.to_vec(),
+        })
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(ip))
+    }
+}
+
+/// Middleware answering 403 to clients outside the allowlist.
+///
+/// Needs the router to be served with `into_make_service_with_connect_info`.
+pub async fn require_allowed(
+    State(allowlist): State<Arc<Allowlist>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if allowlist.allows(peer.ip()) {
+        return next.run(request).await;
+    }
+    debug!("Refused {} from {}", request.uri().path(), peer.ip());
+    (StatusCode::FORBIDDEN, "Forbidden\n").into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(s: &str) -> IpNetwork {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(network("10.0.0.0/8").to_string(), "10.0.0.0/8");
+        assert_eq!(network("192.168.1.5").to_string(), "192.168.1.5/32");
+        assert_eq!(network("fd00::/8").to_string(), "fd00::/8");
+        assert_eq!(network("::1").to_string(), "::1/128");
+
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("fd00::/129".parse::<IpNetwork>().is_err());
+        assert!("10.0.0.0/x".parse::<IpNetwork>().is_err());
+        assert!("prometheus.local".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn test_contains() {
+        let lan = network("192.168.1.0/24");
+        assert!(lan.contains(ip("192.168.1.20")));
+        assert!(!lan.contains(ip("192.168.2.20")));
+        assert!(!lan.contains(ip("fd00::1")));
+
+        assert!(network("0.0.0.0/0").contains(ip("8.8.8.8")));
+        assert!(network("192.168.1.5").contains(ip("192.168.1.5")));
+        assert!(!network("192.168.1.5").contains(ip("192.168.1.6")));
+
+        let ula = network("fd00::/8");
+        assert!(ula.contains(ip("fd12:3456::1")));
+        assert!(!ula.contains(ip("fe80::1")));
+    }
+
+    #[test]
+    fn test_ipv4_mapped_clients() {
+        assert!(network("192.168.1.0/24").contains(ip("::ffff:192.168.1.20")));
+    }
+
+    #[test]
+    fn test_allowlist() {
+        assert!(Allowlist::new(&[]).is_none());
+
+        let allowlist = Allowlist::new(&[network("10.0.0.0/8"), network("::1")]).unwrap();
+        assert!(allowlist.allows(ip("10.1.2.3")));
+        assert!(allowlist.allows(ip("::1")));
+        assert!(!allowlist.allows(ip("192.168.1.1")));
+    }
+}
@@ -0,0 +1,380 @@
+use crate::config::Config;
+use crate::grafana::label_matchers;
+use crate::homewizard::DeviceType;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Output format of `generate alerts`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum RulesFormat {
+    /// A Prometheus rules file, for `rule_files`
+    #[default]
+    Rules,
+    /// A `PrometheusRule` resource for the Prometheus Operator
+    PrometheusRule,
+}
+
+/// Polls without data, beyond the poll interval, before `HomeWizardNoData` fires.
+const NO_DATA_POLLS: u32 = 5;
+/// Continuous flow that counts as a leak when `--leak-after` is not set.
+const LEAK_WINDOW: &str = "1h";
+/// Daily use below this (in m³) is never abnormal, so quiet days do not set a low bar.
+const MIN_ABNORMAL_M3: f64 = 0.1;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Rule {
+    pub alert: String,
+    pub expr: String,
+    #[serde(rename = "for", skip_serializing_if = "Option::is_none")]
+    pub for_: Option<String>,
+    pub labels: BTreeMap<String, String>,
+    pub annotations: BTreeMap<String, String>,
+    /// The metric family the rule is about, to honour the metric filters
+    #[serde(skip)]
+    family: &'static str,
+}
+
+impl Rule {
+    fn new(alert: &str, family: &'static str, expr: String, severity: &str) -> Self {
+        Self {
+            alert: alert.to_string(),
+            expr,
+            for_: None,
+            labels: BTreeMap::from([("severity".to_string(), severity.to_string())]),
+            annotations: BTreeMap::new(),
+            family,
+        }
+    }
+
+    fn for_duration(mut self, duration: Duration) -> Self {
+        if !duration.is_zero() {
+            self.for_ = Some(prometheus_duration(duration));
+        }
+        self
+    }
+
+    fn annotate(mut self, summary: &str, description: &str) -> Self {
+        self.annotations
+            .insert("summary".to_string(), summary.to_string());
+        self.annotations
+            .insert("description".to_string(), description.to_string());
+        self
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Group {
+    name: String,
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Serialize)]
+struct RulesFile {
+    groups: Vec<Group>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PrometheusRule {
+    api_version: &'static str,
+    kind: &'static str,
+    metadata: BTreeMap<&'static str, String>,
+    spec: RulesFile,
+}
+
+/// A duration in the Prometheus format, in the largest unit that keeps it whole.
+fn prometheus_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs().max(1);
+    match seconds {
+        s if s % 3600 == 0 => format!("{}h", s / 3600),
+        s if s % 60 == 0 => format!("{}m", s / 60),
+        s => format!("{s}s"),
+    }
+}
+
+/// Alerting rules for the devices and features in `config`.
+///
+/// Alerts fire after a delay derived from the poll interval, `--down-after`
+/// and `--stale-after`; water usage is abnormal above `usage_factor` times the
+/// daily average of the week before.
+pub fn rules(config: &Config, usage_factor: f64) -> Vec<Rule> {
+    let matchers = label_matchers(config);
+    let selector = if matchers.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", matchers.join(","))
+    };
+    let series = |family: &str| format!("{family}{selector}");
+
+    let no_data_after = if config.stale_after.is_zero() {
+        config.poll_interval * NO_DATA_POLLS
+    } else {
+        config.stale_after
+    };
+    // The exporter already waits `--down-after`; otherwise allow a few failed polls
+    let down_for = if config.down_after.is_zero() {
+        config.poll_interval * 3
+    } else {
+        Duration::ZERO
+    };
+
+    let mut rules = vec![
+        Rule::new(
+            "HomeWizardDeviceDown",
+            "homewizard_up",
+            format!("{} == 0", series("homewizard_up")),
+            "warning",
+        )
+        .for_duration(down_for)
+        .annotate(
+            "HomeWizard device {{ $labels.device }} is down",
+            "The exporter cannot poll {{ $labels.device }}.",
+        ),
+        Rule::new(
+            "HomeWizardNoData",
+            "homewizard_exporter_last_successful_poll_timestamp_seconds",
+            format!(
+                "time() - {} > {}",
+                series("homewizard_exporter_last_successful_poll_timestamp_seconds"),
+                no_data_after.as_secs()
+            ),
+            "warning",
+        )
+        .annotate(
+            "No readings from {{ $labels.device }}",
+            &format!(
+                "{{{{ $labels.device }}}} has not been polled successfully for more than {}.",
+                prometheus_duration(no_data_after)
+            ),
+        ),
+        Rule::new(
+            "HomeWizardExporterMissing",
+            "homewizard_up",
+            format!("absent({})", series("homewizard_up")),
+            "warning",
+        )
+        .for_duration(Duration::from_secs(300))
+        .annotate(
+            "The HomeWizard exporter is not scraped",
+            "Prometheus has no homewizard_up series; the exporter is down or not scraped.",
+        ),
+    ];
+
+    let has_water = config.host.is_empty()
+        || config
+            .devices()
+            .iter()
+            .any(|device| device.device_type == DeviceType::Water);
+    if has_water {
+        rules.push(if config.leak_after.is_zero() {
+            Rule::new(
+                "HomeWizardLeakSuspected",
+                "homewizard_water_active_flow_lpm",
+                format!(
+                    "min_over_time({}[{LEAK_WINDOW}]) > 0",
+                    series("homewizard_water_active_flow_lpm")
+                ),
+                "critical",
+            )
+            .annotate(
+                "Possible water leak at {{ $labels.device }}",
+                &format!("Water has been flowing without a break for {LEAK_WINDOW}."),
+            )
+        } else {
+            Rule::new(
+                "HomeWizardLeakSuspected",
+                "homewizard_water_leak_suspected",
+                format!("{} == 1", series("homewizard_water_leak_suspected")),
+                "critical",
+            )
+            .annotate(
+                "Possible water leak at {{ $labels.device }}",
+                &format!(
+                    "Water has been flowing without a break for {}.",
+                    prometheus_duration(config.leak_after)
+                ),
+            )
+        });
+
+        let total = series("homewizard_water_total_m3");
+        rules.push(
+            Rule::new(
+                "HomeWizardAbnormalUsage",
+                "homewizard_water_total_m3",
+                format!(
+                    "increase({total}[1d]) > {usage_factor} * increase({total}[7d] offset 1d) / 7 and increase({total}[1d]) > {MIN_ABNORMAL_M3}"
+                ),
+                "warning",
+            )
+            .for_duration(Duration::from_secs(900))
+            .annotate(
+                "Unusual water usage at {{ $labels.device }}",
+                &format!(
+                    "{{{{ $value | humanize }}}} m³ used in the last 24 hours, more than {usage_factor} times the daily average of the week before."
+                ),
+            ),
+        );
+
+        if config.budget().is_some() {
+            rules.push(
+                Rule::new(
+                    "HomeWizardBudgetExceeded",
+                    "homewizard_water_budget_used_percent",
+                    format!("{} >= 100", series("homewizard_water_budget_used_percent")),
+                    "info",
+                )
+                .annotate(
+                    "Monthly water budget used up at {{ $labels.device }}",
+                    "The water used this billing cycle has reached the monthly budget.",
+                ),
+            );
+        }
+    }
+
+    let filter = config.metric_filter();
+    rules.retain(|rule| filter.allows(rule.family));
+    rules
+}
+
+/// The rules of [`rules`] as YAML, in a group or `PrometheusRule` named `name`.
+pub fn render(
+    config: &Config,
+    format: RulesFormat,
+    name: &str,
+    usage_factor: f64,
+) -> Result<String> {
+    let groups = RulesFile {
+        groups: vec![Group {
+            name: name.to_string(),
+            rules: rules(config, usage_factor),
+        }],
+    };
+    Ok(match format {
+        RulesFormat::Rules => serde_yaml::to_string(&groups)?,
+        RulesFormat::PrometheusRule => serde_yaml::to_string(&PrometheusRule {
+            api_version: "monitoring.coreos.com/v1",
+            kind: "PrometheusRule",
+            metadata: BTreeMap::from([("name", name.to_string())]),
+            spec: groups,
+        })?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn config(args: &[&str]) -> Config {
+        Config::try_parse_from(
+            std::iter::once("homewizard-water-exporter").chain(args.iter().copied()),
+        )
+        .unwrap()
+    }
+
+    fn rule<'a>(rules: &'a [Rule], alert: &str) -> Option<&'a Rule> {
+        rules.iter().find(|rule| rule.alert == alert)
+    }
+
+    #[test]
+    fn test_prometheus_duration() {
+        assert_eq!(prometheus_duration(Duration::from_secs(7200)), "2h");
+        assert_eq!(prometheus_duration(Duration::from_secs(180)), "3m");
+        assert_eq!(prometheus_duration(Duration::from_secs(90)), "90s");
+        assert_eq!(prometheus_duration(Duration::from_millis(10)), "1s");
+    }
+
+    #[test]
+    fn test_rules() {
+        let rules = rules(&config(&["--host", "192.168.1.5"]), 2.0);
+
+        let down = rule(&rules, "HomeWizardDeviceDown").unwrap();
+        assert_eq!(down.expr, "homewizard_up == 0");
+        assert_eq!(down.for_.as_deref(), Some("3m"));
+        assert_eq!(
+            rule(&rules, "HomeWizardNoData").unwrap().expr,
+            "time() - homewizard_exporter_last_successful_poll_timestamp_seconds > 300"
+        );
+        assert_eq!(
+            rule(&rules, "HomeWizardLeakSuspected").unwrap().expr,
+            "min_over_time(homewizard_water_active_flow_lpm[1h]) > 0"
+        );
+        assert!(
+            rule(&rules, "HomeWizardAbnormalUsage")
+                .unwrap()
+                .expr
+                .contains("> 2 * increase(homewizard_water_total_m3[7d] offset 1d) / 7")
+        );
+        assert!(rule(&rules, "HomeWizardBudgetExceeded").is_none());
+    }
+
+    #[test]
+    fn test_rules_follow_config() {
+        let config = config(&[
+            "--host",
+            "192.168.1.5",
+            "--poll-interval",
+            "30s",
+            "--down-after",
+            "5m",
+            "--stale-after",
+            "15m",
+            "--leak-after",
+            "45m",
+            "--monthly-budget-m3",
+            "12",
+            "--metrics-exclude",
+            "homewizard_water_total_m3",
+            "--label",
+            "site=home",
+        ]);
+        let rules = rules(&config, 3.0);
+
+        let down = rule(&rules, "HomeWizardDeviceDown").unwrap();
+        assert_eq!(down.expr, r#"homewizard_up{site="home"} == 0"#);
+        assert_eq!(down.for_, None);
+        assert!(
+            rule(&rules, "HomeWizardNoData")
+                .unwrap()
+                .expr
+                .ends_with("> 900")
+        );
+        let leak = rule(&rules, "HomeWizardLeakSuspected").unwrap();
+        assert_eq!(
+            leak.expr,
+            r#"homewizard_water_leak_suspected{site="home"} == 1"#
+        );
+        assert!(leak.annotations["description"].contains("45m"));
+        assert!(rule(&rules, "HomeWizardBudgetExceeded").is_some());
+        // Excluded from /metrics
+        assert!(rule(&rules, "HomeWizardAbnormalUsage").is_none());
+
+        // Water alerts only for water meters
+        let rules = super::rules(&super::tests::config(&["--host", "p1:192.168.1.7"]), 2.0);
+        assert!(rule(&rules, "HomeWizardLeakSuspected").is_none());
+        assert!(rule(&rules, "HomeWizardDeviceDown").is_some());
+    }
+
+    #[test]
+    fn test_render() {
+        let config = config(&["--host", "192.168.1.5"]);
+
+        let yaml = render(&config, RulesFormat::Rules, "homewizard", 2.0).unwrap();
+        let value: serde_yaml::Value = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(value["groups"][0]["name"], "homewizard");
+        assert_eq!(value["groups"][0]["rules"][0]["for"], "3m");
+        assert_eq!(
+            value["groups"][0]["rules"][0]["labels"]["severity"],
+            "warning"
+        );
+
+        let yaml = render(&config, RulesFormat::PrometheusRule, "water", 2.0).unwrap();
+        let value: serde_yaml::Value = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(value["apiVersion"], "monitoring.coreos.com/v1");
+        assert_eq!(value["kind"], "PrometheusRule");
+        assert_eq!(value["metadata"]["name"], "water");
+        assert_eq!(value["spec"]["groups"][0]["name"], "water");
+    }
+}
@@ -0,0 +1,146 @@
+use crate::cloud::DataSource;
+use crate::homewizard::Reading;
+use crate::logging::{RotatingFile, Rotation};
+use std::io::{self, Write};
+use std::path::Path;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Columns of the CSV archive; values a device type does not report are left empty.
+pub const HEADER: &str = "timestamp,device,type,source,total_liter_m3,active_liter_lpm,total_power_import_kwh,total_power_export_kwh,active_power_w,total_gas_m3\n";
+
+/// Appends every successful poll as a row to a CSV file (`--csv-file`).
+pub struct CsvArchive {
+    file: RotatingFile,
+}
+
+impl CsvArchive {
+    /// Open `path` for appending, rotated like the log file.
+    pub fn open(path: &Path, max_bytes: u64, keep: usize, rotation: Rotation) -> io::Result<Self> {
+        Ok(Self {
+            file: RotatingFile::open(path, max_bytes, keep, rotation)?.with_header(HEADER),
+        })
+    }
+
+    pub fn append(&self, device: &str, reading: &Reading, source: DataSource) -> io::Result<()> {
+        let timestamp = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        let line = row(&timestamp, device, reading, source);
+        let mut writer = self.file.make_writer();
+        writer.write_all(line.as_bytes())?;
+        writer.flush()
+    }
+}
+
+fn row(timestamp: &str, device: &str, reading: &Reading, source: DataSource) -> String {
+    let (water_total, flow, import, export, power, gas) = match reading {
+        Reading::Water(data) => (
+            Some(data.total_liter_m3),
+            data.active_liter_lpm,
+            None,
+            None,
+            None,
+            None,
+        ),
+        Reading::P1(data) => (
+            None,
+            None,
+            Some(data.total_power_import_kwh),
+            data.total_power_export_kwh,
+            data.active_power_w,
+            data.total_gas_m3,
+        ),
+        Reading::EnergySocket(data) => (
+            None,
+            None,
+            Some(data.total_power_import_kwh),
+            data.total_power_export_kwh,
+            data.active_power_w,
+            None,
+        ),
+        Reading::Kwh(data) => (
+            None,
+            None,
+            Some(data.total_power_import_kwh),
+            data.total_power_export_kwh,
+            data.active_power_w,
+            None,
+        ),
+    };
+    let values = [water_total, flow, import, export, power, gas]
+        .map(|value| value.map(|value| value.to_string()).unwrap_or_default());
+    format!(
+        "{timestamp},{},{},{},{}\n",
+        field(device),
+        reading.device_type().as_str(),
+        source.as_str(),
+        values.join(",")
+    )
+}
+
+/// `value` quoted if it contains a separator, quote or line break.
+fn field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::homewizard::{HomeWizardP1Data, HomeWizardWaterData};
+
+    #[test]
+    fn test_rows() {
+        let water = Reading::Water(HomeWizardWaterData {
+            total_liter_m3: 42.5,
+            active_liter_lpm: Some(3.25),
+            ..Default::default()
+        });
+        assert_eq!(
+            row("2024-05-01T12:00:00Z", "kitchen", &water, DataSource::Local),
+            "2024-05-01T12:00:00Z,kitchen,water,local,42.5,3.25,,,,\n"
+        );
+
+        let p1 = Reading::P1(HomeWizardP1Data {
+            total_power_import_kwh: 1200.0,
+            total_power_export_kwh: Some(30.5),
+            active_power_w: Some(-250.0),
+            ..Default::default()
+        });
+        assert_eq!(
+            row("2024-05-01T12:00:00Z", "meter", &p1, DataSource::Cloud),
+            "2024-05-01T12:00:00Z,meter,p1,cloud,,,1200,30.5,-250,\n"
+        );
+    }
+
+    #[test]
+    fn test_field_quoting() {
+        assert_eq!(field("kitchen"), "kitchen");
+        assert_eq!(field("sink, left"), "\"sink, left\"");
+        assert_eq!(field("the \"big\" one"), "\"the \"\"big\"\" one\"");
+    }
+
+    #[test]
+    fn test_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("readings.csv");
+        let archive = CsvArchive::open(&path, 0, 1, Rotation::Never).unwrap();
+        let water = Reading::Water(HomeWizardWaterData {
+            total_liter_m3: 1.0,
+            ..Default::default()
+        });
+        archive
+            .append("kitchen", &water, DataSource::Local)
+            .unwrap();
+        archive
+            .append("kitchen", &water, DataSource::Local)
+            .unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(format!("{}\n", lines[0]), HEADER);
+        assert!(lines[1].ends_with(",kitchen,water,local,1,,,,,"));
+    }
+}
@@ -0,0 +1,225 @@
+use anyhow::{Context, Result, bail};
+use axum::extract::{Request, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use subtle::ConstantTimeEq;
+
+/// Credentials a scraper must present (`--metrics-basic-auth`, `--metrics-bearer-token-file`).
+///
+/// Either a known user with the right password or the bearer token is accepted,
+/// as with the `basic_auth_users` of the Prometheus web configuration.
+#[derive(Debug)]
+pub struct ScrapeAuth {
+    // bcrypt hash per user
+    users: HashMap<String, String>,
+    bearer_token: Option<String>,
+    // bcrypt is slow on purpose; remember digests of credentials that matched
+    verified: Mutex<HashSet<[u8; 32]>>,
+}
+
+impl ScrapeAuth {
+    /// `None` when no credentials are configured, leaving the endpoints open.
+    pub fn new(
+        users: &[(String, String)],
+        bearer_token_file: Option<&Path>,
+    ) -> Result<Option<Self>> {
+        let bearer_token = match bearer_token_file {
+            Some(path) => {
+                let token = std::fs::read_to_string(path).with_context(|| {
+                    format!("Failed to read bearer token from {}", path.display())
+                })?;
+                let token = token.trim().to_string();
+                if token.is_empty() {
+                    bail!("Bearer token file {} is empty", path.display());
+                }
+                Some(token)
+            }
+            None => None,
+        };
+        if users.is_empty() && bearer_token.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            users: users.iter().cloned().collect(),
+            bearer_token,
+            verified: Mutex::new(HashSet::new()),
+        }))
+    }
+
+    /// Whether the `Authorization` header carries valid credentials.
+    pub fn allows(&self, headers: &HeaderMap) -> bool {
+        let Some(authorization) = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return false;
+        };
+        if let Some(token) = authorization.strip_prefix("Bearer ") {
+            return self
+                .bearer_token
+                .as_ref()
+                .is_some_and(|expected| bool::from(expected.as_bytes().ct_eq(token.as_bytes())));
+        }
+        let Some((user, password)) = authorization
+            .strip_prefix("Basic ")
+            .and_then(|encoded| STANDARD.decode(encoded).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| {
+                decoded
+                    .split_once(':')
+                    .map(|(user, password)| (user.to_string(), password.to_string()))
+            })
+        else {
+            return false;
+        };
+        self.verify(&user, &password)
+    }
+
+    fn verify(&self, user: &str, password: &str) -> bool {
+        let Some(hash) = self.users.get(user) else {
+            return false;
+        };
+        let digest: [u8; 32] = Sha256::new()
+            .chain_update(user)
+            .chain_update([0])
+            .chain_update(password)
+            .finalize()
+            .into();
+        if self.verified.lock().unwrap().contains(&digest) {
+            return true;
+        }
+        let valid = bcrypt::verify(password, hash).unwrap_or(false);
+        if valid {
+            self.verified.lock().unwrap().insert(digest);
+        }
+        valid
+    }
+
+    fn challenge(&self) -> &'static str {
+        if self.users.is_empty() {
+            "Bearer"
+        } else {
+            "Basic realm=\"homewizard-exporter\""
+        }
+    }
+}
+
+/// Middleware rejecting requests without valid credentials with 401.
+pub async fn require_auth(
+    State(auth): State<Arc<ScrapeAuth>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if auth.allows(request.headers()) {
+        return next.run(request).await;
+    }
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, auth.challenge())],
+        "Unauthorized\n",
+    )
+        .into_response()
+}
+
+/// Parse a `user:bcrypt-hash` pair for `--metrics-basic-auth`.
+pub fn parse_basic_auth(s: &str) -> Result<(String, String), String> {
+    let (user, hash) = s
+        .split_once(':')
+        .ok_or_else(|| format!("invalid user '{s}', expected user:bcrypt-hash"))?;
+    if user.is_empty() {
+        return Err(format!("invalid user '{s}', the user name is empty"));
+    }
+    if !hash.starts_with("$2") {
+        return Err(format!(
+            "invalid hash for user '{user}', expected a bcrypt hash (e.g. from htpasswd -nBC 10)"
+        ));
+    }
+    Ok((user.to_string(), hash.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use std::io::Write;
+
+    // bcrypt of "secret" at the minimum cost, to keep the tests fast
+    fn users() -> Vec<(String, String)> {
+        vec![("prometheus".to_string(), bcrypt::hash("secret", 4).unwrap())]
+    }
+
+    fn headers(authorization: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(authorization).unwrap(),
+        );
+        headers
+    }
+
+    fn basic(user: &str, password: &str) -> HeaderMap {
+        headers(&format!(
+            "Basic {}",
+            STANDARD.encode(format!("{user}:{password}"))
+        ))
+    }
+
+    #[test]
+    fn test_disabled_without_credentials() {
+        assert!(ScrapeAuth::new(&[], None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_basic_auth() {
+        let auth = ScrapeAuth::new(&users(), None).unwrap().unwrap();
+
+        assert!(auth.allows(&basic("prometheus", "secret")));
+        // Served from the cache the second time
+        assert!(auth.allows(&basic("prometheus", "secret")));
+        assert!(!auth.allows(&basic("prometheus", "wrong")));
+        assert!(!auth.allows(&basic("grafana", "secret")));
+        assert!(!auth.allows(&headers("Basic not-base64")));
+        assert!(!auth.allows(&HeaderMap::new()));
+        assert!(!auth.allows(&headers("Bearer secret")));
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "s3cr3t-token").unwrap();
+        let auth = ScrapeAuth::new(&[], Some(file.path())).unwrap().unwrap();
+
+        assert!(auth.allows(&headers("Bearer s3cr3t-token")));
+        assert!(!auth.allows(&headers("Bearer s3cr3t")));
+        assert!(!auth.allows(&basic("prometheus", "s3cr3t-token")));
+        assert_eq!(auth.challenge(), "Bearer");
+    }
+
+    #[test]
+    fn test_bearer_token_file_errors() {
+        assert!(ScrapeAuth::new(&[], Some(Path::new("/nonexistent/token"))).is_err());
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let error = ScrapeAuth::new(&[], Some(file.path())).unwrap_err();
+        assert!(error.to_string().contains("empty"));
+    }
+
+    #[test]
+    fn test_parse_basic_auth() {
+        let hash = "$2y$10$X0sHTqNK0Qa4OaLnNwPNe.4B3UYDhqeEXpdm6Oa5y5cBaQPIKN2s.";
+        assert_eq!(
+            parse_basic_auth(&format!("prometheus:{hash}")),
+            Ok(("prometheus".to_string(), hash.to_string()))
+        );
+        assert!(parse_basic_auth("prometheus").is_err());
+        assert!(parse_basic_auth(&format!(":{hash}")).is_err());
+        assert!(parse_basic_auth("prometheus:plaintext").is_err());
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Rolling averages of the flow over fixed windows, per device.
+///
+/// The average is the mean of the samples taken in the window, one per poll; a
+/// window shorter than the poll interval holds only the latest sample.
+#[derive(Debug)]
+pub struct FlowAverages {
+    windows: Vec<Duration>,
+    samples: HashMap<String, VecDeque<(Instant, f64)>>,
+}
+
+impl FlowAverages {
+    pub fn new(windows: Vec<Duration>) -> Self {
+        Self {
+            windows,
+            samples: HashMap::new(),
+        }
+    }
+
+    pub fn windows(&self) -> &[Duration] {
+        &self.windows
+    }
+
+    /// Record a flow sample; returns the average per window, in the order they were configured.
+    pub fn observe(&mut self, device: &str, flow_lpm: f64, now: Instant) -> Vec<(Duration, f64)> {
+        let longest = self.windows.iter().max().copied().unwrap_or_default();
+        let samples = self.samples.entry(device.to_string()).or_default();
+        samples.push_back((now, flow_lpm));
+        while let Some((at, _)) = samples.front()
+            && now.duration_since(*at) >= longest
+            && samples.len() > 1
+        {
+            samples.pop_front();
+        }
+
+        self.windows
+            .iter()
+            .map(|&window| {
+                let in_window: Vec<f64> = samples
+                    .iter()
+                    .filter(|(at, _)| now.duration_since(*at) < window)
+                    .map(|(_, flow)| *flow)
+                    .collect();
+                // The latest sample always counts, however short the window
+                let average = if in_window.is_empty() {
+                    flow_lpm
+                } else {
+                    in_window.iter().sum::<f64>() / in_window.len() as f64
+                };
+                (window, average)
+            })
+            .collect()
+    }
+
+    /// Drop the samples of a device whose flow is unknown.
+    pub fn forget(&mut self, device: &str) {
+        self.samples.remove(device);
+    }
+}
+
+/// The `window` label for a window: `30s`, `5m`, `1h`.
+pub fn window_label(window: Duration) -> String {
+    let secs = window.as_secs();
+    if secs > 0 && secs.is_multiple_of(3600) {
+        format!("{}h", secs / 3600)
+    } else if secs > 0 && secs.is_multiple_of(60) {
+        format!("{}m", secs / 60)
+    } else {
+        format!("{secs}s")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn averages() -> FlowAverages {
+        FlowAverages::new(vec![Duration::from_secs(60), Duration::from_secs(300)])
+    }
+
+    #[test]
+    fn test_averages_per_window() {
+        let mut averages = averages();
+        let start = Instant::now();
+
+        averages.observe("meter", 10.0, start);
+        averages.observe("meter", 0.0, start + Duration::from_secs(60));
+        let result = averages.observe("meter", 5.0, start + Duration::from_secs(120));
+
+        assert_eq!(
+            result,
+            vec![
+                (Duration::from_secs(60), 5.0),
+                (Duration::from_secs(300), 5.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_old_samples_drop_out() {
+        let mut averages = averages();
+        let start = Instant::now();
+
+        averages.observe("meter", 30.0, start);
+        let result = averages.observe("meter", 0.0, start + Duration::from_secs(300));
+
+        assert_eq!(
+            result,
+            vec![
+                (Duration::from_secs(60), 0.0),
+                (Duration::from_secs(300), 0.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_forget() {
+        let mut averages = averages();
+        let start = Instant::now();
+
+        averages.observe("meter", 30.0, start);
+        averages.forget("meter");
+        let result = averages.observe("meter", 3.0, start + Duration::from_secs(10));
+
+        assert_eq!(result[1], (Duration::from_secs(300), 3.0));
+    }
+
+    #[test]
+    fn test_window_label() {
+        assert_eq!(window_label(Duration::from_secs(60)), "1m");
+        assert_eq!(window_label(Duration::from_secs(900)), "15m");
+        assert_eq!(window_label(Duration::from_secs(3600)), "1h");
+        assert_eq!(window_label(Duration::from_secs(90)), "90s");
+    }
+}
@@ -0,0 +1,107 @@
+use std::time::{Duration, Instant};
+
+/// Backs off from a device that keeps failing.
+///
+/// After `threshold` consecutive failures the breaker opens and the device is
+/// only tried once per `probe_interval` instead of on every poll; the first
+/// success closes it again.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    probe_interval: Duration,
+    failures: u32,
+    last_failure: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    /// A `threshold` of 0 disables the breaker.
+    pub fn new(threshold: u32, probe_interval: Duration) -> Self {
+        Self {
+            threshold,
+            probe_interval,
+            failures: 0,
+            last_failure: None,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.threshold > 0 && self.failures >= self.threshold
+    }
+
+    /// Whether the device should be polled at `now`.
+    pub fn allow(&self, now: Instant) -> bool {
+        match self.last_failure {
+            Some(at) if self.is_open() => now.duration_since(at) >= self.probe_interval,
+            _ => true,
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        self.failures = 0;
+        self.last_failure = None;
+    }
+
+    pub fn record_failure(&mut self, now: Instant) {
+        self.failures = self.failures.saturating_add(1);
+        self.last_failure = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROBE: Duration = Duration::from_secs(300);
+
+    #[test]
+    fn test_opens_after_threshold() {
+        let mut breaker = CircuitBreaker::new(3, PROBE);
+        let start = Instant::now();
+
+        breaker.record_failure(start);
+        breaker.record_failure(start);
+        assert!(!breaker.is_open());
+        assert!(breaker.allow(start));
+
+        breaker.record_failure(start);
+        assert!(breaker.is_open());
+        assert!(!breaker.allow(start + Duration::from_secs(60)));
+        assert!(breaker.allow(start + PROBE));
+    }
+
+    #[test]
+    fn test_failed_probe_keeps_it_open() {
+        let mut breaker = CircuitBreaker::new(1, PROBE);
+        let start = Instant::now();
+
+        breaker.record_failure(start);
+        breaker.record_failure(start + PROBE);
+        assert!(breaker.is_open());
+        assert!(!breaker.allow(start + PROBE + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_success_closes() {
+        let mut breaker = CircuitBreaker::new(1, PROBE);
+        let start = Instant::now();
+
+        breaker.record_failure(start);
+        assert!(breaker.is_open());
+
+        breaker.record_success();
+        assert!(!breaker.is_open());
+        assert!(breaker.allow(start));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut breaker = CircuitBreaker::new(0, PROBE);
+        let start = Instant::now();
+
+        for _ in 0..100 {
+            breaker.record_failure(start);
+        }
+        assert!(!breaker.is_open());
+        assert!(breaker.allow(start));
+    }
+}
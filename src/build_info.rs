@@ -0,0 +1,48 @@
+use serde::Serialize;
+
+/// What this binary was built from, as served by `/version` and exported as
+/// `homewizard_exporter_build_info`. Filled in by the build script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// Abbreviated commit hash, or `unknown` when built outside a git checkout
+    pub git_sha: &'static str,
+    /// `YYYY-MM-DD`, in UTC
+    pub build_date: &'static str,
+    pub rustc: &'static str,
+}
+
+pub const BUILD_INFO: BuildInfo = BuildInfo {
+    version: env!("CARGO_PKG_VERSION"),
+    git_sha: env!("BUILD_GIT_SHA"),
+    build_date: env!("BUILD_DATE"),
+    rustc: env!("BUILD_RUSTC_VERSION"),
+};
+
+impl BuildInfo {
+    /// Label names of the build info metric, in the order of [`BuildInfo::label_values`].
+    pub const LABELS: [&'static str; 4] = ["version", "git_sha", "build_date", "rustc"];
+
+    pub fn label_values(&self) -> [&'static str; 4] {
+        [self.version, self.git_sha, self.build_date, self.rustc]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_info() {
+        assert_eq!(BUILD_INFO.version, env!("CARGO_PKG_VERSION"));
+        assert!(!BUILD_INFO.git_sha.is_empty());
+        let date = BUILD_INFO.build_date.as_bytes();
+        assert_eq!((date.len(), date[4], date[7]), (10, b'-', b'-'));
+        assert!(BUILD_INFO.rustc.starts_with('1'));
+
+        let json = serde_json::to_value(BUILD_INFO).unwrap();
+        for (label, value) in BuildInfo::LABELS.iter().zip(BUILD_INFO.label_values()) {
+            assert_eq!(json[label], value);
+        }
+    }
+}
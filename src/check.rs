@@ -0,0 +1,359 @@
+use crate::auth::ScrapeAuth;
+use crate::config::{Config, ListenAddress};
+use crate::heartbeat::Heartbeat;
+use crate::homewizard::HomeWizardClient;
+#[cfg(feature = "https")]
+use crate::https::TlsConfig;
+#[cfg(feature = "influxdb")]
+use crate::influxdb::InfluxWriter;
+use crate::metrics::Metrics;
+#[cfg(feature = "remote-write")]
+use crate::remote_write;
+use crate::resolver::CachingResolver;
+use crate::state;
+use crate::vmimport::VmImporter;
+use std::collections::HashSet;
+use std::fmt;
+
+/// Result of one step of the `check` subcommand, printed as one summary line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub subject: String,
+    /// What was found, or what is wrong.
+    pub outcome: Result<String, String>,
+}
+
+impl Finding {
+    fn new(subject: impl Into<String>, outcome: anyhow::Result<String>) -> Self {
+        Self {
+            subject: subject.into(),
+            outcome: outcome.map_err(|e| format!("{e:#}")),
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.outcome.is_ok()
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.outcome {
+            Ok(detail) => write!(f, "ok    {}: {}", self.subject, detail),
+            Err(error) => write!(f, "FAIL  {}: {}", self.subject, error),
+        }
+    }
+}
+
+/// Check what the exporter would otherwise only find out at startup: files it
+/// reads, credentials, labels and the other settings that are validated late.
+pub fn check_config(config: &Config) -> Vec<Finding> {
+    let mut findings = vec![Finding::new(
+        "configuration",
+        Ok(match &config.config {
+            Some(path) => format!("loaded {}", path.display()),
+            None => "from the command line and environment".to_string(),
+        }),
+    )];
+
+    let devices = config.devices();
+    let mut names = HashSet::new();
+    let duplicates: Vec<&str> = devices
+        .iter()
+        .map(|device| device.name())
+        .filter(|name| !names.insert(*name))
+        .collect();
+    findings.push(Finding::new(
+        "devices",
+        if !duplicates.is_empty() {
+            Err(anyhow::anyhow!(
+                "duplicate device names: {}",
+                duplicates.join(", ")
+            ))
+        } else if devices.is_empty() {
+            Ok("none".to_string())
+        } else {
+            Ok(devices
+                .iter()
+                .map(|device| match &device.alias {
+                    Some(alias) => format!("{alias} ({})", device.address),
+                    None => device.address.clone(),
+                })
+                .collect::<Vec<_>>()
+                .join(", "))
+        },
+    ));
+
+    let listen = config.listen_address();
+    #[cfg(feature = "https")]
+    let tls = config.tls_files().map(TlsConfig::load).transpose();
+    // Without HTTPS support the configuration rejects the certificate options
+    #[cfg(not(feature = "https"))]
+    let tls = anyhow::Ok(None::<()>);
+    findings.push(Finding::new(
+        "listen",
+        tls.map(|tls| match (&listen, tls) {
+            (ListenAddress::Tcp(_), Some(_)) => format!("{listen} (HTTPS)"),
+            _ => format!("{listen} (HTTP)"),
+        }),
+    ));
+
+    findings.push(Finding::new(
+        "scrape auth",
+        ScrapeAuth::new(
+            &config.metrics_basic_auth,
+            config.metrics_bearer_token_file.as_deref(),
+        )
+        .map(|auth| {
+            if auth.is_some() {
+                "enabled"
+            } else {
+                "disabled"
+            }
+            .to_string()
+        }),
+    ));
+
+    findings.push(Finding::new(
+        "device token",
+        config
+            .device_token()
+            .map(|token| if token.is_some() { "set" } else { "not set" }.to_string()),
+    ));
+
+    findings.push(Finding::new(
+        "metrics",
+        Metrics::with_labels(config.labels.iter().cloned().collect())
+            .and_then(|metrics| metrics.with_flow_buckets(&config.flow_buckets))
+            .map(|_| format!("{} extra labels", config.labels.len())),
+    ));
+
+    if let Some(path) = &config.state_file {
+        findings.push(Finding::new(
+            "state file",
+            state::load(path).map(|snapshot| match snapshot {
+                Some(_) => format!("{} is readable", path.display()),
+                None => format!("{} will be created", path.display()),
+            }),
+        ));
+    }
+
+    if let Some(url) = &config.heartbeat_url {
+        findings.push(Finding::new(
+            "heartbeat",
+            Heartbeat::new(
+                url.clone(),
+                config.heartbeat_fail_url.clone(),
+                config.http_timeout,
+            )
+            .map(|_| url.clone()),
+        ));
+    }
+
+    #[cfg(feature = "influxdb")]
+    if let (Some(url), Some(target)) = (&config.influxdb_url, config.influxdb_target()) {
+        findings.push(Finding::new(
+            "InfluxDB",
+            InfluxWriter::new(url, &target, None, config.http_timeout)
+                .map(|writer| writer.url().to_string()),
+        ));
+    }
+
+    #[cfg(feature = "mqtt")]
+    if let Some(url) = &config.mqtt_url {
+        findings.push(Finding::new(
+            "MQTT",
+            config.mqtt_options().map(|_| url.clone()),
+        ));
+    }
+
+    #[cfg(feature = "kafka")]
+    if !config.kafka_brokers.is_empty() {
+        findings.push(Finding::new(
+            "Kafka",
+            config
+                .kafka_options()
+                .map(|_| config.kafka_brokers.join(", ")),
+        ));
+    }
+
+    #[cfg(feature = "notifications")]
+    match config.notification_channels() {
+        Ok(channels) if channels.is_empty() => {}
+        result => findings.push(Finding::new(
+            "notifications",
+            result.map(|channels| {
+                channels
+                    .iter()
+                    .map(|channel| channel.service.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            }),
+        )),
+    }
+
+    #[cfg(feature = "nats")]
+    if let Some(url) = &config.nats_url {
+        findings.push(Finding::new(
+            "NATS",
+            config.nats_options().map(|_| url.clone()),
+        ));
+    }
+
+    if let Some(url) = &config.vm_import_url {
+        findings.push(Finding::new(
+            "VictoriaMetrics",
+            VmImporter::new(url, None, config.http_timeout)
+                .map(|importer| importer.url().to_string()),
+        ));
+    }
+
+    #[cfg(feature = "remote-write")]
+    if let Some(url) = &config.remote_write_url {
+        findings.push(Finding::new(
+            "remote write",
+            remote_write::validate_url(url).map(|_| url.clone()),
+        ));
+    }
+
+    findings
+}
+
+/// Connect to each configured device and fetch its info and a reading.
+pub async fn check_devices(config: &Config) -> Vec<Finding> {
+    let token = match config.device_token() {
+        Ok(token) => token,
+        // Already reported by `check_config`
+        Err(_) => return Vec::new(),
+    };
+    let mut findings = Vec::new();
+    for device in config.devices() {
+        let timeout = config.http_timeout;
+        let api_version = config.api_version_for(&device.address).await;
+        let url = api_version.data_url(&device.address);
+        let outcome = async {
+            let client = tokio::task::spawn_blocking(move || {
+                HomeWizardClient::with_api_version(
+                    url,
+                    timeout,
+                    CachingResolver::new(),
+                    api_version,
+                )
+            })
+            .await??
+            .with_device_type(device.device_type)
+            .with_retry(config.retry_policy());
+            let client = match &token {
+                Some(token) => client.with_token(token.clone()),
+                None => client,
+            };
+            let info = client.fetch_device_info().await?;
+            client.fetch_reading().await?;
+            anyhow::Ok(format!(
+                "{} ({}), firmware {}, API {}, reading ok",
+                info.product_name,
+                info.product_type,
+                info.firmware_version,
+                api_version.as_str()
+            ))
+        }
+        .await;
+        findings.push(Finding::new(format!("device {}", device.name()), outcome));
+    }
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn config(args: &[&str]) -> Config {
+        Config::try_parse_from(
+            std::iter::once("homewizard-water-exporter").chain(args.iter().copied()),
+        )
+        .unwrap()
+    }
+
+    fn finding<'a>(findings: &'a [Finding], subject: &str) -> &'a Finding {
+        findings
+            .iter()
+            .find(|finding| finding.subject == subject)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_check_config() {
+        let findings = check_config(&config(&["--host", "kitchen=192.168.1.5,192.168.1.6"]));
+
+        assert!(findings.iter().all(Finding::is_ok));
+        assert_eq!(
+            finding(&findings, "devices").to_string(),
+            "ok    devices: kitchen (192.168.1.5), 192.168.1.6"
+        );
+        assert_eq!(
+            finding(&findings, "listen").to_string(),
+            "ok    listen: 0.0.0.0:9899 (HTTP)"
+        );
+    }
+
+    #[test]
+    fn test_check_config_problems() {
+        let findings = check_config(&config(&[
+            "--host",
+            "kitchen=192.168.1.5,kitchen=192.168.1.6",
+            "--tls-cert",
+            "/nonexistent/cert.pem",
+            "--tls-key",
+            "/nonexistent/key.pem",
+            "--metrics-bearer-token-file",
+            "/nonexistent/token",
+        ]));
+
+        assert_eq!(
+            finding(&findings, "devices").to_string(),
+            "FAIL  devices: duplicate device names: kitchen"
+        );
+        #[cfg(feature = "https")]
+        assert!(!finding(&findings, "listen").is_ok());
+        assert!(!finding(&findings, "scrape auth").is_ok());
+        assert!(finding(&findings, "device token").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_check_devices() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "product_type": "HWE-WTR",
+                "product_name": "Watermeter",
+                "serial": "5c2fafabcdef",
+                "firmware_version": "2.03",
+                "api_version": "v1"
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/data"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "total_liter_m3": 42.5,
+                "active_liter_lpm": 0.0,
+                "total_liter_offset_m3": 0
+            })))
+            .mount(&server)
+            .await;
+
+        let host = format!("garden={},127.0.0.1:1", server.address());
+        let findings = check_devices(&config(&["--host", &host, "--fetch-retries", "0"])).await;
+
+        assert_eq!(
+            findings[0].to_string(),
+            "ok    device garden: Watermeter (HWE-WTR), firmware 2.03, API v1, reading ok"
+        );
+        assert_eq!(findings[1].subject, "device 127.0.0.1:1");
+        assert!(!findings[1].is_ok());
+    }
+}
@@ -0,0 +1,196 @@
+use crate::homewizard::{HomeWizardClient, HomeWizardError, Reading};
+use std::sync::atomic::{AtomicU32, Ordering};
+use tracing::{info, warn};
+
+/// Where a reading came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataSource {
+    Local,
+    Cloud,
+}
+
+impl DataSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DataSource::Local => "local",
+            DataSource::Cloud => "cloud",
+        }
+    }
+}
+
+/// Polls the local API and falls back to the HomeWizard cloud after repeated failures.
+///
+/// The local API is still tried on every poll while the cloud is in use, so the
+/// exporter switches back as soon as the device is reachable again.
+pub struct FallbackClient {
+    local: HomeWizardClient,
+    cloud: Option<HomeWizardClient>,
+    fallback_after: u32,
+    failures: AtomicU32,
+}
+
+impl FallbackClient {
+    pub fn new(
+        local: HomeWizardClient,
+        cloud: Option<HomeWizardClient>,
+        fallback_after: u32,
+    ) -> Self {
+        Self {
+            local,
+            cloud,
+            fallback_after: fallback_after.max(1),
+            failures: AtomicU32::new(0),
+        }
+    }
+
+    /// Client for the local API of the device.
+    pub fn local(&self) -> &HomeWizardClient {
+        &self.local
+    }
+
+    pub async fn fetch_data(&self) -> Result<(Reading, DataSource), HomeWizardError> {
+        let local_error = match self.local.fetch_reading().await {
+            Ok(data) => {
+                if self.failures.swap(0, Ordering::Relaxed) >= self.fallback_after
+                    && self.cloud.is_some()
+                {
+                    info!("Local API reachable again, switching back from cloud");
+                }
+                return Ok((data, DataSource::Local));
+            }
+            Err(e) => e,
+        };
+
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        let Some(cloud) = &self.cloud else {
+            return Err(local_error);
+        };
+        if failures < self.fallback_after {
+            return Err(local_error);
+        }
+
+        if failures == self.fallback_after {
+            warn!(
+                "Local API failed {} times in a row, falling back to cloud: {}",
+                failures, local_error
+            );
+        }
+        cloud
+            .fetch_reading()
+            .await
+            .map(|data| (data, DataSource::Cloud))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn reading(total: f64) -> serde_json::Value {
+        serde_json::json!({
+            "wifi_ssid": "TestNetwork",
+            "wifi_strength": 75.5,
+            "total_liter_m3": total,
+            "active_liter_lpm": 0.0,
+            "total_liter_offset_m3": 0.0
+        })
+    }
+
+    fn total(reading: Reading) -> f64 {
+        match reading {
+            Reading::Water(data) => data.total_liter_m3,
+            other => panic!("unexpected reading {other:?}"),
+        }
+    }
+
+    fn client(url: String) -> HomeWizardClient {
+        HomeWizardClient::new(url, Duration::from_secs(1)).unwrap()
+    }
+
+    async fn cloud_server() -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/cloud/meter"))
+            .and(header("Authorization", "Bearer token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(reading(2.0)))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    #[test]
+    fn test_data_source_as_str() {
+        assert_eq!(DataSource::Local.as_str(), "local");
+        assert_eq!(DataSource::Cloud.as_str(), "cloud");
+    }
+
+    #[tokio::test]
+    async fn test_local_success_uses_local() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/data"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(reading(1.0)))
+            .mount(&server)
+            .await;
+
+        let fallback =
+            FallbackClient::new(client(format!("{}/api/v1/data", server.uri())), None, 3);
+        let (reading, source) = fallback.fetch_data().await.unwrap();
+        assert_eq!(total(reading), 1.0);
+        assert_eq!(source, DataSource::Local);
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_after_threshold() {
+        let cloud = cloud_server().await;
+        let fallback = FallbackClient::new(
+            client("http://127.0.0.1:1/api/v1/data".to_string()),
+            Some(client(format!("{}/cloud/meter", cloud.uri())).with_token("token")),
+            2,
+        );
+
+        assert!(fallback.fetch_data().await.is_err());
+        let (reading, source) = fallback.fetch_data().await.unwrap();
+        assert_eq!(total(reading), 2.0);
+        assert_eq!(source, DataSource::Cloud);
+    }
+
+    #[tokio::test]
+    async fn test_without_cloud_keeps_failing() {
+        let fallback = FallbackClient::new(
+            client("http://127.0.0.1:1/api/v1/data".to_string()),
+            None,
+            1,
+        );
+        assert!(fallback.fetch_data().await.is_err());
+        assert!(fallback.fetch_data().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_switches_back_to_local() {
+        let cloud = cloud_server().await;
+        let local = MockServer::start().await;
+        let down = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount_as_scoped(&local)
+            .await;
+
+        let fallback = FallbackClient::new(
+            client(format!("{}/api/v1/data", local.uri())),
+            Some(client(format!("{}/cloud/meter", cloud.uri())).with_token("token")),
+            1,
+        );
+        assert_eq!(fallback.fetch_data().await.unwrap().1, DataSource::Cloud);
+
+        drop(down);
+        Mock::given(method("GET"))
+            .and(path("/api/v1/data"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(reading(1.0)))
+            .mount(&local)
+            .await;
+        assert_eq!(fallback.fetch_data().await.unwrap().1, DataSource::Local);
+    }
+}
@@ -0,0 +1,946 @@
+use crate::homewizard::{HomeWizardWaterData, Reading};
+use crate::metrics::{GALLONS_PER_M3, Units, set_counter};
+use crate::state::{AggregateUsage, CounterSample};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{CounterVec, GaugeVec, Opts};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// How readings are turned into series.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReadingOptions {
+    pub units: Units,
+    /// Calibration added to the net total; `None` leaves it out (`--net-total`)
+    pub net_total_calibration: Option<f64>,
+    /// `--disable-wifi-metrics` turns off the Wi-Fi series and the SSID info metric
+    pub wifi_metrics: bool,
+    /// Also export the sums over all water meters (`--aggregate`)
+    pub aggregate: bool,
+}
+
+impl Default for ReadingOptions {
+    fn default() -> Self {
+        Self {
+            units: Units::Metric,
+            net_total_calibration: None,
+            wifi_metrics: true,
+            aggregate: false,
+        }
+    }
+}
+
+/// Holds the latest reading of every device and turns it into metric families
+/// at gather time.
+///
+/// Nothing is kept per series, so a device going away or changing its SSID only
+/// needs its reading replaced or dropped.
+#[derive(Clone)]
+pub struct ReadingCollector {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    readings: Mutex<BTreeMap<String, Reading>>,
+    // Counters restored from the state file, served until the device's first reading
+    restored: Mutex<Vec<CounterSample>>,
+    options: Mutex<ReadingOptions>,
+    aggregate: Mutex<AggregateTotal>,
+    descs: Vec<Desc>,
+}
+
+/// The water used by all meters, summed from the increase of each meter's
+/// total, so the aggregate counter never goes down when a meter drops out.
+#[derive(Default)]
+struct AggregateTotal {
+    // Last total of every meter seen, kept when its reading is dropped
+    totals: BTreeMap<String, f64>,
+    sum: f64,
+}
+
+impl AggregateTotal {
+    fn add(&mut self, device: &str, total: f64) {
+        // A new meter brings its whole total; after a reset it counts up from zero
+        let used = match self.totals.insert(device.to_string(), total) {
+            Some(previous) if total >= previous => total - previous,
+            _ => total,
+        };
+        self.sum += used;
+    }
+}
+
+impl ReadingCollector {
+    pub fn new() -> prometheus::Result<Self> {
+        let descs = ReadingFamilies::new()?
+            .collectors()
+            .into_iter()
+            .flat_map(|collector| collector.desc().into_iter().cloned())
+            .collect();
+        Ok(Self {
+            inner: Arc::new(Inner {
+                readings: Mutex::new(BTreeMap::new()),
+                restored: Mutex::new(Vec::new()),
+                options: Mutex::new(ReadingOptions::default()),
+                aggregate: Mutex::new(AggregateTotal::default()),
+                descs,
+            }),
+        })
+    }
+
+    pub fn set_options(&self, update: impl FnOnce(&mut ReadingOptions)) {
+        update(&mut self.inner.options.lock().unwrap());
+    }
+
+    /// Replace the latest reading of `device`.
+    pub fn update(&self, device: &str, mut reading: Reading) {
+        self.forget_restored(device);
+        if let Reading::Water(data) = &reading {
+            self.inner
+                .aggregate
+                .lock()
+                .unwrap()
+                .add(device, data.total_liter_m3);
+        }
+        let mut readings = self.inner.readings.lock().unwrap();
+        if let Some(previous) = readings.get(device) {
+            keep_network(&mut reading, previous);
+        }
+        readings.insert(device.to_string(), reading);
+    }
+
+    /// Drop everything known about `device`, until its next reading.
+    pub fn remove(&self, device: &str) {
+        self.forget_restored(device);
+        self.inner.readings.lock().unwrap().remove(device);
+    }
+
+    /// Serve the counters of these families from a snapshot until the devices
+    /// report again; samples of other families are ignored.
+    pub fn restore(&self, samples: &[CounterSample]) {
+        let names: Vec<String> = self
+            .inner
+            .descs
+            .iter()
+            .map(|desc| desc.fq_name.clone())
+            .collect();
+        let mut restored = self.inner.restored.lock().unwrap();
+        restored.extend(
+            samples
+                .iter()
+                .filter(|sample| names.contains(&sample.name))
+                .cloned(),
+        );
+    }
+
+    /// The water used by all meters, to save in the state file.
+    pub fn aggregate_usage(&self) -> AggregateUsage {
+        let aggregate = self.inner.aggregate.lock().unwrap();
+        AggregateUsage {
+            totals: aggregate.totals.clone(),
+            used_m3: aggregate.sum,
+        }
+    }
+
+    /// Continue the aggregate total from a snapshot; call before the first reading.
+    pub fn restore_aggregate(&self, usage: &AggregateUsage) {
+        let mut aggregate = self.inner.aggregate.lock().unwrap();
+        aggregate.totals.extend(usage.totals.clone());
+        aggregate.sum += usage.used_m3;
+    }
+
+    fn forget_restored(&self, device: &str) {
+        self.inner
+            .restored
+            .lock()
+            .unwrap()
+            .retain(|sample| sample.labels.get("device").map(String::as_str) != Some(device));
+    }
+
+    fn families(&self) -> prometheus::Result<Vec<MetricFamily>> {
+        let families = ReadingFamilies::new()?;
+        let options = *self.inner.options.lock().unwrap();
+        for sample in self.inner.restored.lock().unwrap().iter() {
+            families.restore(sample);
+        }
+        let readings = self.inner.readings.lock().unwrap();
+        for (device, reading) in readings.iter() {
+            families.add(device, reading, options);
+        }
+        if options.aggregate {
+            let total = self.inner.aggregate.lock().unwrap().sum;
+            families.add_aggregate(readings.values(), total);
+        }
+        Ok(families
+            .collectors()
+            .into_iter()
+            .flat_map(|collector| collector.collect())
+            .filter(|family| !family.get_metric().is_empty())
+            .collect())
+    }
+}
+
+impl Collector for ReadingCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.inner.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        // The families are fixed at compile time, so building them cannot fail
+        self.families().unwrap_or_default()
+    }
+}
+
+/// Type of the series an extra field is exported as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExtraMetricKind {
+    #[default]
+    Gauge,
+    Counter,
+}
+
+/// A payload field the exporter does not know, exported as a metric of its own
+/// (`--extra-metric`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtraMetric {
+    pub field: String,
+    pub metric: String,
+    pub kind: ExtraMetricKind,
+}
+
+impl ExtraMetric {
+    fn series(&self) -> prometheus::Result<ExtraSeries> {
+        let opts = Opts::new(
+            self.metric.as_str(),
+            format!("Field `{}` as reported by the device", self.field),
+        );
+        Ok(match self.kind {
+            ExtraMetricKind::Gauge => ExtraSeries::Gauge(GaugeVec::new(opts, &["device"])?),
+            ExtraMetricKind::Counter => ExtraSeries::Counter(CounterVec::new(opts, &["device"])?),
+        })
+    }
+}
+
+enum ExtraSeries {
+    Gauge(GaugeVec),
+    Counter(CounterVec),
+}
+
+impl ExtraSeries {
+    fn collector(&self) -> &dyn Collector {
+        match self {
+            ExtraSeries::Gauge(gauge) => gauge,
+            ExtraSeries::Counter(counter) => counter,
+        }
+    }
+}
+
+/// Exports the configured extra fields of the latest readings.
+pub struct ExtraCollector {
+    readings: ReadingCollector,
+    metrics: Vec<ExtraMetric>,
+    descs: Vec<Desc>,
+}
+
+impl ReadingCollector {
+    /// A collector for `metrics`, taken from the readings of this one.
+    pub fn extra_collector(&self, metrics: Vec<ExtraMetric>) -> prometheus::Result<ExtraCollector> {
+        let mut descs = Vec::new();
+        for metric in &metrics {
+            descs.extend(metric.series()?.collector().desc().into_iter().cloned());
+        }
+        Ok(ExtraCollector {
+            readings: self.clone(),
+            metrics,
+            descs,
+        })
+    }
+}
+
+impl Collector for ExtraCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let readings = self.readings.inner.readings.lock().unwrap();
+        let mut families = Vec::new();
+        for metric in &self.metrics {
+            // Validated when the descriptors were built
+            let Ok(series) = metric.series() else {
+                continue;
+            };
+            for (device, reading) in readings.iter() {
+                // Numbers as they are, switches as 0 or 1, anything else is left out
+                let value = match reading.extra().get(&metric.field) {
+                    Some(Value::Number(number)) => number.as_f64(),
+                    Some(Value::Bool(on)) => Some(f64::from(u8::from(*on))),
+                    _ => None,
+                };
+                match (&series, value) {
+                    (ExtraSeries::Gauge(gauge), Some(value)) => {
+                        gauge.with_label_values(&[device]).set(value)
+                    }
+                    // Counters cannot go below zero
+                    (ExtraSeries::Counter(counter), Some(value)) if value >= 0.0 => {
+                        set_counter(counter, &[device], value)
+                    }
+                    _ => {}
+                }
+            }
+            families.extend(series.collector().collect());
+        }
+        families
+            .into_iter()
+            .filter(|family| !family.get_metric().is_empty())
+            .collect()
+    }
+}
+
+/// Keep the last known Wi-Fi details when a reading leaves them out, as
+/// battery-powered meters do between connections.
+fn keep_network(reading: &mut Reading, previous: &Reading) {
+    match (reading, previous) {
+        (Reading::Water(data), Reading::Water(previous)) => {
+            if data.wifi_ssid.is_empty() {
+                data.wifi_ssid.clone_from(&previous.wifi_ssid);
+            }
+            data.wifi_strength = data.wifi_strength.or(previous.wifi_strength);
+            data.wifi_rssi_db = data.wifi_rssi_db.or(previous.wifi_rssi_db);
+        }
+        (Reading::P1(data), Reading::P1(previous)) => {
+            data.wifi_strength = data.wifi_strength.or(previous.wifi_strength);
+        }
+        (Reading::EnergySocket(data), Reading::EnergySocket(previous)) => {
+            data.wifi_strength = data.wifi_strength.or(previous.wifi_strength);
+        }
+        (Reading::Kwh(data), Reading::Kwh(previous)) => {
+            data.wifi_strength = data.wifi_strength.or(previous.wifi_strength);
+        }
+        _ => {}
+    }
+}
+
+/// One set of series, filled from the readings on every gather.
+struct ReadingFamilies {
+    // Water meter metrics
+    total_water: CounterVec,
+    active_flow: GaugeVec,
+    net_total: CounterVec,
+    total_gallons: CounterVec,
+    active_flow_gpm: GaugeVec,
+    water_offset: GaugeVec,
+    meter_info: GaugeVec,
+    aggregate_total: CounterVec,
+    aggregate_flow: GaugeVec,
+    aggregate_meters: GaugeVec,
+
+    // Network metrics
+    wifi_strength: GaugeVec,
+    wifi_rssi: GaugeVec,
+
+    // P1 meter metrics
+    p1_energy_import: CounterVec,
+    p1_energy_export: CounterVec,
+    p1_active_power: GaugeVec,
+    p1_voltage: GaugeVec,
+    p1_gas: CounterVec,
+
+    // Energy Socket metrics
+    socket_energy_import: CounterVec,
+    socket_energy_export: CounterVec,
+    socket_active_power: GaugeVec,
+    socket_switch_state: GaugeVec,
+
+    // kWh meter metrics
+    kwh_energy_import: CounterVec,
+    kwh_energy_export: CounterVec,
+    kwh_active_power: GaugeVec,
+    kwh_phase_power: GaugeVec,
+    kwh_voltage: GaugeVec,
+    kwh_current: GaugeVec,
+}
+
+impl ReadingFamilies {
+    fn new() -> prometheus::Result<Self> {
+        let counter = |name: &str, help: &str| CounterVec::new(Opts::new(name, help), &["device"]);
+        let gauge = |name: &str, help: &str| GaugeVec::new(Opts::new(name, help), &["device"]);
+        let phase_gauge =
+            |name: &str, help: &str| GaugeVec::new(Opts::new(name, help), &["device", "phase"]);
+
+        Ok(Self {
+            total_water: counter("homewizard_water_total_m3", "Total water consumption in m³")?,
+            active_flow: gauge(
+                "homewizard_water_active_flow_lpm",
+                "Current water flow in liters per minute",
+            )?,
+            net_total: counter(
+                "homewizard_water_net_total_m3",
+                "Total water consumption minus the device offset, plus the calibration, in m³",
+            )?,
+            total_gallons: counter(
+                "homewizard_water_total_gallons",
+                "Total water consumption in US gallons",
+            )?,
+            active_flow_gpm: gauge(
+                "homewizard_water_active_flow_gpm",
+                "Current water flow in US gallons per minute",
+            )?,
+            water_offset: gauge("homewizard_water_offset_m3", "Water meter offset in m³")?,
+            meter_info: GaugeVec::new(
+                Opts::new("homewizard_water_meter_info", "Water meter information"),
+                &["device", "wifi_ssid"],
+            )?,
+            aggregate_total: CounterVec::new(
+                Opts::new(
+                    "homewizard_water_aggregate_total_m3",
+                    "Water used by all water meters in m³, summed from the increase of each meter",
+                ),
+                &[],
+            )?,
+            aggregate_flow: GaugeVec::new(
+                Opts::new(
+                    "homewizard_water_aggregate_active_flow_lpm",
+                    "Sum of the current flows of all water meters in liters per minute",
+                ),
+                &[],
+            )?,
+            aggregate_meters: GaugeVec::new(
+                Opts::new(
+                    "homewizard_water_aggregate_meters",
+                    "Number of water meters with a reading in the aggregate series",
+                ),
+                &[],
+            )?,
+            wifi_strength: gauge(
+                "homewizard_water_wifi_strength_percent",
+                "WiFi signal strength percentage",
+            )?,
+            wifi_rssi: gauge(
+                "homewizard_water_wifi_rssi_dbm",
+                "WiFi signal strength in dBm, derived from the percentage when not reported",
+            )?,
+            p1_energy_import: counter(
+                "homewizard_p1_energy_import_kwh",
+                "Total energy imported from the grid in kWh",
+            )?,
+            p1_energy_export: counter(
+                "homewizard_p1_energy_export_kwh",
+                "Total energy exported to the grid in kWh",
+            )?,
+            p1_active_power: gauge(
+                "homewizard_p1_active_power_w",
+                "Current net power in watts (negative when exporting)",
+            )?,
+            p1_voltage: phase_gauge(
+                "homewizard_p1_voltage_v",
+                "Current voltage per phase in volts",
+            )?,
+            p1_gas: counter("homewizard_p1_gas_m3", "Total gas consumption in m³")?,
+            socket_energy_import: counter(
+                "homewizard_energy_socket_energy_import_kwh",
+                "Total energy consumed through the socket in kWh",
+            )?,
+            socket_energy_export: counter(
+                "homewizard_energy_socket_energy_export_kwh",
+                "Total energy delivered back through the socket in kWh",
+            )?,
+            socket_active_power: gauge(
+                "homewizard_energy_socket_active_power_w",
+                "Current power through the socket in watts",
+            )?,
+            socket_switch_state: gauge(
+                "homewizard_energy_socket_switch_state",
+                "Whether the socket relay is switched on (1) or off (0)",
+            )?,
+            kwh_energy_import: counter(
+                "homewizard_kwh_energy_import_kwh",
+                "Total energy imported through the kWh meter in kWh",
+            )?,
+            kwh_energy_export: counter(
+                "homewizard_kwh_energy_export_kwh",
+                "Total energy exported through the kWh meter in kWh",
+            )?,
+            kwh_active_power: gauge(
+                "homewizard_kwh_active_power_w",
+                "Current total power in watts",
+            )?,
+            kwh_phase_power: phase_gauge(
+                "homewizard_kwh_phase_power_w",
+                "Current power per phase in watts",
+            )?,
+            kwh_voltage: phase_gauge(
+                "homewizard_kwh_voltage_v",
+                "Current voltage per phase in volts",
+            )?,
+            kwh_current: phase_gauge("homewizard_kwh_current_a", "Current per phase in amperes")?,
+        })
+    }
+
+    fn collectors(&self) -> Vec<&dyn Collector> {
+        vec![
+            &self.total_water,
+            &self.active_flow,
+            &self.net_total,
+            &self.total_gallons,
+            &self.active_flow_gpm,
+            &self.water_offset,
+            &self.meter_info,
+            &self.aggregate_total,
+            &self.aggregate_flow,
+            &self.aggregate_meters,
+            &self.wifi_strength,
+            &self.wifi_rssi,
+            &self.p1_energy_import,
+            &self.p1_energy_export,
+            &self.p1_active_power,
+            &self.p1_voltage,
+            &self.p1_gas,
+            &self.socket_energy_import,
+            &self.socket_energy_export,
+            &self.socket_active_power,
+            &self.socket_switch_state,
+            &self.kwh_energy_import,
+            &self.kwh_energy_export,
+            &self.kwh_active_power,
+            &self.kwh_phase_power,
+            &self.kwh_voltage,
+            &self.kwh_current,
+        ]
+    }
+
+    fn restore(&self, sample: &CounterSample) {
+        let counters = [
+            &self.total_water,
+            &self.net_total,
+            &self.total_gallons,
+            &self.p1_energy_import,
+            &self.p1_energy_export,
+            &self.p1_gas,
+            &self.socket_energy_import,
+            &self.socket_energy_export,
+            &self.kwh_energy_import,
+            &self.kwh_energy_export,
+        ];
+        if let Some(counter) = counters
+            .into_iter()
+            .find(|counter| counter.desc()[0].fq_name == sample.name)
+            && let Some(device) = sample.labels.get("device")
+        {
+            set_counter(counter, &[device], sample.value);
+        }
+    }
+
+    fn add(&self, device: &str, reading: &Reading, options: ReadingOptions) {
+        match reading {
+            Reading::Water(data) => {
+                set_counter(&self.total_water, &[device], data.total_liter_m3);
+                if let Some(calibration) = options.net_total_calibration {
+                    let net = data.total_liter_m3 - data.total_liter_offset_m3 + calibration;
+                    // Counters cannot go below zero
+                    set_counter(&self.net_total, &[device], net.max(0.0));
+                }
+                if options.units == Units::Us {
+                    set_counter(
+                        &self.total_gallons,
+                        &[device],
+                        data.total_liter_m3 * GALLONS_PER_M3,
+                    );
+                }
+                // A battery-powered meter may not report the flow; leave it out rather
+                // than export a stale value
+                if let Some(flow) = data.active_liter_lpm {
+                    self.active_flow.with_label_values(&[device]).set(flow);
+                    if options.units == Units::Us {
+                        self.active_flow_gpm
+                            .with_label_values(&[device])
+                            .set(flow * GALLONS_PER_M3 / 1000.0);
+                    }
+                }
+                self.water_offset
+                    .with_label_values(&[device])
+                    .set(data.total_liter_offset_m3);
+                self.add_wifi(device, data.wifi_strength, data.wifi_rssi_db, options);
+                if !data.wifi_ssid.is_empty() && options.wifi_metrics {
+                    self.meter_info
+                        .with_label_values(&[device, &data.wifi_ssid])
+                        .set(1.0);
+                }
+            }
+            Reading::P1(data) => {
+                set_counter(
+                    &self.p1_energy_import,
+                    &[device],
+                    data.total_power_import_kwh,
+                );
+                if let Some(export) = data.total_power_export_kwh {
+                    set_counter(&self.p1_energy_export, &[device], export);
+                }
+                if let Some(power) = data.active_power_w {
+                    self.p1_active_power.with_label_values(&[device]).set(power);
+                }
+                let phases = [
+                    ("l1", data.active_voltage_l1_v),
+                    ("l2", data.active_voltage_l2_v),
+                    ("l3", data.active_voltage_l3_v),
+                ];
+                for (phase, voltage) in phases {
+                    if let Some(voltage) = voltage {
+                        self.p1_voltage
+                            .with_label_values(&[device, phase])
+                            .set(voltage);
+                    }
+                }
+                if let Some(gas) = data.total_gas_m3 {
+                    set_counter(&self.p1_gas, &[device], gas);
+                }
+                self.add_wifi(device, data.wifi_strength, None, options);
+            }
+            Reading::EnergySocket(data) => {
+                set_counter(
+                    &self.socket_energy_import,
+                    &[device],
+                    data.total_power_import_kwh,
+                );
+                if let Some(export) = data.total_power_export_kwh {
+                    set_counter(&self.socket_energy_export, &[device], export);
+                }
+                if let Some(power) = data.active_power_w {
+                    self.socket_active_power
+                        .with_label_values(&[device])
+                        .set(power);
+                }
+                self.socket_switch_state
+                    .with_label_values(&[device])
+                    .set(f64::from(u8::from(data.power_on)));
+                self.add_wifi(device, data.wifi_strength, None, options);
+            }
+            Reading::Kwh(data) => {
+                set_counter(
+                    &self.kwh_energy_import,
+                    &[device],
+                    data.total_power_import_kwh,
+                );
+                if let Some(export) = data.total_power_export_kwh {
+                    set_counter(&self.kwh_energy_export, &[device], export);
+                }
+                if let Some(power) = data.active_power_w {
+                    self.kwh_active_power
+                        .with_label_values(&[device])
+                        .set(power);
+                }
+                for phase in data.phases() {
+                    let values = [
+                        (&self.kwh_phase_power, phase.power_w),
+                        (&self.kwh_voltage, phase.voltage_v),
+                        (&self.kwh_current, phase.current_a),
+                    ];
+                    for (gauge, value) in values {
+                        if let Some(value) = value {
+                            gauge.with_label_values(&[device, phase.phase]).set(value);
+                        }
+                    }
+                }
+                self.add_wifi(device, data.wifi_strength, None, options);
+            }
+        }
+    }
+
+    /// Sums over the water meters, without a `device` label so they are not
+    /// counted twice by `sum()` over the per-device series. `total` is the
+    /// water used by all meters, which keeps counting across dropped meters.
+    fn add_aggregate<'a>(&self, readings: impl Iterator<Item = &'a Reading>, total: f64) {
+        let meters: Vec<&HomeWizardWaterData> = readings
+            .filter_map(|reading| match reading {
+                Reading::Water(data) => Some(data),
+                _ => None,
+            })
+            .collect();
+        if meters.is_empty() {
+            return;
+        }
+        set_counter(&self.aggregate_total, &[], total);
+        self.aggregate_flow
+            .with_label_values::<&str>(&[])
+            .set(meters.iter().filter_map(|data| data.active_liter_lpm).sum());
+        self.aggregate_meters
+            .with_label_values::<&str>(&[])
+            .set(meters.len() as f64);
+    }
+
+    /// The RSSI is derived from the percentage when the device does not report it,
+    /// using the usual `2 * (dBm + 100)` mapping.
+    fn add_wifi(
+        &self,
+        device: &str,
+        strength_percent: Option<f64>,
+        rssi_dbm: Option<f64>,
+        options: ReadingOptions,
+    ) {
+        if !options.wifi_metrics {
+            return;
+        }
+        if let Some(strength) = strength_percent {
+            self.wifi_strength
+                .with_label_values(&[device])
+                .set(strength);
+        }
+        if let Some(rssi) = rssi_dbm.or(strength_percent.map(|strength| strength / 2.0 - 100.0)) {
+            self.wifi_rssi.with_label_values(&[device]).set(rssi);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::homewizard::HomeWizardP1Data;
+
+    fn water(total_m3: f64, ssid: &str) -> Reading {
+        Reading::Water(HomeWizardWaterData {
+            wifi_ssid: ssid.to_string(),
+            wifi_strength: Some(80.0),
+            total_liter_m3: total_m3,
+            active_liter_lpm: Some(2.5),
+            total_liter_offset_m3: 0.0,
+            wifi_rssi_db: None,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_extra_collector() {
+        let collector = ReadingCollector::new().unwrap();
+        let mut reading = water(12.5, "Home");
+        if let Reading::Water(data) = &mut reading {
+            data.extra
+                .insert("pressure_bar".to_string(), serde_json::json!(2.5));
+            data.extra
+                .insert("leak".to_string(), serde_json::json!(true));
+            data.extra
+                .insert("mode".to_string(), serde_json::json!("eco"));
+        }
+        collector.update("kitchen", reading);
+        collector.update("garden", water(1.0, "Home"));
+        let metric = |field: &str, metric: &str, kind| ExtraMetric {
+            field: field.to_string(),
+            metric: metric.to_string(),
+            kind,
+        };
+        let extra = collector
+            .extra_collector(vec![
+                metric("pressure_bar", "water_pressure_bar", ExtraMetricKind::Gauge),
+                metric("leak", "water_leak_total", ExtraMetricKind::Counter),
+                metric("mode", "water_mode", ExtraMetricKind::Gauge),
+            ])
+            .unwrap();
+        assert_eq!(extra.desc().len(), 3);
+
+        let families = extra.collect();
+        assert_eq!(value(&families, "water_pressure_bar", "kitchen"), Some(2.5));
+        assert_eq!(value(&families, "water_leak_total", "kitchen"), Some(1.0));
+        // Devices without the field, and values that are not numbers, are left out
+        assert_eq!(value(&families, "water_pressure_bar", "garden"), None);
+        assert!(families.iter().all(|family| family.name() != "water_mode"));
+    }
+
+    fn value(families: &[MetricFamily], name: &str, device: &str) -> Option<f64> {
+        families
+            .iter()
+            .find(|family| family.name() == name)?
+            .get_metric()
+            .iter()
+            .find(|metric| {
+                metric
+                    .get_label()
+                    .iter()
+                    .any(|label| label.name() == "device" && label.value() == device)
+            })
+            .map(|metric| metric.get_counter().value() + metric.get_gauge().value())
+    }
+
+    #[test]
+    fn test_collects_latest_reading() {
+        let collector = ReadingCollector::new().unwrap();
+        collector.update("kitchen", water(10.0, "Home"));
+        collector.update("kitchen", water(12.5, "Home"));
+
+        let families = collector.collect();
+        assert_eq!(
+            value(&families, "homewizard_water_total_m3", "kitchen"),
+            Some(12.5)
+        );
+        assert_eq!(
+            value(&families, "homewizard_water_active_flow_lpm", "kitchen"),
+            Some(2.5)
+        );
+    }
+
+    #[test]
+    fn test_ssid_change_replaces_info() {
+        let collector = ReadingCollector::new().unwrap();
+        collector.update("kitchen", water(10.0, "Old"));
+        collector.update("kitchen", water(10.0, "New"));
+
+        let families = collector.collect();
+        let info = families
+            .iter()
+            .find(|family| family.name() == "homewizard_water_meter_info")
+            .unwrap();
+        assert_eq!(info.get_metric().len(), 1);
+        assert_eq!(info.get_metric()[0].get_label()[1].value(), "New");
+    }
+
+    #[test]
+    fn test_remove() {
+        let collector = ReadingCollector::new().unwrap();
+        collector.update("kitchen", water(10.0, "Home"));
+        collector.update("meter", Reading::P1(HomeWizardP1Data::default()));
+        collector.remove("kitchen");
+
+        let families = collector.collect();
+        assert_eq!(
+            value(&families, "homewizard_water_total_m3", "kitchen"),
+            None
+        );
+        assert_eq!(
+            value(&families, "homewizard_p1_energy_import_kwh", "meter"),
+            Some(0.0)
+        );
+    }
+
+    #[test]
+    fn test_restored_counters_until_first_reading() {
+        let collector = ReadingCollector::new().unwrap();
+        collector.restore(&[
+            CounterSample {
+                name: "homewizard_water_total_m3".to_string(),
+                labels: BTreeMap::from([("device".to_string(), "kitchen".to_string())]),
+                value: 10.0,
+            },
+            CounterSample {
+                name: "homewizard_exporter_scrapes_total".to_string(),
+                labels: BTreeMap::from([("device".to_string(), "kitchen".to_string())]),
+                value: 3.0,
+            },
+        ]);
+
+        let families = collector.collect();
+        assert_eq!(
+            value(&families, "homewizard_water_total_m3", "kitchen"),
+            Some(10.0)
+        );
+        assert_eq!(
+            value(&families, "homewizard_exporter_scrapes_total", "kitchen"),
+            None
+        );
+
+        collector.update("kitchen", water(11.0, "Home"));
+        assert_eq!(
+            value(&collector.collect(), "homewizard_water_total_m3", "kitchen"),
+            Some(11.0)
+        );
+    }
+
+    #[test]
+    fn test_options() {
+        let collector = ReadingCollector::new().unwrap();
+        collector.set_options(|options| {
+            options.units = Units::Us;
+            options.wifi_metrics = false;
+        });
+        collector.update("kitchen", water(1.0, "Home"));
+
+        let families = collector.collect();
+        assert!(value(&families, "homewizard_water_total_gallons", "kitchen").is_some());
+        assert_eq!(
+            value(
+                &families,
+                "homewizard_water_wifi_strength_percent",
+                "kitchen"
+            ),
+            None
+        );
+        assert!(
+            !families
+                .iter()
+                .any(|family| family.name() == "homewizard_water_meter_info")
+        );
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let aggregate = |families: &[MetricFamily], name: &str| {
+            families
+                .iter()
+                .find(|family| family.name() == name)
+                .map(|family| {
+                    let metric = &family.get_metric()[0];
+                    assert!(metric.get_label().is_empty());
+                    metric.get_counter().value() + metric.get_gauge().value()
+                })
+        };
+        let collector = ReadingCollector::new().unwrap();
+        collector.update("kitchen", water(10.0, "Home"));
+        collector.update("garden", water(2.5, "Home"));
+        assert_eq!(
+            aggregate(&collector.collect(), "homewizard_water_aggregate_total_m3"),
+            None
+        );
+
+        collector.set_options(|options| options.aggregate = true);
+        let families = collector.collect();
+        assert_eq!(
+            aggregate(&families, "homewizard_water_aggregate_total_m3"),
+            Some(12.5)
+        );
+        assert_eq!(
+            aggregate(&families, "homewizard_water_aggregate_active_flow_lpm"),
+            Some(5.0)
+        );
+        assert_eq!(
+            aggregate(&families, "homewizard_water_aggregate_meters"),
+            Some(2.0)
+        );
+    }
+
+    #[test]
+    fn test_aggregate_never_decreases() {
+        let total = |collector: &ReadingCollector| {
+            collector
+                .collect()
+                .iter()
+                .find(|family| family.name() == "homewizard_water_aggregate_total_m3")
+                .map(|family| family.get_metric()[0].get_counter().value())
+                .unwrap()
+        };
+        let collector = ReadingCollector::new().unwrap();
+        collector.set_options(|options| options.aggregate = true);
+        collector.update("kitchen", water(10.0, "Home"));
+        collector.update("garden", water(2.5, "Home"));
+        assert_eq!(total(&collector), 12.5);
+
+        // The garden meter goes stale; its water stays counted
+        collector.remove("garden");
+        assert_eq!(total(&collector), 12.5);
+        collector.update("kitchen", water(11.0, "Home"));
+        assert_eq!(total(&collector), 13.5);
+
+        // Back with more water, only the increase is added
+        collector.update("garden", water(3.0, "Home"));
+        assert_eq!(total(&collector), 14.0);
+        // A meter reset counts up from zero
+        collector.update("kitchen", water(0.5, "Home"));
+        assert_eq!(total(&collector), 14.5);
+
+        // After a restart the sum continues, and the stale garden meter is not counted again
+        collector.remove("garden");
+        let restarted = ReadingCollector::new().unwrap();
+        restarted.set_options(|options| options.aggregate = true);
+        restarted.restore_aggregate(&collector.aggregate_usage());
+        restarted.update("kitchen", water(1.0, "Home"));
+        assert_eq!(total(&restarted), 15.0);
+        restarted.update("garden", water(3.5, "Home"));
+        assert_eq!(total(&restarted), 15.5);
+    }
+}
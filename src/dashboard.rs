@@ -0,0 +1,295 @@
+use crate::build_info::BUILD_INFO;
+use crate::devices::{DeviceState, DeviceStatus};
+use crate::homewizard::Reading;
+use crate::readings::TimedReading;
+use std::fmt::Write;
+
+/// Endpoints linked from the dashboard, with what they serve.
+const ENDPOINTS: [(&str, &str); 11] = [
+    ("/metrics", "Prometheus metrics"),
+    ("/health", "Health check"),
+    ("/livez", "Liveness check"),
+    ("/ready", "Readiness check"),
+    (
+        "/health/details",
+        "Readiness and per-device poll status (JSON)",
+    ),
+    ("/devices", "Per-device status (JSON)"),
+    ("/json", "Latest readings (JSON)"),
+    ("/history", "Recent readings (JSON, ?minutes=60)"),
+    ("/events", "Live readings (Server-Sent Events)"),
+    ("/ws", "Live readings (WebSocket)"),
+    ("/version", "Build information (JSON)"),
+];
+
+const SPARKLINE_WIDTH: f64 = 240.0;
+const SPARKLINE_HEIGHT: f64 = 40.0;
+
+const STYLE: &str = "body{font-family:system-ui,sans-serif;margin:2em;color:#222}\
+table{border-collapse:collapse;margin-bottom:1.5em}\
+th,td{padding:.35em .8em;border-bottom:1px solid #ddd;text-align:left;vertical-align:middle}\
+.up{color:#1a7f37}.down{color:#cf222e}.unknown{color:#777}\
+.error{color:#cf222e;font-size:.9em}svg{display:block}polyline{fill:none;stroke:#0969da;stroke-width:1.5}";
+
+/// The root page: device status, latest readings with a sparkline of the
+/// flow (or power) over `history`, and links to the other endpoints.
+///
+/// `history` is `None` when `--history-retention` is disabled. The page
+/// refreshes itself every 30 seconds and needs no external assets.
+pub fn render(
+    devices: &[DeviceStatus],
+    latest: &[TimedReading],
+    history: Option<&[TimedReading]>,
+    now: u64,
+) -> String {
+    let mut page = String::new();
+    let _ = write!(
+        page,
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+         <meta http-equiv=\"refresh\" content=\"30\">\
+         <title>HomeWizard Water Prometheus Exporter</title><style>{STYLE}</style></head><body>\n\
+         <h1>HomeWizard Water Prometheus Exporter</h1>\n<p>Version {} ({}, built {})</p>\n",
+        BUILD_INFO.version, BUILD_INFO.git_sha, BUILD_INFO.build_date
+    );
+
+    page.push_str("<h2>Devices</h2>\n");
+    if devices.is_empty() {
+        page.push_str("<p>No devices configured.</p>\n");
+    } else {
+        page.push_str(
+            "<table><tr><th>Device</th><th>Type</th><th>State</th><th>Last success</th>\
+             <th>Reading</th><th>Last hour</th></tr>\n",
+        );
+        for device in devices {
+            let reading = latest.iter().find(|reading| reading.device == device.name);
+            let _ = writeln!(
+                page,
+                "<tr><td>{}<br><small>{}</small></td><td>{}</td><td class=\"{state}\">{state}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape(&device.name),
+                escape(&device.address),
+                device.device_type,
+                device
+                    .last_success
+                    .map(|at| ago(now.saturating_sub(at)))
+                    .unwrap_or_else(|| "never".to_string()),
+                reading
+                    .map(|reading| summary(&reading.reading))
+                    .unwrap_or_else(|| "-".to_string()),
+                match history {
+                    Some(history) => sparkline(
+                        history
+                            .iter()
+                            .filter(|reading| reading.device == device.name)
+                            .filter_map(|reading| rate(&reading.reading)),
+                    ),
+                    None => "<small>history disabled</small>".to_string(),
+                },
+                state = state(device.state),
+            );
+            if let Some(error) = &device.last_error {
+                let _ = writeln!(
+                    page,
+                    "<tr><td></td><td colspan=\"5\" class=\"error\">{}</td></tr>",
+                    escape(error)
+                );
+            }
+        }
+        page.push_str("</table>\n");
+    }
+
+    page.push_str("<h2>Endpoints</h2>\n<ul>\n");
+    for (path, description) in ENDPOINTS {
+        let _ = writeln!(
+            page,
+            "<li><a href=\"{path}\">{path}</a> - {description}</li>"
+        );
+    }
+    page.push_str("</ul>\n</body></html>\n");
+    page
+}
+
+fn state(state: DeviceState) -> &'static str {
+    match state {
+        DeviceState::Unknown => "unknown",
+        DeviceState::Up => "up",
+        DeviceState::Down => "down",
+    }
+}
+
+/// The headline values of a reading.
+fn summary(reading: &Reading) -> String {
+    match reading {
+        Reading::Water(data) => match data.active_liter_lpm {
+            Some(flow) => format!("{:.3} m³, {flow:.1} L/min", data.total_liter_m3),
+            None => format!("{:.3} m³", data.total_liter_m3),
+        },
+        Reading::P1(data) => energy_summary(data.total_power_import_kwh, data.active_power_w),
+        Reading::EnergySocket(data) => {
+            energy_summary(data.total_power_import_kwh, data.active_power_w)
+        }
+        Reading::Kwh(data) => energy_summary(data.total_power_import_kwh, data.active_power_w),
+    }
+}
+
+fn energy_summary(import_kwh: f64, power_w: Option<f64>) -> String {
+    match power_w {
+        Some(power) => format!("{import_kwh:.1} kWh, {power:.0} W"),
+        None => format!("{import_kwh:.1} kWh"),
+    }
+}
+
+/// The value drawn in the sparkline: flow for water, power for the others.
+fn rate(reading: &Reading) -> Option<f64> {
+    match reading {
+        Reading::Water(data) => data.active_liter_lpm,
+        Reading::P1(data) => data.active_power_w,
+        Reading::EnergySocket(data) => data.active_power_w,
+        Reading::Kwh(data) => data.active_power_w,
+    }
+}
+
+/// An inline SVG line of `values`, scaled to fit; fewer than two values draw nothing.
+fn sparkline(values: impl Iterator<Item = f64>) -> String {
+    let values: Vec<f64> = values.filter(|value| value.is_finite()).collect();
+    if values.len() < 2 {
+        return String::new();
+    }
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let step = SPARKLINE_WIDTH / (values.len() - 1) as f64;
+    let points: Vec<String> = values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            // A flat line is drawn in the middle
+            let height = if max > min {
+                (value - min) / (max - min)
+            } else {
+                0.5
+            };
+            format!(
+                "{:.1},{:.1}",
+                i as f64 * step,
+                SPARKLINE_HEIGHT - height * SPARKLINE_HEIGHT
+            )
+        })
+        .collect();
+    format!(
+        "<svg width=\"{SPARKLINE_WIDTH}\" height=\"{SPARKLINE_HEIGHT}\" viewBox=\"0 0 {SPARKLINE_WIDTH} {SPARKLINE_HEIGHT}\"><title>{min} to {max}</title><polyline points=\"{}\"/></svg>",
+        points.join(" ")
+    )
+}
+
+fn ago(seconds: u64) -> String {
+    match seconds {
+        0..60 => format!("{seconds}s ago"),
+        60..3600 => format!("{}m ago", seconds / 60),
+        _ => format!("{}h ago", seconds / 3600),
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::homewizard::HomeWizardWaterData;
+
+    fn status(name: &str, state: DeviceState) -> DeviceStatus {
+        DeviceStatus {
+            name: name.to_string(),
+            alias: None,
+            address: "192.168.1.5".to_string(),
+            device_type: "water",
+            product_type: None,
+            state,
+            last_poll: Some(9_990),
+            last_success: Some(9_990),
+            last_error: None,
+            consecutive_failures: 0,
+            circuit_breaker_open: false,
+        }
+    }
+
+    fn reading(device: &str, timestamp: u64, flow: f64) -> TimedReading {
+        TimedReading {
+            device: device.to_string(),
+            device_type: "water",
+            timestamp,
+            source: "local",
+            reading: Reading::Water(HomeWizardWaterData {
+                total_liter_m3: 42.5,
+                active_liter_lpm: Some(flow),
+                ..Default::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let mut down = status("<garden>", DeviceState::Down);
+        down.last_success = None;
+        down.last_error = Some("HTTP request failed: timeout".to_string());
+        let history = [
+            reading("kitchen", 9_000, 0.0),
+            reading("kitchen", 9_600, 6.0),
+        ];
+        let page = render(
+            &[status("kitchen", DeviceState::Up), down],
+            &[reading("kitchen", 9_990, 6.0)],
+            Some(&history),
+            10_000,
+        );
+
+        assert!(page.starts_with("<!DOCTYPE html>"));
+        assert!(
+            page.contains("<td class=\"up\">up</td><td>10s ago</td><td>42.500 m³, 6.0 L/min</td>")
+        );
+        assert!(page.contains("<polyline points=\"0.0,40.0 240.0,0.0\"/>"));
+        assert!(page.contains("&lt;garden&gt;"));
+        assert!(page.contains("<td class=\"down\">down</td><td>never</td><td>-</td><td></td>"));
+        assert!(page.contains("HTTP request failed: timeout"));
+        assert!(page.contains("<a href=\"/metrics\">/metrics</a>"));
+
+        let page = render(&[status("kitchen", DeviceState::Up)], &[], None, 10_000);
+        assert!(page.contains("history disabled"));
+    }
+
+    #[test]
+    fn test_sparkline() {
+        assert_eq!(sparkline([1.0].into_iter()), "");
+        assert!(sparkline([2.0, 2.0, 2.0].into_iter()).contains("0.0,20.0 120.0,20.0 240.0,20.0"));
+        assert!(
+            sparkline([0.0, f64::NAN, 10.0, 5.0].into_iter())
+                .contains("0.0,40.0 120.0,0.0 240.0,20.0")
+        );
+    }
+
+    #[test]
+    fn test_ago() {
+        assert_eq!(ago(5), "5s ago");
+        assert_eq!(ago(125), "2m ago");
+        assert_eq!(ago(7300), "2h ago");
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(
+            escape("a & <b> \"c\" 'd'"),
+            "a &amp; &lt;b&gt; &quot;c&quot; &#39;d&#39;"
+        );
+    }
+}
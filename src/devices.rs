@@ -0,0 +1,458 @@
+use crate::events::{EventKind, Events};
+use crate::homewizard::DeviceType;
+use serde::Serialize;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A configured device: `--host` entries are `[type:][alias=]address`, for
+/// example `192.168.1.10`, `kitchen=192.168.1.10` or `p1:meter=192.168.1.20`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    pub alias: Option<String>,
+    pub address: String,
+    pub device_type: DeviceType,
+}
+
+impl Device {
+    pub fn parse(spec: &str) -> Self {
+        let spec = spec.trim();
+        // Only known type names count as a prefix, so IPv6 addresses stay intact
+        let (device_type, spec) = match spec.split_once(':') {
+            Some((prefix, rest)) => match DeviceType::from_name(prefix) {
+                Some(device_type) => (device_type, rest),
+                None => (DeviceType::Water, spec),
+            },
+            None => (DeviceType::Water, spec),
+        };
+
+        match spec.split_once('=') {
+            Some((alias, address)) if !alias.trim().is_empty() => Self {
+                alias: Some(alias.trim().to_string()),
+                address: address.trim().to_string(),
+                device_type,
+            },
+            _ => Self {
+                alias: None,
+                address: spec.trim_start_matches('=').trim().to_string(),
+                device_type,
+            },
+        }
+    }
+
+    /// Name used for the `device` label: the alias if set, otherwise the address.
+    pub fn name(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.address)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceState {
+    /// Not polled yet.
+    Unknown,
+    Up,
+    Down,
+}
+
+/// Status of one device as served by `/devices`. Timestamps are Unix seconds.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeviceStatus {
+    pub name: String,
+    pub alias: Option<String>,
+    pub address: String,
+    #[serde(rename = "type")]
+    pub device_type: &'static str,
+    pub product_type: Option<String>,
+    pub state: DeviceState,
+    pub last_poll: Option<u64>,
+    pub last_success: Option<u64>,
+    pub last_error: Option<String>,
+    /// Failed polls since the last successful one
+    pub consecutive_failures: u32,
+    /// Whether polling is backed off after repeated failures
+    pub circuit_breaker_open: bool,
+}
+
+impl DeviceStatus {
+    fn unknown(device: &Device) -> Self {
+        Self {
+            name: device.name().to_string(),
+            alias: device.alias.clone(),
+            address: device.address.clone(),
+            device_type: device.device_type.as_str(),
+            product_type: None,
+            state: DeviceState::Unknown,
+            last_poll: None,
+            last_success: None,
+            last_error: None,
+            consecutive_failures: 0,
+            circuit_breaker_open: false,
+        }
+    }
+}
+
+/// Poll status of all configured devices, shared between the pollers and the server.
+#[derive(Debug, Clone, Default)]
+pub struct Devices {
+    statuses: Arc<RwLock<Vec<DeviceStatus>>>,
+    down_after: Duration,
+    not_ready_after: Duration,
+    events: Events,
+}
+
+impl Devices {
+    pub fn new(devices: &[Device]) -> Self {
+        let statuses = devices.iter().map(DeviceStatus::unknown).collect();
+
+        Self {
+            statuses: Arc::new(RwLock::new(statuses)),
+            down_after: Duration::ZERO,
+            not_ready_after: Duration::ZERO,
+            events: Events::default(),
+        }
+    }
+
+    /// Replace the configured devices, keeping the status of those that stay the same.
+    pub fn set_devices(&self, devices: &[Device]) {
+        let mut statuses = self.statuses.write().unwrap();
+        let mut previous = std::mem::take(&mut *statuses);
+        *statuses = devices
+            .iter()
+            .map(|device| {
+                let fresh = DeviceStatus::unknown(device);
+                match previous.iter().position(|status| {
+                    (&status.name, &status.address, status.device_type)
+                        == (&fresh.name, &fresh.address, fresh.device_type)
+                }) {
+                    Some(index) => previous.swap_remove(index),
+                    None => fresh,
+                }
+            })
+            .collect();
+    }
+
+    /// Keep a device up through failed polls until it has not answered for
+    /// `down_after`, for meters that only wake up now and then.
+    pub fn with_down_after(mut self, down_after: Duration) -> Self {
+        self.down_after = down_after;
+        self
+    }
+
+    /// Emit an event when a device goes offline or comes back.
+    pub fn with_events(mut self, events: Events) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Report the exporter not ready once no device has been polled successfully
+    /// for `not_ready_after`; zero only waits for the first successful poll.
+    pub fn with_not_ready_after(mut self, not_ready_after: Duration) -> Self {
+        self.not_ready_after = not_ready_after;
+        self
+    }
+
+    /// Whether the exporter has readings worth scraping, or why not.
+    pub fn readiness(&self) -> Result<(), String> {
+        self.readiness_at(unix_now())
+    }
+
+    fn readiness_at(&self, now: u64) -> Result<(), String> {
+        let statuses = self.statuses.read().unwrap();
+        if statuses.is_empty() {
+            return Ok(());
+        }
+        let last_success = statuses
+            .iter()
+            .filter_map(|status| status.last_success)
+            .max()
+            .ok_or("no successful poll yet")?;
+        let since = now.saturating_sub(last_success);
+        if !self.not_ready_after.is_zero() && since >= self.not_ready_after.as_secs() {
+            return Err(format!("no successful poll in {since}s"));
+        }
+        Ok(())
+    }
+
+    pub fn set_product_type(&self, name: &str, product_type: String) {
+        self.update(name, |status| status.product_type = Some(product_type));
+    }
+
+    pub fn record_success(&self, name: &str) {
+        let now = unix_now();
+        let mut previous = DeviceState::Up;
+        self.update(name, |status| {
+            previous = status.state;
+            status.state = DeviceState::Up;
+            status.last_poll = Some(now);
+            status.last_success = Some(now);
+            status.consecutive_failures = 0;
+        });
+        if previous == DeviceState::Down {
+            self.events.emit(name, EventKind::DeviceRecovered);
+        }
+    }
+
+    /// Record a failed poll and return the resulting state; the error is kept
+    /// until the next failure replaces it.
+    pub fn record_failure(&self, name: &str, error: String) -> DeviceState {
+        let now = unix_now();
+        let down_after = self.down_after.as_secs();
+        let mut state = DeviceState::Down;
+        let mut previous = DeviceState::Down;
+        self.update(name, |status| {
+            previous = status.state;
+            let recent = status
+                .last_success
+                .is_some_and(|at| now.saturating_sub(at) < down_after);
+            if recent {
+                state = DeviceState::Up;
+            }
+            status.state = state;
+            status.last_poll = Some(now);
+            status.last_error = Some(error.clone());
+            status.consecutive_failures = status.consecutive_failures.saturating_add(1);
+        });
+        if state == DeviceState::Down && previous != DeviceState::Down {
+            self.events.emit(name, EventKind::DeviceOffline { error });
+        }
+        state
+    }
+
+    pub fn set_breaker_open(&self, name: &str, open: bool) {
+        self.update(name, |status| status.circuit_breaker_open = open);
+    }
+
+    pub fn snapshot(&self) -> Vec<DeviceStatus> {
+        self.statuses.read().unwrap().clone()
+    }
+
+    fn update(&self, name: &str, apply: impl FnOnce(&mut DeviceStatus)) {
+        let mut statuses = self.statuses.write().unwrap();
+        if let Some(status) = statuses.iter_mut().find(|status| status.name == name) {
+            apply(status);
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_address() {
+        let device = Device::parse("192.168.1.100");
+        assert_eq!(device.alias, None);
+        assert_eq!(device.address, "192.168.1.100");
+        assert_eq!(device.name(), "192.168.1.100");
+    }
+
+    #[test]
+    fn test_parse_alias() {
+        let device = Device::parse("garden=192.168.1.101");
+        assert_eq!(device.alias.as_deref(), Some("garden"));
+        assert_eq!(device.address, "192.168.1.101");
+        assert_eq!(device.name(), "garden");
+    }
+
+    #[test]
+    fn test_parse_empty_alias() {
+        let device = Device::parse("=192.168.1.101");
+        assert_eq!(device.alias, None);
+        assert_eq!(device.address, "192.168.1.101");
+    }
+
+    #[test]
+    fn test_parse_device_type() {
+        let device = Device::parse("p1:meter=192.168.1.20");
+        assert_eq!(device.device_type, DeviceType::P1);
+        assert_eq!(device.name(), "meter");
+        assert_eq!(device.address, "192.168.1.20");
+
+        let device = Device::parse("p1:192.168.1.20");
+        assert_eq!(device.device_type, DeviceType::P1);
+        assert_eq!(device.address, "192.168.1.20");
+
+        assert_eq!(Device::parse("192.168.1.20").device_type, DeviceType::Water);
+    }
+
+    #[test]
+    fn test_parse_ipv6_is_not_a_type() {
+        let device = Device::parse("[fe80::1]");
+        assert_eq!(device.device_type, DeviceType::Water);
+        assert_eq!(device.address, "[fe80::1]");
+    }
+
+    #[test]
+    fn test_devices_start_unknown() {
+        let devices = Devices::new(&[Device::parse("kitchen=10.0.0.1")]);
+        let snapshot = devices.snapshot();
+
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].name, "kitchen");
+        assert_eq!(snapshot[0].state, DeviceState::Unknown);
+        assert_eq!(snapshot[0].last_poll, None);
+    }
+
+    #[test]
+    fn test_devices_record_poll_results() {
+        let devices = Devices::new(&[Device::parse("10.0.0.1"), Device::parse("10.0.0.2")]);
+
+        devices.record_failure("10.0.0.1", "connection refused".to_string());
+        devices.record_success("10.0.0.2");
+        devices.set_product_type("10.0.0.2", "HWE-WTR".to_string());
+
+        let snapshot = devices.snapshot();
+        assert_eq!(snapshot[0].state, DeviceState::Down);
+        assert_eq!(
+            snapshot[0].last_error.as_deref(),
+            Some("connection refused")
+        );
+        assert!(snapshot[0].last_poll.is_some());
+        assert_eq!(snapshot[0].last_success, None);
+        assert_eq!(snapshot[0].consecutive_failures, 1);
+
+        assert_eq!(snapshot[1].state, DeviceState::Up);
+        assert_eq!(snapshot[1].product_type.as_deref(), Some("HWE-WTR"));
+        assert!(snapshot[1].last_success.is_some());
+    }
+
+    #[test]
+    fn test_devices_set_devices() {
+        let devices = Devices::new(&[Device::parse("kitchen=10.0.0.1"), Device::parse("10.0.0.2")]);
+        devices.record_success("kitchen");
+        devices.record_success("10.0.0.2");
+
+        devices.set_devices(&[
+            Device::parse("garden=10.0.0.3"),
+            Device::parse("kitchen=10.0.0.1"),
+        ]);
+        let snapshot = devices.snapshot();
+        let names: Vec<&str> = snapshot.iter().map(|status| status.name.as_str()).collect();
+        assert_eq!(names, ["garden", "kitchen"]);
+        assert_eq!(snapshot[0].state, DeviceState::Unknown);
+        // Unchanged devices keep their status
+        assert_eq!(snapshot[1].state, DeviceState::Up);
+
+        // A device moved to another address starts over
+        devices.set_devices(&[Device::parse("kitchen=10.0.0.9")]);
+        assert_eq!(devices.snapshot()[0].state, DeviceState::Unknown);
+    }
+
+    #[test]
+    fn test_devices_stay_up_within_down_after() {
+        let devices = Devices::new(&[Device::parse("battery=10.0.0.1")])
+            .with_down_after(Duration::from_secs(3600));
+
+        // Never seen: down right away
+        assert_eq!(
+            devices.record_failure("battery", "timed out".to_string()),
+            DeviceState::Down
+        );
+
+        devices.record_success("battery");
+        assert_eq!(
+            devices.record_failure("battery", "timed out".to_string()),
+            DeviceState::Up
+        );
+        let snapshot = devices.snapshot();
+        assert_eq!(snapshot[0].state, DeviceState::Up);
+        assert_eq!(snapshot[0].last_error.as_deref(), Some("timed out"));
+    }
+
+    #[test]
+    fn test_events() {
+        let events = Events::new();
+        let mut received = events.subscribe();
+        let devices = Devices::new(&[Device::parse("kitchen=10.0.0.1")])
+            .with_down_after(Duration::from_secs(3600))
+            .with_events(events);
+
+        // Going down once is one event
+        devices.record_failure("kitchen", "timed out".to_string());
+        devices.record_failure("kitchen", "timed out".to_string());
+        devices.record_success("kitchen");
+        // Still up within --down-after
+        devices.record_failure("kitchen", "timed out".to_string());
+        devices.record_success("kitchen");
+
+        let kinds: Vec<EventKind> = std::iter::from_fn(|| received.try_recv().ok())
+            .map(|event| event.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                EventKind::DeviceOffline {
+                    error: "timed out".to_string()
+                },
+                EventKind::DeviceRecovered
+            ]
+        );
+    }
+
+    #[test]
+    fn test_consecutive_failures() {
+        let devices = Devices::new(&[Device::parse("10.0.0.1")]);
+        for _ in 0..3 {
+            devices.record_failure("10.0.0.1", "timed out".to_string());
+        }
+        devices.set_breaker_open("10.0.0.1", true);
+        let status = &devices.snapshot()[0];
+        assert_eq!(status.consecutive_failures, 3);
+        assert!(status.circuit_breaker_open);
+
+        devices.record_success("10.0.0.1");
+        devices.set_breaker_open("10.0.0.1", false);
+        let status = &devices.snapshot()[0];
+        assert_eq!(status.consecutive_failures, 0);
+        assert!(!status.circuit_breaker_open);
+        // The last error is kept for debugging
+        assert_eq!(status.last_error.as_deref(), Some("timed out"));
+    }
+
+    #[test]
+    fn test_readiness() {
+        assert_eq!(Devices::default().readiness(), Ok(()));
+
+        let devices = Devices::new(&[Device::parse("10.0.0.1"), Device::parse("10.0.0.2")])
+            .with_not_ready_after(Duration::from_secs(300));
+        assert_eq!(
+            devices.readiness(),
+            Err("no successful poll yet".to_string())
+        );
+
+        devices.record_failure("10.0.0.1", "timed out".to_string());
+        assert!(devices.readiness().is_err());
+
+        // One device answering is enough
+        devices.record_success("10.0.0.2");
+        let now = unix_now();
+        assert_eq!(devices.readiness_at(now), Ok(()));
+        assert_eq!(devices.readiness_at(now + 299), Ok(()));
+        assert_eq!(
+            devices.readiness_at(now + 300),
+            Err("no successful poll in 300s".to_string())
+        );
+
+        // Without a limit, only the first success counts
+        let devices = devices.with_not_ready_after(Duration::ZERO);
+        assert_eq!(devices.readiness_at(now + 86_400), Ok(()));
+    }
+
+    #[test]
+    fn test_device_status_serialization() {
+        let devices = Devices::new(&[Device::parse("10.0.0.1")]);
+        let json = serde_json::to_value(devices.snapshot()).unwrap();
+
+        assert_eq!(json[0]["state"], "unknown");
+        assert_eq!(json[0]["type"], "water");
+        assert_eq!(json[0]["address"], "10.0.0.1");
+        assert!(json[0]["alias"].is_null());
+    }
+}
@@ -0,0 +1,328 @@
+use crate::homewizard::DeviceType;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// mDNS services HomeWizard devices announce: the v1 and the v2 local API.
+pub const SERVICES: [&str; 2] = ["_hwenergy._tcp.local", "_homewizard._tcp.local"];
+
+const MDNS: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251)), 5353);
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const CLASS_IN: u16 = 1;
+
+/// A device that answered the mDNS query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Discovered {
+    pub address: IpAddr,
+    /// Service instance name, e.g. `watermeter-ABCDEF._hwenergy._tcp.local`
+    pub instance: String,
+    pub product_type: Option<String>,
+    pub product_name: Option<String>,
+    pub serial: Option<String>,
+}
+
+impl Discovered {
+    /// The device as a `--host` entry, with the type prefix if it is not a watermeter.
+    pub fn host_spec(&self) -> String {
+        let address = match self.address {
+            IpAddr::V6(address) => format!("[{address}]"),
+            IpAddr::V4(address) => address.to_string(),
+        };
+        match self
+            .product_type
+            .as_deref()
+            .and_then(DeviceType::from_product_type)
+        {
+            Some(DeviceType::Water) | None => address,
+            Some(device_type) => format!("{}:{address}", device_type.as_str()),
+        }
+    }
+
+    fn merge(&mut self, other: Discovered) {
+        self.product_type = self.product_type.take().or(other.product_type);
+        self.product_name = self.product_name.take().or(other.product_name);
+        self.serial = self.serial.take().or(other.serial);
+    }
+}
+
+/// Ask the local network for HomeWizard devices and collect the answers for `timeout`.
+///
+/// The query goes out from an ephemeral port, so devices answer directly
+/// instead of to the multicast group and no port 5353 listener is needed.
+pub async fn discover(timeout: Duration) -> Result<Vec<Discovered>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.send_to(&query(&SERVICES), MDNS).await?;
+
+    let mut found: Vec<Discovered> = Vec::new();
+    let mut buf = vec![0; 9000];
+    let deadline = tokio::time::Instant::now() + timeout;
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, from) = received?;
+        for device in parse_response(&buf[..len], from.ip()) {
+            // Devices with both APIs enabled answer for each service
+            match found
+                .iter_mut()
+                .find(|known| known.address == device.address)
+            {
+                Some(known) => known.merge(device),
+                None => found.push(device),
+            }
+        }
+    }
+    found.sort_by_key(|device| device.address);
+    Ok(found)
+}
+
+/// A DNS query for the PTR records of `services`.
+fn query(services: &[&str]) -> Vec<u8> {
+    let mut packet = vec![0; 12];
+    packet[4..6].copy_from_slice(&(services.len() as u16).to_be_bytes());
+    for service in services {
+        for label in service.split('.') {
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.push(0);
+        packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    }
+    packet
+}
+
+/// Devices announced in an mDNS response from `from`; malformed packets yield none.
+fn parse_response(packet: &[u8], from: IpAddr) -> Vec<Discovered> {
+    let mut instances = Vec::new();
+    let mut txt: HashMap<String, HashMap<String, String>> = HashMap::new();
+
+    let parsed = (|| {
+        let mut reader = Reader { packet, pos: 4 };
+        let questions = reader.u16()?;
+        let records = [reader.u16()?, reader.u16()?, reader.u16()?]
+            .iter()
+            .map(|&count| usize::from(count))
+            .sum::<usize>();
+        for _ in 0..questions {
+            reader.name()?;
+            reader.skip(4)?;
+        }
+        for _ in 0..records {
+            let owner = reader.name()?.to_ascii_lowercase();
+            let record_type = reader.u16()?;
+            reader.skip(6)?;
+            let len = usize::from(reader.u16()?);
+            let end = reader
+                .pos
+                .checked_add(len)
+                .filter(|end| *end <= packet.len())?;
+            match record_type {
+                TYPE_PTR if SERVICES.contains(&owner.as_str()) => instances.push(reader.name()?),
+                TYPE_TXT => {
+                    txt.insert(owner, text_entries(&packet[reader.pos..end]));
+                }
+                _ => {}
+            }
+            reader.pos = end;
+        }
+        Some(())
+    })();
+    if parsed.is_none() {
+        return Vec::new();
+    }
+
+    instances
+        .into_iter()
+        .map(|instance| {
+            let mut entries = txt
+                .remove(&instance.to_ascii_lowercase())
+                .unwrap_or_default();
+            Discovered {
+                address: from,
+                product_type: entries.remove("product_type"),
+                product_name: entries.remove("product_name"),
+                serial: entries.remove("serial"),
+                instance,
+            }
+        })
+        .collect()
+}
+
+/// `key=value` strings of a TXT record.
+fn text_entries(mut data: &[u8]) -> HashMap<String, String> {
+    let mut entries = HashMap::new();
+    while let Some((&len, rest)) = data.split_first() {
+        let Some(entry) = rest.get(..usize::from(len)) else {
+            break;
+        };
+        if let Some((key, value)) = String::from_utf8_lossy(entry).split_once('=') {
+            entries.insert(key.to_ascii_lowercase(), value.to_string());
+        }
+        data = &rest[usize::from(len)..];
+    }
+    entries
+}
+
+struct Reader<'a> {
+    packet: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.packet.get(self.pos..self.pos + 2)?;
+        self.pos += 2;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.pos = self
+            .pos
+            .checked_add(len)
+            .filter(|pos| *pos <= self.packet.len())?;
+        Some(())
+    }
+
+    /// A domain name, following compression pointers.
+    fn name(&mut self) -> Option<String> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        let mut jumps = 0;
+        loop {
+            let len = *self.packet.get(pos)?;
+            match len {
+                0 => {
+                    if jumps == 0 {
+                        self.pos = pos + 1;
+                    }
+                    return Some(labels.join("."));
+                }
+                len if len & 0xc0 == 0xc0 => {
+                    let target =
+                        usize::from(u16::from_be_bytes([len & 0x3f, *self.packet.get(pos + 1)?]));
+                    if jumps == 0 {
+                        self.pos = pos + 2;
+                    }
+                    // Bounded, so a pointer loop cannot hang the parser
+                    jumps += 1;
+                    if jumps > 16 {
+                        return None;
+                    }
+                    pos = target;
+                }
+                len => {
+                    let label = self.packet.get(pos + 1..pos + 1 + usize::from(len))?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    pos += 1 + usize::from(len);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(packet: &mut Vec<u8>, name: &str) {
+        for label in name.split('.') {
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.push(0);
+    }
+
+    fn record(packet: &mut Vec<u8>, owner: &[u8], record_type: u16, rdata: &[u8]) {
+        packet.extend_from_slice(owner);
+        packet.extend_from_slice(&record_type.to_be_bytes());
+        packet.extend_from_slice(&[0x80, 0x01, 0, 0, 0x11, 0x94]);
+        packet.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        packet.extend_from_slice(rdata);
+    }
+
+    /// A response as sent by a watermeter: PTR to the instance, TXT with the details.
+    fn response() -> Vec<u8> {
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 1];
+        let service_at = packet.len() as u8;
+        let mut service = Vec::new();
+        name(&mut service, "_hwenergy._tcp.local");
+        // The instance name points back at the service name in the PTR owner
+        let mut instance = vec![17];
+        instance.extend_from_slice(b"watermeter-ABCDEF");
+        instance.extend_from_slice(&[0xc0, service_at]);
+        let instance_at = (packet.len() + service.len() + 10) as u8;
+        record(&mut packet, &service, TYPE_PTR, &instance);
+
+        let mut text = Vec::new();
+        for entry in [
+            "api_enabled=1",
+            "product_type=HWE-WTR",
+            "product_name=Watermeter",
+            "serial=5c2fafabcdef",
+        ] {
+            text.push(entry.len() as u8);
+            text.extend_from_slice(entry.as_bytes());
+        }
+        record(&mut packet, &[0xc0, instance_at], TYPE_TXT, &text);
+        packet
+    }
+
+    #[test]
+    fn test_query() {
+        let packet = query(&["_hwenergy._tcp.local"]);
+        assert_eq!(&packet[..12], &[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&packet[12..23], b"\x09_hwenergy\x04");
+        assert_eq!(&packet[packet.len() - 4..], &[0, 12, 0, 1]);
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let from: IpAddr = "192.168.1.30".parse().unwrap();
+        let found = parse_response(&response(), from);
+
+        assert_eq!(
+            found,
+            vec![Discovered {
+                address: from,
+                instance: "watermeter-ABCDEF._hwenergy._tcp.local".to_string(),
+                product_type: Some("HWE-WTR".to_string()),
+                product_name: Some("Watermeter".to_string()),
+                serial: Some("5c2fafabcdef".to_string()),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed_response() {
+        let from: IpAddr = "192.168.1.30".parse().unwrap();
+        let packet = response();
+        assert!(parse_response(&packet[..packet.len() - 5], from).is_empty());
+        assert!(parse_response(&[0; 5], from).is_empty());
+
+        // A pointer to itself
+        let mut looping = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+        looping.extend_from_slice(&[0xc0, 12]);
+        assert!(parse_response(&looping, from).is_empty());
+    }
+
+    #[test]
+    fn test_host_spec() {
+        let mut device = Discovered {
+            address: "192.168.1.30".parse().unwrap(),
+            instance: "p1meter-ABCDEF._hwenergy._tcp.local".to_string(),
+            product_type: Some("HWE-P1".to_string()),
+            product_name: None,
+            serial: None,
+        };
+        assert_eq!(device.host_spec(), "p1:192.168.1.30");
+
+        device.product_type = Some("HWE-WTR".to_string());
+        assert_eq!(device.host_spec(), "192.168.1.30");
+
+        device.address = "fe80::1".parse().unwrap();
+        device.product_type = None;
+        assert_eq!(device.host_spec(), "[fe80::1]");
+    }
+}
@@ -0,0 +1,178 @@
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// Events kept for subscribers that fall behind.
+const EVENT_BUFFER: usize = 256;
+
+/// Something that happened to a device, for webhooks and notifiers.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Event {
+    #[serde(flatten)]
+    pub kind: EventKind,
+    pub device: String,
+    /// Unix seconds
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum EventKind {
+    /// The device stopped answering, after `--down-after` if set.
+    DeviceOffline { error: String },
+    /// An offline device answered again.
+    DeviceRecovered,
+    /// Water has been flowing without a break for `--leak-after`.
+    LeakSuspected { flow_lpm: f64, flowing_seconds: u64 },
+    /// The meter total went backwards.
+    CounterReset { previous_m3: f64, total_m3: f64 },
+}
+
+impl EventKind {
+    /// Every event name, as used in the `event` field.
+    pub const NAMES: [&str; 4] = [
+        "device-offline",
+        "device-recovered",
+        "leak-suspected",
+        "counter-reset",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::DeviceOffline { .. } => Self::NAMES[0],
+            Self::DeviceRecovered => Self::NAMES[1],
+            Self::LeakSuspected { .. } => Self::NAMES[2],
+            Self::CounterReset { .. } => Self::NAMES[3],
+        }
+    }
+}
+
+impl Event {
+    pub fn new(device: &str, kind: EventKind) -> Self {
+        Self {
+            kind,
+            device: device.to_string(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+
+    /// A short heading for notifications.
+    pub fn title(&self) -> String {
+        let what = match self.kind {
+            EventKind::DeviceOffline { .. } => "offline",
+            EventKind::DeviceRecovered => "back online",
+            EventKind::LeakSuspected { .. } => "possible leak",
+            EventKind::CounterReset { .. } => "meter reset",
+        };
+        format!("{}: {what}", self.device)
+    }
+
+    /// A sentence describing the event.
+    pub fn message(&self) -> String {
+        let device = &self.device;
+        match &self.kind {
+            EventKind::DeviceOffline { error } => format!("{device} is offline: {error}"),
+            EventKind::DeviceRecovered => format!("{device} is back online"),
+            EventKind::LeakSuspected {
+                flow_lpm,
+                flowing_seconds,
+            } => format!(
+                "Possible leak at {device}: water has been flowing for {} minutes, now {flow_lpm} L/min",
+                flowing_seconds / 60
+            ),
+            EventKind::CounterReset {
+                previous_m3,
+                total_m3,
+            } => format!(
+                "The meter total of {device} went back from {previous_m3} m³ to {total_m3} m³"
+            ),
+        }
+    }
+}
+
+/// Broadcasts events to whoever subscribed; events without subscribers are dropped.
+#[derive(Debug, Clone)]
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(EVENT_BUFFER).0,
+        }
+    }
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    pub fn emit(&self, device: &str, kind: EventKind) {
+        let _ = self.sender.send(Event::new(device, kind));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_serialize() {
+        let event = Event {
+            kind: EventKind::CounterReset {
+                previous_m3: 120.5,
+                total_m3: 0.25,
+            },
+            device: "kitchen".to_string(),
+            timestamp: 1_700_000_000,
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            json!({
+                "event": "counter-reset",
+                "device": "kitchen",
+                "timestamp": 1_700_000_000,
+                "previous_m3": 120.5,
+                "total_m3": 0.25,
+            })
+        );
+        assert_eq!(
+            event.message(),
+            "The meter total of kitchen went back from 120.5 m³ to 0.25 m³"
+        );
+
+        let event = Event::new("kitchen", EventKind::DeviceRecovered);
+        let value = serde_json::to_value(&event).unwrap();
+        assert_eq!(value["event"], event.kind.name());
+        assert_eq!(event.title(), "kitchen: back online");
+        assert_eq!(event.message(), "kitchen is back online");
+    }
+
+    #[tokio::test]
+    async fn test_emit() {
+        let events = Events::new();
+        // Nobody listening yet
+        events.emit("kitchen", EventKind::DeviceRecovered);
+
+        let mut subscriber = events.subscribe();
+        events.emit(
+            "kitchen",
+            EventKind::DeviceOffline {
+                error: "timed out".to_string(),
+            },
+        );
+        let event = subscriber.recv().await.unwrap();
+        assert_eq!(event.kind.name(), "device-offline");
+        assert_eq!(event.message(), "kitchen is offline: timed out");
+    }
+}
@@ -0,0 +1,303 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// Scrapes other instances of this exporter and re-exposes their series.
+///
+/// Every federated series gets an `instance` label with the `host:port` of the
+/// exporter it came from, so meters on isolated networks can be collected by
+/// one exporter that Prometheus scrapes.
+pub struct Federation {
+    client: reqwest::Client,
+    targets: Vec<String>,
+    scraped: Mutex<HashMap<String, Option<String>>>,
+}
+
+impl Federation {
+    pub fn new(targets: Vec<String>, timeout: Duration) -> Result<Self> {
+        let client = reqwest::Client::builder().timeout(timeout).build()?;
+        Ok(Self {
+            client,
+            targets,
+            scraped: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Scrape every target once, keeping the latest successful body per target.
+    pub async fn scrape(&self) {
+        for target in &self.targets {
+            let body = match self.fetch(target).await {
+                Ok(body) => {
+                    debug!("Scraped federated exporter {}", target);
+                    Some(body)
+                }
+                Err(e) => {
+                    warn!("Failed to scrape federated exporter {}: {}", target, e);
+                    None
+                }
+            };
+            self.scraped.lock().unwrap().insert(target.clone(), body);
+        }
+    }
+
+    async fn fetch(&self, target: &str) -> Result<String> {
+        let body = self
+            .client
+            .get(target)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        Ok(body)
+    }
+
+    /// Merge the local exposition with the latest federated scrapes.
+    pub fn render(&self, local: &str) -> String {
+        let scraped = self.scraped.lock().unwrap();
+
+        let mut up = String::from(
+            "# HELP homewizard_water_federation_up Whether the last scrape of a federated exporter succeeded\n# TYPE homewizard_water_federation_up gauge\n",
+        );
+        let mut federated = Vec::new();
+        for target in &self.targets {
+            let instance = instance_label(target);
+            let body = scraped.get(target).and_then(Option::as_deref);
+            up.push_str(&format!(
+                "homewizard_water_federation_up{{instance=\"{}\"}} {}\n",
+                escape_label_value(&instance),
+                u8::from(body.is_some())
+            ));
+            if let Some(body) = body {
+                federated.push((instance, body));
+            }
+        }
+
+        let mut sources = vec![(None, local)];
+        sources.extend(
+            federated
+                .iter()
+                .map(|(instance, body)| (Some(instance.as_str()), *body)),
+        );
+        sources.push((None, &up));
+        merge(&sources)
+    }
+}
+
+/// `host:port` of a target URL, as Prometheus uses for its own `instance` label.
+pub fn instance_label(target: &str) -> String {
+    match reqwest::Url::parse(target) {
+        Ok(url) => match (url.host_str(), url.port_or_known_default()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_string(),
+            _ => target.to_string(),
+        },
+        Err(_) => target.to_string(),
+    }
+}
+
+#[derive(Default)]
+struct Family {
+    help: Option<String>,
+    kind: Option<String>,
+    samples: Vec<String>,
+}
+
+/// Merge text expositions so every family appears once, adding an `instance`
+/// label to the samples of sources that have one.
+pub fn merge(sources: &[(Option<&str>, &str)]) -> String {
+    let mut order: Vec<String> = Vec::new();
+    let mut families: HashMap<String, Family> = HashMap::new();
+
+    for (instance, body) in sources {
+        let mut current: Option<String> = None;
+
+        for line in body.lines() {
+            let line = line.trim_end();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(rest) = line
+                .strip_prefix("# HELP ")
+                .or(line.strip_prefix("# TYPE "))
+            {
+                let (name, value) = rest.split_once(' ').unwrap_or((rest, ""));
+                let family = families.entry(name.to_string()).or_insert_with(|| {
+                    order.push(name.to_string());
+                    Family::default()
+                });
+                let slot = if line.starts_with("# HELP ") {
+                    &mut family.help
+                } else {
+                    &mut family.kind
+                };
+                slot.get_or_insert_with(|| value.to_string());
+                current = Some(name.to_string());
+                continue;
+            }
+            if line.starts_with('#') {
+                continue;
+            }
+
+            let name_end = line.find(['{', ' ']).unwrap_or(line.len());
+            let name = &line[..name_end];
+            let family_name = match &current {
+                Some(current) if belongs_to(name, current) => current.clone(),
+                _ => name.to_string(),
+            };
+            let sample = match instance {
+                Some(instance) => add_instance_label(line, name_end, instance),
+                None => line.to_string(),
+            };
+
+            families
+                .entry(family_name.clone())
+                .or_insert_with(|| {
+                    order.push(family_name);
+                    Family::default()
+                })
+                .samples
+                .push(sample);
+        }
+    }
+
+    let mut output = String::new();
+    for name in order {
+        let family = &families[&name];
+        if let Some(help) = &family.help {
+            output.push_str(&format!("# HELP {name} {help}\n"));
+        }
+        if let Some(kind) = &family.kind {
+            output.push_str(&format!("# TYPE {name} {kind}\n"));
+        }
+        for sample in &family.samples {
+            output.push_str(sample);
+            output.push('\n');
+        }
+    }
+    output
+}
+
+/// Whether a sample name is part of the family declared last (histogram and summary suffixes).
+fn belongs_to(sample: &str, family: &str) -> bool {
+    sample
+        .strip_prefix(family)
+        .is_some_and(|suffix| matches!(suffix, "" | "_bucket" | "_sum" | "_count"))
+}
+
+fn add_instance_label(line: &str, name_end: usize, instance: &str) -> String {
+    let (name, rest) = line.split_at(name_end);
+    let label = format!("instance=\"{}\"", escape_label_value(instance));
+
+    match rest.strip_prefix('{') {
+        // Series federated from a federating exporter keep their original instance
+        Some(labels) if labels.starts_with("instance=") || labels.contains(",instance=") => {
+            line.to_string()
+        }
+        Some(labels) if labels.starts_with('}') => format!("{name}{{{label}{labels}"),
+        Some(labels) => format!("{name}{{{label},{labels}"),
+        None => format!("{name}{{{label}}}{rest}"),
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const REMOTE: &str = "# HELP homewizard_water_total_m3 Total water usage in cubic meters\n# TYPE homewizard_water_total_m3 counter\nhomewizard_water_total_m3{device=\"garden\"} 12.5\n";
+
+    #[test]
+    fn test_instance_label() {
+        assert_eq!(
+            instance_label("http://10.0.2.5:9899/metrics"),
+            "10.0.2.5:9899"
+        );
+        assert_eq!(instance_label("http://exporter/metrics"), "exporter:80");
+        assert_eq!(instance_label("not a url"), "not a url");
+    }
+
+    #[test]
+    fn test_add_instance_label() {
+        assert_eq!(
+            add_instance_label("up{job=\"a\"} 1", 2, "h:1"),
+            "up{instance=\"h:1\",job=\"a\"} 1"
+        );
+        assert_eq!(
+            add_instance_label("up 1", 2, "h:1"),
+            "up{instance=\"h:1\"} 1"
+        );
+        assert_eq!(
+            add_instance_label("up{} 1", 2, "h:1"),
+            "up{instance=\"h:1\"} 1"
+        );
+        assert_eq!(
+            add_instance_label("up{instance=\"other\"} 1", 2, "h:1"),
+            "up{instance=\"other\"} 1"
+        );
+    }
+
+    #[test]
+    fn test_merge_deduplicates_families() {
+        let local = "# HELP homewizard_water_total_m3 Total water usage in cubic meters\n# TYPE homewizard_water_total_m3 counter\nhomewizard_water_total_m3{device=\"kitchen\"} 1\n";
+        let merged = merge(&[(None, local), (Some("vlan:9899"), REMOTE)]);
+
+        assert_eq!(
+            merged.matches("# TYPE homewizard_water_total_m3").count(),
+            1
+        );
+        assert!(merged.contains("homewizard_water_total_m3{device=\"kitchen\"} 1\n"));
+        assert!(merged.contains(
+            "homewizard_water_total_m3{instance=\"vlan:9899\",device=\"garden\"} 12.5\n"
+        ));
+    }
+
+    #[test]
+    fn test_merge_keeps_histogram_samples_together() {
+        let body = "# TYPE h histogram\nh_bucket{le=\"1\"} 1\nh_bucket{le=\"+Inf\"} 2\nh_sum 3\nh_count 2\n# TYPE g gauge\ng 1\n";
+        let merged = merge(&[(Some("a:1"), body), (Some("b:1"), body)]);
+
+        let histogram = merged.split("# TYPE g").next().unwrap();
+        assert_eq!(histogram.matches("h_count").count(), 2);
+        assert_eq!(merged.matches("# TYPE").count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_scrape_and_render() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/metrics"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(REMOTE))
+            .mount(&server)
+            .await;
+
+        let up_target = format!("{}/metrics", server.uri());
+        let federation = Federation::new(
+            vec![up_target.clone(), "http://127.0.0.1:1/metrics".to_string()],
+            Duration::from_secs(1),
+        )
+        .unwrap();
+        federation.scrape().await;
+
+        let output = federation.render("");
+        let instance = instance_label(&up_target);
+        assert!(output.contains(&format!(
+            "homewizard_water_total_m3{{instance=\"{instance}\",device=\"garden\"}} 12.5"
+        )));
+        assert!(output.contains(&format!(
+            "homewizard_water_federation_up{{instance=\"{instance}\"}} 1"
+        )));
+        assert!(output.contains("homewizard_water_federation_up{instance=\"127.0.0.1:1\"} 0"));
+    }
+}
@@ -0,0 +1,626 @@
+use crate::config::Config;
+use crate::homewizard::DeviceType;
+use crate::metrics::Units;
+use serde_json::{Value, json};
+
+/// Width of the Grafana grid.
+const GRID_WIDTH: u64 = 24;
+const PANEL_HEIGHT: u64 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Stat,
+    Gauge,
+    Timeseries,
+}
+
+impl Kind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::Stat => "stat",
+            Kind::Gauge => "gauge",
+            Kind::Timeseries => "timeseries",
+        }
+    }
+
+    fn width(self) -> u64 {
+        match self {
+            Kind::Stat | Kind::Gauge => 6,
+            Kind::Timeseries => 12,
+        }
+    }
+}
+
+/// A panel showing one metric family.
+#[derive(Debug, Clone)]
+struct Panel {
+    title: &'static str,
+    kind: Kind,
+    unit: &'static str,
+    family: &'static str,
+    /// PromQL with `{}` standing for the selected series
+    expr: &'static str,
+    legend: &'static str,
+    /// Value texts for a state such as `homewizard_up`: (value, text, color)
+    mappings: &'static [(u8, &'static str, &'static str)],
+    /// Whether the series have a `device` label to select on
+    per_device: bool,
+}
+
+impl Panel {
+    fn new(title: &'static str, kind: Kind, unit: &'static str, family: &'static str) -> Self {
+        Self {
+            title,
+            kind,
+            unit,
+            family,
+            expr: "{}",
+            legend: "{{device}}",
+            mappings: &[],
+            per_device: true,
+        }
+    }
+
+    fn expr(mut self, expr: &'static str) -> Self {
+        self.expr = expr;
+        self
+    }
+
+    fn legend(mut self, legend: &'static str) -> Self {
+        self.legend = legend;
+        self
+    }
+
+    /// A series summed over all devices (`--aggregate`).
+    fn aggregate(mut self) -> Self {
+        self.per_device = false;
+        self.legend = "All meters";
+        self
+    }
+
+    fn mappings(mut self, mappings: &'static [(u8, &'static str, &'static str)]) -> Self {
+        self.mappings = mappings;
+        self
+    }
+}
+
+const UP: &[(u8, &str, &str)] = &[(0, "Down", "red"), (1, "Up", "green")];
+const LEAK: &[(u8, &str, &str)] = &[(0, "No", "green"), (1, "Suspected", "red")];
+const SWITCH: &[(u8, &str, &str)] = &[(0, "Off", "text"), (1, "On", "green")];
+
+/// The panels for what the exporter is configured to export.
+fn panels(config: &Config) -> Vec<Panel> {
+    let mut device_types: Vec<DeviceType> = Vec::new();
+    for device in config.devices() {
+        if !device_types.contains(&device.device_type) {
+            device_types.push(device.device_type);
+        }
+    }
+    if device_types.is_empty() {
+        device_types.push(DeviceType::Water);
+    }
+
+    let mut panels =
+        vec![Panel::new("Device status", Kind::Stat, "none", "homewizard_up").mappings(UP)];
+    for device_type in DeviceType::ALL {
+        if device_types.contains(&device_type) {
+            panels.extend(device_panels(config, device_type));
+        }
+    }
+
+    let filter = config.metric_filter();
+    panels.retain(|panel| filter.allows(panel.family));
+    panels
+}
+
+fn device_panels(config: &Config, device_type: DeviceType) -> Vec<Panel> {
+    use Kind::*;
+    match device_type {
+        DeviceType::Water => {
+            let mut panels = match config.units {
+                Units::Metric => vec![
+                    Panel::new("Total consumption", Stat, "m3", "homewizard_water_total_m3"),
+                    Panel::new("Used today", Stat, "m3", "homewizard_water_usage_today_m3"),
+                    Panel::new(
+                        "Flow",
+                        Timeseries,
+                        "flowlpm",
+                        "homewizard_water_active_flow_lpm",
+                    ),
+                ],
+                Units::Us => vec![
+                    Panel::new(
+                        "Total consumption",
+                        Stat,
+                        "gallons",
+                        "homewizard_water_total_gallons",
+                    ),
+                    Panel::new(
+                        "Used today",
+                        Stat,
+                        "gallons",
+                        "homewizard_water_usage_today_gallons",
+                    ),
+                    Panel::new(
+                        "Flow",
+                        Timeseries,
+                        "flowgpm",
+                        "homewizard_water_active_flow_gpm",
+                    ),
+                ],
+            };
+            panels.extend([
+                Panel::new(
+                    "Usage per hour",
+                    Timeseries,
+                    "m3",
+                    "homewizard_water_total_m3",
+                )
+                .expr("increase({}[1h])"),
+                Panel::new(
+                    "Average flow",
+                    Timeseries,
+                    "flowlpm",
+                    "homewizard_water_flow_avg_lpm",
+                )
+                .legend("{{device}} {{window}}"),
+                Panel::new(
+                    "Peak flow today",
+                    Stat,
+                    "flowlpm",
+                    "homewizard_water_peak_flow_lpm",
+                ),
+            ]);
+            if config.aggregate {
+                panels.extend([
+                    Panel::new(
+                        "Total consumption, all meters",
+                        Stat,
+                        "m3",
+                        "homewizard_water_aggregate_total_m3",
+                    )
+                    .aggregate(),
+                    Panel::new(
+                        "Flow, all meters",
+                        Timeseries,
+                        "flowlpm",
+                        "homewizard_water_aggregate_active_flow_lpm",
+                    )
+                    .aggregate(),
+                ]);
+            }
+            if config.net_total {
+                panels.push(Panel::new(
+                    "Net total",
+                    Stat,
+                    "m3",
+                    "homewizard_water_net_total_m3",
+                ));
+            }
+            if config.cumulative_total {
+                panels.push(Panel::new(
+                    "Cumulative total",
+                    Stat,
+                    "m3",
+                    "homewizard_water_cumulative_total_m3",
+                ));
+            }
+            if config.pricing().is_some() {
+                panels.extend([
+                    Panel::new("Cost today", Stat, "none", "homewizard_water_cost_today"),
+                    Panel::new(
+                        "Estimated cost",
+                        Stat,
+                        "none",
+                        "homewizard_water_cost_estimate_total",
+                    ),
+                ]);
+            }
+            if config.budget().is_some() {
+                panels.extend([
+                    Panel::new(
+                        "Budget used",
+                        Gauge,
+                        "percent",
+                        "homewizard_water_budget_used_percent",
+                    ),
+                    Panel::new(
+                        "Budget remaining",
+                        Stat,
+                        "m3",
+                        "homewizard_water_budget_remaining_m3",
+                    ),
+                ]);
+            }
+            if config.quiet_hours.is_some() {
+                panels.push(Panel::new(
+                    "Night usage",
+                    Timeseries,
+                    "m3",
+                    "homewizard_water_night_usage_m3",
+                ));
+            }
+            if !config.leak_after.is_zero() {
+                panels.push(
+                    Panel::new("Leak", Stat, "none", "homewizard_water_leak_suspected")
+                        .mappings(LEAK),
+                );
+            }
+            if !config.disable_wifi_metrics {
+                panels.push(Panel::new(
+                    "Wi-Fi signal",
+                    Gauge,
+                    "percent",
+                    "homewizard_water_wifi_strength_percent",
+                ));
+            }
+            panels
+        }
+        DeviceType::P1 => vec![
+            Panel::new(
+                "Grid power",
+                Timeseries,
+                "watt",
+                "homewizard_p1_active_power_w",
+            ),
+            Panel::new(
+                "Grid voltage",
+                Timeseries,
+                "volt",
+                "homewizard_p1_voltage_v",
+            )
+            .legend("{{device}} {{phase}}"),
+            Panel::new(
+                "Energy imported",
+                Stat,
+                "kwatth",
+                "homewizard_p1_energy_import_kwh",
+            ),
+            Panel::new(
+                "Energy exported",
+                Stat,
+                "kwatth",
+                "homewizard_p1_energy_export_kwh",
+            ),
+            Panel::new("Gas", Stat, "m3", "homewizard_p1_gas_m3"),
+        ],
+        DeviceType::EnergySocket => vec![
+            Panel::new(
+                "Socket power",
+                Timeseries,
+                "watt",
+                "homewizard_energy_socket_active_power_w",
+            ),
+            Panel::new(
+                "Socket energy",
+                Stat,
+                "kwatth",
+                "homewizard_energy_socket_energy_import_kwh",
+            ),
+            Panel::new(
+                "Socket switch",
+                Stat,
+                "none",
+                "homewizard_energy_socket_switch_state",
+            )
+            .mappings(SWITCH),
+        ],
+        DeviceType::Kwh => vec![
+            Panel::new(
+                "kWh meter power",
+                Timeseries,
+                "watt",
+                "homewizard_kwh_active_power_w",
+            ),
+            Panel::new(
+                "kWh meter phase power",
+                Timeseries,
+                "watt",
+                "homewizard_kwh_phase_power_w",
+            )
+            .legend("{{device}} {{phase}}"),
+            Panel::new(
+                "kWh meter voltage",
+                Timeseries,
+                "volt",
+                "homewizard_kwh_voltage_v",
+            )
+            .legend("{{device}} {{phase}}"),
+            Panel::new(
+                "kWh meter current",
+                Timeseries,
+                "amp",
+                "homewizard_kwh_current_a",
+            )
+            .legend("{{device}} {{phase}}"),
+            Panel::new(
+                "kWh meter imported",
+                Stat,
+                "kwatth",
+                "homewizard_kwh_energy_import_kwh",
+            ),
+            Panel::new(
+                "kWh meter exported",
+                Stat,
+                "kwatth",
+                "homewizard_kwh_energy_export_kwh",
+            ),
+        ],
+    }
+}
+
+/// Label matchers for the `--label` values, which every series carries.
+pub(crate) fn label_matchers(config: &Config) -> Vec<String> {
+    config
+        .labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+            format!("{name}=\"{value}\"")
+        })
+        .collect()
+}
+
+/// A dashboard UID from the title, so importing again replaces the dashboard.
+fn uid(title: &str) -> String {
+    let mut uid = String::new();
+    for c in title.chars() {
+        if c.is_ascii_alphanumeric() {
+            uid.push(c.to_ascii_lowercase());
+        } else if !uid.is_empty() && !uid.ends_with('-') {
+            uid.push('-');
+        }
+    }
+    let uid = uid.trim_end_matches('-');
+    uid.chars().take(40).collect()
+}
+
+fn datasource() -> Value {
+    json!({"type": "prometheus", "uid": "${datasource}"})
+}
+
+fn panel_json(panel: &Panel, id: usize, grid_pos: Value, labels: &str) -> Value {
+    let series = if panel.per_device {
+        format!("{}{{device=~\"$device\"{labels}}}", panel.family)
+    } else {
+        format!("{}{{{}}}", panel.family, labels.trim_start_matches(','))
+    };
+    let mut defaults = json!({
+        "color": {"mode": if panel.kind == Kind::Timeseries { "palette-classic" } else { "thresholds" }},
+        "mappings": [],
+        "thresholds": {"mode": "absolute", "steps": [{"color": "green", "value": null}]},
+        "unit": panel.unit,
+    });
+    if panel.unit == "percent" {
+        defaults["min"] = json!(0);
+        defaults["max"] = json!(100);
+    }
+    if !panel.mappings.is_empty() {
+        let options: serde_json::Map<String, Value> = panel
+            .mappings
+            .iter()
+            .enumerate()
+            .map(|(index, (value, text, color))| {
+                (
+                    value.to_string(),
+                    json!({"text": text, "color": color, "index": index}),
+                )
+            })
+            .collect();
+        defaults["mappings"] = json!([{"type": "value", "options": options}]);
+    }
+
+    let options = match panel.kind {
+        Kind::Timeseries => json!({
+            "legend": {"displayMode": "list", "placement": "bottom", "showLegend": true},
+            "tooltip": {"mode": "multi", "sort": "none"},
+        }),
+        Kind::Stat | Kind::Gauge => json!({
+            "reduceOptions": {"values": false, "calcs": ["lastNotNull"], "fields": ""},
+        }),
+    };
+
+    json!({
+        "datasource": datasource(),
+        "fieldConfig": {"defaults": defaults, "overrides": []},
+        "gridPos": grid_pos,
+        "id": id,
+        "options": options,
+        "targets": [{
+            "datasource": datasource(),
+            "editorMode": "code",
+            "expr": panel.expr.replace("{}", &series),
+            "legendFormat": panel.legend,
+            "range": true,
+            "refId": "A",
+        }],
+        "title": panel.title,
+        "type": panel.kind.as_str(),
+    })
+}
+
+/// A Grafana dashboard for the metric families `config` exports, ready to import.
+///
+/// Queries are limited to the `--label` values and a `device` variable, and
+/// the Prometheus data source is picked on import.
+pub fn dashboard(config: &Config, title: &str) -> Value {
+    let matchers = label_matchers(config);
+    let labels: String = matchers
+        .iter()
+        .map(|matcher| format!(",{matcher}"))
+        .collect();
+    let devices = format!(
+        "label_values(homewizard_up{{{}}}, device)",
+        matchers.join(",")
+    );
+    let (mut x, mut y) = (0, 0);
+    let panels: Vec<Value> = panels(config)
+        .iter()
+        .enumerate()
+        .map(|(index, panel)| {
+            let width = panel.kind.width();
+            if x + width > GRID_WIDTH {
+                x = 0;
+                y += PANEL_HEIGHT;
+            }
+            let grid_pos = json!({"h": PANEL_HEIGHT, "w": width, "x": x, "y": y});
+            x += width;
+            panel_json(panel, index + 1, grid_pos, &labels)
+        })
+        .collect();
+
+    json!({
+        "annotations": {"list": []},
+        "editable": true,
+        "graphTooltip": 1,
+        "panels": panels,
+        "refresh": "30s",
+        "schemaVersion": 39,
+        "tags": ["homewizard"],
+        "templating": {"list": [
+            {
+                "current": {"text": "Prometheus", "value": "prometheus"},
+                "label": "Data Source",
+                "name": "datasource",
+                "query": "prometheus",
+                "type": "datasource",
+            },
+            {
+                "datasource": datasource(),
+                "definition": devices,
+                "includeAll": true,
+                "current": {"text": "All", "value": "$__all"},
+                "label": "Device",
+                "multi": true,
+                "name": "device",
+                "query": {
+                    "query": devices,
+                    "refId": "device",
+                },
+                "refresh": 2,
+                "sort": 1,
+                "type": "query",
+            },
+        ]},
+        "time": {"from": "now-24h", "to": "now"},
+        "timezone": "browser",
+        "title": title,
+        "uid": uid(title),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn config(args: &[&str]) -> Config {
+        Config::try_parse_from(
+            std::iter::once("homewizard-water-exporter").chain(args.iter().copied()),
+        )
+        .unwrap()
+    }
+
+    fn exprs(dashboard: &Value) -> Vec<String> {
+        dashboard["panels"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|panel| panel["targets"][0]["expr"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_dashboard() {
+        let dashboard = dashboard(&config(&["--host", "192.168.1.5"]), "HomeWizard Water");
+        assert_eq!(dashboard["title"], "HomeWizard Water");
+        assert_eq!(dashboard["uid"], "homewizard-water");
+
+        let exprs = exprs(&dashboard);
+        assert!(exprs.contains(&r#"homewizard_water_total_m3{device=~"$device"}"#.to_string()));
+        assert!(exprs.contains(
+            &r#"increase(homewizard_water_total_m3{device=~"$device"}[1h])"#.to_string()
+        ));
+        assert!(
+            exprs
+                .iter()
+                .any(|expr| expr.starts_with("homewizard_water_wifi_strength_percent"))
+        );
+        // Only for features that are enabled or device types that are configured
+        assert!(!exprs.iter().any(|expr| expr.contains("leak_suspected")));
+        assert!(!exprs.iter().any(|expr| expr.contains("cost")));
+        assert!(!exprs.iter().any(|expr| expr.contains("homewizard_p1_")));
+
+        // Panels fill the grid row by row without overlapping
+        let mut cells = std::collections::HashSet::new();
+        for panel in dashboard["panels"].as_array().unwrap() {
+            let pos = &panel["gridPos"];
+            let (x, y, w) = (
+                pos["x"].as_u64().unwrap(),
+                pos["y"].as_u64().unwrap(),
+                pos["w"].as_u64().unwrap(),
+            );
+            assert!(x + w <= GRID_WIDTH);
+            for column in x..x + w {
+                assert!(cells.insert((column, y)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_dashboard_follows_config() {
+        let config = config(&[
+            "--host",
+            "p1:192.168.1.7",
+            "--host",
+            "192.168.1.5",
+            "--units",
+            "us",
+            "--leak-after",
+            "30m",
+            "--price-per-m3",
+            "1.5",
+            "--disable-wifi-metrics",
+            "--metrics-exclude",
+            "homewizard_p1_gas_*",
+            "--label",
+            "site=home \"A\"",
+            "--aggregate",
+        ]);
+        let dashboard = dashboard(&config, "Home");
+        let exprs = exprs(&dashboard);
+        let has = |family: &str| {
+            exprs
+                .iter()
+                .any(|expr| expr.contains(&format!("{family}{{")))
+        };
+
+        assert!(has("homewizard_water_total_gallons"));
+        assert!(has("homewizard_water_active_flow_gpm"));
+        assert!(!has("homewizard_water_active_flow_lpm"));
+        assert!(has("homewizard_water_leak_suspected"));
+        assert!(has("homewizard_water_cost_today"));
+        assert!(has("homewizard_p1_active_power_w"));
+        assert!(!has("homewizard_p1_gas_m3"));
+        assert!(!has("homewizard_water_wifi_strength_percent"));
+
+        assert!(
+            exprs.contains(&r#"homewizard_up{device=~"$device",site="home \"A\""}"#.to_string())
+        );
+        assert!(
+            exprs
+                .contains(&r#"homewizard_water_aggregate_total_m3{site="home \"A\""}"#.to_string())
+        );
+        assert_eq!(
+            dashboard["templating"]["list"][1]["query"]["query"],
+            r#"label_values(homewizard_up{site="home \"A\""}, device)"#
+        );
+    }
+
+    #[test]
+    fn test_uid() {
+        assert_eq!(uid("HomeWizard"), "homewizard");
+        assert_eq!(uid("  Water & energy (home) "), "water-energy-home");
+        assert_eq!(uid(&"x".repeat(50)).len(), 40);
+    }
+}
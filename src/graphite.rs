@@ -0,0 +1,181 @@
+use crate::readings::{Readings, TimedReading};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tracing::{debug, warn};
+
+/// The plaintext protocol lines of `readings`: `<prefix>.<device>.<field> <value> <timestamp>`.
+pub fn lines(prefix: &str, readings: &[TimedReading]) -> String {
+    let mut lines = String::new();
+    for reading in readings {
+        let device = component(&reading.device);
+        for (field, value) in reading.reading.fields() {
+            if value.is_finite() {
+                let _ = writeln!(
+                    lines,
+                    "{prefix}.{device}.{field} {value} {}",
+                    reading.timestamp
+                );
+            }
+        }
+    }
+    lines
+}
+
+/// A device name as one node of a metric path.
+fn component(text: &str) -> String {
+    text.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Pushes the latest readings to a Graphite (carbon) plaintext listener
+/// (`--graphite-address`) on an interval.
+pub struct GraphiteSink {
+    address: String,
+    prefix: String,
+    timeout: Duration,
+    /// Timestamp of the last reading sent per device, so a device that stopped
+    /// answering is not sent again
+    sent: HashMap<String, u64>,
+}
+
+impl GraphiteSink {
+    pub fn new(address: String, prefix: String, timeout: Duration) -> Self {
+        Self {
+            address,
+            prefix,
+            timeout,
+            sent: HashMap::new(),
+        }
+    }
+
+    /// Send the readings taken since the previous push, over a new connection.
+    pub async fn push(&mut self, latest: Vec<TimedReading>) -> Result<()> {
+        let new: Vec<TimedReading> = latest
+            .into_iter()
+            .filter(|reading| {
+                self.sent
+                    .get(&reading.device)
+                    .is_none_or(|sent| reading.timestamp > *sent)
+            })
+            .collect();
+        if new.is_empty() {
+            return Ok(());
+        }
+
+        let lines = lines(&self.prefix, &new);
+        tokio::time::timeout(self.timeout, async {
+            let mut stream = TcpStream::connect(&self.address).await?;
+            stream.write_all(lines.as_bytes()).await?;
+            stream.shutdown().await
+        })
+        .await
+        .context("timed out")?
+        .with_context(|| format!("failed to send to {}", self.address))?;
+
+        for reading in &new {
+            self.sent.insert(reading.device.clone(), reading.timestamp);
+        }
+        debug!("Sent {} readings to Graphite", new.len());
+        Ok(())
+    }
+
+    /// Push the latest `readings` every `interval` from a background task.
+    pub fn start(mut self, readings: Readings, interval: Duration) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.push(readings.latest()).await {
+                    warn!("Failed to push readings to Graphite: {:#}", e);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::homewizard::{HomeWizardWaterData, Reading};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    fn reading(device: &str, timestamp: u64) -> TimedReading {
+        TimedReading {
+            device: device.to_string(),
+            device_type: "water",
+            timestamp,
+            source: "local",
+            reading: Reading::Water(HomeWizardWaterData {
+                total_liter_m3: 42.5,
+                active_liter_lpm: Some(3.0),
+                ..Default::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn test_lines() {
+        assert_eq!(
+            lines("home.water", &[reading("kitchen sink", 1_700_000_000)]),
+            "home.water.kitchen_sink.total_liter_m3 42.5 1700000000\n\
+             home.water.kitchen_sink.active_liter_lpm 3 1700000000\n"
+        );
+        assert_eq!(component("192.168.1.5"), "192_168_1_5");
+    }
+
+    #[tokio::test]
+    async fn test_push() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut sink = GraphiteSink::new(
+            listener.local_addr().unwrap().to_string(),
+            "homewizard".to_string(),
+            Duration::from_secs(5),
+        );
+        let received = tokio::spawn(async move {
+            let mut received = Vec::new();
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut text = String::new();
+                stream.read_to_string(&mut text).await.unwrap();
+                received.push(text);
+            }
+            received
+        });
+
+        sink.push(vec![reading("kitchen", 100)]).await.unwrap();
+        // Nothing new: no connection
+        sink.push(vec![reading("kitchen", 100)]).await.unwrap();
+        sink.push(vec![reading("kitchen", 100), reading("garden", 150)])
+            .await
+            .unwrap();
+
+        let received = received.await.unwrap();
+        assert!(received[0].starts_with("homewizard.kitchen.total_liter_m3 42.5 100\n"));
+        assert!(received[1].starts_with("homewizard.garden."));
+        assert!(!received[1].contains("kitchen"));
+    }
+
+    #[tokio::test]
+    async fn test_push_unreachable() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let mut sink = GraphiteSink::new(address, "homewizard".to_string(), Duration::from_secs(5));
+        assert!(sink.push(vec![reading("kitchen", 100)]).await.is_err());
+        // Sent again on the next push
+        assert!(sink.sent.is_empty());
+    }
+}
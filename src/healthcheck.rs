@@ -0,0 +1,150 @@
+use crate::config::ListenAddress;
+use anyhow::{Context, Result, bail};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+/// Ask the exporter listening on `address` whether it is ready, for container
+/// `HEALTHCHECK`s in images without curl. Returns the body of `/ready`.
+pub async fn check(address: &ListenAddress, https: bool, timeout: Duration) -> Result<String> {
+    let (status, body) = match address {
+        ListenAddress::Tcp(addr) => {
+            // The certificate names the public host, not the loopback address
+            let client = reqwest::Client::builder()
+                .timeout(timeout)
+                .danger_accept_invalid_certs(https)
+                .build()?;
+            let url = ready_url(*addr, https);
+            let response = client
+                .get(&url)
+                .send()
+                .await
+                .with_context(|| format!("failed to reach {url}"))?;
+            let status = response.status().as_u16();
+            (status, response.text().await?)
+        }
+        #[cfg(unix)]
+        ListenAddress::Unix(path) => tokio::time::timeout(timeout, get_unix(path))
+            .await
+            .context("timed out")?
+            .with_context(|| format!("failed to reach {}", path.display()))?,
+        #[cfg(not(unix))]
+        ListenAddress::Unix(_) => bail!("Unix domain sockets are not supported on this platform"),
+    };
+
+    let body = body.trim().to_string();
+    if !(200..300).contains(&status) {
+        bail!("/ready answered {status}: {body}");
+    }
+    Ok(body)
+}
+
+/// `/ready` on `addr`, through the loopback interface when listening on all of them.
+fn ready_url(addr: SocketAddr, https: bool) -> String {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    let scheme = if https { "https" } else { "http" };
+    format!("{scheme}://{}/ready", SocketAddr::new(ip, addr.port()))
+}
+
+/// Status and body of `/ready` over a Unix socket, which reqwest cannot connect to.
+#[cfg(unix)]
+async fn get_unix(path: &std::path::Path) -> Result<(u16, String)> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut stream = tokio::net::UnixStream::connect(path).await?;
+    stream
+        .write_all(b"GET /ready HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .with_context(|| format!("invalid HTTP response: {head:?}"))?;
+    Ok((status, body.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::http::StatusCode;
+    use axum::routing::get;
+
+    fn app(status: StatusCode, body: &'static str) -> Router {
+        Router::new().route("/ready", get(move || async move { (status, body) }))
+    }
+
+    #[test]
+    fn test_ready_url() {
+        assert_eq!(
+            ready_url("0.0.0.0:9899".parse().unwrap(), false),
+            "http://127.0.0.1:9899/ready"
+        );
+        assert_eq!(
+            ready_url("[::]:9899".parse().unwrap(), true),
+            "https://[::1]:9899/ready"
+        );
+        assert_eq!(
+            ready_url("192.168.1.5:9100".parse().unwrap(), false),
+            "http://192.168.1.5:9100/ready"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_tcp() {
+        let timeout = Duration::from_secs(5);
+        for (status, body, ok) in [
+            (StatusCode::OK, "OK", true),
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Not ready: no successful poll yet",
+                false,
+            ),
+        ] {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = ListenAddress::Tcp(listener.local_addr().unwrap());
+            tokio::spawn(async move { axum::serve(listener, app(status, body)).await });
+
+            let result = check(&address, false, timeout).await;
+            if ok {
+                assert_eq!(result.unwrap(), "OK");
+            } else {
+                let error = result.unwrap_err().to_string();
+                assert_eq!(
+                    error,
+                    "/ready answered 503: Not ready: no successful poll yet"
+                );
+            }
+        }
+
+        // Nothing listening
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = ListenAddress::Tcp(listener.local_addr().unwrap());
+        drop(listener);
+        assert!(check(&address, false, timeout).await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_check_unix() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("exporter.sock");
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app(StatusCode::OK, "OK")).await });
+
+        let address = ListenAddress::Unix(path);
+        assert_eq!(
+            check(&address, false, Duration::from_secs(5))
+                .await
+                .unwrap(),
+            "OK"
+        );
+    }
+}
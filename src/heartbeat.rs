@@ -0,0 +1,254 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// Dead-man's-switch pings to healthchecks.io, Uptime Kuma and similar services.
+///
+/// The success URL is pinged while every device's last poll succeeded, the fail
+/// URL once one of them failed `fail_after` times in a row. If the exporter dies
+/// entirely the pings stop, and the monitoring service alerts on the missing check-in.
+pub struct Heartbeat {
+    client: reqwest::Client,
+    url: String,
+    fail_url: String,
+    fail_after: u32,
+    // Consecutive failed polls per device
+    device_failures: Mutex<HashMap<String, u32>>,
+}
+
+impl Heartbeat {
+    /// `fail_url` defaults to `url` with `/fail` appended, as used by healthchecks.io.
+    pub fn new(url: String, fail_url: Option<String>, timeout: Duration) -> Result<Self> {
+        let client = reqwest::Client::builder().timeout(timeout).build()?;
+        let fail_url = fail_url.unwrap_or_else(|| format!("{}/fail", url.trim_end_matches('/')));
+
+        Ok(Self {
+            client,
+            url,
+            fail_url,
+            fail_after: 1,
+            device_failures: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Only report a device as failing after this many failed polls in a row
+    /// (`--heartbeat-fail-after`); the failures before that are not pinged at all.
+    pub fn with_fail_after(mut self, fail_after: u32) -> Self {
+        self.fail_after = fail_after.max(1);
+        self
+    }
+
+    pub fn fail_url(&self) -> &str {
+        &self.fail_url
+    }
+
+    /// Stop counting a device that is no longer configured.
+    pub fn remove_device(&self, device: &str) {
+        self.device_failures.lock().unwrap().remove(device);
+    }
+
+    /// Record the outcome of a device poll and ping the matching URL.
+    pub async fn report(&self, device: &str, success: bool) {
+        let failing = {
+            let mut failures = self.device_failures.lock().unwrap();
+            let count = failures.entry(device.to_string()).or_default();
+            *count = if success { 0 } else { count.saturating_add(1) };
+            let mut failing: Vec<String> = failures
+                .iter()
+                .filter(|(_, count)| **count >= self.fail_after)
+                .map(|(device, _)| device.clone())
+                .collect();
+            failing.sort();
+            failing
+        };
+        // A failure that does not count yet says nothing either way
+        if !success && failing.is_empty() {
+            debug!("Heartbeat skipped for a failed poll of {}", device);
+            return;
+        }
+
+        let result = if failing.is_empty() {
+            self.client.get(&self.url).send().await
+        } else {
+            let body = format!("poll failed for: {}", failing.join(", "));
+            self.client.post(&self.fail_url).body(body).send().await
+        };
+
+        match result.and_then(|response| response.error_for_status()) {
+            Ok(_) => debug!("Heartbeat sent (failing devices: {})", failing.len()),
+            Err(e) => warn!("Failed to send heartbeat: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_string, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn test_default_fail_url() {
+        let heartbeat = Heartbeat::new(
+            "https://hc-ping.com/abc/".to_string(),
+            None,
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert_eq!(heartbeat.fail_url(), "https://hc-ping.com/abc/fail");
+    }
+
+    #[test]
+    fn test_custom_fail_url() {
+        let heartbeat = Heartbeat::new(
+            "https://kuma.local/api/push/abc?status=up".to_string(),
+            Some("https://kuma.local/api/push/abc?status=down".to_string()),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert_eq!(
+            heartbeat.fail_url(),
+            "https://kuma.local/api/push/abc?status=down"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_report_success_pings_url() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/ping"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let heartbeat = Heartbeat::new(
+            format!("{}/ping", server.uri()),
+            None,
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        heartbeat.report("meter", true).await;
+    }
+
+    #[tokio::test]
+    async fn test_report_failure_pings_fail_url() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/ping/fail"))
+            .and(body_string("poll failed for: meter"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let heartbeat = Heartbeat::new(
+            format!("{}/ping", server.uri()),
+            None,
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        heartbeat.report("meter", false).await;
+    }
+
+    #[tokio::test]
+    async fn test_one_failing_device_keeps_heartbeat_failing() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/ping/fail"))
+            .and(body_string("poll failed for: garden"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/ping"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let heartbeat = Heartbeat::new(
+            format!("{}/ping", server.uri()),
+            None,
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        heartbeat.report("garden", false).await;
+        heartbeat.report("kitchen", true).await;
+    }
+
+    #[tokio::test]
+    async fn test_fail_after_repeated_failures() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/ping/fail"))
+            .and(body_string("poll failed for: meter"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/ping"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let heartbeat = Heartbeat::new(
+            format!("{}/ping", server.uri()),
+            None,
+            Duration::from_secs(5),
+        )
+        .unwrap()
+        .with_fail_after(3);
+        heartbeat.report("meter", false).await;
+        heartbeat.report("meter", false).await;
+        // A success in between starts the count over
+        heartbeat.report("meter", true).await;
+        for _ in 0..3 {
+            heartbeat.report("meter", false).await;
+        }
+        heartbeat.report("meter", true).await;
+    }
+
+    #[tokio::test]
+    async fn test_removed_device_no_longer_fails_heartbeat() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/ping/fail"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/ping"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let heartbeat = Heartbeat::new(
+            format!("{}/ping", server.uri()),
+            None,
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        heartbeat.report("garden", false).await;
+        heartbeat.remove_device("garden");
+        heartbeat.report("kitchen", true).await;
+    }
+
+    #[tokio::test]
+    async fn test_report_tolerates_unreachable_endpoint() {
+        let heartbeat = Heartbeat::new(
+            "http://127.0.0.1:12345/ping".to_string(),
+            None,
+            Duration::from_secs(1),
+        )
+        .unwrap();
+        heartbeat.report("meter", true).await;
+    }
+}
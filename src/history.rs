@@ -0,0 +1,120 @@
+use crate::homewizard::Reading;
+use crate::readings::{StoredReading, TimedReading};
+use anyhow::{Context, Result};
+use rusqlite::{Connection, params};
+use std::path::Path;
+use tracing::warn;
+
+/// SQLite database mirroring the in-memory history, so it survives restarts
+/// (`--history-db`).
+///
+/// Every reading is a row; the total and flow of water meters get columns of
+/// their own so the database can be queried directly, and the full reading is
+/// kept as JSON. Rows older than the retention are deleted as new ones come in.
+#[derive(Debug)]
+pub struct HistoryDb {
+    connection: Connection,
+}
+
+impl HistoryDb {
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let connection = Connection::open(path).context("failed to open history database")?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS readings (
+                    device TEXT NOT NULL,
+                    type TEXT NOT NULL,
+                    timestamp INTEGER NOT NULL,
+                    source TEXT NOT NULL,
+                    total_m3 REAL,
+                    flow_lpm REAL,
+                    reading TEXT NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS readings_timestamp ON readings (timestamp);",
+            )
+            .context("failed to create history table")?;
+        Ok(Self { connection })
+    }
+
+    /// The readings from `since` (Unix seconds) on, oldest first, skipping rows
+    /// that cannot be read back.
+    pub fn load(&self, since: u64) -> Result<Vec<TimedReading>> {
+        let mut statement = self.connection.prepare(
+            "SELECT device, type, timestamp, source, reading FROM readings
+             WHERE timestamp >= ?1 ORDER BY timestamp, rowid",
+        )?;
+        let rows = statement.query_map(params![unix(since)], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+            ))
+        })?;
+        let mut readings = Vec::new();
+        for row in rows {
+            let (device, device_type, timestamp, source, reading) = row?;
+            let reading = serde_json::from_str(&reading)
+                .map_err(anyhow::Error::from)
+                .and_then(|reading| {
+                    TimedReading::try_from(StoredReading {
+                        device,
+                        device_type,
+                        timestamp: timestamp.max(0) as u64,
+                        source,
+                        reading,
+                    })
+                });
+            match reading {
+                Ok(reading) => readings.push(reading),
+                Err(e) => warn!("Skipping unreadable history entry: {:#}", e),
+            }
+        }
+        Ok(readings)
+    }
+
+    /// Store `reading`, and delete the rows from before `cutoff` (Unix seconds).
+    pub fn insert(&self, reading: &TimedReading, cutoff: u64) -> Result<()> {
+        let (total, flow) = match &reading.reading {
+            Reading::Water(data) => (Some(data.total_liter_m3), data.active_liter_lpm),
+            _ => (None, None),
+        };
+        self.connection.execute(
+            "INSERT INTO readings (device, type, timestamp, source, total_m3, flow_lpm, reading)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                reading.device,
+                reading.device_type,
+                unix(reading.timestamp),
+                reading.source,
+                total,
+                flow,
+                serde_json::to_string(&reading.reading)?,
+            ],
+        )?;
+        self.prune(cutoff)
+    }
+
+    /// Delete the rows from before `cutoff` (Unix seconds).
+    pub fn prune(&self, cutoff: u64) -> Result<()> {
+        self.connection.execute(
+            "DELETE FROM readings WHERE timestamp < ?1",
+            params![unix(cutoff)],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_device(&self, device: &str) -> Result<()> {
+        self.connection
+            .execute("DELETE FROM readings WHERE device = ?1", params![device])?;
+        Ok(())
+    }
+}
+
+fn unix(seconds: u64) -> i64 {
+    i64::try_from(seconds).unwrap_or(i64::MAX)
+}
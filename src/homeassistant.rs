@@ -0,0 +1,346 @@
+use crate::build_info::BUILD_INFO;
+use crate::homewizard::{DeviceType, Reading};
+use crate::mqtt::Message;
+use serde_json::{Value, json};
+use std::time::Duration;
+
+/// How a reading's value shows up in Home Assistant.
+struct Sensor {
+    field: &'static str,
+    name: &'static str,
+    device_class: Option<&'static str>,
+    unit: &'static str,
+    state_class: &'static str,
+    /// Shown under the device's diagnostics rather than its sensors
+    diagnostic: bool,
+}
+
+const fn sensor(
+    field: &'static str,
+    name: &'static str,
+    device_class: Option<&'static str>,
+    unit: &'static str,
+    state_class: &'static str,
+) -> Sensor {
+    Sensor {
+        field,
+        name,
+        device_class,
+        unit,
+        state_class,
+        diagnostic: false,
+    }
+}
+
+/// The fields of [`Reading::fields`] announced as sensors; others are left out.
+const SENSORS: &[Sensor] = &[
+    sensor(
+        "total_liter_m3",
+        "Total water",
+        Some("water"),
+        "m³",
+        "total_increasing",
+    ),
+    sensor(
+        "active_liter_lpm",
+        "Water flow",
+        Some("volume_flow_rate"),
+        "L/min",
+        "measurement",
+    ),
+    sensor(
+        "total_power_import_kwh",
+        "Energy import",
+        Some("energy"),
+        "kWh",
+        "total_increasing",
+    ),
+    sensor(
+        "total_power_export_kwh",
+        "Energy export",
+        Some("energy"),
+        "kWh",
+        "total_increasing",
+    ),
+    sensor(
+        "total_gas_m3",
+        "Total gas",
+        Some("gas"),
+        "m³",
+        "total_increasing",
+    ),
+    sensor("active_power_w", "Power", Some("power"), "W", "measurement"),
+    sensor(
+        "active_power_l1_w",
+        "Power L1",
+        Some("power"),
+        "W",
+        "measurement",
+    ),
+    sensor(
+        "active_power_l2_w",
+        "Power L2",
+        Some("power"),
+        "W",
+        "measurement",
+    ),
+    sensor(
+        "active_power_l3_w",
+        "Power L3",
+        Some("power"),
+        "W",
+        "measurement",
+    ),
+    sensor(
+        "active_voltage_v",
+        "Voltage",
+        Some("voltage"),
+        "V",
+        "measurement",
+    ),
+    sensor(
+        "active_voltage_l1_v",
+        "Voltage L1",
+        Some("voltage"),
+        "V",
+        "measurement",
+    ),
+    sensor(
+        "active_voltage_l2_v",
+        "Voltage L2",
+        Some("voltage"),
+        "V",
+        "measurement",
+    ),
+    sensor(
+        "active_voltage_l3_v",
+        "Voltage L3",
+        Some("voltage"),
+        "V",
+        "measurement",
+    ),
+    sensor(
+        "active_current_a",
+        "Current",
+        Some("current"),
+        "A",
+        "measurement",
+    ),
+    sensor(
+        "active_current_l1_a",
+        "Current L1",
+        Some("current"),
+        "A",
+        "measurement",
+    ),
+    sensor(
+        "active_current_l2_a",
+        "Current L2",
+        Some("current"),
+        "A",
+        "measurement",
+    ),
+    sensor(
+        "active_current_l3_a",
+        "Current L3",
+        Some("current"),
+        "A",
+        "measurement",
+    ),
+    Sensor {
+        diagnostic: true,
+        ..sensor("wifi_strength", "Wi-Fi strength", None, "%", "measurement")
+    },
+    Sensor {
+        diagnostic: true,
+        ..sensor(
+            "wifi_rssi_db",
+            "Wi-Fi RSSI",
+            Some("signal_strength"),
+            "dBm",
+            "measurement",
+        )
+    },
+];
+
+/// Home Assistant MQTT discovery (`--mqtt-homeassistant`): retained configuration
+/// messages that make each device and its sensors appear in Home Assistant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Discovery {
+    /// Discovery prefix Home Assistant listens on, `homeassistant` by default
+    pub prefix: String,
+    /// The exporter's availability topic, see [`crate::mqtt::MqttOptions`]
+    pub availability_topic: Option<String>,
+    /// Sensors without a newer reading become unavailable after this long
+    pub expire_after: Option<Duration>,
+}
+
+impl Discovery {
+    /// The configuration of each sensor of `reading`, whose state is published to `state_topic`.
+    pub fn messages(&self, device: &str, reading: &Reading, state_topic: &str) -> Vec<Message> {
+        let id = format!("homewizard_{}", object_id(device));
+        let device_info = json!({
+            "identifiers": [id],
+            "name": device,
+            "manufacturer": "HomeWizard",
+            "model": model(reading.device_type()),
+        });
+
+        reading
+            .fields()
+            .into_iter()
+            .filter_map(|(field, _)| SENSORS.iter().find(|sensor| sensor.field == field))
+            .map(|sensor| {
+                let mut config = json!({
+                    "name": sensor.name,
+                    "unique_id": format!("{id}_{}", sensor.field),
+                    "state_topic": state_topic,
+                    "value_template": format!("{{{{ value_json.reading.{} }}}}", sensor.field),
+                    "unit_of_measurement": sensor.unit,
+                    "state_class": sensor.state_class,
+                    "device": device_info,
+                    "origin": {
+                        "name": "homewizard-water-exporter",
+                        "sw_version": BUILD_INFO.version,
+                        "support_url": env!("CARGO_PKG_REPOSITORY"),
+                    },
+                });
+                let fields = config.as_object_mut().expect("an object");
+                if let Some(device_class) = sensor.device_class {
+                    fields.insert("device_class".into(), device_class.into());
+                }
+                if sensor.diagnostic {
+                    fields.insert("entity_category".into(), "diagnostic".into());
+                }
+                if let Some(topic) = &self.availability_topic {
+                    fields.insert("availability_topic".into(), topic.as_str().into());
+                }
+                if let Some(expire_after) = self.expire_after {
+                    fields.insert("expire_after".into(), expire_after.as_secs().into());
+                }
+                Message {
+                    topic: format!("{}/sensor/{id}/{}/config", self.prefix, sensor.field),
+                    payload: Value::to_string(&config).into_bytes(),
+                    qos: 1,
+                    retain: true,
+                }
+            })
+            .collect()
+    }
+}
+
+/// A device name reduced to the characters allowed in discovery topics and ids.
+fn object_id(device: &str) -> String {
+    device
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn model(device_type: DeviceType) -> &'static str {
+    match device_type {
+        DeviceType::Water => "Watermeter",
+        DeviceType::P1 => "P1 Meter",
+        DeviceType::EnergySocket => "Energy Socket",
+        DeviceType::Kwh => "kWh Meter",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::homewizard::{HomeWizardEnergySocketData, HomeWizardWaterData};
+
+    fn discovery() -> Discovery {
+        Discovery {
+            prefix: "homeassistant".to_string(),
+            availability_topic: Some("homewizard/status".to_string()),
+            expire_after: Some(Duration::from_secs(180)),
+        }
+    }
+
+    fn config(message: &Message) -> Value {
+        serde_json::from_slice(&message.payload).unwrap()
+    }
+
+    #[test]
+    fn test_water_sensors() {
+        let reading = Reading::Water(HomeWizardWaterData {
+            total_liter_m3: 42.5,
+            active_liter_lpm: Some(3.0),
+            wifi_strength: Some(80.0),
+            ..Default::default()
+        });
+        let messages = discovery().messages("Kitchen Sink", &reading, "homewizard/Kitchen Sink");
+        let topics: Vec<&str> = messages.iter().map(|m| m.topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            [
+                "homeassistant/sensor/homewizard_kitchen_sink/total_liter_m3/config",
+                "homeassistant/sensor/homewizard_kitchen_sink/active_liter_lpm/config",
+                "homeassistant/sensor/homewizard_kitchen_sink/wifi_strength/config",
+            ]
+        );
+        assert!(messages.iter().all(|m| m.retain && m.qos == 1));
+
+        let total = config(&messages[0]);
+        assert_eq!(total["unique_id"], "homewizard_kitchen_sink_total_liter_m3");
+        assert_eq!(total["state_topic"], "homewizard/Kitchen Sink");
+        assert_eq!(
+            total["value_template"],
+            "{{ value_json.reading.total_liter_m3 }}"
+        );
+        assert_eq!(total["device_class"], "water");
+        assert_eq!(total["unit_of_measurement"], "m³");
+        assert_eq!(total["state_class"], "total_increasing");
+        assert_eq!(total["availability_topic"], "homewizard/status");
+        assert_eq!(total["expire_after"], 180);
+        assert_eq!(total["device"]["identifiers"][0], "homewizard_kitchen_sink");
+        assert_eq!(total["device"]["name"], "Kitchen Sink");
+        assert_eq!(total["device"]["model"], "Watermeter");
+        assert!(total.get("entity_category").is_none());
+
+        let flow = config(&messages[1]);
+        assert_eq!(flow["device_class"], "volume_flow_rate");
+        assert_eq!(flow["unit_of_measurement"], "L/min");
+
+        let wifi = config(&messages[2]);
+        assert_eq!(wifi["entity_category"], "diagnostic");
+        assert!(wifi.get("device_class").is_none());
+    }
+
+    #[test]
+    fn test_unannounced_fields() {
+        // The switch state is not a sensor
+        let reading = Reading::EnergySocket(HomeWizardEnergySocketData {
+            power_on: true,
+            active_power_w: Some(0.0),
+            ..Default::default()
+        });
+        let discovery = Discovery {
+            availability_topic: None,
+            expire_after: None,
+            ..discovery()
+        };
+        let messages = discovery.messages("socket", &reading, "homewizard/socket");
+        assert!(messages.iter().all(|m| !m.topic.contains("power_on")));
+        let power = config(&messages[1]);
+        assert_eq!(power["device_class"], "power");
+        assert_eq!(power["device"]["model"], "Energy Socket");
+        assert!(power.get("availability_topic").is_none());
+        assert!(power.get("expire_after").is_none());
+    }
+
+    #[test]
+    fn test_object_id() {
+        assert_eq!(object_id("kitchen"), "kitchen");
+        assert_eq!(object_id("192.168.1.5"), "192_168_1_5");
+        assert_eq!(object_id("Garden-Tap/2"), "garden-tap_2");
+    }
+}
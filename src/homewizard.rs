@@ -1,5 +1,14 @@
+use crate::recording::{self, Recorder, Replay};
+use crate::resolver::CachingResolver;
+use crate::simulate::Simulator;
 use anyhow::Result;
-use serde::Deserialize;
+use futures_util::Stream;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Map;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -9,51 +18,875 @@ pub enum HomeWizardError {
 
     #[error("Failed to parse response: {0}")]
     ParseError(String),
+
+    #[error("HTTP status: {0}")]
+    HttpStatus(reqwest::StatusCode),
+}
+
+impl HomeWizardError {
+    /// Whether retrying may help: network failures and server errors, not bad responses.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            HomeWizardError::RequestFailed(e) => !e.is_decode(),
+            HomeWizardError::HttpStatus(status) => status.is_server_error(),
+            HomeWizardError::ParseError(_) => false,
+        }
+    }
+
+    /// Short failure class, used as the `reason` label of the scrape error counter.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            HomeWizardError::RequestFailed(e) if e.is_timeout() => "timeout",
+            HomeWizardError::RequestFailed(e) if e.is_connect() => "connect",
+            HomeWizardError::RequestFailed(e) if e.is_decode() => "parse",
+            HomeWizardError::RequestFailed(_) => "request",
+            HomeWizardError::ParseError(_) => "parse",
+            HomeWizardError::HttpStatus(_) => "http_status",
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// Data of the Watermeter.
+///
+/// On batteries the meter only wakes up now and then and may leave out or null
+/// everything but the total, so the other fields are optional.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
 pub struct HomeWizardWaterData {
+    #[serde(default)]
     pub wifi_ssid: String,
-    pub wifi_strength: f64,
+    #[serde(default)]
+    pub wifi_strength: Option<f64>,
+    /// Only known with the v2 API, which reports the RSSI instead of a percentage
+    #[serde(default)]
+    pub wifi_rssi_db: Option<f64>,
     pub total_liter_m3: f64,
-    pub active_liter_lpm: f64,
+    #[serde(default)]
+    pub active_liter_lpm: Option<f64>,
+    #[serde(default, deserialize_with = "null_as_default")]
     pub total_liter_offset_m3: f64,
+    /// Fields this version does not know, as newer firmware may add them
+    #[serde(flatten)]
+    pub extra: Map<String, serde_json::Value>,
+}
+
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// Data of the P1 meter dongle, read from the smart energy meter's P1 port.
+///
+/// Phase and gas fields are absent on single-phase meters and meters without a
+/// gas meter attached. v2 field names are accepted as aliases.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct HomeWizardP1Data {
+    #[serde(default)]
+    pub wifi_ssid: Option<String>,
+    #[serde(default)]
+    pub wifi_strength: Option<f64>,
+    #[serde(alias = "energy_import_kwh")]
+    pub total_power_import_kwh: f64,
+    /// Absent on meters that never export; not taken as zero, which would look
+    /// like a reset once the field comes back
+    #[serde(default, alias = "energy_export_kwh")]
+    pub total_power_export_kwh: Option<f64>,
+    #[serde(default, alias = "power_w")]
+    pub active_power_w: Option<f64>,
+    #[serde(default, alias = "voltage_l1_v")]
+    pub active_voltage_l1_v: Option<f64>,
+    #[serde(default, alias = "voltage_l2_v")]
+    pub active_voltage_l2_v: Option<f64>,
+    #[serde(default, alias = "voltage_l3_v")]
+    pub active_voltage_l3_v: Option<f64>,
+    #[serde(default)]
+    pub total_gas_m3: Option<f64>,
+    /// Fields this version does not know, as newer firmware may add them
+    #[serde(flatten)]
+    pub extra: Map<String, serde_json::Value>,
+}
+
+/// Data of a kWh meter (HWE-KWH1/HWE-KWH3 and the older SDM230/SDM630).
+///
+/// Single-phase meters report unsuffixed `active_voltage_v`/`active_current_a`,
+/// three-phase meters report `_l1` to `_l3` fields. v2 field names are accepted
+/// as aliases.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct HomeWizardKwhData {
+    #[serde(default)]
+    pub wifi_ssid: Option<String>,
+    #[serde(default)]
+    pub wifi_strength: Option<f64>,
+    #[serde(alias = "energy_import_kwh")]
+    pub total_power_import_kwh: f64,
+    /// Absent on meters that never export; not taken as zero, which would look
+    /// like a reset once the field comes back
+    #[serde(default, alias = "energy_export_kwh")]
+    pub total_power_export_kwh: Option<f64>,
+    #[serde(default, alias = "power_w")]
+    pub active_power_w: Option<f64>,
+    #[serde(default, alias = "power_l1_w")]
+    pub active_power_l1_w: Option<f64>,
+    #[serde(default, alias = "power_l2_w")]
+    pub active_power_l2_w: Option<f64>,
+    #[serde(default, alias = "power_l3_w")]
+    pub active_power_l3_w: Option<f64>,
+    #[serde(default, alias = "voltage_v")]
+    pub active_voltage_v: Option<f64>,
+    #[serde(default, alias = "voltage_l1_v")]
+    pub active_voltage_l1_v: Option<f64>,
+    #[serde(default, alias = "voltage_l2_v")]
+    pub active_voltage_l2_v: Option<f64>,
+    #[serde(default, alias = "voltage_l3_v")]
+    pub active_voltage_l3_v: Option<f64>,
+    #[serde(default, alias = "current_a")]
+    pub active_current_a: Option<f64>,
+    #[serde(default, alias = "current_l1_a")]
+    pub active_current_l1_a: Option<f64>,
+    #[serde(default, alias = "current_l2_a")]
+    pub active_current_l2_a: Option<f64>,
+    #[serde(default, alias = "current_l3_a")]
+    pub active_current_l3_a: Option<f64>,
+    /// Fields this version does not know, as newer firmware may add them
+    #[serde(flatten)]
+    pub extra: Map<String, serde_json::Value>,
+}
+
+/// Readings of one phase; unreported values are `None`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PhaseReading {
+    pub phase: &'static str,
+    pub power_w: Option<f64>,
+    pub voltage_v: Option<f64>,
+    pub current_a: Option<f64>,
 }
 
+impl HomeWizardKwhData {
+    /// Per-phase readings; a single-phase meter reports as phase `l1`.
+    pub fn phases(&self) -> Vec<PhaseReading> {
+        let phases = [
+            PhaseReading {
+                phase: "l1",
+                power_w: self.active_power_l1_w,
+                voltage_v: self.active_voltage_l1_v.or(self.active_voltage_v),
+                current_a: self.active_current_l1_a.or(self.active_current_a),
+            },
+            PhaseReading {
+                phase: "l2",
+                power_w: self.active_power_l2_w,
+                voltage_v: self.active_voltage_l2_v,
+                current_a: self.active_current_l2_a,
+            },
+            PhaseReading {
+                phase: "l3",
+                power_w: self.active_power_l3_w,
+                voltage_v: self.active_voltage_l3_v,
+                current_a: self.active_current_l3_a,
+            },
+        ];
+        phases
+            .into_iter()
+            .filter(|p| p.power_w.is_some() || p.voltage_v.is_some() || p.current_a.is_some())
+            .collect()
+    }
+}
+
+/// Data of an Energy Socket. The relay state comes from `/api/v1/state`.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct HomeWizardEnergySocketData {
+    #[serde(default)]
+    pub wifi_ssid: Option<String>,
+    #[serde(default)]
+    pub wifi_strength: Option<f64>,
+    pub total_power_import_kwh: f64,
+    /// Absent when the socket never delivered energy back; not taken as zero
+    #[serde(default)]
+    pub total_power_export_kwh: Option<f64>,
+    #[serde(default)]
+    pub active_power_w: Option<f64>,
+    #[serde(default)]
+    pub power_on: bool,
+    /// Fields this version does not know, as newer firmware may add them
+    #[serde(flatten)]
+    pub extra: Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SocketState {
+    power_on: bool,
+}
+
+/// Kind of HomeWizard device, selecting the endpoint schema and metric set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DeviceType {
+    #[default]
+    Water,
+    P1,
+    EnergySocket,
+    Kwh,
+}
+
+impl DeviceType {
+    pub const ALL: [DeviceType; 4] = [
+        DeviceType::Water,
+        DeviceType::P1,
+        DeviceType::EnergySocket,
+        DeviceType::Kwh,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceType::Water => "water",
+            DeviceType::P1 => "p1",
+            DeviceType::EnergySocket => "energy_socket",
+            DeviceType::Kwh => "kwh",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|device_type| device_type.as_str() == name)
+    }
+
+    /// The type of a device reporting `product_type` in `/api`.
+    pub fn from_product_type(product_type: &str) -> Option<Self> {
+        match product_type {
+            "HWE-WTR" => Some(DeviceType::Water),
+            "HWE-P1" => Some(DeviceType::P1),
+            "HWE-SKT" => Some(DeviceType::EnergySocket),
+            "HWE-KWH1" | "HWE-KWH3" | "SDM230-wifi" | "SDM630-wifi" => Some(DeviceType::Kwh),
+            _ => None,
+        }
+    }
+}
+
+/// One reading of any supported device type.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Reading {
+    Water(HomeWizardWaterData),
+    P1(HomeWizardP1Data),
+    EnergySocket(HomeWizardEnergySocketData),
+    Kwh(HomeWizardKwhData),
+}
+
+impl Reading {
+    pub fn device_type(&self) -> DeviceType {
+        match self {
+            Reading::Water(_) => DeviceType::Water,
+            Reading::P1(_) => DeviceType::P1,
+            Reading::EnergySocket(_) => DeviceType::EnergySocket,
+            Reading::Kwh(_) => DeviceType::Kwh,
+        }
+    }
+
+    /// Fields of the payload the exporter does not know.
+    pub fn extra(&self) -> &Map<String, serde_json::Value> {
+        match self {
+            Reading::Water(data) => &data.extra,
+            Reading::P1(data) => &data.extra,
+            Reading::EnergySocket(data) => &data.extra,
+            Reading::Kwh(data) => &data.extra,
+        }
+    }
+
+    /// The numeric values of the reading, named as in the device API, for the
+    /// push sinks; values the device did not report are left out.
+    pub fn fields(&self) -> Vec<(&'static str, f64)> {
+        let values = match self {
+            Reading::Water(data) => vec![
+                ("total_liter_m3", Some(data.total_liter_m3)),
+                ("active_liter_lpm", data.active_liter_lpm),
+                ("wifi_strength", data.wifi_strength),
+                ("wifi_rssi_db", data.wifi_rssi_db),
+            ],
+            Reading::P1(data) => vec![
+                ("total_power_import_kwh", Some(data.total_power_import_kwh)),
+                ("total_power_export_kwh", data.total_power_export_kwh),
+                ("active_power_w", data.active_power_w),
+                ("active_voltage_l1_v", data.active_voltage_l1_v),
+                ("active_voltage_l2_v", data.active_voltage_l2_v),
+                ("active_voltage_l3_v", data.active_voltage_l3_v),
+                ("total_gas_m3", data.total_gas_m3),
+                ("wifi_strength", data.wifi_strength),
+            ],
+            Reading::EnergySocket(data) => vec![
+                ("total_power_import_kwh", Some(data.total_power_import_kwh)),
+                ("total_power_export_kwh", data.total_power_export_kwh),
+                ("active_power_w", data.active_power_w),
+                ("power_on", Some(if data.power_on { 1.0 } else { 0.0 })),
+                ("wifi_strength", data.wifi_strength),
+            ],
+            Reading::Kwh(data) => vec![
+                ("total_power_import_kwh", Some(data.total_power_import_kwh)),
+                ("total_power_export_kwh", data.total_power_export_kwh),
+                ("active_power_w", data.active_power_w),
+                ("active_power_l1_w", data.active_power_l1_w),
+                ("active_power_l2_w", data.active_power_l2_w),
+                ("active_power_l3_w", data.active_power_l3_w),
+                ("active_voltage_v", data.active_voltage_v),
+                ("active_voltage_l1_v", data.active_voltage_l1_v),
+                ("active_voltage_l2_v", data.active_voltage_l2_v),
+                ("active_voltage_l3_v", data.active_voltage_l3_v),
+                ("active_current_a", data.active_current_a),
+                ("active_current_l1_a", data.active_current_l1_a),
+                ("active_current_l2_a", data.active_current_l2_a),
+                ("active_current_l3_a", data.active_current_l3_a),
+                ("wifi_strength", data.wifi_strength),
+            ],
+        };
+        values
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, value?)))
+            .collect()
+    }
+}
+
+/// Device identification from the `/api` endpoint.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct HomeWizardDeviceInfo {
+    #[serde(default)]
+    pub product_type: String,
+    #[serde(default)]
+    pub product_name: String,
+    #[serde(default)]
+    pub serial: String,
+    #[serde(default)]
+    pub firmware_version: String,
+    #[serde(default)]
+    pub api_version: String,
+}
+
+/// Settings from the system endpoint (`/api/v1/system`, or `/api/system` on API v2).
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct HomeWizardSystem {
+    /// Whether the device communicates with the HomeWizard cloud
+    pub cloud_enabled: bool,
+}
+
+/// Version of the local device API to poll.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ApiVersion {
+    /// `http://<host>/api/v1/data`, unauthenticated
+    #[default]
+    V1,
+    /// `https://<host>/api/measurement` with a bearer token
+    V2,
+}
+
+impl ApiVersion {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::V1 => "v1",
+            Self::V2 => "v2",
+        }
+    }
+
+    /// Endpoint with the readings of `host`.
+    pub fn data_url(self, host: &str) -> String {
+        match self {
+            Self::V1 => format!("http://{}/api/v1/data", host),
+            Self::V2 => format!("https://{}/api/measurement", host),
+        }
+    }
+}
+
+/// Find out which API `host` speaks: v2 if it identifies itself as such over HTTPS,
+/// v1 if it answers over plain HTTP, and `None` if it does not answer at all.
+pub async fn detect_api_version(host: &str, timeout: Duration) -> Result<Option<ApiVersion>> {
+    for api_version in [ApiVersion::V2, ApiVersion::V1] {
+        let url = api_version.data_url(host);
+        // Building the client loads the TLS root store
+        let client = tokio::task::spawn_blocking(move || {
+            HomeWizardClient::with_api_version(url, timeout, CachingResolver::new(), api_version)
+        })
+        .await??;
+        match client.fetch_device_info().await {
+            // Whatever else listens on HTTPS is not the v2 API
+            Ok(info) if api_version == ApiVersion::V2 && !info.api_version.starts_with('2') => {}
+            Ok(_) => return Ok(Some(api_version)),
+            Err(e) => tracing::debug!(
+                "{} does not speak API {}: {}",
+                host,
+                api_version.as_str(),
+                e
+            ),
+        }
+    }
+    Ok(None)
+}
+
+/// Water fields of the v2 `/api/measurement` response; Wi-Fi moved to `/api/system`.
+#[derive(Debug, Deserialize)]
+struct V2Measurement {
+    total_liter_m3: f64,
+    #[serde(default)]
+    active_liter_lpm: Option<f64>,
+    #[serde(default, deserialize_with = "null_as_default")]
+    total_liter_offset_m3: f64,
+    #[serde(flatten)]
+    extra: Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct V2System {
+    #[serde(default)]
+    wifi_ssid: String,
+    #[serde(default)]
+    wifi_rssi_db: Option<f64>,
+}
+
+/// Retries of a failed fetch, waiting an exponentially growing, jittered delay in between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub const NONE: RetryPolicy = RetryPolicy {
+        retries: 0,
+        initial_backoff: Duration::ZERO,
+        max_backoff: Duration::ZERO,
+    };
+
+    /// Upper bound of the delay before retry `retry` (1-based); the actual delay is
+    /// drawn uniformly below it ("full jitter") so devices polled together drift apart.
+    pub fn max_delay(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+
+    fn delay(&self, retry: u32) -> Duration {
+        let max = self.max_delay(retry).as_millis() as u64;
+        Duration::from_millis(fastrand::u64(0..=max))
+    }
+}
+
+/// Longest wait between the fetches of [`HomeWizardClient::readings`] while the device fails.
+const MAX_STREAM_BACKOFF: Duration = Duration::from_secs(300);
+
 pub struct HomeWizardClient {
     client: reqwest::Client,
     url: String,
+    resolver: CachingResolver,
+    token: Option<String>,
+    api_version: ApiVersion,
+    device_type: DeviceType,
+    retry: RetryPolicy,
+    last_attempts: AtomicU32,
+    /// Answers in place of the device (`--simulate`)
+    simulator: Option<Arc<Simulator>>,
+    /// Archives the responses under the device name (`--record`)
+    recorder: Option<(Arc<Recorder>, String)>,
+    /// Answers with the recorded responses of the device name (`--replay`)
+    replay: Option<(Arc<Replay>, String)>,
 }
 
 impl HomeWizardClient {
     pub fn new(url: String, timeout: std::time::Duration) -> Result<Self> {
-        let client = reqwest::Client::builder().timeout(timeout).build()?;
+        Self::with_resolver(url, timeout, CachingResolver::new())
+    }
+
+    /// Create a client that looks up the device through `resolver`, so addresses
+    /// resolved ahead of time are reused for every poll.
+    pub fn with_resolver(
+        url: String,
+        timeout: std::time::Duration,
+        resolver: CachingResolver,
+    ) -> Result<Self> {
+        Self::with_api_version(url, timeout, resolver, ApiVersion::V1)
+    }
+
+    /// Create a client for the given device API version.
+    ///
+    /// v2 devices serve HTTPS with a certificate issued by HomeWizard's own CA for
+    /// a name that never matches the address used on the LAN, so certificate
+    /// verification is disabled for them; the bearer token authenticates the client.
+    pub fn with_api_version(
+        url: String,
+        timeout: std::time::Duration,
+        resolver: CachingResolver,
+        api_version: ApiVersion,
+    ) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .dns_resolver(Arc::new(resolver.clone()))
+            .danger_accept_invalid_certs(api_version == ApiVersion::V2)
+            .build()?;
+
+        Ok(Self {
+            client,
+            url,
+            resolver,
+            token: None,
+            api_version,
+            device_type: DeviceType::Water,
+            retry: RetryPolicy::NONE,
+            last_attempts: AtomicU32::new(0),
+            simulator: None,
+            recorder: None,
+            replay: None,
+        })
+    }
+
+    /// Send `token` as a bearer token with every request.
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Poll the device as the given type; defaults to a water meter.
+    pub fn with_device_type(mut self, device_type: DeviceType) -> Self {
+        self.device_type = device_type;
+        self
+    }
+
+    /// Retry transient fetch failures according to `retry`.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Take readings from `simulator` instead of requesting them from the device.
+    pub fn with_simulator(mut self, simulator: Simulator) -> Self {
+        self.simulator = Some(Arc::new(simulator));
+        self
+    }
+
+    /// Archive every response of the device, as `device`, to `recorder`.
+    pub fn with_recorder(mut self, recorder: Arc<Recorder>, device: impl Into<String>) -> Self {
+        self.recorder = Some((recorder, device.into()));
+        self
+    }
+
+    /// Answer with the responses `replay` recorded for `device` instead of
+    /// requesting them from the device.
+    pub fn with_replay(mut self, replay: Arc<Replay>, device: impl Into<String>) -> Self {
+        self.replay = Some((replay, device.into()));
+        self
+    }
+
+    /// Number of requests the last fetch took, including retries.
+    pub fn last_attempts(&self) -> u32 {
+        self.last_attempts.load(Ordering::Relaxed)
+    }
+
+    /// Host part of the device URL, as handed to the DNS resolver.
+    pub fn host(&self) -> Option<String> {
+        reqwest::Url::parse(&self.url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+    }
+
+    /// Fetch the device identification from `/api` on the same host.
+    pub async fn fetch_device_info(&self) -> Result<HomeWizardDeviceInfo, HomeWizardError> {
+        if let Some(simulator) = &self.simulator {
+            return Ok(simulator.device_info(self.device_type));
+        }
+        self.get_json(self.endpoint("/api")?).await
+    }
+
+    /// Fetch a reading in the schema of the configured device type.
+    pub async fn fetch_reading(&self) -> Result<Reading, HomeWizardError> {
+        if let Some(simulator) = &self.simulator {
+            self.last_attempts.store(1, Ordering::Relaxed);
+            return Ok(simulator.reading(self.device_type));
+        }
+        self.with_retries(|| async {
+            match self.device_type {
+                DeviceType::Water => self.fetch_water().await.map(Reading::Water),
+                DeviceType::P1 => self.get_json(self.url.clone()).await.map(Reading::P1),
+                DeviceType::EnergySocket => {
+                    self.fetch_energy_socket().await.map(Reading::EnergySocket)
+                }
+                DeviceType::Kwh => self.get_json(self.url.clone()).await.map(Reading::Kwh),
+            }
+        })
+        .await
+    }
+
+    /// Fetch a reading every `interval`, the first one right away.
+    ///
+    /// Failures are yielded as well, and double the wait before the next fetch up
+    /// to five minutes, or `interval` if that is longer. Dropping the stream stops
+    /// fetching.
+    pub fn readings(
+        &self,
+        interval: Duration,
+    ) -> impl Stream<Item = Result<HomeWizardWaterData, HomeWizardError>> + '_ {
+        let start = (tokio::time::Instant::now(), 0);
+        futures_util::stream::unfold(start, move |(next, failures)| async move {
+            tokio::time::sleep_until(next).await;
+            let result = self.fetch_data().await;
+            let failures = match result {
+                Ok(_) => 0,
+                Err(_) => failures + 1,
+            };
+            // Scheduled from the previous target time, so slow fetches do not drift
+            Some((result, (next + stream_delay(interval, failures), failures)))
+        })
+    }
 
-        Ok(Self { client, url })
+    /// Run `fetch`, retrying transient failures; a Wi-Fi blip should not cost a poll.
+    async fn with_retries<T, F, Fut>(&self, fetch: F) -> Result<T, HomeWizardError>
+    where
+        F: Fn() -> Fut,
+        Fut: std::future::Future<Output = Result<T, HomeWizardError>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let result = fetch().await;
+            match result {
+                Err(e) if e.is_transient() && attempt <= self.retry.retries => {
+                    let delay = self.retry.delay(attempt);
+                    tracing::debug!(
+                        "Fetch attempt {} failed ({}), retrying in {:?}",
+                        attempt,
+                        e,
+                        delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => {
+                    self.last_attempts.store(attempt, Ordering::Relaxed);
+                    return result;
+                }
+            }
+        }
+    }
+
+    async fn fetch_energy_socket(&self) -> Result<HomeWizardEnergySocketData, HomeWizardError> {
+        let mut data: HomeWizardEnergySocketData = self.get_json(self.url.clone()).await?;
+        let state: SocketState = self.get_json(self.endpoint("/api/v1/state")?).await?;
+        data.power_on = state.power_on;
+        Ok(data)
     }
 
     pub async fn fetch_data(&self) -> Result<HomeWizardWaterData, HomeWizardError> {
-        let response = self.client.get(&self.url).send().await?;
+        if let Some(simulator) = &self.simulator {
+            self.last_attempts.store(1, Ordering::Relaxed);
+            if let Reading::Water(data) = simulator.reading(DeviceType::Water) {
+                return Ok(data);
+            }
+        }
+        self.with_retries(|| self.fetch_water()).await
+    }
 
+    async fn fetch_water(&self) -> Result<HomeWizardWaterData, HomeWizardError> {
+        match self.api_version {
+            ApiVersion::V1 => self.get_json(self.url.clone()).await,
+            ApiVersion::V2 => {
+                let measurement: V2Measurement = self.get_json(self.url.clone()).await?;
+                let system: V2System = self.get_json(self.endpoint("/api/system")?).await?;
+
+                Ok(HomeWizardWaterData {
+                    wifi_ssid: system.wifi_ssid,
+                    wifi_strength: system.wifi_rssi_db.map(rssi_to_percent),
+                    wifi_rssi_db: system.wifi_rssi_db,
+                    total_liter_m3: measurement.total_liter_m3,
+                    active_liter_lpm: measurement.active_liter_lpm,
+                    total_liter_offset_m3: measurement.total_liter_offset_m3,
+                    extra: measurement.extra,
+                })
+            }
+        }
+    }
+
+    /// Fetch the system settings of the device.
+    pub async fn fetch_system(&self) -> Result<HomeWizardSystem, HomeWizardError> {
+        if self.simulator.is_some() {
+            return Ok(HomeWizardSystem {
+                cloud_enabled: true,
+            });
+        }
+        self.get_json(self.endpoint(self.system_path())?).await
+    }
+
+    /// Turn communication of the device with the HomeWizard cloud on or off.
+    pub async fn set_cloud_enabled(&self, enabled: bool) -> Result<(), HomeWizardError> {
+        let body = serde_json::json!({ "cloud_enabled": enabled });
+        self.put(self.system_path(), Some(&body)).await
+    }
+
+    /// Make the device blink its status LED for a few seconds.
+    pub async fn identify(&self) -> Result<(), HomeWizardError> {
+        let path = match self.api_version {
+            ApiVersion::V1 => "/api/v1/identify",
+            ApiVersion::V2 => "/api/system/identify",
+        };
+        self.put(path, None).await
+    }
+
+    fn system_path(&self) -> &'static str {
+        match self.api_version {
+            ApiVersion::V1 => "/api/v1/system",
+            ApiVersion::V2 => "/api/system",
+        }
+    }
+
+    /// Change something on the device; a simulated device accepts anything and a
+    /// replayed one nothing.
+    async fn put(
+        &self,
+        path: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<(), HomeWizardError> {
+        if self.simulator.is_some() {
+            return Ok(());
+        }
+        if self.replay.is_some() {
+            return Err(HomeWizardError::HttpStatus(
+                reqwest::StatusCode::NOT_IMPLEMENTED,
+            ));
+        }
+        let mut request = self.client.put(self.endpoint(path)?);
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        let response = self.send(request).await?;
         if !response.status().is_success() {
-            return Err(HomeWizardError::ParseError(format!(
-                "HTTP status: {}",
-                response.status()
-            )));
+            return Err(HomeWizardError::HttpStatus(response.status()));
         }
+        Ok(())
+    }
 
-        let data = response.json::<HomeWizardWaterData>().await?;
-        Ok(data)
+    /// Another endpoint on the device the client polls.
+    fn endpoint(&self, path: &str) -> Result<String, HomeWizardError> {
+        let mut url = reqwest::Url::parse(&self.url)
+            .map_err(|e| HomeWizardError::ParseError(e.to_string()))?;
+        url.set_path(path);
+        Ok(url.into())
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, url: String) -> Result<T, HomeWizardError> {
+        if let Some((replay, device)) = &self.replay {
+            let path = url_path(&url)?;
+            return match replay.response(device, &path) {
+                Some(entry) => decode(entry.status, entry.body.clone()),
+                None => Err(HomeWizardError::HttpStatus(reqwest::StatusCode::NOT_FOUND)),
+            };
+        }
+
+        let response = self.send(self.client.get(url)).await?;
+
+        if let Some((recorder, device)) = &self.recorder {
+            let status = response.status().as_u16();
+            let path = response.url().path().to_string();
+            let body = recording::body(&response.bytes().await?);
+            if let Err(e) = recorder.record(device, self.device_type, &path, status, &body) {
+                tracing::warn!("Failed to record response of {}: {}", device, e);
+            }
+            return decode(status, body);
+        }
+
+        if !response.status().is_success() {
+            return Err(HomeWizardError::HttpStatus(response.status()));
+        }
+
+        Ok(response.json::<T>().await?)
+    }
+
+    /// Send a request to the device with the credentials of the API version.
+    async fn send(
+        &self,
+        mut request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, HomeWizardError> {
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        if self.api_version == ApiVersion::V2 {
+            request = request.header("X-Api-Version", "2");
+        }
+
+        match request.send().await {
+            Ok(response) => Ok(response),
+            Err(e) => {
+                // The device may have moved to another address; resolve it again next time
+                if e.is_connect()
+                    && let Some(host) = self.host()
+                {
+                    self.resolver.invalidate(&host);
+                }
+                Err(e.into())
+            }
+        }
     }
 }
 
+fn url_path(url: &str) -> Result<String, HomeWizardError> {
+    reqwest::Url::parse(url)
+        .map(|url| url.path().to_string())
+        .map_err(|e| HomeWizardError::ParseError(e.to_string()))
+}
+
+/// Check the status and parse the body of a recorded response.
+fn decode<T: DeserializeOwned>(status: u16, body: serde_json::Value) -> Result<T, HomeWizardError> {
+    let status = reqwest::StatusCode::from_u16(status)
+        .map_err(|e| HomeWizardError::ParseError(e.to_string()))?;
+    if !status.is_success() {
+        return Err(HomeWizardError::HttpStatus(status));
+    }
+    serde_json::from_value(body).map_err(|e| HomeWizardError::ParseError(e.to_string()))
+}
+
+/// Wait before the next fetch of a reading stream after `failures` failed ones in a row.
+fn stream_delay(interval: Duration, failures: u32) -> Duration {
+    let factor = 2u32.saturating_pow(failures.min(16));
+    interval
+        .saturating_mul(factor)
+        .min(interval.max(MAX_STREAM_BACKOFF))
+}
+
+/// Map an RSSI in dBm to the 0-100 % scale of the v1 `wifi_strength` field.
+fn rssi_to_percent(rssi_dbm: f64) -> f64 {
+    (2.0 * (rssi_dbm + 100.0)).clamp(0.0, 100.0)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::time::Duration;
-    use wiremock::matchers::{method, path};
+    use wiremock::matchers::{body_json, header, method, path};
     use wiremock::{Mock, MockServer, ResponseTemplate};
 
+    #[test]
+    fn test_reading_fields() {
+        let water = Reading::Water(HomeWizardWaterData {
+            total_liter_m3: 42.5,
+            active_liter_lpm: Some(3.0),
+            ..Default::default()
+        });
+        assert_eq!(
+            water.fields(),
+            [("total_liter_m3", 42.5), ("active_liter_lpm", 3.0)]
+        );
+
+        let socket = Reading::EnergySocket(HomeWizardEnergySocketData {
+            total_power_import_kwh: 10.0,
+            active_power_w: Some(5.0),
+            power_on: true,
+            wifi_strength: Some(80.0),
+            ..Default::default()
+        });
+        assert_eq!(
+            socket.fields(),
+            [
+                ("total_power_import_kwh", 10.0),
+                ("active_power_w", 5.0),
+                ("power_on", 1.0),
+                ("wifi_strength", 80.0),
+            ]
+        );
+    }
+
     #[test]
     fn test_homewizard_client_creation() {
         let client = HomeWizardClient::new(
@@ -72,10 +905,76 @@ mod tests {
         assert!(client.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_detect_api_version() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "product_type": "HWE-WTR",
+                "api_version": "v1"
+            })))
+            .mount(&mock_server)
+            .await;
+        let host = mock_server.address().to_string();
+
+        // The mock server only speaks plain HTTP
+        assert_eq!(
+            detect_api_version(&host, Duration::from_secs(5))
+                .await
+                .unwrap(),
+            Some(ApiVersion::V1)
+        );
+
+        // Nothing listens on a port that was just freed
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            detect_api_version(&closed, Duration::from_secs(5))
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_api_version_data_url() {
+        assert_eq!(
+            ApiVersion::V1.data_url("192.168.1.100"),
+            "http://192.168.1.100/api/v1/data"
+        );
+        assert_eq!(
+            ApiVersion::V2.data_url("192.168.1.100"),
+            "https://192.168.1.100/api/measurement"
+        );
+        assert_eq!(
+            ApiVersion::V1.data_url("homewizard.local"),
+            "http://homewizard.local/api/v1/data"
+        );
+    }
+
+    #[test]
+    fn test_homewizard_client_host() {
+        let client = HomeWizardClient::new(
+            "http://homewizard.local/api/v1/data".to_string(),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert_eq!(client.host().as_deref(), Some("homewizard.local"));
+    }
+
     #[test]
     fn test_homewizard_error_display() {
         let error = HomeWizardError::ParseError("Invalid JSON".to_string());
         assert_eq!(error.to_string(), "Failed to parse response: Invalid JSON");
+        assert_eq!(error.reason(), "parse");
+
+        let error = HomeWizardError::HttpStatus(reqwest::StatusCode::UNAUTHORIZED);
+        assert_eq!(error.to_string(), "HTTP status: 401 Unauthorized");
+        assert_eq!(error.reason(), "http_status");
     }
 
     #[test]
@@ -95,9 +994,9 @@ mod tests {
 
         let data = data.unwrap();
         assert_eq!(data.wifi_ssid, "HomeNetwork");
-        assert_eq!(data.wifi_strength, 75.5);
+        assert_eq!(data.wifi_strength, Some(75.5));
         assert_eq!(data.total_liter_m3, 1234.567);
-        assert_eq!(data.active_liter_lpm, 15.5);
+        assert_eq!(data.active_liter_lpm, Some(15.5));
         assert_eq!(data.total_liter_offset_m3, 100.0);
     }
 
@@ -118,9 +1017,53 @@ mod tests {
 
         let data = data.unwrap();
         assert_eq!(data.wifi_ssid, "Test");
-        assert_eq!(data.wifi_strength, 50.0);
+        assert_eq!(data.wifi_strength, Some(50.0));
         assert_eq!(data.total_liter_m3, 100.0);
-        assert_eq!(data.active_liter_lpm, 0.0);
+        assert_eq!(data.active_liter_lpm, Some(0.0));
+        assert_eq!(data.total_liter_offset_m3, 0.0);
+    }
+
+    #[test]
+    fn test_unknown_and_missing_fields() {
+        // A firmware update adding fields
+        let data: HomeWizardWaterData = serde_json::from_str(
+            r#"{"total_liter_m3": 100.0, "leak_detected": false, "battery_pct": 87}"#,
+        )
+        .unwrap();
+        assert_eq!(data.total_liter_m3, 100.0);
+        assert_eq!(data.extra["battery_pct"], 87);
+        assert_eq!(data.extra.len(), 2);
+        // Serialized readings keep the unknown fields
+        let json = serde_json::to_value(&data).unwrap();
+        assert_eq!(json["leak_detected"], false);
+
+        // ... and one leaving out the power
+        let data: HomeWizardP1Data =
+            serde_json::from_str(r#"{"energy_import_kwh": 1200.5, "tariff": 2}"#).unwrap();
+        assert_eq!(data.total_power_import_kwh, 1200.5);
+        assert_eq!(data.total_power_export_kwh, None);
+        assert_eq!(data.active_power_w, None);
+        assert_eq!(data.extra["tariff"], 2);
+        // Aliased v2 names are not unknown fields
+        assert!(!data.extra.contains_key("energy_import_kwh"));
+
+        // Without its total a reading is still rejected, rather than read as a reset
+        assert!(
+            serde_json::from_str::<HomeWizardWaterData>(r#"{"active_liter_lpm": 1.0}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn test_battery_water_data_deserialization() {
+        let data: HomeWizardWaterData = serde_json::from_str(
+            r#"{"total_liter_m3": 123.456, "active_liter_lpm": null, "total_liter_offset_m3": null}"#,
+        )
+        .unwrap();
+
+        assert_eq!(data.total_liter_m3, 123.456);
+        assert_eq!(data.active_liter_lpm, None);
+        assert_eq!(data.wifi_strength, None);
+        assert_eq!(data.wifi_ssid, "");
         assert_eq!(data.total_liter_offset_m3, 0.0);
     }
 
@@ -128,10 +1071,12 @@ mod tests {
     fn test_homewizard_water_data_clone() {
         let data = HomeWizardWaterData {
             wifi_ssid: "Test".to_string(),
-            wifi_strength: 50.0,
+            wifi_strength: Some(50.0),
             total_liter_m3: 100.0,
-            active_liter_lpm: 5.0,
+            active_liter_lpm: Some(5.0),
             total_liter_offset_m3: 10.0,
+            wifi_rssi_db: None,
+            ..Default::default()
         };
 
         let cloned = data.clone();
@@ -159,9 +1104,9 @@ mod tests {
 
         let data = data.unwrap();
         assert_eq!(data.wifi_ssid, "HighUsage");
-        assert_eq!(data.wifi_strength, 100.0);
+        assert_eq!(data.wifi_strength, Some(100.0));
         assert_eq!(data.total_liter_m3, 9999.999);
-        assert_eq!(data.active_liter_lpm, 999.0);
+        assert_eq!(data.active_liter_lpm, Some(999.0));
         assert_eq!(data.total_liter_offset_m3, 500.0);
     }
 
@@ -182,9 +1127,9 @@ mod tests {
 
         let data = data.unwrap();
         assert_eq!(data.wifi_ssid, "ZeroTest");
-        assert_eq!(data.wifi_strength, 0.0);
+        assert_eq!(data.wifi_strength, Some(0.0));
         assert_eq!(data.total_liter_m3, 0.0);
-        assert_eq!(data.active_liter_lpm, 0.0);
+        assert_eq!(data.active_liter_lpm, Some(0.0));
         assert_eq!(data.total_liter_offset_m3, 0.0);
     }
 
@@ -247,12 +1192,65 @@ mod tests {
 
         let data = result.unwrap();
         assert_eq!(data.wifi_ssid, "TestNetwork");
-        assert_eq!(data.wifi_strength, 75.5);
+        assert_eq!(data.wifi_strength, Some(75.5));
         assert_eq!(data.total_liter_m3, 1234.567);
-        assert_eq!(data.active_liter_lpm, 15.5);
+        assert_eq!(data.active_liter_lpm, Some(15.5));
         assert_eq!(data.total_liter_offset_m3, 100.0);
     }
 
+    #[tokio::test]
+    async fn test_fetch_data_sends_bearer_token() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/data"))
+            .and(header("Authorization", "Bearer secret"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "wifi_ssid": "TestNetwork",
+                "wifi_strength": 75.5,
+                "total_liter_m3": 1234.567,
+                "active_liter_lpm": 15.5,
+                "total_liter_offset_m3": 100.0
+            })))
+            .mount(&mock_server)
+            .await;
+
+        let client = HomeWizardClient::new(
+            format!("{}/api/v1/data", mock_server.uri()),
+            Duration::from_secs(5),
+        )
+        .unwrap()
+        .with_token("secret");
+
+        assert!(client.fetch_data().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_device_info() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "product_type": "HWE-WTR",
+                "product_name": "Watermeter",
+                "serial": "5c2fafabcdef",
+                "firmware_version": "2.03",
+                "api_version": "v1"
+            })))
+            .mount(&mock_server)
+            .await;
+
+        let client = HomeWizardClient::new(
+            format!("{}/api/v1/data", mock_server.uri()),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+
+        let info = client.fetch_device_info().await.unwrap();
+        assert_eq!(info.product_type, "HWE-WTR");
+        assert_eq!(info.serial, "5c2fafabcdef");
+        assert_eq!(info.firmware_version, "2.03");
+    }
+
     #[tokio::test]
     async fn test_fetch_data_http_error() {
         let mock_server = MockServer::start().await;
@@ -273,13 +1271,63 @@ mod tests {
         assert!(result.is_err());
 
         match result.unwrap_err() {
-            HomeWizardError::ParseError(msg) => {
-                assert!(msg.contains("HTTP status: 500"));
-            }
-            _ => panic!("Expected ParseError"),
+            HomeWizardError::HttpStatus(status) => assert_eq!(status.as_u16(), 500),
+            _ => panic!("Expected HttpStatus"),
         }
     }
 
+    #[test]
+    fn test_stream_delay() {
+        let minute = Duration::from_secs(60);
+        assert_eq!(stream_delay(minute, 0), minute);
+        assert_eq!(stream_delay(minute, 1), 2 * minute);
+        assert_eq!(stream_delay(minute, 2), 4 * minute);
+        assert_eq!(stream_delay(minute, 3), 5 * minute);
+        assert_eq!(stream_delay(minute, u32::MAX), 5 * minute);
+        // Intervals beyond the cap are kept
+        assert_eq!(stream_delay(10 * minute, 4), 10 * minute);
+    }
+
+    #[tokio::test]
+    async fn test_readings_stream() {
+        use futures_util::StreamExt;
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/data"))
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/data"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "total_liter_m3": 12.5,
+                "active_liter_lpm": 3.0,
+            })))
+            .mount(&mock_server)
+            .await;
+
+        let client = HomeWizardClient::new(
+            format!("{}/api/v1/data", mock_server.uri()),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        let started = tokio::time::Instant::now();
+        let readings: Vec<_> = client
+            .readings(Duration::from_millis(20))
+            .take(3)
+            .collect()
+            .await;
+
+        assert!(matches!(readings[0], Err(HomeWizardError::HttpStatus(_))));
+        assert_eq!(readings[1].as_ref().unwrap().total_liter_m3, 12.5);
+        assert!(readings[2].is_ok());
+        // The failure doubled the wait before the second fetch
+        assert!(started.elapsed() >= Duration::from_millis(60));
+        assert_eq!(mock_server.received_requests().await.unwrap().len(), 3);
+    }
+
     #[tokio::test]
     async fn test_fetch_data_malformed_json() {
         let mock_server = MockServer::start().await;
@@ -330,7 +1378,9 @@ mod tests {
         let result = client.fetch_data().await;
         assert!(result.is_err());
 
-        match result.unwrap_err() {
+        let error = result.unwrap_err();
+        assert_eq!(error.reason(), "timeout");
+        match error {
             HomeWizardError::RequestFailed(_) => {
                 // This is expected for timeout errors
             }
@@ -350,7 +1400,9 @@ mod tests {
         let result = client.fetch_data().await;
         assert!(result.is_err());
 
-        match result.unwrap_err() {
+        let error = result.unwrap_err();
+        assert_eq!(error.reason(), "connect");
+        match error {
             HomeWizardError::RequestFailed(_) => {
                 // This is expected for connection refused errors
             }
@@ -358,6 +1410,54 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_fetch_data_with_resolver() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/api/v1/data"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "wifi_ssid": "TestNetwork",
+                "wifi_strength": 75.5,
+                "total_liter_m3": 1234.567,
+                "active_liter_lpm": 15.5,
+                "total_liter_offset_m3": 100.0
+            })))
+            .mount(&mock_server)
+            .await;
+
+        let resolver = CachingResolver::new();
+        let client = HomeWizardClient::with_resolver(
+            format!(
+                "http://localhost:{}/api/v1/data",
+                mock_server.address().port()
+            ),
+            Duration::from_secs(5),
+            resolver.clone(),
+        )
+        .unwrap();
+
+        let data = client.fetch_data().await.unwrap();
+        assert_eq!(data.total_liter_m3, 1234.567);
+        assert!(resolver.cached("localhost").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_data_connection_refused_invalidates_resolver() {
+        let resolver = CachingResolver::new();
+        resolver.lookup("localhost").await.unwrap();
+
+        let client = HomeWizardClient::with_resolver(
+            "http://localhost:12345/api/v1/data".to_string(),
+            Duration::from_secs(5),
+            resolver.clone(),
+        )
+        .unwrap();
+
+        assert!(client.fetch_data().await.is_err());
+        assert!(resolver.cached("localhost").is_none());
+    }
+
     #[tokio::test]
     async fn test_fetch_data_missing_fields() {
         let mock_server = MockServer::start().await;
@@ -412,10 +1512,8 @@ mod tests {
         assert!(result.is_err());
 
         match result.unwrap_err() {
-            HomeWizardError::ParseError(msg) => {
-                assert!(msg.contains("HTTP status: 404"));
-            }
-            _ => panic!("Expected ParseError"),
+            HomeWizardError::HttpStatus(status) => assert_eq!(status.as_u16(), 404),
+            _ => panic!("Expected HttpStatus"),
         }
     }
 
@@ -445,4 +1543,448 @@ mod tests {
             _ => panic!("Expected RequestFailed error"),
         }
     }
+
+    #[test]
+    fn test_rssi_to_percent() {
+        assert_eq!(rssi_to_percent(-50.0), 100.0);
+        assert_eq!(rssi_to_percent(-75.0), 50.0);
+        assert_eq!(rssi_to_percent(-110.0), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_data_v2() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/measurement"))
+            .and(header("Authorization", "Bearer token"))
+            .and(header("X-Api-Version", "2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "total_liter_m3": 1234.567,
+                "active_liter_lpm": 15.5,
+                "total_liter_offset_m3": 100.0
+            })))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/system"))
+            .and(header("Authorization", "Bearer token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "wifi_ssid": "TestNetwork",
+                "wifi_rssi_db": -60,
+                "uptime_s": 356,
+                "cloud_enabled": false
+            })))
+            .mount(&mock_server)
+            .await;
+
+        let client = HomeWizardClient::with_api_version(
+            format!("{}/api/measurement", mock_server.uri()),
+            Duration::from_secs(5),
+            CachingResolver::new(),
+            ApiVersion::V2,
+        )
+        .unwrap()
+        .with_token("token");
+
+        let data = client.fetch_data().await.unwrap();
+        assert_eq!(data.total_liter_m3, 1234.567);
+        assert_eq!(data.active_liter_lpm, Some(15.5));
+        assert_eq!(data.wifi_ssid, "TestNetwork");
+        assert_eq!(data.wifi_strength, Some(80.0));
+        assert_eq!(data.wifi_rssi_db, Some(-60.0));
+    }
+
+    #[tokio::test]
+    async fn test_identify() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .and(path("/api/v1/identify"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "identify": "ok"
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path("/api/system/identify"))
+            .and(header("Authorization", "Bearer token"))
+            .and(header("X-Api-Version", "2"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let v1 = HomeWizardClient::new(
+            format!("{}/api/v1/data", mock_server.uri()),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        v1.identify().await.unwrap();
+
+        let v2 = HomeWizardClient::with_api_version(
+            format!("{}/api/measurement", mock_server.uri()),
+            Duration::from_secs(5),
+            CachingResolver::new(),
+            ApiVersion::V2,
+        )
+        .unwrap()
+        .with_token("token");
+        v2.identify().await.unwrap();
+
+        // Devices without an LED to blink do not have the endpoint
+        let missing = HomeWizardClient::new(
+            format!("{}/elsewhere/api/v1/data", mock_server.uri()),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        mock_server.reset().await;
+        assert!(matches!(
+            missing.identify().await,
+            Err(HomeWizardError::HttpStatus(status)) if status == 404
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cloud_setting() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/system"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "cloud_enabled": true
+            })))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path("/api/v1/system"))
+            .and(body_json(serde_json::json!({ "cloud_enabled": false })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "cloud_enabled": false
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let client = HomeWizardClient::new(
+            format!("{}/api/v1/data", mock_server.uri()),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert!(client.fetch_system().await.unwrap().cloud_enabled);
+        client.set_cloud_enabled(false).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fetch_data_v2_unauthorized() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/measurement"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&mock_server)
+            .await;
+
+        let client = HomeWizardClient::with_api_version(
+            format!("{}/api/measurement", mock_server.uri()),
+            Duration::from_secs(5),
+            CachingResolver::new(),
+            ApiVersion::V2,
+        )
+        .unwrap()
+        .with_token("wrong");
+
+        let error = client.fetch_data().await.unwrap_err();
+        assert!(error.to_string().contains("401"));
+    }
+
+    #[test]
+    fn test_device_type_from_name() {
+        assert_eq!(DeviceType::from_name("water"), Some(DeviceType::Water));
+        assert_eq!(DeviceType::from_name("p1"), Some(DeviceType::P1));
+        assert_eq!(DeviceType::from_name("kwh"), Some(DeviceType::Kwh));
+        assert_eq!(
+            DeviceType::from_name("energy_socket"),
+            Some(DeviceType::EnergySocket)
+        );
+        assert_eq!(DeviceType::from_name("fe80"), None);
+    }
+
+    #[test]
+    fn test_reading_serialization() {
+        let reading = Reading::EnergySocket(HomeWizardEnergySocketData {
+            total_power_import_kwh: 12.5,
+            active_power_w: Some(40.0),
+            power_on: true,
+            ..Default::default()
+        });
+        let json = serde_json::to_value(&reading).unwrap();
+
+        // Flat, in the field names of the v1 API, with the relay state included
+        assert_eq!(json["total_power_import_kwh"], 12.5);
+        assert_eq!(json["active_power_w"], 40.0);
+        assert_eq!(json["power_on"], true);
+    }
+
+    #[test]
+    fn test_device_type_from_product_type() {
+        assert_eq!(
+            DeviceType::from_product_type("HWE-WTR"),
+            Some(DeviceType::Water)
+        );
+        assert_eq!(
+            DeviceType::from_product_type("HWE-P1"),
+            Some(DeviceType::P1)
+        );
+        assert_eq!(
+            DeviceType::from_product_type("HWE-SKT"),
+            Some(DeviceType::EnergySocket)
+        );
+        assert_eq!(
+            DeviceType::from_product_type("SDM630-wifi"),
+            Some(DeviceType::Kwh)
+        );
+        assert_eq!(DeviceType::from_product_type("HWE-BAT"), None);
+    }
+
+    #[test]
+    fn test_p1_data_deserialization() {
+        let data: HomeWizardP1Data = serde_json::from_str(
+            r#"{
+                "wifi_ssid": "TestNetwork",
+                "wifi_strength": 100,
+                "total_power_import_kwh": 13779.338,
+                "total_power_export_kwh": 0,
+                "active_power_w": -543,
+                "active_voltage_l1_v": 230.1,
+                "total_gas_m3": 2569.646
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(data.total_power_import_kwh, 13779.338);
+        assert_eq!(data.active_power_w, Some(-543.0));
+        assert_eq!(data.active_voltage_l1_v, Some(230.1));
+        assert_eq!(data.active_voltage_l2_v, None);
+        assert_eq!(data.total_gas_m3, Some(2569.646));
+    }
+
+    #[test]
+    fn test_p1_data_v2_field_names() {
+        let data: HomeWizardP1Data = serde_json::from_str(
+            r#"{"energy_import_kwh": 1.5, "energy_export_kwh": 0.5, "power_w": 100, "voltage_l1_v": 229.0}"#,
+        )
+        .unwrap();
+
+        assert_eq!(data.total_power_import_kwh, 1.5);
+        assert_eq!(data.total_power_export_kwh, Some(0.5));
+        assert_eq!(data.active_voltage_l1_v, Some(229.0));
+        assert_eq!(data.total_gas_m3, None);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_reading_p1() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/data"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "total_power_import_kwh": 10.0,
+                "total_power_export_kwh": 2.0,
+                "active_power_w": 450
+            })))
+            .mount(&mock_server)
+            .await;
+
+        let client = HomeWizardClient::new(
+            format!("{}/api/v1/data", mock_server.uri()),
+            Duration::from_secs(5),
+        )
+        .unwrap()
+        .with_device_type(DeviceType::P1);
+
+        match client.fetch_reading().await.unwrap() {
+            Reading::P1(data) => assert_eq!(data.active_power_w, Some(450.0)),
+            other => panic!("unexpected reading {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_reading_energy_socket() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/data"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "wifi_ssid": "TestNetwork",
+                "wifi_strength": 92,
+                "total_power_import_kwh": 30.511,
+                "total_power_export_kwh": 0,
+                "active_power_w": 543.2
+            })))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/state"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "power_on": true,
+                "switch_lock": false,
+                "brightness": 255
+            })))
+            .mount(&mock_server)
+            .await;
+
+        let client = HomeWizardClient::new(
+            format!("{}/api/v1/data", mock_server.uri()),
+            Duration::from_secs(5),
+        )
+        .unwrap()
+        .with_device_type(DeviceType::EnergySocket);
+
+        match client.fetch_reading().await.unwrap() {
+            Reading::EnergySocket(data) => {
+                assert_eq!(data.total_power_import_kwh, 30.511);
+                assert_eq!(data.active_power_w, Some(543.2));
+                assert!(data.power_on);
+            }
+            other => panic!("unexpected reading {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_reading_energy_socket_without_state() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/data"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "total_power_import_kwh": 1.0,
+                "active_power_w": 0
+            })))
+            .mount(&mock_server)
+            .await;
+
+        let client = HomeWizardClient::new(
+            format!("{}/api/v1/data", mock_server.uri()),
+            Duration::from_secs(5),
+        )
+        .unwrap()
+        .with_device_type(DeviceType::EnergySocket);
+
+        assert!(client.fetch_reading().await.is_err());
+    }
+
+    #[test]
+    fn test_kwh_single_phase() {
+        let data: HomeWizardKwhData = serde_json::from_str(
+            r#"{
+                "total_power_import_kwh": 2.705,
+                "total_power_export_kwh": 0,
+                "active_power_w": 74.0,
+                "active_power_l1_w": 74.0,
+                "active_voltage_v": 230.4,
+                "active_current_a": 0.321
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            data.phases(),
+            vec![PhaseReading {
+                phase: "l1",
+                power_w: Some(74.0),
+                voltage_v: Some(230.4),
+                current_a: Some(0.321),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_kwh_three_phase_v2() {
+        let data: HomeWizardKwhData = serde_json::from_str(
+            r#"{
+                "energy_import_kwh": 1200.5,
+                "power_w": 900,
+                "power_l1_w": 300, "power_l2_w": 400, "power_l3_w": 200,
+                "voltage_l1_v": 230, "voltage_l2_v": 231, "voltage_l3_v": 229,
+                "current_l1_a": 1.3, "current_l2_a": 1.7, "current_l3_a": 0.9
+            }"#,
+        )
+        .unwrap();
+
+        let phases = data.phases();
+        assert_eq!(data.total_power_import_kwh, 1200.5);
+        assert_eq!(data.total_power_export_kwh, None);
+        assert_eq!(phases.len(), 3);
+        assert_eq!(phases[1].phase, "l2");
+        assert_eq!(phases[1].voltage_v, Some(231.0));
+        assert_eq!(phases[2].current_a, Some(0.9));
+    }
+
+    #[test]
+    fn test_retry_policy_backoff() {
+        let policy = RetryPolicy {
+            retries: 5,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(1),
+        };
+
+        assert_eq!(policy.max_delay(1), Duration::from_millis(200));
+        assert_eq!(policy.max_delay(2), Duration::from_millis(400));
+        assert_eq!(policy.max_delay(3), Duration::from_millis(800));
+        assert_eq!(policy.max_delay(4), Duration::from_secs(1));
+        assert!(policy.delay(3) <= Duration::from_millis(800));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_data_retries_transient_failures() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/data"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/data"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "total_liter_m3": 12.0
+            })))
+            .mount(&mock_server)
+            .await;
+
+        let client = HomeWizardClient::new(
+            format!("{}/api/v1/data", mock_server.uri()),
+            Duration::from_secs(5),
+        )
+        .unwrap()
+        .with_retry(RetryPolicy {
+            retries: 2,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+        });
+
+        let data = client.fetch_data().await.unwrap();
+        assert_eq!(data.total_liter_m3, 12.0);
+        assert_eq!(client.last_attempts(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_data_does_not_retry_client_errors() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/data"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let client = HomeWizardClient::new(
+            format!("{}/api/v1/data", mock_server.uri()),
+            Duration::from_secs(5),
+        )
+        .unwrap()
+        .with_retry(RetryPolicy {
+            retries: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+        });
+
+        assert!(client.fetch_data().await.is_err());
+        assert_eq!(client.last_attempts(), 1);
+    }
 }
@@ -0,0 +1,354 @@
+use crate::homewizard::Reading;
+use anyhow::{Context, Result, bail};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+/// Lines waiting to be written; newer readings are dropped while it is full.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// Most lines sent in one request.
+const MAX_BATCH: usize = 500;
+
+/// Where the points go: a v1 database or a v2 bucket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    V1 { database: String },
+    V2 { org: String, bucket: String },
+}
+
+/// How readings become points: one per reading, with the reading's values as fields.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineFormat {
+    /// `{type}` is replaced with the device type
+    pub measurement: String,
+    /// Added to the `device` and `type` tags of every point
+    pub tags: Vec<(String, String)>,
+}
+
+impl LineFormat {
+    /// The point of a reading in the line protocol, with a timestamp in seconds.
+    pub fn line(&self, device: &str, reading: &Reading, timestamp: u64) -> Option<String> {
+        let device_type = reading.device_type().as_str();
+        let mut line = escape(
+            &self.measurement.replace("{type}", device_type),
+            &[',', ' '],
+        );
+        for (name, value) in [("device", device), ("type", device_type)]
+            .into_iter()
+            .chain(self.tags.iter().map(|(n, v)| (n.as_str(), v.as_str())))
+        {
+            // Empty tag values are not allowed
+            if !value.is_empty() {
+                let _ = write!(line, ",{}={}", escape(name, TAG), escape(value, TAG));
+            }
+        }
+
+        let fields: Vec<String> = reading
+            .fields()
+            .into_iter()
+            .filter(|(_, value)| value.is_finite())
+            .map(|(name, value)| format!("{}={value}", escape(name, TAG)))
+            .collect();
+        if fields.is_empty() {
+            return None;
+        }
+        let _ = write!(line, " {} {timestamp}", fields.join(","));
+        Some(line)
+    }
+}
+
+/// Characters escaped in tag keys, tag values and field keys.
+const TAG: &[char] = &[',', '=', ' '];
+
+fn escape(text: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if special.contains(&c) || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Writes points to InfluxDB over HTTP (`--influxdb-url`).
+pub struct InfluxWriter {
+    client: reqwest::Client,
+    url: reqwest::Url,
+    token: Option<String>,
+}
+
+impl InfluxWriter {
+    /// `url` is the server's base URL, such as `http://influxdb:8086`.
+    ///
+    /// `token` is sent as `Authorization: Token`; InfluxDB 1.8 takes `user:password` there.
+    pub fn new(
+        url: &str,
+        target: &Target,
+        token: Option<String>,
+        timeout: Duration,
+    ) -> Result<Self> {
+        let base = url.trim_end_matches('/');
+        let mut url = match target {
+            Target::V1 { .. } => reqwest::Url::parse(&format!("{base}/write")),
+            Target::V2 { .. } => reqwest::Url::parse(&format!("{base}/api/v2/write")),
+        }
+        .context("invalid InfluxDB URL")?;
+        if !matches!(url.scheme(), "http" | "https") {
+            bail!("InfluxDB URL must use http or https");
+        }
+        {
+            let mut query = url.query_pairs_mut();
+            match target {
+                Target::V1 { database } => query.append_pair("db", database),
+                Target::V2 { org, bucket } => {
+                    query.append_pair("org", org).append_pair("bucket", bucket)
+                }
+            }
+            .append_pair("precision", "s");
+        }
+
+        Ok(Self {
+            client: reqwest::Client::builder().timeout(timeout).build()?,
+            url,
+            token,
+        })
+    }
+
+    pub fn url(&self) -> &reqwest::Url {
+        &self.url
+    }
+
+    pub async fn write(&self, lines: &[String]) -> Result<()> {
+        let mut request = self.client.post(self.url.clone()).body(lines.join("\n"));
+        if let Some(token) = &self.token {
+            request = request.header(reqwest::header::AUTHORIZATION, format!("Token {token}"));
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            bail!("InfluxDB returned {status}: {}", body.trim());
+        }
+        debug!("Wrote {} points to InfluxDB", lines.len());
+        Ok(())
+    }
+
+    /// Run the writer on a background task; readings queued while a write is in
+    /// flight go out together in the next one.
+    pub fn start(self, format: LineFormat) -> InfluxDb {
+        let (tx, mut rx) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(async move {
+            let mut lines = Vec::new();
+            while rx.recv_many(&mut lines, MAX_BATCH).await > 0 {
+                // Points that could not be written are dropped, as Telegraf does by default
+                if let Err(e) = self.write(&lines).await {
+                    warn!(
+                        "Failed to write {} points to InfluxDB: {:#}",
+                        lines.len(),
+                        e
+                    );
+                }
+                lines.clear();
+            }
+        });
+        InfluxDb {
+            format: Arc::new(format),
+            queue: tx,
+        }
+    }
+}
+
+/// Queue of the InfluxDB writer.
+#[derive(Clone)]
+pub struct InfluxDb {
+    format: Arc<LineFormat>,
+    queue: mpsc::Sender<String>,
+}
+
+impl InfluxDb {
+    /// Queue a reading taken now.
+    pub fn record(&self, device: &str, reading: &Reading) {
+        let now = chrono::Utc::now().timestamp().max(0) as u64;
+        let Some(line) = self.format.line(device, reading, now) else {
+            return;
+        };
+        if self.queue.try_send(line).is_err() {
+            warn!("InfluxDB queue is full, dropping a point");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::homewizard::{HomeWizardP1Data, HomeWizardWaterData};
+    use wiremock::matchers::{body_string, header, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn water() -> Reading {
+        Reading::Water(HomeWizardWaterData {
+            total_liter_m3: 42.5,
+            active_liter_lpm: Some(3.0),
+            ..Default::default()
+        })
+    }
+
+    fn format(measurement: &str, tags: &[(&str, &str)]) -> LineFormat {
+        LineFormat {
+            measurement: measurement.to_string(),
+            tags: tags
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_line() {
+        assert_eq!(
+            format("homewizard", &[("site", "home")])
+                .line("kitchen", &water(), 1_700_000_000)
+                .unwrap(),
+            "homewizard,device=kitchen,type=water,site=home total_liter_m3=42.5,active_liter_lpm=3 1700000000"
+        );
+
+        let p1 = Reading::P1(HomeWizardP1Data {
+            total_power_import_kwh: 1200.0,
+            active_power_w: Some(-250.0),
+            ..Default::default()
+        });
+        assert_eq!(
+            format("hw_{type}", &[]).line("meter", &p1, 1).unwrap(),
+            "hw_p1,device=meter,type=p1 total_power_import_kwh=1200,active_power_w=-250 1"
+        );
+    }
+
+    #[test]
+    fn test_line_escaping() {
+        assert_eq!(
+            format("water usage", &[("room", "")])
+                .line("sink, left=1", &water(), 1)
+                .unwrap(),
+            "water\\ usage,device=sink\\,\\ left\\=1,type=water total_liter_m3=42.5,active_liter_lpm=3 1"
+        );
+
+        let nan = Reading::Water(HomeWizardWaterData {
+            total_liter_m3: f64::NAN,
+            ..Default::default()
+        });
+        assert_eq!(format("homewizard", &[]).line("kitchen", &nan, 1), None);
+    }
+
+    #[test]
+    fn test_write_urls() {
+        let timeout = Duration::from_secs(5);
+        let v1 = Target::V1 {
+            database: "home".to_string(),
+        };
+        assert_eq!(
+            InfluxWriter::new("http://influxdb:8086/", &v1, None, timeout)
+                .unwrap()
+                .url()
+                .as_str(),
+            "http://influxdb:8086/write?db=home&precision=s"
+        );
+        let v2 = Target::V2 {
+            org: "my org".to_string(),
+            bucket: "water".to_string(),
+        };
+        assert_eq!(
+            InfluxWriter::new(
+                "https://eu-central-1-1.aws.cloud2.influxdata.com",
+                &v2,
+                None,
+                timeout
+            )
+            .unwrap()
+            .url()
+            .as_str(),
+            "https://eu-central-1-1.aws.cloud2.influxdata.com/api/v2/write?org=my+org&bucket=water&precision=s"
+        );
+        assert!(InfluxWriter::new("influxdb:8086", &v1, None, timeout).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_write() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v2/write"))
+            .and(query_param("bucket", "water"))
+            .and(header("Authorization", "Token secret"))
+            .and(body_string("a value=1 1\nb value=2 1"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/write"))
+            .respond_with(
+                ResponseTemplate::new(404).set_body_string("{\"error\":\"database not found\"}"),
+            )
+            .mount(&server)
+            .await;
+
+        let target = Target::V2 {
+            org: "home".to_string(),
+            bucket: "water".to_string(),
+        };
+        let writer = InfluxWriter::new(
+            &server.uri(),
+            &target,
+            Some("secret".to_string()),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        writer
+            .write(&["a value=1 1".to_string(), "b value=2 1".to_string()])
+            .await
+            .unwrap();
+
+        let target = Target::V1 {
+            database: "missing".to_string(),
+        };
+        let writer =
+            InfluxWriter::new(&server.uri(), &target, None, Duration::from_secs(5)).unwrap();
+        let error = writer
+            .write(&["a value=1 1".to_string()])
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("database not found"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn test_record() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/write"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&server)
+            .await;
+        let target = Target::V1 {
+            database: "home".to_string(),
+        };
+        let influxdb = InfluxWriter::new(&server.uri(), &target, None, Duration::from_secs(5))
+            .unwrap()
+            .start(format("homewizard", &[]));
+        influxdb.record("kitchen", &water());
+
+        for _ in 0..50 {
+            if let Some(requests) = server.received_requests().await
+                && let Some(request) = requests.first()
+            {
+                let body = String::from_utf8_lossy(&request.body).to_string();
+                assert!(body.starts_with("homewizard,device=kitchen,type=water "));
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("no point was written");
+    }
+}
@@ -0,0 +1,342 @@
+use crate::readings::TimedReading;
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use rskafka::BackoffConfig;
+use rskafka::client::partition::{Compression, PartitionClient, UnknownTopicHandling};
+use rskafka::client::{Client, ClientBuilder, Credentials, SaslConfig};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tokio_rustls::rustls::ClientConfig;
+use tracing::{debug, warn};
+
+/// Records waiting to be sent; newer ones are dropped while it is full.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// Most records sent in one request.
+const MAX_BATCH: usize = 500;
+
+const DEFAULT_PORT: u16 = 9092;
+
+/// Where and how to produce (`--kafka-broker` and friends).
+#[derive(Clone)]
+pub struct KafkaOptions {
+    /// Bootstrap brokers, asked for the leaders of the topic's partitions
+    pub brokers: Vec<(String, u16)>,
+    pub topic: String,
+    pub tls: Option<Arc<ClientConfig>>,
+    /// User name and password for SASL/PLAIN
+    pub sasl: Option<(String, String)>,
+    pub client_id: String,
+    /// How long a batch is retried, across leader changes and throttling
+    pub timeout: Duration,
+}
+
+impl KafkaOptions {
+    /// Options for `host[:9092]` bootstrap brokers.
+    pub fn new(brokers: &[String], topic: String) -> Result<Self> {
+        let brokers = brokers
+            .iter()
+            .map(|broker| parse_broker(broker))
+            .collect::<Result<Vec<_>>>()?;
+        if brokers.is_empty() {
+            bail!("no Kafka brokers");
+        }
+        Ok(Self {
+            brokers,
+            topic,
+            tls: None,
+            sasl: None,
+            client_id: "homewizard-water-exporter".to_string(),
+            timeout: Duration::from_secs(10),
+        })
+    }
+
+    /// The bootstrap brokers as `host:port`, with IPv6 addresses in brackets.
+    fn bootstrap_brokers(&self) -> Vec<String> {
+        self.brokers
+            .iter()
+            .map(|(host, port)| match host.contains(':') {
+                true => format!("[{host}]:{port}"),
+                false => format!("{host}:{port}"),
+            })
+            .collect()
+    }
+}
+
+fn parse_broker(broker: &str) -> Result<(String, u16)> {
+    let url = reqwest::Url::parse(&format!("kafka://{broker}"))
+        .ok()
+        .filter(|url| url.path().is_empty())
+        .with_context(|| format!("invalid Kafka broker `{broker}`, expected host[:port]"))?;
+    let host = url
+        .host_str()
+        .filter(|host| !host.is_empty())
+        .with_context(|| format!("Kafka broker `{broker}` has no host"))?;
+    Ok((
+        host.trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string(),
+        url.port().unwrap_or(DEFAULT_PORT),
+    ))
+}
+
+/// A message for the topic: the device name as the key, so a device's readings
+/// stay in order on one partition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    /// Unix milliseconds
+    pub timestamp_ms: i64,
+}
+
+impl Record {
+    fn to_kafka(&self) -> rskafka::record::Record {
+        rskafka::record::Record {
+            key: Some(self.key.clone()),
+            value: Some(self.value.clone()),
+            headers: BTreeMap::new(),
+            timestamp: DateTime::from_timestamp_millis(self.timestamp_ms).unwrap_or_else(Utc::now),
+        }
+    }
+}
+
+/// Produces records to a Kafka topic from a background task.
+///
+/// The client follows partition leaders, retries retriable broker errors and
+/// honours throttling for up to [`KafkaOptions::timeout`]; records that still
+/// cannot be sent are logged and dropped, and the next batch reconnects.
+#[derive(Clone)]
+pub struct KafkaProducer {
+    queue: mpsc::Sender<Record>,
+}
+
+impl KafkaProducer {
+    pub fn start(options: KafkaOptions) -> Self {
+        let (queue, records) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(run(options, records));
+        Self { queue }
+    }
+
+    pub fn send(&self, record: Record) {
+        if self.queue.try_send(record).is_err() {
+            warn!("Kafka queue is full, dropping a record");
+        }
+    }
+
+    /// Produce every recorded reading as JSON, keyed by its device.
+    pub fn publish_readings(&self, mut readings: broadcast::Receiver<TimedReading>) {
+        let producer = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match readings.recv().await {
+                    Ok(reading) => match serde_json::to_vec(&reading) {
+                        Ok(value) => producer.send(Record {
+                            key: reading.device.into_bytes(),
+                            value,
+                            timestamp_ms: (reading.timestamp as i64).saturating_mul(1000),
+                        }),
+                        Err(e) => warn!("Failed to serialize reading for Kafka: {}", e),
+                    },
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Kafka producer fell behind, skipped {} readings", skipped)
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+}
+
+async fn run(options: KafkaOptions, mut queue: mpsc::Receiver<Record>) {
+    let mut topic = None;
+    let mut records = Vec::new();
+    while queue.recv_many(&mut records, MAX_BATCH).await > 0 {
+        let result = match &mut topic {
+            Some(topic) => Ok(topic),
+            None => Topic::connect(&options)
+                .await
+                .map(|connected| topic.insert(connected)),
+        };
+        let result = match result {
+            Ok(topic) => topic.produce(&records).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => debug!("Sent {} records to Kafka", records.len()),
+            Err(e) => {
+                warn!("Failed to send {} records to Kafka: {:#}", records.len(), e);
+                topic = None;
+            }
+        }
+        records.clear();
+    }
+}
+
+/// The topic on a connected cluster, with a client per partition written to.
+struct Topic {
+    client: Client,
+    name: String,
+    partitions: usize,
+    clients: HashMap<i32, PartitionClient>,
+}
+
+impl Topic {
+    async fn connect(options: &KafkaOptions) -> Result<Self> {
+        let mut builder = ClientBuilder::new(options.bootstrap_brokers())
+            .client_id(options.client_id.as_str())
+            .backoff_config(BackoffConfig {
+                deadline: Some(options.timeout),
+                ..Default::default()
+            });
+        if let Some(tls) = &options.tls {
+            builder = builder.tls_config(tls.clone());
+        }
+        if let Some((username, password)) = &options.sasl {
+            builder = builder.sasl_config(SaslConfig::Plain(Credentials::new(
+                username.clone(),
+                password.clone(),
+            )));
+        }
+        let client = builder
+            .build()
+            .await
+            .context("failed to connect to the Kafka brokers")?;
+        let partitions = client
+            .list_topics()
+            .await?
+            .into_iter()
+            .find(|topic| topic.name == options.topic)
+            .map(|topic| topic.partitions.len())
+            .filter(|&partitions| partitions > 0)
+            .with_context(|| format!("topic {} does not exist", options.topic))?;
+        Ok(Self {
+            client,
+            name: options.topic.clone(),
+            partitions,
+            clients: HashMap::new(),
+        })
+    }
+
+    async fn produce(&mut self, records: &[Record]) -> Result<()> {
+        let mut by_partition: BTreeMap<i32, Vec<rskafka::record::Record>> = BTreeMap::new();
+        for record in records {
+            by_partition
+                .entry(partition(&record.key, self.partitions))
+                .or_default()
+                .push(record.to_kafka());
+        }
+
+        for (partition, records) in by_partition {
+            if !self.clients.contains_key(&partition) {
+                let client = self
+                    .client
+                    .partition_client(self.name.as_str(), partition, UnknownTopicHandling::Error)
+                    .await?;
+                self.clients.insert(partition, client);
+            }
+            self.clients[&partition]
+                .produce(records, Compression::NoCompression)
+                .await
+                .with_context(|| format!("partition {partition} of {}", self.name))?;
+        }
+        Ok(())
+    }
+}
+
+/// The partition of a key, as chosen by Kafka's default partitioner.
+fn partition(key: &[u8], partitions: usize) -> i32 {
+    ((murmur2(key) & 0x7fff_ffff) as usize % partitions) as i32
+}
+
+/// The murmur2 hash of the Java client, so keys land where other producers put them.
+fn murmur2(data: &[u8]) -> u32 {
+    const M: u32 = 0x5bd1_e995;
+    let mut h = 0x9747_b28c ^ data.len() as u32;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes(chunk.try_into().expect("4 bytes"));
+        k = k.wrapping_mul(M);
+        k ^= k >> 24;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M) ^ k;
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, byte) in rest.iter().enumerate().rev() {
+            h ^= u32::from(*byte) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^ (h >> 15)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_broker() {
+        assert_eq!(
+            parse_broker("kafka-1").unwrap(),
+            ("kafka-1".to_string(), 9092)
+        );
+        assert_eq!(
+            parse_broker("10.0.0.5:19092").unwrap(),
+            ("10.0.0.5".to_string(), 19092)
+        );
+        assert_eq!(
+            parse_broker("[::1]:9093").unwrap(),
+            ("::1".to_string(), 9093)
+        );
+        assert!(parse_broker("kafka:port").is_err());
+        assert!(parse_broker("kafka:9092/topic").is_err());
+        assert!(KafkaOptions::new(&[], "homewizard".to_string()).is_err());
+
+        let options = KafkaOptions::new(
+            &["kafka-1".to_string(), "[::1]:9093".to_string()],
+            "homewizard".to_string(),
+        )
+        .unwrap();
+        assert_eq!(options.bootstrap_brokers(), ["kafka-1:9092", "[::1]:9093"]);
+    }
+
+    #[test]
+    fn test_partition() {
+        // From the Java client's tests
+        assert_eq!(murmur2(b"21") as i32, -973_932_308);
+        assert_eq!(murmur2(b"foobar") as i32, -790_332_482);
+        assert_eq!(murmur2(b"abc") as i32, 479_470_107);
+        assert!((0..3).contains(&partition(b"kitchen", 3)));
+        assert_eq!(partition(b"kitchen", 1), 0);
+    }
+
+    #[test]
+    fn test_record() {
+        let record = Record {
+            key: b"kitchen".to_vec(),
+            value: b"{\"total\":42.5}".to_vec(),
+            timestamp_ms: 1_700_000_000_500,
+        }
+        .to_kafka();
+        assert_eq!(record.key.as_deref(), Some(&b"kitchen"[..]));
+        assert_eq!(record.value.as_deref(), Some(&b"{\"total\":42.5}"[..]));
+        assert_eq!(record.timestamp.timestamp_millis(), 1_700_000_000_500);
+        assert!(record.headers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_broker() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let mut options =
+            KafkaOptions::new(&[format!("127.0.0.1:{port}")], "homewizard".to_string()).unwrap();
+        options.timeout = Duration::from_millis(300);
+        assert!(Topic::connect(&options).await.is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Watches for water flowing without a break, such as a running tap or a burst pipe.
+///
+/// Flow is only sampled once per poll, so "without a break" means every poll since
+/// the flow started saw a nonzero flow.
+#[derive(Debug)]
+pub struct LeakDetector {
+    leak_after: Duration,
+    flowing_since: HashMap<String, Instant>,
+}
+
+impl LeakDetector {
+    /// A `leak_after` of zero disables the detector.
+    pub fn new(leak_after: Duration) -> Self {
+        Self {
+            leak_after,
+            flowing_since: HashMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.leak_after.is_zero()
+    }
+
+    /// Record a flow reading; returns how long water has been flowing without a break.
+    pub fn observe(&mut self, device: &str, flow_lpm: f64, now: Instant) -> Duration {
+        if flow_lpm <= 0.0 {
+            self.flowing_since.remove(device);
+            return Duration::ZERO;
+        }
+        let since = *self.flowing_since.entry(device.to_string()).or_insert(now);
+        now.duration_since(since)
+    }
+
+    /// Whether water flowing for `duration` counts as a suspected leak.
+    pub fn is_leak(&self, duration: Duration) -> bool {
+        self.is_enabled() && duration >= self.leak_after
+    }
+
+    /// Drop the state of a device whose flow is unknown.
+    pub fn forget(&mut self, device: &str) {
+        self.flowing_since.remove(device);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEAK_AFTER: Duration = Duration::from_secs(1800);
+
+    #[test]
+    fn test_continuous_flow_is_a_leak() {
+        let mut detector = LeakDetector::new(LEAK_AFTER);
+        let start = Instant::now();
+
+        assert_eq!(detector.observe("meter", 2.0, start), Duration::ZERO);
+        let duration = detector.observe("meter", 0.5, start + Duration::from_secs(600));
+        assert_eq!(duration, Duration::from_secs(600));
+        assert!(!detector.is_leak(duration));
+
+        let duration = detector.observe("meter", 0.5, start + LEAK_AFTER);
+        assert!(detector.is_leak(duration));
+    }
+
+    #[test]
+    fn test_no_flow_ends_it() {
+        let mut detector = LeakDetector::new(LEAK_AFTER);
+        let start = Instant::now();
+
+        detector.observe("meter", 2.0, start);
+        detector.observe("meter", 0.0, start + Duration::from_secs(60));
+        let duration = detector.observe("meter", 2.0, start + LEAK_AFTER);
+        assert_eq!(duration, Duration::ZERO);
+        assert!(!detector.is_leak(duration));
+    }
+
+    #[test]
+    fn test_devices_are_independent() {
+        let mut detector = LeakDetector::new(LEAK_AFTER);
+        let start = Instant::now();
+
+        detector.observe("kitchen", 2.0, start);
+        let duration = detector.observe("garden", 2.0, start + LEAK_AFTER);
+        assert_eq!(duration, Duration::ZERO);
+        let duration = detector.observe("kitchen", 2.0, start + LEAK_AFTER);
+        assert!(detector.is_leak(duration));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let detector = LeakDetector::new(Duration::ZERO);
+        assert!(!detector.is_enabled());
+        assert!(!detector.is_leak(Duration::from_secs(86400)));
+    }
+}
@@ -0,0 +1,68 @@
+//! Prometheus exporter for HomeWizard Water Meters.
+//!
+//! The binary in `main.rs` wires these modules together; they are exposed as a
+//! library so benchmarks and other tools can reuse the client and metrics.
+
+pub mod access;
+pub mod alerts;
+pub mod archive;
+pub mod auth;
+pub mod averages;
+pub mod breaker;
+pub mod build_info;
+pub mod check;
+pub mod cloud;
+pub mod collector;
+pub mod config;
+pub mod dashboard;
+pub mod devices;
+pub mod discover;
+pub mod events;
+pub mod federation;
+pub mod grafana;
+#[cfg(feature = "graphite")]
+pub mod graphite;
+pub mod healthcheck;
+pub mod heartbeat;
+#[cfg(feature = "sqlite")]
+pub mod history;
+#[cfg(feature = "mqtt")]
+pub mod homeassistant;
+pub mod homewizard;
+#[cfg(feature = "https")]
+pub mod https;
+#[cfg(feature = "influxdb")]
+pub mod influxdb;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod leak;
+pub mod logging;
+pub mod metrics;
+pub mod mock;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "nats")]
+pub mod nats;
+#[cfg(feature = "notifications")]
+pub mod notify;
+pub mod pairing;
+pub mod probe;
+pub mod readings;
+pub mod recording;
+#[cfg(feature = "remote-write")]
+pub mod remote_write;
+pub mod resolver;
+pub mod scheduler;
+pub mod self_update;
+pub mod server;
+pub mod simulate;
+#[cfg(feature = "notifications")]
+pub mod smtp;
+pub mod state;
+#[cfg(feature = "statsd")]
+pub mod statsd;
+pub mod systemd;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod vmimport;
+pub mod websocket;
@@ -0,0 +1,613 @@
+use serde_json::{Map, Value};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::Layer;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, MakeWriter};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// How log lines are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum LogFormat {
+    /// One line per event with timestamp, level, target and fields
+    #[default]
+    Full,
+    /// Like `full`, but shorter
+    Compact,
+    /// Multi-line and indented, for reading in a terminal
+    Pretty,
+    /// One JSON object per line, for Loki, Elasticsearch and the like
+    Json,
+}
+
+/// Where logs go besides `--log-file`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum LogBackend {
+    /// Standard output, formatted by `--log-format`
+    #[default]
+    Stdout,
+    /// The systemd journal, with the event fields as journal fields
+    Journald,
+    /// The local syslog daemon through `/dev/log`
+    Syslog,
+}
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_SOCKET: &str = "/dev/log";
+const IDENTIFIER: &str = env!("CARGO_PKG_NAME");
+
+/// The layer writing to `backend`; `format` only applies to standard output.
+pub fn backend_layer<S>(
+    backend: LogBackend,
+    format: LogFormat,
+) -> io::Result<Box<dyn Layer<S> + Send + Sync>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    match backend {
+        LogBackend::Stdout => Ok(layer(format, io::stdout, true)),
+        #[cfg(unix)]
+        LogBackend::Journald => Ok(SocketLayer::new(backend, Path::new(JOURNALD_SOCKET))?.boxed()),
+        #[cfg(unix)]
+        LogBackend::Syslog => Ok(SocketLayer::new(backend, Path::new(SYSLOG_SOCKET))?.boxed()),
+        #[cfg(not(unix))]
+        LogBackend::Journald | LogBackend::Syslog => Err(io::ErrorKind::Unsupported.into()),
+    }
+}
+
+/// Sends each event as a datagram to journald or syslog, which add the
+/// timestamp themselves.
+#[cfg(unix)]
+pub struct SocketLayer {
+    backend: LogBackend,
+    socket: std::os::unix::net::UnixDatagram,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl SocketLayer {
+    pub fn new(backend: LogBackend, path: &Path) -> io::Result<Self> {
+        if !path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} does not exist", path.display()),
+            ));
+        }
+        Ok(Self {
+            backend,
+            socket: std::os::unix::net::UnixDatagram::unbound()?,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+#[cfg(unix)]
+impl<S: Subscriber> Layer<S> for SocketLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut fields = Map::new();
+        event.record(&mut JsonVisitor(&mut fields));
+        let message = match fields.remove("message") {
+            Some(Value::String(message)) => message,
+            Some(value) => value.to_string(),
+            None => String::new(),
+        };
+        let datagram = match self.backend {
+            LogBackend::Journald => {
+                journald_entry(*metadata.level(), metadata.target(), &message, &fields)
+            }
+            _ => syslog_line(*metadata.level(), &message, &fields).into_bytes(),
+        };
+        // Nowhere left to report a failure to log
+        let _ = self.socket.send_to(&datagram, &self.path);
+    }
+}
+
+/// Syslog severity of a level.
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+fn field_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// An entry in the journal's native protocol: `KEY=value` lines, with a
+/// length-prefixed value for anything spanning lines.
+fn journald_entry(
+    level: Level,
+    target: &str,
+    message: &str,
+    fields: &Map<String, Value>,
+) -> Vec<u8> {
+    let mut entry = Vec::new();
+    let mut field = |key: &str, value: &str| {
+        if value.contains('\n') {
+            entry.extend_from_slice(key.as_bytes());
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            entry.extend_from_slice(value.as_bytes());
+            entry.push(b'\n');
+        } else {
+            entry.extend_from_slice(format!("{key}={value}\n").as_bytes());
+        }
+    };
+    field("MESSAGE", message);
+    field("PRIORITY", &severity(level).to_string());
+    field("SYSLOG_IDENTIFIER", IDENTIFIER);
+    field("TARGET", target);
+    for (key, value) in fields {
+        // Journal field names are upper case letters, digits and underscores,
+        // and may not start with an underscore
+        let key: String = key
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let key = key.trim_start_matches(|c: char| c == '_' || c.is_ascii_digit());
+        if !key.is_empty() {
+            field(key, &field_text(value));
+        }
+    }
+    entry
+}
+
+/// An RFC 3164 line from the daemon facility, without the timestamp the daemon adds.
+fn syslog_line(level: Level, message: &str, fields: &Map<String, Value>) -> String {
+    const DAEMON: u8 = 3;
+    let mut line = format!(
+        "<{}>{}[{}]: {}",
+        DAEMON * 8 + severity(level),
+        IDENTIFIER,
+        std::process::id(),
+        message
+    );
+    for (key, value) in fields {
+        line.push_str(&format!(" {key}={}", field_text(value)));
+    }
+    line
+}
+
+/// When the log or CSV file is rotated, besides reaching its size limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Rotation {
+    /// Only by size
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl Rotation {
+    /// Identifies the current period; the file is rotated when it changes.
+    fn period(&self) -> Option<String> {
+        let format = match self {
+            Rotation::Never => return None,
+            Rotation::Hourly => "%Y-%m-%d %H",
+            Rotation::Daily => "%Y-%m-%d",
+        };
+        Some(chrono::Local::now().format(format).to_string())
+    }
+}
+
+/// The formatting layer for `format`, writing to `writer`; `ansi` colors the text formats.
+pub fn layer<S, W>(format: LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match format {
+        LogFormat::Full => layer.boxed(),
+        LogFormat::Compact => layer.compact().boxed(),
+        LogFormat::Pretty => layer.pretty().boxed(),
+        LogFormat::Json => layer.event_format(JsonFormat).boxed(),
+    }
+}
+
+/// Writes each event as a JSON object: `timestamp`, `level`, `target`, the
+/// `message` and the event's fields, with numbers and booleans kept as such.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+        let mut fields = Map::new();
+        fields.insert(
+            "timestamp".to_string(),
+            Value::from(chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true)),
+        );
+        fields.insert("level".to_string(), Value::from(metadata.level().as_str()));
+        fields.insert("target".to_string(), Value::from(metadata.target()));
+        event.record(&mut JsonVisitor(&mut fields));
+        writeln!(writer, "{}", Value::Object(fields))
+    }
+}
+
+/// A log file that is rotated by size and optionally by time (`--log-file`).
+///
+/// Rotated files get a numeric suffix, `.1` being the most recent; only the
+/// newest `keep` of them are kept.
+pub struct RotatingFile {
+    state: Mutex<FileState>,
+}
+
+struct FileState {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    keep: usize,
+    rotation: Rotation,
+    period: Option<String>,
+    header: Vec<u8>,
+}
+
+impl RotatingFile {
+    /// Open `path` for appending; `max_bytes` of 0 disables rotation by size.
+    pub fn open(path: &Path, max_bytes: u64, keep: usize, rotation: Rotation) -> io::Result<Self> {
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = open_append(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            state: Mutex::new(FileState {
+                path: path.to_path_buf(),
+                file,
+                size,
+                max_bytes,
+                keep,
+                rotation,
+                period: rotation.period(),
+                header: Vec::new(),
+            }),
+        })
+    }
+
+    /// Start every new or rotated file with `header`.
+    pub fn with_header(self, header: impl Into<Vec<u8>>) -> Self {
+        let mut state = self.state.into_inner().unwrap_or_else(|e| e.into_inner());
+        state.header = header.into();
+        Self {
+            state: Mutex::new(state),
+        }
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl FileState {
+    fn write(&mut self, buf: &[u8], period: Option<String>) -> io::Result<usize> {
+        let too_big =
+            self.max_bytes > 0 && self.size > 0 && self.size + buf.len() as u64 > self.max_bytes;
+        if too_big || period != self.period {
+            self.rotate()?;
+            self.period = period;
+        }
+        if self.size == 0 && !self.header.is_empty() {
+            self.file.write_all(&self.header)?;
+            self.size = self.header.len() as u64;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{n}"));
+            PathBuf::from(name)
+        };
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            let _ = std::fs::remove_file(rotated(self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = std::fs::rename(rotated(n), rotated(n + 1));
+            }
+            std::fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Writer for one log event, handed out by [`RotatingFile`].
+pub struct RotatingWriter<'a>(MutexGuard<'a, FileState>);
+
+impl Write for RotatingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let period = self.0.rotation.period();
+        self.0.write(buf, period)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.file.flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for RotatingFile {
+    type Writer = RotatingWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RotatingWriter(self.state.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl JsonVisitor<'_> {
+    fn insert(&mut self, field: &Field, value: impl Into<Value>) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+}
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value);
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value);
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value);
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        self.insert(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, format!("{value:?}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tracing::{info, warn};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn capture(format: LogFormat, log: impl FnOnce()) -> String {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber =
+            tracing_subscriber::registry().with(layer(format, move || writer.clone(), false));
+        tracing::subscriber::with_default(subscriber, log);
+        String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap()
+    }
+
+    #[test]
+    fn test_json_format() {
+        let output = capture(LogFormat::Json, || {
+            info!(
+                device = "kitchen",
+                duration_ms = 12u64,
+                "Fetched {}",
+                "data"
+            );
+            warn!(reason = "timeout", up = false, "Failed");
+        });
+        let lines: Vec<Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["level"], "INFO");
+        assert_eq!(lines[0]["message"], "Fetched data");
+        assert_eq!(lines[0]["device"], "kitchen");
+        assert_eq!(lines[0]["duration_ms"], 12);
+        assert!(lines[0]["timestamp"].as_str().unwrap().ends_with('Z'));
+        assert_eq!(lines[1]["level"], "WARN");
+        assert_eq!(lines[1]["reason"], "timeout");
+        assert_eq!(lines[1]["up"], false);
+    }
+
+    fn read(path: &Path) -> String {
+        std::fs::read_to_string(path).unwrap_or_default()
+    }
+
+    fn rotated(path: &Path, n: usize) -> PathBuf {
+        PathBuf::from(format!("{}.{n}", path.display()))
+    }
+
+    #[test]
+    fn test_rotate_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs/exporter.log");
+        let file = RotatingFile::open(&path, 10, 2, Rotation::Never).unwrap();
+
+        for line in ["one\n", "two\n", "three\n", "four\n", "five\n"] {
+            file.make_writer().write_all(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(read(&path), "four\nfive\n");
+        assert_eq!(read(&rotated(&path, 1)), "three\n");
+        assert_eq!(read(&rotated(&path, 2)), "one\ntwo\n");
+        assert!(!rotated(&path, 3).exists());
+    }
+
+    #[test]
+    fn test_rotate_by_period() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("exporter.log");
+        let file = RotatingFile::open(&path, 0, 3, Rotation::Daily).unwrap();
+        let mut state = file.state.lock().unwrap();
+
+        let today = state.period.clone();
+        state.write(b"today\n", today.clone()).unwrap();
+        state.write(b"still today\n", today).unwrap();
+        state
+            .write(b"tomorrow\n", Some("2099-01-01".to_string()))
+            .unwrap();
+
+        assert_eq!(read(&path), "tomorrow\n");
+        assert_eq!(read(&rotated(&path, 1)), "today\nstill today\n");
+    }
+
+    #[test]
+    fn test_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("readings.csv");
+        let file = RotatingFile::open(&path, 12, 1, Rotation::Never)
+            .unwrap()
+            .with_header("a,b\n");
+
+        for line in ["1,2\n", "3,4\n", "5,6\n"] {
+            file.make_writer().write_all(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(read(&path), "a,b\n5,6\n");
+        assert_eq!(read(&rotated(&path, 1)), "a,b\n1,2\n3,4\n");
+
+        // An existing file already has its header
+        let file = RotatingFile::open(&path, 0, 1, Rotation::Never)
+            .unwrap()
+            .with_header("a,b\n");
+        file.make_writer().write_all(b"7,8\n").unwrap();
+        assert_eq!(read(&path), "a,b\n5,6\n7,8\n");
+    }
+
+    #[test]
+    fn test_appends_to_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("exporter.log");
+        std::fs::write(&path, "before\n").unwrap();
+
+        let file = RotatingFile::open(&path, 0, 1, Rotation::Never).unwrap();
+        file.make_writer().write_all(b"after\n").unwrap();
+
+        assert_eq!(read(&path), "before\nafter\n");
+    }
+
+    #[test]
+    fn test_journald_entry() {
+        let mut fields = Map::new();
+        fields.insert("device".to_string(), Value::from("kitchen"));
+        fields.insert("duration_ms".to_string(), Value::from(12));
+        fields.insert("_private".to_string(), Value::from("x"));
+        let entry = journald_entry(Level::WARN, "exporter", "Failed", &fields);
+
+        assert_eq!(
+            String::from_utf8(entry).unwrap(),
+            format!(
+                "MESSAGE=Failed\nPRIORITY=4\nSYSLOG_IDENTIFIER={IDENTIFIER}\nTARGET=exporter\nPRIVATE=x\nDEVICE=kitchen\nDURATION_MS=12\n"
+            )
+        );
+
+        let entry = journald_entry(Level::ERROR, "exporter", "two\nlines", &Map::new());
+        assert!(entry.starts_with(b"MESSAGE\n\x09\0\0\0\0\0\0\0two\nlines\nPRIORITY=3\n"));
+    }
+
+    #[test]
+    fn test_syslog_line() {
+        let mut fields = Map::new();
+        fields.insert("device".to_string(), Value::from("kitchen"));
+        let line = syslog_line(Level::INFO, "Fetched", &fields);
+
+        assert_eq!(
+            line,
+            format!(
+                "<30>{IDENTIFIER}[{}]: Fetched device=kitchen",
+                std::process::id()
+            )
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_socket_layer() {
+        use std::os::unix::net::UnixDatagram;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.sock");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        let layer = SocketLayer::new(LogBackend::Journald, &path).unwrap();
+
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || info!(device = "kitchen", "Fetched"));
+
+        let mut buf = [0; 512];
+        let len = receiver.recv(&mut buf).unwrap();
+        let entry = String::from_utf8_lossy(&buf[..len]);
+        assert!(
+            entry.starts_with("MESSAGE=Fetched\nPRIORITY=6\n"),
+            "{entry}"
+        );
+        assert!(entry.contains("DEVICE=kitchen\n"), "{entry}");
+
+        assert!(SocketLayer::new(LogBackend::Syslog, &dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_text_formats() {
+        for format in [LogFormat::Full, LogFormat::Compact, LogFormat::Pretty] {
+            let output = capture(format, || info!(device = "kitchen", "Fetched data"));
+            assert!(output.contains("Fetched data"), "{format:?}: {output}");
+            assert!(output.contains("kitchen"), "{format:?}: {output}");
+        }
+    }
+}
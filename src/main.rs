@@ -1,367 +1,1620 @@
-mod config;
-mod homewizard;
-mod metrics;
-
-use anyhow::Result;
-use axum::{Router, routing::get};
-use clap::Parser;
+use anyhow::{Context, Result};
+#[cfg(feature = "https")]
+use axum::serve::ListenerExt;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde_json::{Map, Value};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinSet;
 use tokio::time::interval;
-use tracing::{error, info, warn};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-
-use crate::config::Config;
-use crate::homewizard::HomeWizardClient;
-use crate::metrics::Metrics;
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::{
+    EnvFilter, Registry, layer::SubscriberExt, reload, util::SubscriberInitExt,
+};
 
-type SharedMetrics = Arc<RwLock<String>>;
+use homewizard_water_exporter::access::Allowlist;
+use homewizard_water_exporter::alerts;
+use homewizard_water_exporter::archive::CsvArchive;
+use homewizard_water_exporter::auth::ScrapeAuth;
+use homewizard_water_exporter::breaker::CircuitBreaker;
+use homewizard_water_exporter::build_info::BUILD_INFO;
+use homewizard_water_exporter::check;
+use homewizard_water_exporter::cloud::FallbackClient;
+use homewizard_water_exporter::config::{
+    self, Artifact, Command, Config, ListenAddress, OutputFormat, ScrapeMode,
+};
+use homewizard_water_exporter::devices::{Device, DeviceState, Devices};
+use homewizard_water_exporter::discover;
+use homewizard_water_exporter::events::Events;
+use homewizard_water_exporter::federation::Federation;
+use homewizard_water_exporter::grafana;
+#[cfg(feature = "graphite")]
+use homewizard_water_exporter::graphite::GraphiteSink;
+use homewizard_water_exporter::healthcheck;
+use homewizard_water_exporter::heartbeat::Heartbeat;
+use homewizard_water_exporter::homewizard::{DeviceType, HomeWizardClient};
+#[cfg(feature = "https")]
+use homewizard_water_exporter::https::{self, TlsConfig, TlsListener};
+#[cfg(feature = "influxdb")]
+use homewizard_water_exporter::influxdb::{InfluxDb, InfluxWriter, LineFormat};
+#[cfg(feature = "kafka")]
+use homewizard_water_exporter::kafka::KafkaProducer;
+use homewizard_water_exporter::logging::{self, RotatingFile};
+use homewizard_water_exporter::metrics::Metrics;
+use homewizard_water_exporter::mock::{self, MockDevice};
+#[cfg(feature = "mqtt")]
+use homewizard_water_exporter::mqtt::MqttClient;
+#[cfg(feature = "nats")]
+use homewizard_water_exporter::nats::NatsClient;
+#[cfg(feature = "notifications")]
+use homewizard_water_exporter::notify::Notifications;
+use homewizard_water_exporter::pairing::{self, Pairing};
+use homewizard_water_exporter::probe::Prober;
+use homewizard_water_exporter::readings::Readings;
+use homewizard_water_exporter::recording::{self, Recorder, Replay};
+#[cfg(feature = "remote-write")]
+use homewizard_water_exporter::remote_write::{self, Batch, RemoteWriter, Wal};
+use homewizard_water_exporter::resolver::CachingResolver;
+use homewizard_water_exporter::scheduler::PollScheduler;
+use homewizard_water_exporter::self_update::{self, SelfUpdater, UpdateStatus};
+use homewizard_water_exporter::server::{
+    self, ActionRequest, ActionTrigger, AppState, DeviceAction, PollRequest, PollTrigger,
+    SharedMetrics,
+};
+use homewizard_water_exporter::simulate::Simulator;
+use homewizard_water_exporter::state;
+#[cfg(feature = "statsd")]
+use homewizard_water_exporter::statsd::{StatsdFormat, StatsdSink};
+use homewizard_water_exporter::systemd::Notifier;
+use homewizard_water_exporter::vmimport::{VmImport, VmImporter};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Parse configuration
-    let config = Config::parse();
+    let config = Config::load()?;
 
-    // Initialize logging
+    match config.command {
+        None | Some(Command::Serve) => serve(config).await,
+        Some(Command::Fetch { format, .. }) => fetch(&config, format).await,
+        Some(Command::Discover { timeout }) => discover(timeout).await,
+        Some(Command::Schema) => {
+            println!("{}", serde_json::to_string_pretty(&config::json_schema())?);
+            Ok(())
+        }
+        Some(Command::Generate { ref artifact }) => {
+            let output = match artifact {
+                Artifact::Dashboard { title } => {
+                    serde_json::to_string_pretty(&grafana::dashboard(&config, title))?
+                }
+                Artifact::Alerts {
+                    format,
+                    name,
+                    usage_factor,
+                } => alerts::render(&config, *format, name, *usage_factor)?,
+            };
+            println!("{output}");
+            Ok(())
+        }
+        Some(Command::Check { connect }) => check(&config, connect).await,
+        Some(Command::Healthcheck { timeout }) => healthcheck(&config, timeout).await,
+        Some(Command::SelfUpdate { check, force }) => self_update(check, force).await,
+        Some(Command::MockDevice {
+            port,
+            bind,
+            device_type,
+            ref script,
+            repeat,
+        }) => {
+            mock_device(
+                &config,
+                SocketAddr::new(bind, port),
+                device_type,
+                script.as_deref(),
+                repeat,
+            )
+            .await
+        }
+        Some(Command::Authorize {
+            ref host,
+            ref token_file,
+            timeout,
+        }) => authorize(host, token_file, timeout).await,
+    }
+}
+
+async fn serve(config: Config) -> Result<()> {
+    // Initialize logging; the filter is swapped when the configuration is reloaded
+    let (log_filter, log_reload) = reload::Layer::new(log_filter(&config));
+    let log_file = match &config.log_file {
+        Some(path) => Some(
+            RotatingFile::open(
+                path,
+                config.log_file_max_bytes,
+                config.log_file_keep,
+                config.log_file_rotation,
+            )
+            .with_context(|| format!("Failed to open log file {}", path.display()))?,
+        ),
+        None => None,
+    };
     tracing_subscriber::registry()
+        .with(log_filter)
         .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| config.log_level.clone().into()),
+            logging::backend_layer(config.log_backend, config.log_format).with_context(|| {
+                format!(
+                    "Failed to connect to the {:?} log backend",
+                    config.log_backend
+                )
+            })?,
         )
-        .with(tracing_subscriber::fmt::layer())
+        .with(log_file.map(|file| logging::layer(config.log_format, file, false)))
         .init();
 
-    info!("Starting HomeWizard Water Prometheus Exporter");
-    info!("HomeWizard host: {}", config.host);
+    info!(
+        "Starting HomeWizard Water Prometheus Exporter {} ({})",
+        BUILD_INFO.version, BUILD_INFO.git_sha
+    );
+    info!("HomeWizard hosts: {}", config.host.join(", "));
     info!("Metrics port: {}", config.port);
-    info!("Poll interval: {}s", config.poll_interval);
+    info!("Poll interval: {:?}", config.poll_interval);
+
+    // Bind the listener first so /livez answers while the devices are still being looked up
+    let listen = config.listen_address();
+    #[cfg(feature = "https")]
+    let tls = match config.tls_files() {
+        Some(files) => Some(Arc::new(TlsConfig::load(files)?)),
+        None => None,
+    };
+    // Without HTTPS support the configuration rejects the certificate options
+    #[cfg(not(feature = "https"))]
+    let tls = None::<std::convert::Infallible>;
+    info!(
+        "Starting metrics server on {} ({})",
+        listen,
+        if tls.is_some() { "HTTPS" } else { "HTTP" }
+    );
+    let listener = match &listen {
+        ListenAddress::Tcp(addr) => Bound::Tcp(tokio::net::TcpListener::bind(addr).await?),
+        #[cfg(unix)]
+        ListenAddress::Unix(path) => {
+            Bound::Unix(server::bind_unix(path, config.listen_socket_mode)?)
+        }
+        #[cfg(not(unix))]
+        ListenAddress::Unix(_) => {
+            anyhow::bail!("Unix domain sockets are not supported on this platform")
+        }
+    };
+
+    // Initialize metrics, shared by all devices
+    let events = Events::new();
+    let metrics = Arc::new(new_metrics(&config)?.with_events(events.clone()));
+    if let Some(path) = &config.state_file {
+        match state::load(path) {
+            Ok(Some(mut snapshot)) => {
+                let devices = config.devices();
+                let names: Vec<&str> = devices.iter().map(Device::name).collect();
+                snapshot.retain_devices(&names);
+                metrics.restore(&snapshot);
+                info!("Restored state from {}", path.display());
+            }
+            Ok(None) => info!("No state file at {} yet", path.display()),
+            Err(e) => warn!("Ignoring state file: {:#}", e),
+        }
+    }
+    let shared_metrics = server::shared_metrics("");
 
-    // Initialize metrics
-    let metrics = Arc::new(Metrics::new()?);
-    let shared_metrics: SharedMetrics = Arc::new(RwLock::new(String::new()));
+    let heartbeat = heartbeat(&config)?;
+    #[cfg(feature = "remote-write")]
+    let remote_write = start_remote_write(&config)?;
+    let vm_import = start_vm_import(&config)?;
+    #[cfg(feature = "influxdb")]
+    let influxdb = start_influxdb(&config)?;
 
-    // Initialize HomeWizard client
-    let client = HomeWizardClient::new(config.homewizard_url(), config.http_timeout_duration())?;
+    let federation = if config.federate.is_empty() {
+        None
+    } else {
+        info!("Federating exporters: {}", config.federate.join(", "));
+        Some(Arc::new(Federation::new(
+            config.federate.clone(),
+            config.http_timeout,
+        )?))
+    };
 
-    // Start polling task
-    let poll_metrics = metrics.clone();
-    let poll_shared_metrics = shared_metrics.clone();
-    let poll_interval = config.poll_interval_duration();
+    let notifier = Notifier::from_env();
+    if let Some(timeout) = notifier.watchdog_timeout() {
+        info!("systemd watchdog timeout: {}s", timeout.as_secs());
+        if config.scrape_mode == ScrapeMode::Poll && timeout <= config.poll_interval {
+            warn!(
+                "The systemd watchdog timeout ({:?}) is not longer than the poll interval ({:?}); the service will be restarted between polls",
+                timeout, config.poll_interval
+            );
+        }
+    }
 
-    tokio::spawn(async move {
-        let mut interval = interval(poll_interval);
-        interval.tick().await; // First tick completes immediately
+    let readings = Readings::new().with_history(config.history_retention);
+    #[cfg(feature = "sqlite")]
+    let readings = match &config.history_db {
+        Some(path) => readings
+            .with_history_db(path)
+            .with_context(|| format!("failed to open history database {}", path.display()))?,
+        None => readings,
+    };
 
-        loop {
-            interval.tick().await;
+    let csv = match &config.csv_file {
+        Some(path) => Some(Arc::new(
+            CsvArchive::open(
+                path,
+                config.csv_file_max_bytes,
+                config.csv_file_keep,
+                config.csv_file_rotation,
+            )
+            .with_context(|| format!("Failed to open CSV file {}", path.display()))?,
+        )),
+        None => None,
+    };
 
-            match client.fetch_data().await {
-                Ok(data) => {
-                    info!("Successfully fetched data from HomeWizard Water Meter");
+    #[cfg(feature = "mqtt")]
+    if let Some(options) = config.mqtt_options()? {
+        info!(
+            "Publishing readings to MQTT broker {}:{}",
+            options.host, options.port
+        );
+        MqttClient::start(options).publish_readings(
+            readings.subscribe(),
+            config.mqtt_topic.clone(),
+            config.mqtt_qos,
+            config.mqtt_retain,
+            config.homeassistant_discovery(),
+        );
+    }
 
-                    if let Err(e) = poll_metrics.update(&data) {
-                        error!("Failed to update metrics: {}", e);
-                        continue;
-                    }
+    #[cfg(feature = "kafka")]
+    if let Some(options) = config.kafka_options()? {
+        info!("Producing readings to Kafka topic {}", options.topic);
+        KafkaProducer::start(options).publish_readings(readings.subscribe());
+    }
 
-                    match poll_metrics.gather() {
-                        Ok(metrics_text) => {
-                            let mut metrics_guard = poll_shared_metrics.write().await;
-                            *metrics_guard = metrics_text;
-                        }
-                        Err(e) => {
-                            error!("Failed to gather metrics: {}", e);
-                        }
-                    }
+    #[cfg(feature = "nats")]
+    if let Some(options) = config.nats_options()? {
+        info!(
+            "Publishing readings to NATS server {}:{}",
+            options.host, options.port
+        );
+        NatsClient::start(options)
+            .publish_readings(readings.subscribe(), config.nats_subject.clone());
+    }
+
+    #[cfg(feature = "statsd")]
+    if let Some(address) = &config.statsd_address {
+        info!("Sending readings to StatsD at {}", address);
+        let format = StatsdFormat {
+            prefix: config.statsd_prefix.clone(),
+            dogstatsd: config.statsd_dogstatsd,
+            tags: config.labels.clone(),
+        };
+        StatsdSink::connect(address, format)
+            .await?
+            .start(readings.subscribe());
+    }
+
+    #[cfg(feature = "graphite")]
+    if let Some(address) = &config.graphite_address {
+        info!(
+            "Pushing readings to Graphite at {} every {:?}",
+            address, config.graphite_interval
+        );
+        GraphiteSink::new(
+            address.clone(),
+            config.graphite_prefix.clone(),
+            config.http_timeout,
+        )
+        .start(readings.clone(), config.graphite_interval);
+    }
+
+    #[cfg(feature = "notifications")]
+    {
+        let channels = config.notification_channels()?;
+        if !channels.is_empty() {
+            let names: Vec<&str> = channels
+                .iter()
+                .map(|channel| channel.service.as_str())
+                .collect();
+            info!("Sending device events to {}", names.join(", "));
+            Notifications::new(channels, config.http_timeout)?
+                .start(events.subscribe(), metrics.clone());
+        }
+    }
+
+    let devices = Devices::new(&config.devices())
+        .with_down_after(config.down_after)
+        .with_not_ready_after(config.not_ready_after)
+        .with_events(events);
+    if !config.not_ready_after.is_zero() && config.not_ready_after <= config.poll_interval {
+        warn!(
+            "--not-ready-after ({:?}) is not longer than the poll interval ({:?}); /ready will report 503 between polls",
+            config.not_ready_after, config.poll_interval
+        );
+    }
+    let context = PollContext {
+        scheduler: Arc::new(PollScheduler::new(
+            config.max_concurrent_polls as usize,
+            metrics.clone(),
+        )),
+        metrics,
+        shared_metrics: shared_metrics.clone(),
+        devices: devices.clone(),
+        readings,
+        csv,
+        recorder: open_recorder(&config)?,
+        replay: open_replay(&config)?,
+        #[cfg(feature = "influxdb")]
+        influxdb,
+        heartbeat,
+        #[cfg(feature = "remote-write")]
+        remote_write,
+        vm_import,
+        federation,
+        notifier: notifier.clone(),
+        poll_now: broadcast::channel(16).0,
+        actions: broadcast::channel(16).0,
+    };
+
+    let (scrape, requests) = match config.scrape_mode {
+        ScrapeMode::Poll => (None, None),
+        ScrapeMode::OnDemand => {
+            info!("Polling devices on demand when /metrics is scraped");
+            let (trigger, requests) = mpsc::channel(64);
+            (
+                Some(trigger),
+                Some(Arc::new(tokio::sync::Mutex::new(requests))),
+            )
+        }
+    };
+    let pollers = Pollers::start(&config, &context, requests);
+    tokio::spawn(reload_config(Reloader::new(
+        config.clone(),
+        context.clone(),
+        pollers,
+        log_reload,
+    )));
+
+    if let Some(path) = config.state_file.clone() {
+        tokio::spawn(save_state(
+            path,
+            config.state_save_interval,
+            context.metrics.clone(),
+        ));
+    }
+
+    // Initialize HTTP server
+    let probe = if config.probe {
+        info!("Serving /probe for devices configured in Prometheus");
+        Some(Arc::new(Prober::new(config.clone())?))
+    } else {
+        None
+    };
+
+    let auth = ScrapeAuth::new(
+        &config.metrics_basic_auth,
+        config.metrics_bearer_token_file.as_deref(),
+    )?
+    .map(Arc::new);
+
+    let app = server::router(AppState {
+        metrics: shared_metrics,
+        devices,
+        readings: context.readings.clone(),
+        scrape,
+        probe,
+        auth,
+        allowlist: Allowlist::new(&config.allowed_networks).map(Arc::new),
+        poll: config.enable_admin_api.then(|| context.poll_now.clone()),
+        actions: config.enable_admin_api.then(|| context.actions.clone()),
+    });
+
+    notifier.ready();
+    match (listener, tls) {
+        #[cfg(feature = "https")]
+        (Bound::Tcp(listener), Some(tls)) => {
+            tokio::spawn(https::watch(tls.clone()));
+            // axum only knows the peer address of its own listeners; the no-op tap
+            // makes it available for the allowlist
+            let listener = TlsListener::new(listener, tls).tap_io(|_| {});
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(shutdown_signal())
+            .await?
+        }
+        (Bound::Tcp(listener), None) => {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(shutdown_signal())
+            .await?
+        }
+        // The configuration rejects HTTPS and the allowlist on a Unix socket
+        #[cfg(unix)]
+        (Bound::Unix(listener), _) => {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown_signal())
+                .await?;
+            if let ListenAddress::Unix(path) = &listen {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    notifier.stopping();
+
+    // Keep what happened since the last periodic save
+    if let Some(path) = &config.state_file {
+        state::save(path, &context.metrics.snapshot())?;
+    }
+
+    Ok(())
+}
+
+/// The bound metrics listener.
+enum Bound {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Shutting down");
+}
+
+/// Periodically write the exporter-side state so a crash loses at most one interval.
+async fn save_state(path: PathBuf, every: Duration, metrics: Arc<Metrics>) {
+    let mut interval = interval(every);
+    // The first tick is immediate; there is nothing new to save yet
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        if let Err(e) = state::save(&path, &metrics.snapshot()) {
+            warn!("Failed to save state: {:#}", e);
+        }
+    }
+}
+
+/// Poll every device once and print the metrics or the readings.
+async fn fetch(config: &Config, format: OutputFormat) -> Result<()> {
+    // stdout is for the metrics
+    tracing_subscriber::registry()
+        .with(log_filter(config))
+        .with(logging::layer(config.log_format, std::io::stderr, true))
+        .init();
+
+    let devices = config.devices();
+    let metrics = Arc::new(new_metrics(config)?);
+    let context = PollContext {
+        scheduler: Arc::new(PollScheduler::new(
+            config.max_concurrent_polls as usize,
+            metrics.clone(),
+        )),
+        metrics,
+        shared_metrics: server::shared_metrics(""),
+        devices: Devices::new(&devices),
+        readings: Readings::new(),
+        csv: None,
+        recorder: open_recorder(config)?,
+        replay: open_replay(config)?,
+        #[cfg(feature = "influxdb")]
+        influxdb: None,
+        heartbeat: None,
+        #[cfg(feature = "remote-write")]
+        remote_write: None,
+        vm_import: None,
+        federation: None,
+        notifier: Notifier::default(),
+        poll_now: broadcast::channel(1).0,
+        actions: broadcast::channel(1).0,
+    };
+    let mut readings = Vec::new();
+    let mut failed = 0;
+    for device in devices {
+        let name = device.name().to_string();
+        let device_type = device.device_type.as_str();
+        let Some(poller) = DevicePoller::connect(config, device, &context).await else {
+            failed += 1;
+            continue;
+        };
+        match format {
+            OutputFormat::Prom => {
+                if !poller.poll(config, &context).await {
+                    failed += 1;
                 }
+            }
+            OutputFormat::Json => match poller.client.fetch_data().await {
+                Ok((reading, source)) => readings.push(serde_json::json!({
+                    "device": name,
+                    "type": device_type,
+                    "source": source.as_str(),
+                    "reading": reading,
+                })),
                 Err(e) => {
-                    warn!("Failed to fetch data from HomeWizard: {}", e);
+                    warn!("Failed to fetch data from HomeWizard {}: {}", name, e);
+                    failed += 1;
                 }
-            }
+            },
         }
-    });
+    }
 
-    // Initialize HTTP server
-    let app = Router::new()
-        .route("/metrics", get(metrics_handler))
-        .route("/health", get(health_handler))
-        .route("/", get(root_handler))
-        .with_state(shared_metrics);
+    match format {
+        OutputFormat::Prom => print!("{}", context.metrics.gather()?),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&readings)?),
+    }
+    if failed > 0 {
+        anyhow::bail!("{failed} of {} devices did not answer", config.host.len());
+    }
+    Ok(())
+}
 
-    let addr = config.metrics_bind_address();
-    info!("Starting metrics server on {}", &addr);
+async fn discover(timeout: Duration) -> Result<()> {
+    let found = discover::discover(timeout).await?;
+    if found.is_empty() {
+        eprintln!("No HomeWizard devices answered within {timeout:?}");
+        return Ok(());
+    }
+    for device in &found {
+        println!(
+            "{:<24} {:<12} {:<16} {}",
+            device.host_spec(),
+            device.product_type.as_deref().unwrap_or("-"),
+            device.product_name.as_deref().unwrap_or("-"),
+            device.serial.as_deref().unwrap_or("-")
+        );
+    }
+    Ok(())
+}
 
-    let listener = tokio::net::TcpListener::bind(&addr).await?;
-    axum::serve(listener, app).await?;
+async fn mock_device(
+    config: &Config,
+    address: SocketAddr,
+    device_type: DeviceType,
+    script: Option<&Path>,
+    repeat: bool,
+) -> Result<()> {
+    tracing_subscriber::registry()
+        .with(log_filter(config))
+        .with(logging::layer(config.log_format, std::io::stderr, true))
+        .init();
 
+    let mut device = MockDevice::new(device_type);
+    if let Some(path) = script {
+        device = device.with_script(mock::load_script(path)?, repeat);
+    }
+    if config.simulate {
+        device = device.with_simulator(Simulator::new(&address.to_string()));
+    }
+    let listener = tokio::net::TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Failed to bind {address}"))?;
+    info!(
+        "Mock {} device listening on http://{}/api/v1/data",
+        device_type.as_str(),
+        address
+    );
+    axum::serve(listener, mock::router(Arc::new(device)))
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
     Ok(())
 }
 
-async fn metrics_handler(
-    axum::extract::State(metrics): axum::extract::State<SharedMetrics>,
-) -> String {
-    let metrics_guard = metrics.read().await;
-    metrics_guard.clone()
+async fn check(config: &Config, connect: bool) -> Result<()> {
+    let mut findings = check::check_config(config);
+    if connect {
+        findings.extend(check::check_devices(config).await);
+    }
+    for finding in &findings {
+        println!("{finding}");
+    }
+
+    let failed = findings.iter().filter(|finding| !finding.is_ok()).count();
+    if failed > 0 {
+        anyhow::bail!("{failed} of {} checks failed", findings.len());
+    }
+    Ok(())
 }
 
-async fn health_handler() -> &'static str {
-    "OK"
+async fn healthcheck(config: &Config, timeout: Duration) -> Result<()> {
+    let body =
+        healthcheck::check(&config.listen_address(), config.tls_cert.is_some(), timeout).await?;
+    println!("{body}");
+    Ok(())
 }
 
-async fn root_handler() -> &'static str {
-    "HomeWizard Water Prometheus Exporter\n\nEndpoints:\n  /metrics - Prometheus metrics\n  /health  - Health check\n"
+async fn self_update(check: bool, force: bool) -> Result<()> {
+    let current_exe = std::env::current_exe()?;
+    let updater = SelfUpdater::new(self_update::GITHUB_API_URL)?;
+
+    match updater.run(&current_exe, check, force).await? {
+        UpdateStatus::UpToDate { version } => {
+            println!("Already up to date (latest release: {version})")
+        }
+        UpdateStatus::Available { version } => println!(
+            "Version {version} is available (current: {}); run `self-update` to install it",
+            env!("CARGO_PKG_VERSION")
+        ),
+        UpdateStatus::Updated { version } => {
+            println!("Updated {} to version {version}", current_exe.display())
+        }
+    }
+    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use axum::body::Body;
-    use axum::http::{Request, StatusCode};
-    use std::sync::Arc;
-    use tokio::sync::RwLock;
-    use tower::ServiceExt;
-
-    fn create_test_app() -> Router {
-        let shared_metrics: SharedMetrics = Arc::new(RwLock::new(
-            "# HELP test_metric A test metric\n# TYPE test_metric counter\ntest_metric 42\n"
-                .to_string(),
-        ));
+async fn authorize(host: &str, token_file: &Path, timeout: Duration) -> Result<()> {
+    let pairing = Pairing::new(&format!("https://{host}"), Duration::from_secs(10))?;
+
+    println!(
+        "Press the button on the device at {host} within {}s...",
+        timeout.as_secs()
+    );
+    let token = pairing
+        .wait_for_token(timeout, Duration::from_secs(2))
+        .await?;
+    pairing::write_token(token_file, &token)?;
+
+    println!(
+        "Paired as {}; token stored in {}",
+        pairing::USER_NAME,
+        token_file.display()
+    );
+    println!(
+        "Run the exporter with --api-version v2 --token-file {}",
+        token_file.display()
+    );
+    Ok(())
+}
+
+/// The registry for all devices, as configured.
+fn new_metrics(config: &Config) -> Result<Metrics> {
+    Metrics::with_labels(config.labels.iter().cloned().collect())?
+        .with_cumulative_total(config.cumulative_total)
+        .with_leak_after(config.leak_after)
+        .with_pricing(config.pricing())
+        .with_filter(config.metric_filter())
+        .with_net_total(config.net_total_calibration())
+        .with_wifi_metrics(!config.disable_wifi_metrics)
+        .with_aggregate(config.aggregate)
+        .with_units(config.units)
+        .with_budget(config.budget())
+        .with_quiet_hours(config.quiet_hours)
+        .with_flow_average_windows(config.flow_average_windows.clone())
+        .with_flow_buckets(&config.flow_buckets)?
+        .with_extra_metrics(&config.extra_metrics)
+}
+
+/// `RUST_LOG` if set, otherwise `--log-level`.
+fn log_filter(config: &Config) -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| config.log_level.clone().into())
+}
+
+/// Queue of the remote-write sender.
+#[cfg(feature = "remote-write")]
+type RemoteWrite = mpsc::Sender<Batch>;
+
+/// The sinks replaced on reload when their settings change.
+struct Sinks {
+    heartbeat: Option<Arc<Heartbeat>>,
+    #[cfg(feature = "remote-write")]
+    remote_write: Option<RemoteWrite>,
+}
+
+/// Scrape requests for the on-demand poller; kept across restarts of the poller.
+type ScrapeRequests = Arc<tokio::sync::Mutex<mpsc::Receiver<oneshot::Sender<()>>>>;
 
-        Router::new()
-            .route("/metrics", get(metrics_handler))
-            .route("/health", get(health_handler))
-            .route("/", get(root_handler))
-            .with_state(shared_metrics)
+/// The polling tasks of the current configuration.
+struct Pollers {
+    tasks: JoinSet<()>,
+    requests: Option<ScrapeRequests>,
+}
+
+impl Pollers {
+    fn start(config: &Config, context: &PollContext, requests: Option<ScrapeRequests>) -> Self {
+        let mut tasks = JoinSet::new();
+        match &requests {
+            Some(requests) => {
+                tasks.spawn(poll_on_demand(
+                    config.clone(),
+                    context.clone(),
+                    requests.clone(),
+                ));
+            }
+            // One polling task per device
+            None => {
+                for device in config.devices() {
+                    tasks.spawn(poll_device(config.clone(), device, context.clone()));
+                }
+            }
+        }
+        if context.federation.is_some() {
+            tasks.spawn(federate(config.clone(), context.clone()));
+        }
+        Self { tasks, requests }
     }
+}
 
-    #[tokio::test]
-    async fn test_health_handler() {
-        let app = create_test_app();
-
-        let response = app
-            .oneshot(
-                Request::builder()
-                    .uri("/health")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
+/// How long to wait for more events after the `--config` file changed, before reloading it.
+const CONFIG_SETTLE_TIME: Duration = Duration::from_millis(200);
 
-        assert_eq!(response.status(), StatusCode::OK);
+/// Settings that take effect on reload; everything else needs a restart.
+const RELOADABLE: [&str; 25] = [
+    "host",
+    "labels",
+    "log_level",
+    "poll_interval",
+    "poll_jitter",
+    "http_timeout",
+    "fetch_retries",
+    "fetch_retry_backoff",
+    "breaker_threshold",
+    "breaker_probe_interval",
+    "stale_after",
+    "device_info_interval",
+    "api_version",
+    "token",
+    "token_file",
+    "cloud_url",
+    "cloud_token",
+    "cloud_fallback_after",
+    "heartbeat_url",
+    "heartbeat_fail_url",
+    "heartbeat_fail_after",
+    "remote_write_url",
+    "remote_write_bearer_token",
+    "remote_write_wal",
+    "remote_write_wal_max_bytes",
+];
 
-        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
-            .await
-            .unwrap();
-        assert_eq!(body, "OK");
+/// Applies a changed configuration without restarting the HTTP server.
+///
+/// Devices, polling settings, sinks, `--label`s and the log level change at
+/// runtime; the listener, TLS, authentication and metric options need a restart.
+struct Reloader {
+    config: Config,
+    context: PollContext,
+    pollers: Pollers,
+    log_reload: reload::Handle<EnvFilter, Registry>,
+    // The configuration file as last applied, to log what changed
+    file: Map<String, Value>,
+}
+
+impl Reloader {
+    fn new(
+        config: Config,
+        context: PollContext,
+        pollers: Pollers,
+        log_reload: reload::Handle<EnvFilter, Registry>,
+    ) -> Self {
+        let file = read_config_file(&config).unwrap_or_default();
+        Self {
+            config,
+            context,
+            pollers,
+            log_reload,
+            file,
+        }
     }
 
-    #[tokio::test]
-    async fn test_root_handler() {
-        let app = create_test_app();
+    /// Reload when the configuration file was saved with different settings.
+    fn reload_if_changed(&mut self) {
+        let Some(path) = self.config.config.clone() else {
+            return;
+        };
+        match config::load_file(&path) {
+            Ok(file) if file == self.file => debug!("{} saved without changes", path.display()),
+            Ok(_) => {
+                info!("{} changed, reloading the configuration", path.display());
+                self.reload();
+            }
+            Err(e) => error!("Keeping the current configuration: {:#}", e),
+        }
+    }
 
-        let response = app
-            .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
-            .await
-            .unwrap();
+    /// The heartbeat and remote-write sender for `config`; unchanged ones are kept.
+    fn sinks(&self, config: &Config) -> Result<Sinks> {
+        let (old, new) = (&self.config, config);
+        let heartbeat = if (
+            &new.heartbeat_url,
+            &new.heartbeat_fail_url,
+            new.heartbeat_fail_after,
+            new.http_timeout,
+        ) == (
+            &old.heartbeat_url,
+            &old.heartbeat_fail_url,
+            old.heartbeat_fail_after,
+            old.http_timeout,
+        ) {
+            self.context.heartbeat.clone()
+        } else {
+            heartbeat(new)?
+        };
+        #[cfg(feature = "remote-write")]
+        let remote_write = if (
+            &new.remote_write_url,
+            &new.remote_write_bearer_token,
+            &new.remote_write_wal,
+            new.remote_write_wal_max_bytes,
+            new.http_timeout,
+        ) == (
+            &old.remote_write_url,
+            &old.remote_write_bearer_token,
+            &old.remote_write_wal,
+            old.remote_write_wal_max_bytes,
+            old.http_timeout,
+        ) {
+            self.context.remote_write.clone()
+        } else {
+            start_remote_write(new)?
+        };
+        Ok(Sinks {
+            heartbeat,
+            #[cfg(feature = "remote-write")]
+            remote_write,
+        })
+    }
 
-        assert_eq!(response.status(), StatusCode::OK);
+    fn reload(&mut self) {
+        let mut config = match Config::reload() {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Keeping the current configuration: {:#}", e);
+                return;
+            }
+        };
+        config.scrape_mode = self.config.scrape_mode;
 
-        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
-            .await
-            .unwrap();
-        let body_str = String::from_utf8(body.to_vec()).unwrap();
-        assert!(body_str.contains("HomeWizard Water Prometheus Exporter"));
-        assert!(body_str.contains("/metrics"));
-        assert!(body_str.contains("/health"));
+        let sinks = self.sinks(&config).and_then(|sinks| {
+            self.context
+                .metrics
+                .set_labels(config.labels.iter().cloned().collect())?;
+            Ok(sinks)
+        });
+        match sinks {
+            Ok(sinks) => {
+                self.context.heartbeat = sinks.heartbeat;
+                #[cfg(feature = "remote-write")]
+                {
+                    self.context.remote_write = sinks.remote_write;
+                }
+            }
+            Err(e) => {
+                error!("Keeping the current configuration: {:#}", e);
+                return;
+            }
+        }
+        if let Err(e) = self.log_reload.reload(log_filter(&config)) {
+            warn!("Failed to change the log level: {}", e);
+        }
+
+        let file = read_config_file(&config).unwrap_or_default();
+        for change in config::file_changes(&self.file, &file) {
+            if RELOADABLE.contains(&change.key.as_str()) {
+                info!("Changed {}", change);
+            } else {
+                warn!("Changed {}, which takes effect after a restart", change);
+            }
+        }
+        self.file = file;
+
+        let devices = config.devices();
+        let previous = self.config.devices();
+        for device in devices.iter().filter(|device| !previous.contains(device)) {
+            info!("Polling {} ({})", device.name(), device.address);
+        }
+        for device in previous.iter().filter(|device| !devices.contains(device)) {
+            info!("No longer polling {} ({})", device.name(), device.address);
+            if !devices.iter().any(|kept| kept.name() == device.name()) {
+                self.context.metrics.remove_device(device.name());
+                self.context.readings.remove_device(device.name());
+                if let Some(heartbeat) = &self.context.heartbeat {
+                    heartbeat.remove_device(device.name());
+                }
+            }
+        }
+        self.context.devices.set_devices(&devices);
+        self.context.publish();
+
+        // Restart polling, so every task picks up the new settings
+        self.pollers.tasks.abort_all();
+        self.pollers = Pollers::start(&config, &self.context, self.pollers.requests.take());
+        self.config = config;
+        info!("Configuration reloaded");
     }
+}
 
-    #[tokio::test]
-    async fn test_metrics_handler() {
-        let app = create_test_app();
-
-        let response = app
-            .oneshot(
-                Request::builder()
-                    .uri("/metrics")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
+fn read_config_file(config: &Config) -> Option<Map<String, Value>> {
+    config::load_file(config.config.as_deref()?).ok()
+}
 
-        assert_eq!(response.status(), StatusCode::OK);
+/// Watch the directory of the `--config` file for changes.
+///
+/// The directory is watched rather than the file, as editors and Kubernetes
+/// ConfigMaps replace the file instead of writing to it. Every change is passed
+/// on; the reloader compares the contents. Reads are left out, so loading the
+/// file does not trigger another check.
+fn watch_config(path: &Path) -> Result<(RecommendedWatcher, mpsc::UnboundedReceiver<()>)> {
+    let (changed, changes) = mpsc::unbounded_channel();
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) if event.kind.is_access() => {}
+            Ok(_) => {
+                let _ = changed.send(());
+            }
+            Err(e) => warn!("Error watching the configuration file: {}", e),
+        })?;
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    watcher.watch(directory, RecursiveMode::NonRecursive)?;
+    Ok((watcher, changes))
+}
 
-        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
-            .await
-            .unwrap();
-        let body_str = String::from_utf8(body.to_vec()).unwrap();
-        assert!(body_str.contains("test_metric"));
-        assert!(body_str.contains("42"));
+/// Reload the configuration on SIGHUP and when the `--config` file changes.
+async fn reload_config(mut reloader: Reloader) {
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .inspect_err(|e| warn!("Cannot reload the configuration on SIGHUP: {}", e))
+        .ok();
+
+    // The watcher stops when dropped, so it lives as long as this task
+    let mut watch = match &reloader.config.config {
+        Some(path) if reloader.config.watches_config() => watch_config(path)
+            .inspect_err(|e| {
+                warn!(
+                    "Cannot watch {} for changes, reload it with SIGHUP: {:#}",
+                    path.display(),
+                    e
+                )
+            })
+            .ok(),
+        _ => None,
+    };
+
+    loop {
+        #[cfg(unix)]
+        let received_hangup = async {
+            match &mut hangup {
+                Some(signal) => {
+                    signal.recv().await;
+                }
+                None => std::future::pending().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let received_hangup = std::future::pending::<()>();
+
+        let file_changed = async {
+            let Some((_, changes)) = &mut watch else {
+                return std::future::pending().await;
+            };
+            if changes.recv().await.is_none() {
+                return std::future::pending().await;
+            }
+            // A save is often several events (truncate, write, rename); take them as one
+            tokio::time::sleep(CONFIG_SETTLE_TIME).await;
+            while changes.try_recv().is_ok() {}
+        };
+
+        tokio::select! {
+            _ = received_hangup => {
+                info!("Received SIGHUP, reloading the configuration");
+                reloader.reload();
+            }
+            _ = file_changed => reloader.reload_if_changed(),
+        }
     }
+}
 
-    #[tokio::test]
-    async fn test_metrics_handler_with_empty_metrics() {
-        let shared_metrics: SharedMetrics = Arc::new(RwLock::new(String::new()));
-        let app = Router::new()
-            .route("/metrics", get(metrics_handler))
-            .with_state(shared_metrics);
-
-        let response = app
-            .oneshot(
-                Request::builder()
-                    .uri("/metrics")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
+/// Dead-man's-switch pings, if `--heartbeat-url` is set.
+fn heartbeat(config: &Config) -> Result<Option<Arc<Heartbeat>>> {
+    let Some(url) = &config.heartbeat_url else {
+        return Ok(None);
+    };
+    Ok(Some(Arc::new(
+        Heartbeat::new(
+            url.clone(),
+            config.heartbeat_fail_url.clone(),
+            config.http_timeout,
+        )?
+        .with_fail_after(config.heartbeat_fail_after),
+    )))
+}
 
-        assert_eq!(response.status(), StatusCode::OK);
+/// Start the remote-write sender, if `--remote-write-url` is set.
+#[cfg(feature = "remote-write")]
+fn start_remote_write(config: &Config) -> Result<Option<RemoteWrite>> {
+    let Some(url) = &config.remote_write_url else {
+        return Ok(None);
+    };
+    remote_write::validate_url(url)?;
+    info!("Pushing samples to {}", url);
+    let wal = config
+        .remote_write_wal
+        .clone()
+        .map(|path| Wal::new(path, config.remote_write_wal_max_bytes));
+    let writer = RemoteWriter::new(
+        url.clone(),
+        config.remote_write_bearer_token.clone(),
+        wal,
+        config.http_timeout,
+    )?;
+    Ok(Some(writer.start()))
+}
 
-        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
-            .await
-            .unwrap();
-        assert_eq!(body, "");
+/// Start the VictoriaMetrics importer, if `--vm-import-url` is set.
+fn start_vm_import(config: &Config) -> Result<Option<VmImport>> {
+    let Some(url) = &config.vm_import_url else {
+        return Ok(None);
+    };
+    let importer = VmImporter::new(url, config.vm_import_basic_auth(), config.http_timeout)?;
+    info!("Pushing metrics to VictoriaMetrics at {}", importer.url());
+    Ok(Some(importer.start()))
+}
+
+/// Start the InfluxDB writer, if `--influxdb-url` is set.
+#[cfg(feature = "influxdb")]
+fn start_influxdb(config: &Config) -> Result<Option<InfluxDb>> {
+    let (Some(url), Some(target)) = (&config.influxdb_url, config.influxdb_target()) else {
+        return Ok(None);
+    };
+    let writer = InfluxWriter::new(
+        url,
+        &target,
+        config.influxdb_token.clone(),
+        config.http_timeout,
+    )?;
+    info!("Writing readings to InfluxDB at {}", url);
+    let format = LineFormat {
+        measurement: config.influxdb_measurement.clone(),
+        tags: config
+            .labels
+            .iter()
+            .chain(&config.influxdb_tags)
+            .cloned()
+            .collect(),
+    };
+    Ok(Some(writer.start(format)))
+}
+
+/// Everything a polling task reports its results to.
+#[derive(Clone)]
+struct PollContext {
+    metrics: Arc<Metrics>,
+    /// Limits how many devices are polled at once
+    scheduler: Arc<PollScheduler>,
+    shared_metrics: SharedMetrics,
+    devices: Devices,
+    readings: Readings,
+    csv: Option<Arc<CsvArchive>>,
+    recorder: Option<Arc<Recorder>>,
+    replay: Option<Arc<Replay>>,
+    #[cfg(feature = "influxdb")]
+    influxdb: Option<InfluxDb>,
+    heartbeat: Option<Arc<Heartbeat>>,
+    #[cfg(feature = "remote-write")]
+    remote_write: Option<RemoteWrite>,
+    vm_import: Option<VmImport>,
+    federation: Option<Arc<Federation>>,
+    notifier: Notifier,
+    /// Requests to poll right away, from `POST /admin/poll`
+    poll_now: PollTrigger,
+    /// Requests to act on a device, from `POST /admin/identify` and `POST /admin/cloud`
+    actions: ActionTrigger,
+}
+
+impl PollContext {
+    /// Render the registry, merged with the federated exporters if any, for `/metrics`,
+    /// and push it to VictoriaMetrics.
+    fn publish(&self) {
+        match self.metrics.gather() {
+            Ok(metrics_text) => {
+                if let Some(vm_import) = &self.vm_import {
+                    vm_import.record(metrics_text.clone());
+                }
+                let metrics_text = match &self.federation {
+                    Some(federation) => federation.render(&metrics_text),
+                    None => metrics_text,
+                };
+                self.shared_metrics.store(Arc::new(metrics_text.into()));
+            }
+            Err(e) => {
+                error!("Failed to gather metrics: {}", e);
+            }
+        }
     }
+}
 
-    #[tokio::test]
-    async fn test_not_found_route() {
-        let app = create_test_app();
-
-        let response = app
-            .oneshot(
-                Request::builder()
-                    .uri("/nonexistent")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
+/// Client and bookkeeping of one device, polled by the interval loop or on demand.
+struct DevicePoller {
+    name: String,
+    host: String,
+    client: FallbackClient,
+    info_refreshed: tokio::sync::Mutex<Option<Instant>>,
+    last_success: std::sync::Mutex<Option<Instant>>,
+}
+
+impl DevicePoller {
+    async fn connect(config: &Config, device: Device, context: &PollContext) -> Option<Self> {
+        let name = device.name().to_string();
+        let host = device.address;
+        let mut client = match connect_client(config, &host).await {
+            Ok(client) => client.with_device_type(device.device_type),
+            Err(e) => {
+                error!("Failed to initialize HomeWizard client for {}: {}", host, e);
+                return None;
+            }
+        };
+        if let Some(recorder) = &context.recorder {
+            client = client.with_recorder(recorder.clone(), &name);
+        }
+        if let Some(replay) = &context.replay {
+            client = client.with_replay(replay.clone(), &name);
+        }
+        let cloud = match cloud_client(config, &host).await {
+            Ok(cloud) => cloud.map(|cloud| cloud.with_device_type(device.device_type)),
+            Err(e) => {
+                error!("Failed to initialize cloud client for {}: {}", host, e);
+                return None;
+            }
+        };
 
-        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        Some(Self {
+            name,
+            host,
+            client: FallbackClient::new(client, cloud, config.cloud_fallback_after),
+            info_refreshed: tokio::sync::Mutex::new(None),
+            last_success: std::sync::Mutex::new(None),
+        })
     }
 
-    #[tokio::test]
-    async fn test_metrics_handler_concurrent_access() {
-        let shared_metrics: SharedMetrics = Arc::new(RwLock::new(
-            "# HELP test_metric A test metric\n# TYPE test_metric counter\ntest_metric 42\n"
-                .to_string(),
-        ));
+    /// Carry out an admin action on the device and report the outcome back.
+    async fn act(&self, request: ActionRequest, context: &PollContext) {
+        let client = self.client.local();
+        let result = match request.action {
+            DeviceAction::Identify => client.identify().await,
+            DeviceAction::SetCloud(enabled) => client.set_cloud_enabled(enabled).await,
+        };
+        let what = match request.action {
+            DeviceAction::Identify => "identify",
+            DeviceAction::SetCloud(true) => "turn on its cloud connection",
+            DeviceAction::SetCloud(false) => "turn off its cloud connection",
+        };
+        match &result {
+            Ok(()) => {
+                info!("Asked {} to {}", self.name, what);
+                if let DeviceAction::SetCloud(enabled) = request.action {
+                    context.metrics.set_cloud_enabled(&self.name, enabled);
+                    context.publish();
+                }
+            }
+            Err(e) => warn!("Failed to ask {} to {}: {}", self.name, what, e),
+        }
+        let _ = request.done.send(result.map_err(|e| e.to_string())).await;
+    }
 
-        // Make multiple concurrent requests
-        let mut handles = Vec::new();
-        for _ in 0..10 {
-            let app = Router::new()
-                .route("/metrics", get(metrics_handler))
-                .with_state(shared_metrics.clone());
+    /// Poll the device once and publish the result; returns whether the device answered.
+    async fn poll(&self, config: &Config, context: &PollContext) -> bool {
+        let _slot = context.scheduler.slot().await;
+        let name = &self.name;
 
-            let handle = tokio::spawn(async move {
-                let response = app
-                    .oneshot(
-                        Request::builder()
-                            .uri("/metrics")
-                            .body(Body::empty())
-                            .unwrap(),
-                    )
-                    .await
-                    .unwrap();
+        // Firmware updates change the info, so refresh it now and then
+        let mut info_refreshed = self.info_refreshed.lock().await;
+        if info_refreshed.is_none_or(|at| at.elapsed() >= config.device_info_interval) {
+            match self.client.local().fetch_device_info().await {
+                Ok(info) => {
+                    context.metrics.set_device_info(name, &info);
+                    context.devices.set_product_type(name, info.product_type);
+                    *info_refreshed = Some(Instant::now());
+                }
+                Err(e) => warn!("Failed to fetch device info from {}: {}", self.host, e),
+            }
+            // Older firmware has no system endpoint, so this is not worth a warning
+            match self.client.local().fetch_system().await {
+                Ok(system) => context
+                    .metrics
+                    .set_cloud_enabled(name, system.cloud_enabled),
+                Err(e) => debug!("Failed to fetch system settings from {}: {}", self.host, e),
+            }
+        }
+        drop(info_refreshed);
+
+        let started = Instant::now();
+        let result = self.client.fetch_data().await;
+        let duration = started.elapsed();
+        context
+            .metrics
+            .record_fetch_attempts(name, self.client.local().last_attempts());
+        context.metrics.observe_poll_duration(name, duration);
 
-                assert_eq!(response.status(), StatusCode::OK);
-                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
-                    .await
-                    .unwrap();
-                String::from_utf8(body.to_vec()).unwrap()
-            });
-            handles.push(handle);
+        let fetched = result.is_ok();
+        let success = match result {
+            Ok((reading, source)) => {
+                info!(
+                    device = %name,
+                    source = source.as_str(),
+                    duration_ms = duration.as_millis() as u64,
+                    "Successfully fetched data from HomeWizard {} ({})",
+                    name,
+                    source.as_str()
+                );
+                *self.last_success.lock().unwrap() = Some(Instant::now());
+                context.devices.record_success(name);
+                context.metrics.record_poll_success(name);
+                context.metrics.set_source(name, source);
+                context.readings.record(name, &reading, source);
+                if let Some(csv) = &context.csv
+                    && let Err(e) = csv.append(name, &reading, source)
+                {
+                    warn!("Failed to write CSV file: {}", e);
+                }
+                #[cfg(feature = "influxdb")]
+                if let Some(influxdb) = &context.influxdb {
+                    influxdb.record(name, &reading);
+                }
+
+                if let Err(e) = context.metrics.update_reading(name, &reading) {
+                    error!("Failed to update metrics: {}", e);
+                    return true;
+                }
+
+                context.publish();
+
+                #[cfg(feature = "remote-write")]
+                if let Some(remote_write) = &context.remote_write {
+                    let batch =
+                        Batch::from_families(&context.metrics.families(), remote_write::now_ms());
+                    if remote_write.try_send(batch).is_err() {
+                        warn!("Remote-write queue is full, dropping samples");
+                    }
+                }
+                true
+            }
+            Err(e) => {
+                warn!(
+                    device = %name,
+                    reason = e.reason(),
+                    duration_ms = duration.as_millis() as u64,
+                    "Failed to fetch data from HomeWizard {}: {}",
+                    name,
+                    e
+                );
+                // Within --down-after the device still counts as healthy
+                let up = context.devices.record_failure(name, e.to_string()) == DeviceState::Up;
+                context.metrics.record_poll_failure(name, e.reason(), up);
+                if let Some(stale_after) = config.stale_after_duration()
+                    && let Some(at) = *self.last_success.lock().unwrap()
+                    && at.elapsed() >= stale_after
+                {
+                    context.metrics.mark_stale(name);
+                }
+                context.publish();
+                up
+            }
+        };
+
+        // Ping in the background so a slow monitoring service never delays polling
+        if let Some(heartbeat) = &context.heartbeat {
+            let heartbeat = heartbeat.clone();
+            let name = name.clone();
+            tokio::spawn(async move { heartbeat.report(&name, success).await });
+        }
+        fetched
+    }
+}
+
+/// Poll a single device forever, updating its series in the shared registry.
+async fn poll_device(config: Config, device: Device, context: PollContext) {
+    let mut poll_now = context.poll_now.subscribe();
+    let mut actions = context.actions.subscribe();
+    let Some(poller) = DevicePoller::connect(&config, device, &context).await else {
+        return;
+    };
+
+    // The first poll happens right away; later ones are scheduled from the previous
+    // target time rather than from when the poll finished, so slow polls do not drift
+    let mut next_poll = tokio::time::Instant::now();
+
+    let mut breaker = CircuitBreaker::new(config.breaker_threshold, config.breaker_probe_interval);
+
+    loop {
+        let request = tokio::select! {
+            () = tokio::time::sleep_until(next_poll) => None,
+            request = poll_now.recv() => match request {
+                Ok(request) if request.includes(&poller.name) => Some(request),
+                _ => continue,
+            },
+            request = actions.recv() => {
+                if let Ok(request) = request
+                    && request.device == poller.name
+                {
+                    poller.act(request, &context).await;
+                }
+                continue;
+            }
+        };
+        match &request {
+            // Requested polls leave the schedule alone, and bypass the breaker
+            // since they usually follow a fix
+            Some(_) => info!("Polling {} on request", poller.name),
+            None => {
+                // A poll that hangs stops the pings, and systemd restarts the service
+                context.notifier.watchdog();
+                next_poll += config.next_poll_delay();
+                if !breaker.allow(Instant::now()) {
+                    continue;
+                }
+            }
+        }
+
+        if poller.poll(&config, &context).await {
+            if breaker.is_open() {
+                info!(
+                    "{} is reachable again, resuming regular polling",
+                    poller.name
+                );
+            }
+            breaker.record_success();
+        } else {
+            let was_open = breaker.is_open();
+            breaker.record_failure(Instant::now());
+            if breaker.is_open() && !was_open {
+                warn!(
+                    "{} failed {} polls in a row, probing every {:?} until it answers",
+                    poller.name, config.breaker_threshold, config.breaker_probe_interval
+                );
+            }
         }
+        context
+            .metrics
+            .set_breaker_open(&poller.name, breaker.is_open());
+        context
+            .devices
+            .set_breaker_open(&poller.name, breaker.is_open());
+        // Tells `/admin/poll` this device is done
+        drop(request);
+    }
+}
 
-        // Wait for all requests to complete
-        for handle in handles {
-            let body = handle.await.unwrap();
-            assert!(body.contains("test_metric"));
+/// Poll every device when `/metrics` asks for it (`--scrape-mode on-demand`).
+///
+/// Scrapes that arrive while a poll is running share the next poll instead of
+/// starting one each.
+async fn poll_on_demand(config: Config, context: PollContext, requests: ScrapeRequests) {
+    let mut poll_now = context.poll_now.subscribe();
+    let mut actions = context.actions.subscribe();
+    let mut pollers = Vec::new();
+    for device in config.devices() {
+        if let Some(poller) = DevicePoller::connect(&config, device, &context).await {
+            pollers.push(Arc::new(poller));
         }
     }
+    let config = Arc::new(config);
+    // Held until a reload replaces this task
+    let mut requests = requests.lock().await;
+
+    loop {
+        let (waiting, request): (_, Option<PollRequest>) = tokio::select! {
+            first = requests.recv() => {
+                let Some(first) = first else { break };
+                let mut waiting = vec![first];
+                while let Ok(next) = requests.try_recv() {
+                    waiting.push(next);
+                }
+                (waiting, None)
+            }
+            request = poll_now.recv() => match request {
+                Ok(request) => (Vec::new(), Some(request)),
+                Err(_) => continue,
+            },
+            request = actions.recv() => {
+                if let Ok(request) = request
+                    && let Some(poller) = pollers.iter().find(|p| p.name == request.device)
+                {
+                    poller.act(request, &context).await;
+                }
+                continue;
+            }
+        };
+
+        let mut polls = JoinSet::new();
+        let selected = pollers
+            .iter()
+            .filter(|poller| request.as_ref().is_none_or(|r| r.includes(&poller.name)));
+        for poller in selected {
+            let (poller, config, context) = (poller.clone(), config.clone(), context.clone());
+            polls.spawn(async move { poller.poll(&config, &context).await });
+        }
+        polls.join_all().await;
+        context.notifier.watchdog();
+
+        for reply in waiting {
+            let _ = reply.send(());
+        }
+    }
+}
+
+/// Scrape the federated exporters on the poll interval and republish the merged output.
+async fn federate(config: Config, context: PollContext) {
+    let Some(federation) = context.federation.clone() else {
+        return;
+    };
+    let mut interval = interval(config.poll_interval);
+
+    loop {
+        interval.tick().await;
+        federation.scrape().await;
+        context.publish();
+    }
+}
+
+/// Open the `--record` file, if configured.
+fn open_recorder(config: &Config) -> Result<Option<Arc<Recorder>>> {
+    let Some(path) = &config.record else {
+        return Ok(None);
+    };
+    info!("Recording device responses to {}", path.display());
+    let recorder = Recorder::open(path)
+        .with_context(|| format!("Failed to open recording file {}", path.display()))?;
+    Ok(Some(Arc::new(recorder)))
+}
+
+/// Load the `--replay` file, if configured.
+fn open_replay(config: &Config) -> Result<Option<Arc<Replay>>> {
+    let Some(path) = &config.replay else {
+        return Ok(None);
+    };
+    let entries = recording::load(path)
+        .with_context(|| format!("Failed to read recording {}", path.display()))?;
+    info!(
+        "Replaying {} responses from {} at {}x speed",
+        entries.len(),
+        path.display(),
+        config.replay_speed
+    );
+    Ok(Some(Arc::new(Replay::new(entries, config.replay_speed)?)))
+}
+
+/// Build the HomeWizard client while resolving the device host in parallel.
+///
+/// Building the client loads the TLS root store, and resolving a `.local`
+/// hostname can take seconds; neither has to wait for the other. The resolved
+/// addresses end up in the shared cache the client resolves through.
+async fn connect_client(config: &Config, host: &str) -> Result<HomeWizardClient> {
+    let resolver = CachingResolver::new();
+    let timeout = config.http_timeout;
+    // Simulated and replayed devices are never contacted, and may not even exist
+    if config.simulate || config.replay.is_some() {
+        let url = config
+            .api_version
+            .fixed()
+            .unwrap_or_default()
+            .data_url(host);
+        let client =
+            tokio::task::spawn_blocking(move || HomeWizardClient::new(url, timeout)).await??;
+        if config.simulate {
+            return Ok(client.with_simulator(Simulator::new(host)));
+        }
+        return Ok(client);
+    }
+    let api_version = config.api_version_for(host).await;
+    info!("Polling {} with API {}", host, api_version.as_str());
+    let url = api_version.data_url(host);
+    let host = reqwest::Url::parse(&url)?
+        .host_str()
+        .unwrap_or_default()
+        .to_string();
+
+    // IP addresses never go through the resolver, so there is nothing to look up
+    let client = if host.trim_matches(['[', ']']).parse::<IpAddr>().is_ok() {
+        tokio::task::spawn_blocking(move || {
+            HomeWizardClient::with_api_version(url, timeout, CachingResolver::new(), api_version)
+        })
+        .await??
+    } else {
+        let build_resolver = resolver.clone();
+        let build = tokio::task::spawn_blocking(move || {
+            HomeWizardClient::with_api_version(url, timeout, build_resolver, api_version)
+        });
+        let (client, resolved) = tokio::join!(build, resolver.lookup(&host));
+
+        match resolved {
+            Ok(addrs) => info!("Resolved {} to {:?}", host, addrs),
+            Err(e) => warn!("Failed to resolve {}, retrying on first poll: {}", host, e),
+        }
+        client??
+    };
+
+    let client = client.with_retry(config.retry_policy());
+    Ok(match config.device_token()? {
+        Some(token) => client.with_token(token),
+        None => client,
+    })
+}
+
+async fn cloud_client(config: &Config, host: &str) -> Result<Option<HomeWizardClient>> {
+    let Some(url) = config.cloud_url_for(host) else {
+        return Ok(None);
+    };
+    let timeout = config.http_timeout;
+    let client = tokio::task::spawn_blocking(move || HomeWizardClient::new(url, timeout)).await??;
+
+    Ok(Some(match &config.cloud_token {
+        Some(token) => client.with_token(token.clone()),
+        None => client,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
 
     #[tokio::test]
-    async fn test_health_handler_method_not_allowed() {
-        let app = create_test_app();
-
-        let response = app
-            .oneshot(
-                Request::builder()
-                    .method("POST")
-                    .uri("/health")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
+    async fn test_connect_client() {
+        let config =
+            Config::try_parse_from(["homewizard-water-exporter", "--host", "127.0.0.1"]).unwrap();
 
-        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert!(connect_client(&config, &config.host[0]).await.is_ok());
     }
 
     #[tokio::test]
-    async fn test_metrics_update_during_request() {
-        let shared_metrics: SharedMetrics = Arc::new(RwLock::new("initial_metric 1\n".to_string()));
-
-        let app = Router::new()
-            .route("/metrics", get(metrics_handler))
-            .with_state(shared_metrics.clone());
-
-        // Get initial metrics
-        let response = app
-            .clone()
-            .oneshot(
-                Request::builder()
-                    .uri("/metrics")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
+    async fn test_watch_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "host = \"meter\"\n").unwrap();
+        let (_watcher, mut changes) = watch_config(&path).unwrap();
 
-        assert_eq!(response.status(), StatusCode::OK);
-        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
-            .await
-            .unwrap();
-        let body_str = String::from_utf8(body.to_vec()).unwrap();
-        assert!(body_str.contains("initial_metric 1"));
-
-        // Update metrics
-        {
-            let mut metrics_guard = shared_metrics.write().await;
-            *metrics_guard = "updated_metric 2\n".to_string();
-        }
-
-        // Get updated metrics
-        let response = app
-            .oneshot(
-                Request::builder()
-                    .uri("/metrics")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
+        // Reading the file is not a change
+        config::load_file(&path).unwrap();
+        let idle = tokio::time::timeout(Duration::from_millis(200), changes.recv()).await;
+        assert!(idle.is_err());
 
-        assert_eq!(response.status(), StatusCode::OK);
-        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        // Replaced the way editors save, by renaming a new file over it
+        let saved = dir.path().join("config.toml.tmp");
+        std::fs::write(&saved, "host = \"garden\"\n").unwrap();
+        std::fs::rename(&saved, &path).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), changes.recv())
             .await
+            .unwrap()
             .unwrap();
-        let body_str = String::from_utf8(body.to_vec()).unwrap();
-        assert!(body_str.contains("updated_metric 2"));
     }
 
-    #[test]
-    fn test_shared_metrics_type_alias() {
-        let shared_metrics: SharedMetrics = Arc::new(RwLock::new("test".to_string()));
+    #[tokio::test]
+    async fn test_connect_client_with_hostname() {
+        let config =
+            Config::try_parse_from(["homewizard-water-exporter", "--host", "localhost"]).unwrap();
 
-        // Test that the type alias works correctly
-        let runtime = tokio::runtime::Runtime::new().unwrap();
-        runtime.block_on(async {
-            let guard = shared_metrics.read().await;
-            assert_eq!(*guard, "test");
-        });
+        assert!(connect_client(&config, &config.host[0]).await.is_ok());
     }
 }
@@ -1,110 +1,1409 @@
-use crate::homewizard::HomeWizardWaterData;
-use anyhow::Result;
-use prometheus::{Counter, Encoder, Gauge, GaugeVec, Opts, Registry, TextEncoder};
+use crate::averages::{FlowAverages, window_label};
+use crate::build_info::{BUILD_INFO, BuildInfo};
+use crate::cloud::DataSource;
+use crate::collector::{ExtraMetric, ReadingCollector};
+use crate::events::{EventKind, Events};
+use crate::homewizard::{
+    HomeWizardDeviceInfo, HomeWizardEnergySocketData, HomeWizardKwhData, HomeWizardP1Data,
+    HomeWizardWaterData, Reading,
+};
+use crate::leak::LeakDetector;
+use crate::state::{BillingCycle, CounterSample, DailyBaseline, DailyPeak, NightUsage, Snapshot};
+use anyhow::{Context, Result, bail};
+use chrono::{Datelike, Days, Local, Months, NaiveDate, NaiveDateTime, NaiveTime};
+use prometheus::core::Collector;
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
+use prometheus::{
+    CounterVec, Encoder, Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec, Opts, Registry,
+    TextEncoder,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+/// Default `--flow-buckets`, in liters per minute: a dripping tap up to a garden hose.
+pub const DEFAULT_FLOW_BUCKETS: [f64; 12] = [
+    0.5, 1.0, 2.0, 4.0, 6.0, 8.0, 10.0, 12.0, 15.0, 20.0, 25.0, 30.0,
+];
+
+/// Default `--flow-average-windows`.
+pub const DEFAULT_FLOW_AVERAGE_WINDOWS: [Duration; 3] = [
+    Duration::from_secs(60),
+    Duration::from_secs(300),
+    Duration::from_secs(900),
+];
+
+/// Water tariff for the cost estimate (`--price-per-m3`, `--standing-charge`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pricing {
+    pub per_m3: f64,
+    /// Fixed charge per day, regardless of consumption
+    pub standing_charge: f64,
+}
+
+/// Label names of the exported series, which `--label` may not reuse.
+const LABEL_NAMES: [&str; 19] = [
+    "device",
+    "channel",
+    "event",
+    "phase",
+    "reason",
+    "source",
+    "window",
+    "wifi_ssid",
+    "product_type",
+    "product_name",
+    "serial",
+    "firmware_version",
+    "api_version",
+    "le",
+    "quantile",
+    "version",
+    "git_sha",
+    "build_date",
+    "rustc",
+];
+
+/// US gallons in a m³.
+pub(crate) const GALLONS_PER_M3: f64 = 264.172_052;
+
+/// Units of the exported water series.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Units {
+    /// m³ and liters per minute
+    #[default]
+    Metric,
+    /// Also export the totals in US gallons and the flow in gallons per minute
+    Us,
+}
+
+/// Which metric families are exported (`--metrics-include`, `--metrics-exclude`).
+///
+/// Patterns match whole family names, with `*` matching any run of characters.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetricFilter {
+    /// When not empty, only families matching one of these are exported
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl MetricFilter {
+    pub fn allows(&self, family: &str) -> bool {
+        let matches =
+            |patterns: &[String]| patterns.iter().any(|pattern| glob_match(pattern, family));
+        (self.include.is_empty() || matches(&self.include)) && !matches(&self.exclude)
+    }
+}
+
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // No `*`: the whole name must match
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Monthly water budget (`--monthly-budget-m3`, `--billing-cycle-start-day`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Budget {
+    pub monthly_m3: f64,
+    /// Day of the month the billing cycle starts on (1-28)
+    pub cycle_start_day: u32,
+}
+
+impl Budget {
+    /// First day of the billing cycle that `today` falls in.
+    pub fn cycle_start(&self, today: NaiveDate) -> NaiveDate {
+        let start = today.with_day(self.cycle_start_day).unwrap_or(today);
+        if start <= today {
+            start
+        } else {
+            start - Months::new(1)
+        }
+    }
+}
+
+/// Daily window of expected low usage (`--quiet-hours 01:00-05:00`), in local time.
+/// The end is exclusive; a window may wrap past midnight (`23:00-05:00`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuietHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl QuietHours {
+    /// Date the quiet hours containing `now` started on, if `now` is within them.
+    pub fn night_of(&self, now: NaiveDateTime) -> Option<NaiveDate> {
+        let time = now.time();
+        let date = now.date();
+        if self.start < self.end {
+            (self.start <= time && time < self.end).then_some(date)
+        } else if time >= self.start {
+            Some(date)
+        } else if time < self.end {
+            date.checked_sub_days(Days::new(1))
+        } else {
+            None
+        }
+    }
+}
+
+impl FromStr for QuietHours {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |time: &str| {
+            NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                .map_err(|_| format!("invalid time '{time}', expected HH:MM"))
+        };
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("invalid quiet hours '{s}', expected HH:MM-HH:MM"))?;
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start == end {
+            return Err(format!("quiet hours '{s}' are empty"));
+        }
+        Ok(Self { start, end })
+    }
+}
+
+/// Label values of the `homewizard_water_device_info` series of a device.
+#[derive(Debug, Clone, PartialEq)]
+struct DeviceInfoLabels {
+    product_type: String,
+    product_name: String,
+    serial: String,
+    firmware_version: String,
+    api_version: String,
+}
+
+impl DeviceInfoLabels {
+    /// Values in the order of the info metric's labels, after `device`.
+    fn label_values<'a>(&'a self, device: &'a str) -> [&'a str; 6] {
+        [
+            device,
+            &self.product_type,
+            &self.product_name,
+            &self.serial,
+            &self.firmware_version,
+            &self.api_version,
+        ]
+    }
+}
+
+/// Prometheus metrics for all polled devices.
+///
+/// Every device shares the same registry and metric families; series are told
+/// apart by the `device` label, so gathering costs one pass over the families
+/// regardless of how many meters are configured.
 pub struct Metrics {
+    // The series read straight off the devices, built from their latest readings at gather time
+    readings: ReadingCollector,
+
     // Water consumption metrics
-    total_water: Counter,
-    active_flow: Gauge,
-    water_offset: Gauge,
+    // Exported next to `usage_today` with `--units us`
+    usage_today_gallons: GaugeVec,
+    units: Units,
+    filter: MetricFilter,
+    flow_histogram: HistogramVec,
+    flow_average: GaugeVec,
+    flow_averages: Mutex<FlowAverages>,
+    usage_events: CounterVec,
+    peak_flow: GaugeVec,
+    peak_flows: Mutex<HashMap<String, DailyPeak>>,
+    // Whether water was flowing at the last reading per device
+    flowing: Mutex<HashMap<String, bool>>,
+    usage_today: GaugeVec,
+    meter_resets: CounterVec,
+    cumulative_water: CounterVec,
+    // Export `cumulative_water` (--cumulative-total)
+    cumulative_total: bool,
+    // Last meter total per device, to notice it going backwards
+    water_totals: Mutex<HashMap<String, f64>>,
+    cost_total: CounterVec,
+    cost_today: GaugeVec,
+    pricing: Option<Pricing>,
+    budget_used: GaugeVec,
+    budget_remaining: GaugeVec,
+    budget_used_percent: GaugeVec,
+    budget: Option<Budget>,
+    billing_cycles: Mutex<HashMap<String, BillingCycle>>,
+    night_usage: GaugeVec,
+    quiet_hours: Option<QuietHours>,
+    night_usages: Mutex<HashMap<String, NightUsage>>,
+    leak_suspected: GaugeVec,
+    leak_duration: GaugeVec,
+    leaks: Mutex<LeakDetector>,
+    // Total at the start of the current day per device, persisted across restarts
+    daily_baselines: Mutex<HashMap<String, DailyBaseline>>,
 
-    // Network metrics
-    wifi_strength: Gauge,
+    // Exporter self-metrics
+    up: GaugeVec,
+    scrapes_total: CounterVec,
+    scrape_errors_total: CounterVec,
+    poll_duration: HistogramVec,
+    // Scheduler of the polls (`--max-concurrent-polls`)
+    polls_waiting: Gauge,
+    polls_in_flight: Gauge,
+    poll_queue_wait: Histogram,
+    poll_concurrency_limit: Gauge,
+    fetch_attempts_total: CounterVec,
+    breaker_open: GaugeVec,
+    last_successful_poll: GaugeVec,
+    stale: GaugeVec,
+    notification_failures: CounterVec,
+    // Where device events such as leaks and meter resets go
+    events: Events,
 
     // Info metric
-    meter_info: GaugeVec,
+    data_source: GaugeVec,
+    device_info: GaugeVec,
+    cloud_enabled: GaugeVec,
+    // Label values of the current info series per device, replaced when they change
+    device_infos: Mutex<HashMap<String, DeviceInfoLabels>>,
 
     registry: Registry,
+    // `--label`s, added to every series at gather time so a reload can change them
+    labels: RwLock<BTreeMap<String, String>>,
 }
 
 impl Metrics {
     pub fn new() -> Result<Self> {
+        Self::with_labels(HashMap::new())
+    }
+
+    /// Metrics whose every series carries these constant labels (`--label`).
+    pub fn with_labels(labels: HashMap<String, String>) -> Result<Self> {
         let registry = Registry::new();
 
+        let readings = ReadingCollector::new()?;
+        registry.register(Box::new(readings.clone()))?;
+
         // Water consumption metrics
-        let total_water = Counter::with_opts(Opts::new(
-            "homewizard_water_total_m3",
-            "Total water consumption in m³",
-        ))?;
-        registry.register(Box::new(total_water.clone()))?;
-
-        let active_flow = Gauge::with_opts(Opts::new(
-            "homewizard_water_active_flow_lpm",
-            "Current water flow in liters per minute",
-        ))?;
-        registry.register(Box::new(active_flow.clone()))?;
-
-        let water_offset = Gauge::with_opts(Opts::new(
-            "homewizard_water_offset_m3",
-            "Water meter offset in m³",
-        ))?;
-        registry.register(Box::new(water_offset.clone()))?;
-
-        // Network metrics
-        let wifi_strength = Gauge::with_opts(Opts::new(
-            "homewizard_water_wifi_strength_percent",
-            "WiFi signal strength percentage",
-        ))?;
-        registry.register(Box::new(wifi_strength.clone()))?;
-
-        // Info metric
-        let meter_info = GaugeVec::new(
-            Opts::new("homewizard_water_meter_info", "Water meter information"),
-            &["wifi_ssid"],
-        )?;
-        registry.register(Box::new(meter_info.clone()))?;
-
-        Ok(Self {
-            total_water,
-            active_flow,
-            water_offset,
-            wifi_strength,
-            meter_info,
+        let usage_today_gallons = GaugeVec::new(
+            Opts::new(
+                "homewizard_water_usage_today_gallons",
+                "Water consumed since local midnight in US gallons",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(usage_today_gallons.clone()))?;
+
+        let flow_histogram = flow_histogram(&DEFAULT_FLOW_BUCKETS)?;
+        registry.register(Box::new(flow_histogram.clone()))?;
+
+        let flow_average = GaugeVec::new(
+            Opts::new(
+                "homewizard_water_flow_avg_lpm",
+                "Average flow over the window in liters per minute",
+            ),
+            &["device", "window"],
+        )?;
+        registry.register(Box::new(flow_average.clone()))?;
+
+        let peak_flow = GaugeVec::new(
+            Opts::new(
+                "homewizard_water_peak_flow_lpm",
+                "Highest flow seen since local midnight in liters per minute",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(peak_flow.clone()))?;
+
+        let usage_events = CounterVec::new(
+            Opts::new(
+                "homewizard_water_usage_events_total",
+                "Number of times the flow went from zero to nonzero",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(usage_events.clone()))?;
+
+        let usage_today = GaugeVec::new(
+            Opts::new(
+                "homewizard_water_usage_today_m3",
+                "Water consumed since local midnight in m³",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(usage_today.clone()))?;
+
+        let meter_resets = CounterVec::new(
+            Opts::new(
+                "homewizard_water_meter_resets_total",
+                "Number of times the meter total went backwards (meter reset or replaced)",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(meter_resets.clone()))?;
+
+        let cost_total = CounterVec::new(
+            Opts::new(
+                "homewizard_water_cost_estimate_total",
+                "Estimated water cost, consumption plus standing charge, since tracking began",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(cost_total.clone()))?;
+
+        let cost_today = GaugeVec::new(
+            Opts::new(
+                "homewizard_water_cost_today",
+                "Estimated water cost since local midnight, including the standing charge",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(cost_today.clone()))?;
+
+        let budget_used = GaugeVec::new(
+            Opts::new(
+                "homewizard_water_budget_used_m3",
+                "Water used in the current billing cycle in m³",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(budget_used.clone()))?;
+
+        let budget_remaining = GaugeVec::new(
+            Opts::new(
+                "homewizard_water_budget_remaining_m3",
+                "Water left in the monthly budget in m³ (negative when over budget)",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(budget_remaining.clone()))?;
+
+        let budget_used_percent = GaugeVec::new(
+            Opts::new(
+                "homewizard_water_budget_used_percent",
+                "Percentage of the monthly budget used in the current billing cycle",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(budget_used_percent.clone()))?;
+
+        let night_usage = GaugeVec::new(
+            Opts::new(
+                "homewizard_water_night_usage_m3",
+                "Water used during the latest quiet hours (--quiet-hours) in m³",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(night_usage.clone()))?;
+
+        let leak_suspected = GaugeVec::new(
+            Opts::new(
+                "homewizard_water_leak_suspected",
+                "Whether water has been flowing without a break for longer than --leak-after",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(leak_suspected.clone()))?;
+
+        let leak_duration = GaugeVec::new(
+            Opts::new(
+                "homewizard_water_leak_duration_seconds",
+                "Seconds water has been flowing without a break",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(leak_duration.clone()))?;
+
+        let cumulative_water = CounterVec::new(
+            Opts::new(
+                "homewizard_water_cumulative_total_m3",
+                "Total water consumption in m³, continued across meter resets",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(cumulative_water.clone()))?;
+
+        // Exporter self-metrics
+        let up = GaugeVec::new(
+            Opts::new(
+                "homewizard_up",
+                "Whether the last poll of the device succeeded (1) or failed (0)",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(up.clone()))?;
+
+        let scrapes_total = CounterVec::new(
+            Opts::new(
+                "homewizard_exporter_scrapes_total",
+                "Total number of device polls",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(scrapes_total.clone()))?;
+
+        let scrape_errors_total = CounterVec::new(
+            Opts::new(
+                "homewizard_exporter_scrape_errors_total",
+                "Total number of failed device polls by reason",
+            ),
+            &["device", "reason"],
+        )?;
+        registry.register(Box::new(scrape_errors_total.clone()))?;
+
+        // Local API requests take milliseconds; the upper buckets catch timeouts
+        let poll_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "homewizard_exporter_poll_duration_seconds",
+                "Duration of device polls in seconds, successful or not",
+            )
+            .buckets(vec![0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]),
+            &["device"],
+        )?;
+        registry.register(Box::new(poll_duration.clone()))?;
+
+        let polls_waiting = Gauge::new(
+            "homewizard_exporter_polls_waiting",
+            "Device polls waiting for a free slot (see --max-concurrent-polls)",
+        )?;
+        registry.register(Box::new(polls_waiting.clone()))?;
+
+        let polls_in_flight = Gauge::new(
+            "homewizard_exporter_polls_in_flight",
+            "Device polls running right now",
+        )?;
+        registry.register(Box::new(polls_in_flight.clone()))?;
+
+        // Polls only wait when more devices are due than there are slots
+        let poll_queue_wait = Histogram::with_opts(
+            HistogramOpts::new(
+                "homewizard_exporter_poll_queue_wait_seconds",
+                "Time device polls waited for a free slot in seconds",
+            )
+            .buckets(vec![
+                0.001, 0.01, 0.1, 0.25, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+            ]),
+        )?;
+        registry.register(Box::new(poll_queue_wait.clone()))?;
+
+        let poll_concurrency_limit = Gauge::new(
+            "homewizard_exporter_poll_concurrency_limit",
+            "Most device polls that run at once (--max-concurrent-polls)",
+        )?;
+        registry.register(Box::new(poll_concurrency_limit.clone()))?;
+
+        let fetch_attempts_total = CounterVec::new(
+            Opts::new(
+                "homewizard_exporter_fetch_attempts_total",
+                "Total number of device requests, including retries",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(fetch_attempts_total.clone()))?;
+
+        let stale = GaugeVec::new(
+            Opts::new(
+                "homewizard_exporter_stale",
+                "Whether the readings of the device were dropped for being older than --stale-after",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(stale.clone()))?;
+
+        let breaker_open = GaugeVec::new(
+            Opts::new(
+                "homewizard_exporter_circuit_breaker_open",
+                "Whether polling of the device is backed off after repeated failures",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(breaker_open.clone()))?;
+
+        let last_successful_poll = GaugeVec::new(
+            Opts::new(
+                "homewizard_exporter_last_successful_poll_timestamp_seconds",
+                "Unix time of the last successful poll of the device",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(last_successful_poll.clone()))?;
+
+        let notification_failures = CounterVec::new(
+            Opts::new(
+                "homewizard_exporter_notification_failures_total",
+                "Events that could not be delivered to a webhook or notifier after retrying",
+            ),
+            &["channel", "event"],
+        )?;
+        registry.register(Box::new(notification_failures.clone()))?;
+
+        // A separate series instead of a label on every metric, so switching between
+        // the local API and the cloud does not break the other series
+        let data_source = GaugeVec::new(
+            Opts::new(
+                "homewizard_water_data_source",
+                "Source of the latest reading (local API or cloud fallback)",
+            ),
+            &["device", "source"],
+        )?;
+        registry.register(Box::new(data_source.clone()))?;
+
+        let device_info = GaugeVec::new(
+            Opts::new(
+                "homewizard_water_device_info",
+                "Device identification from the /api endpoint",
+            ),
+            &[
+                "device",
+                "product_type",
+                "product_name",
+                "serial",
+                "firmware_version",
+                "api_version",
+            ],
+        )?;
+        registry.register(Box::new(device_info.clone()))?;
+
+        let cloud_enabled = GaugeVec::new(
+            Opts::new(
+                "homewizard_water_cloud_enabled",
+                "Whether the device communicates with the HomeWizard cloud",
+            ),
+            &["device"],
+        )?;
+        registry.register(Box::new(cloud_enabled.clone()))?;
+
+        let build_info = GaugeVec::new(
+            Opts::new(
+                "homewizard_exporter_build_info",
+                "Version, git revision, build date and compiler of the running exporter",
+            ),
+            &BuildInfo::LABELS,
+        )?;
+        build_info
+            .with_label_values(&BUILD_INFO.label_values())
+            .set(1.0);
+        registry.register(Box::new(build_info))?;
+
+        let metrics = Self {
+            readings,
+            usage_today_gallons,
+            units: Units::Metric,
+            filter: MetricFilter::default(),
+            flow_histogram,
+            flow_average,
+            flow_averages: Mutex::new(FlowAverages::new(DEFAULT_FLOW_AVERAGE_WINDOWS.to_vec())),
+            usage_events,
+            peak_flow,
+            peak_flows: Mutex::new(HashMap::new()),
+            flowing: Mutex::new(HashMap::new()),
+            usage_today,
+            meter_resets,
+            cumulative_water,
+            cumulative_total: false,
+            water_totals: Mutex::new(HashMap::new()),
+            cost_total,
+            cost_today,
+            pricing: None,
+            budget_used,
+            budget_remaining,
+            budget_used_percent,
+            budget: None,
+            billing_cycles: Mutex::new(HashMap::new()),
+            night_usage,
+            quiet_hours: None,
+            night_usages: Mutex::new(HashMap::new()),
+            leak_suspected,
+            leak_duration,
+            leaks: Mutex::new(LeakDetector::new(Duration::ZERO)),
+            daily_baselines: Mutex::new(HashMap::new()),
+            up,
+            scrapes_total,
+            scrape_errors_total,
+            poll_duration,
+            polls_waiting,
+            polls_in_flight,
+            poll_queue_wait,
+            poll_concurrency_limit,
+            fetch_attempts_total,
+            breaker_open,
+            stale,
+            last_successful_poll,
+            notification_failures,
+            events: Events::default(),
+            data_source,
+            device_info,
+            cloud_enabled,
+            device_infos: Mutex::new(HashMap::new()),
             registry,
-        })
+            labels: RwLock::new(BTreeMap::new()),
+        };
+        metrics.set_labels(labels)?;
+        Ok(metrics)
+    }
+
+    /// Replace the constant labels of every series, as on a configuration reload.
+    pub fn set_labels(&self, labels: HashMap<String, String>) -> Result<()> {
+        if let Some(name) = labels
+            .keys()
+            .find(|name| LABEL_NAMES.contains(&name.as_str()))
+        {
+            bail!("Label `{name}` is already used by the exporter");
+        }
+        *self.labels.write().unwrap() = labels.into_iter().collect();
+        Ok(())
+    }
+
+    /// Also export `homewizard_water_cumulative_total_m3` (`--cumulative-total`).
+    pub fn with_cumulative_total(mut self, enabled: bool) -> Self {
+        self.cumulative_total = enabled;
+        self
     }
 
-    pub fn update(&self, data: &HomeWizardWaterData) -> Result<()> {
-        // Update water metrics
-        self.total_water.reset();
-        self.total_water.inc_by(data.total_liter_m3);
+    /// Emit leak and meter reset events to `events`.
+    pub fn with_events(mut self, events: Events) -> Self {
+        self.events = events;
+        self
+    }
 
-        self.active_flow.set(data.active_liter_lpm);
-        self.water_offset.set(data.total_liter_offset_m3);
+    /// Suspect a leak after water flowed without a break for `leak_after` (`--leak-after`).
+    pub fn with_leak_after(self, leak_after: Duration) -> Self {
+        *self.leaks.lock().unwrap() = LeakDetector::new(leak_after);
+        self
+    }
 
-        // Update network metrics
-        self.wifi_strength.set(data.wifi_strength);
+    /// Replace the buckets of the flow histogram (`--flow-buckets`).
+    pub fn with_flow_buckets(mut self, buckets: &[f64]) -> Result<Self> {
+        let histogram = flow_histogram(buckets)?;
+        self.registry
+            .unregister(Box::new(self.flow_histogram.clone()))?;
+        self.registry.register(Box::new(histogram.clone()))?;
+        self.flow_histogram = histogram;
+        Ok(self)
+    }
 
-        // Update info metric
-        self.meter_info.reset();
-        self.meter_info
-            .with_label_values(&[&data.wifi_ssid])
-            .set(1.0);
+    /// Also export these fields the exporter does not know (`--extra-metric`).
+    pub fn with_extra_metrics(self, metrics: &[ExtraMetric]) -> Result<Self> {
+        if !metrics.is_empty() {
+            let collector = self.readings.extra_collector(metrics.to_vec())?;
+            self.registry
+                .register(Box::new(collector))
+                .context("an --extra-metric has the name of another metric")?;
+        }
+        Ok(self)
+    }
+
+    /// Replace the windows of the rolling flow averages (`--flow-average-windows`).
+    pub fn with_flow_average_windows(self, windows: Vec<Duration>) -> Self {
+        *self.flow_averages.lock().unwrap() = FlowAverages::new(windows);
+        self
+    }
+
+    pub fn with_filter(mut self, filter: MetricFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Export the offset-corrected total, with `calibration_m3` added (`--net-total`).
+    pub fn with_net_total(self, calibration_m3: Option<f64>) -> Self {
+        self.readings
+            .set_options(|options| options.net_total_calibration = calibration_m3);
+        self
+    }
+
+    /// Also export sums over all water meters (`--aggregate`).
+    pub fn with_aggregate(self, enabled: bool) -> Self {
+        self.readings
+            .set_options(|options| options.aggregate = enabled);
+        self
+    }
+
+    pub fn with_wifi_metrics(self, enabled: bool) -> Self {
+        self.readings
+            .set_options(|options| options.wifi_metrics = enabled);
+        self
+    }
+
+    pub fn with_units(mut self, units: Units) -> Self {
+        self.units = units;
+        self.readings.set_options(|options| options.units = units);
+        self
+    }
+
+    /// Track consumption against a monthly budget.
+    pub fn with_budget(mut self, budget: Option<Budget>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Add up the water used during these hours every night.
+    pub fn with_quiet_hours(mut self, quiet_hours: Option<QuietHours>) -> Self {
+        self.quiet_hours = quiet_hours;
+        self
+    }
+
+    /// Export cost estimates at this tariff.
+    pub fn with_pricing(mut self, pricing: Option<Pricing>) -> Self {
+        self.pricing = pricing;
+        self
+    }
+
+    pub fn update(&self, device: &str, data: &HomeWizardWaterData) -> Result<()> {
+        self.update_reading(device, &Reading::Water(data.clone()))
+    }
+
+    /// Everything computed from a water reading over time; the reading itself is
+    /// exported by the [`ReadingCollector`].
+    fn update_water(&self, device: &str, data: &HomeWizardWaterData) {
+        let now = Instant::now();
+        let local_now = Local::now().naive_local();
+        let used = self.update_totals(device, data.total_liter_m3, local_now.date());
+        self.update_night_usage(device, used.unwrap_or_default(), local_now);
+
+        match data.active_liter_lpm {
+            Some(flow) => {
+                // Only running water, so the quantiles are not drowned out by idle polls
+                if flow > 0.0 {
+                    self.flow_histogram
+                        .with_label_values(&[device])
+                        .observe(flow);
+                }
+                self.count_usage_event(device, flow);
+                self.update_peak_flow(device, flow, local_now.date());
+                self.update_flow_averages(device, flow, now);
+                self.update_leak(device, flow, now);
+            }
+            None => {
+                self.leaks.lock().unwrap().forget(device);
+                self.flowing.lock().unwrap().remove(device);
+                let _ = self.peak_flow.remove_label_values(&[device]);
+                self.remove_flow_averages(device);
+                let _ = self.leak_suspected.remove_label_values(&[device]);
+                let _ = self.leak_duration.remove_label_values(&[device]);
+            }
+        }
+    }
+
+    /// Everything derived from the meter total: resets, daily usage, cost and
+    /// budget. Returns the water used since the previous reading, if there was one.
+    fn update_totals(&self, device: &str, total_m3: f64, today: NaiveDate) -> Option<f64> {
+        let used = self.track_meter_resets(device, total_m3);
+        let (usage_today, new_day) = self.update_daily_usage(device, total_m3, today);
+
+        if let Some(pricing) = self.pricing {
+            let cost = self.cost_total.with_label_values(&[device]);
+            cost.inc_by(used.unwrap_or_default() * pricing.per_m3);
+            if new_day {
+                cost.inc_by(pricing.standing_charge);
+            }
+            self.cost_today
+                .with_label_values(&[device])
+                .set(usage_today * pricing.per_m3 + pricing.standing_charge);
+        }
+        if let Some(budget) = self.budget {
+            self.update_budget(device, used.unwrap_or_default(), today, budget);
+        }
+        used
+    }
+
+    /// Add the water used since the previous reading to tonight's quiet hours
+    /// usage when the reading falls within them. Outside them the last night's
+    /// total stays, so it can be alerted on during the day.
+    fn update_night_usage(&self, device: &str, used_m3: f64, now: NaiveDateTime) {
+        let Some(quiet_hours) = self.quiet_hours else {
+            return;
+        };
+        let mut usages = self.night_usages.lock().unwrap();
+        if let Some(night) = quiet_hours.night_of(now) {
+            let usage = usages.entry(device.to_string()).or_insert(NightUsage {
+                night,
+                used_m3: 0.0,
+            });
+            if usage.night != night {
+                *usage = NightUsage {
+                    night,
+                    used_m3: 0.0,
+                };
+            }
+            usage.used_m3 += used_m3;
+        }
+        if let Some(usage) = usages.get(device) {
+            self.night_usage
+                .with_label_values(&[device])
+                .set(usage.used_m3);
+        }
+    }
+
+    /// Add up the water used in the billing cycle. Counting the increments rather
+    /// than comparing with the total at the start keeps a meter reset from
+    /// wiping out the cycle so far.
+    fn update_budget(&self, device: &str, used_m3: f64, today: NaiveDate, budget: Budget) {
+        let start = budget.cycle_start(today);
+        let mut cycles = self.billing_cycles.lock().unwrap();
+        let cycle = cycles.entry(device.to_string()).or_insert(BillingCycle {
+            start,
+            used_m3: 0.0,
+        });
+        if cycle.start == start {
+            cycle.used_m3 += used_m3;
+        } else {
+            *cycle = BillingCycle {
+                start,
+                used_m3: 0.0,
+            };
+        }
+
+        self.budget_used
+            .with_label_values(&[device])
+            .set(cycle.used_m3);
+        self.budget_remaining
+            .with_label_values(&[device])
+            .set(budget.monthly_m3 - cycle.used_m3);
+        self.budget_used_percent
+            .with_label_values(&[device])
+            .set(cycle.used_m3 / budget.monthly_m3 * 100.0);
+    }
+
+    /// Count the meter total going backwards, and add the water used since the
+    /// last reading to the cumulative total. After a reset the meter counts up
+    /// from zero, so everything it reports was used since.
+    ///
+    /// Returns the water used since the previous reading, if there was one.
+    fn track_meter_resets(&self, device: &str, total: f64) -> Option<f64> {
+        let previous = self
+            .water_totals
+            .lock()
+            .unwrap()
+            .insert(device.to_string(), total);
+        let resets = self.meter_resets.with_label_values(&[device]);
+        let used = match previous {
+            Some(previous) if total < previous => {
+                resets.inc();
+                self.events.emit(
+                    device,
+                    EventKind::CounterReset {
+                        previous_m3: previous,
+                        total_m3: total,
+                    },
+                );
+                Some(total)
+            }
+            Some(previous) => Some(total - previous),
+            None => None,
+        };
+        if self.cumulative_total {
+            self.cumulative_water
+                .with_label_values(&[device])
+                .inc_by(used.unwrap_or(total));
+        }
+        used
+    }
+
+    /// Count a successful poll.
+    pub fn record_poll_success(&self, device: &str) {
+        self.scrapes_total.with_label_values(&[device]).inc();
+        self.up.with_label_values(&[device]).set(1.0);
+        self.stale.with_label_values(&[device]).set(0.0);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.last_successful_poll
+            .with_label_values(&[device])
+            .set(now.as_secs_f64());
+    }
+
+    pub fn record_fetch_attempts(&self, device: &str, attempts: u32) {
+        self.fetch_attempts_total
+            .with_label_values(&[device])
+            .inc_by(f64::from(attempts));
+    }
+
+    pub fn set_breaker_open(&self, device: &str, open: bool) {
+        self.breaker_open
+            .with_label_values(&[device])
+            .set(f64::from(u8::from(open)));
+    }
+
+    pub fn observe_poll_duration(&self, device: &str, duration: Duration) {
+        self.poll_duration
+            .with_label_values(&[device])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn set_poll_concurrency_limit(&self, limit: usize) {
+        self.poll_concurrency_limit.set(limit as f64);
+    }
+
+    /// A poll started waiting for a free slot.
+    pub fn poll_queued(&self) {
+        self.polls_waiting.inc();
+    }
+
+    /// A poll got a slot after waiting for `waited`.
+    pub fn poll_started(&self, waited: Duration) {
+        self.polls_waiting.dec();
+        self.polls_in_flight.inc();
+        self.poll_queue_wait.observe(waited.as_secs_f64());
+    }
+
+    /// A poll gave its slot back.
+    pub fn poll_finished(&self) {
+        self.polls_in_flight.dec();
+    }
+
+    /// Count a failed poll; `up` says whether the device still counts as up
+    /// (see `--down-after`).
+    pub fn record_poll_failure(&self, device: &str, reason: &str, up: bool) {
+        self.scrapes_total.with_label_values(&[device]).inc();
+        self.scrape_errors_total
+            .with_label_values(&[device, reason])
+            .inc();
+        self.up
+            .with_label_values(&[device])
+            .set(f64::from(u8::from(up)));
+    }
+
+    /// Count an event that could not be delivered to a channel such as `slack`.
+    pub fn record_notification_failure(&self, channel: &str, event: &str) {
+        self.notification_failures
+            .with_label_values(&[channel, event])
+            .inc();
+    }
 
+    /// Store the latest reading of `device`, and update what is computed from it.
+    pub fn update_reading(&self, device: &str, reading: &Reading) -> Result<()> {
+        if let Reading::Water(data) = reading {
+            self.update_water(device, data);
+        }
+        self.readings.update(device, reading.clone());
         Ok(())
     }
 
+    pub fn update_p1(&self, device: &str, data: &HomeWizardP1Data) -> Result<()> {
+        self.update_reading(device, &Reading::P1(data.clone()))
+    }
+
+    pub fn update_energy_socket(
+        &self,
+        device: &str,
+        data: &HomeWizardEnergySocketData,
+    ) -> Result<()> {
+        self.update_reading(device, &Reading::EnergySocket(data.clone()))
+    }
+
+    pub fn update_kwh(&self, device: &str, data: &HomeWizardKwhData) -> Result<()> {
+        self.update_reading(device, &Reading::Kwh(data.clone()))
+    }
+
+    /// Count water starting to flow. The first reading of a device only sets the
+    /// state: water already running then may have started long before.
+    fn count_usage_event(&self, device: &str, flow_lpm: f64) {
+        let flowing = flow_lpm > 0.0;
+        let was_flowing = self
+            .flowing
+            .lock()
+            .unwrap()
+            .insert(device.to_string(), flowing);
+        let events = self.usage_events.with_label_values(&[device]);
+        if flowing && was_flowing == Some(false) {
+            events.inc();
+        }
+    }
+
+    fn update_flow_averages(&self, device: &str, flow_lpm: f64, now: Instant) {
+        let mut averages = self.flow_averages.lock().unwrap();
+        for (window, average) in averages.observe(device, flow_lpm, now) {
+            self.flow_average
+                .with_label_values(&[device, &window_label(window)])
+                .set(average);
+        }
+    }
+
+    fn remove_flow_averages(&self, device: &str) {
+        let mut averages = self.flow_averages.lock().unwrap();
+        averages.forget(device);
+        for window in averages.windows() {
+            let _ = self
+                .flow_average
+                .remove_label_values(&[device, &window_label(*window)]);
+        }
+    }
+
+    fn update_peak_flow(&self, device: &str, flow_lpm: f64, today: NaiveDate) {
+        let mut peaks = self.peak_flows.lock().unwrap();
+        let peak = peaks.entry(device.to_string()).or_insert(DailyPeak {
+            date: today,
+            flow_lpm,
+        });
+        if peak.date != today {
+            *peak = DailyPeak {
+                date: today,
+                flow_lpm,
+            };
+        }
+        peak.flow_lpm = peak.flow_lpm.max(flow_lpm);
+
+        self.peak_flow
+            .with_label_values(&[device])
+            .set(peak.flow_lpm);
+    }
+
+    fn update_leak(&self, device: &str, flow_lpm: f64, now: Instant) {
+        let mut leaks = self.leaks.lock().unwrap();
+        if !leaks.is_enabled() {
+            return;
+        }
+        let duration = leaks.observe(device, flow_lpm, now);
+        self.leak_duration
+            .with_label_values(&[device])
+            .set(duration.as_secs_f64());
+        let suspected = self.leak_suspected.with_label_values(&[device]);
+        let leak = leaks.is_leak(duration);
+        if leak && suspected.get() == 0.0 {
+            self.events.emit(
+                device,
+                EventKind::LeakSuspected {
+                    flow_lpm,
+                    flowing_seconds: duration.as_secs(),
+                },
+            );
+        }
+        suspected.set(f64::from(u8::from(leak)));
+    }
+
+    /// The first reading of a day becomes the baseline that "used today" counts from.
+    /// Returns the usage today, and whether this is the first reading of the day.
+    fn update_daily_usage(&self, device: &str, total_m3: f64, today: NaiveDate) -> (f64, bool) {
+        let mut baselines = self.daily_baselines.lock().unwrap();
+        let mut new_day = false;
+        let baseline = baselines.entry(device.to_string()).or_insert_with(|| {
+            new_day = true;
+            DailyBaseline {
+                date: today,
+                total_m3,
+            }
+        });
+        new_day |= baseline.date != today;
+        // A new day, or a meter that went backwards (replaced or reset)
+        if baseline.date != today || total_m3 < baseline.total_m3 {
+            *baseline = DailyBaseline {
+                date: today,
+                total_m3,
+            };
+        }
+
+        let usage_today = total_m3 - baseline.total_m3;
+        self.usage_today
+            .with_label_values(&[device])
+            .set(usage_today);
+        if self.units == Units::Us {
+            self.usage_today_gallons
+                .with_label_values(&[device])
+                .set(usage_today * GALLONS_PER_M3);
+        }
+        (usage_today, new_day)
+    }
+
+    /// Exporter-side state to persist across restarts.
+    pub fn snapshot(&self) -> Snapshot {
+        let counters = self
+            .registry
+            .gather()
+            .iter()
+            .filter(|family| family.get_field_type() == MetricType::COUNTER)
+            .flat_map(|family| {
+                family.get_metric().iter().map(|metric| CounterSample {
+                    name: family.name().to_string(),
+                    labels: metric
+                        .get_label()
+                        .iter()
+                        .map(|label| (label.name().to_string(), label.value().to_string()))
+                        .collect(),
+                    value: metric.get_counter().value(),
+                })
+            })
+            .collect();
+
+        Snapshot {
+            daily_usage: self
+                .daily_baselines
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(device, baseline)| (device.clone(), *baseline))
+                .collect(),
+            night_usage: self
+                .night_usages
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(device, usage)| (device.clone(), *usage))
+                .collect(),
+            billing_cycle: self
+                .billing_cycles
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(device, cycle)| (device.clone(), *cycle))
+                .collect(),
+            peak_flow: self
+                .peak_flows
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(device, peak)| (device.clone(), *peak))
+                .collect(),
+            aggregate_usage: self.readings.aggregate_usage(),
+            counters,
+        }
+    }
+
+    /// Restore state saved by [`Metrics::snapshot`]; call before the first poll.
+    ///
+    /// Restored device totals are served until the first successful poll replaces
+    /// them, so a restart while a meter is offline leaves no gap.
+    pub fn restore(&self, snapshot: &Snapshot) {
+        let mut baselines = self.daily_baselines.lock().unwrap();
+        for (device, baseline) in &snapshot.daily_usage {
+            baselines.insert(device.clone(), *baseline);
+        }
+        let mut peaks = self.peak_flows.lock().unwrap();
+        for (device, peak) in &snapshot.peak_flow {
+            peaks.insert(device.clone(), *peak);
+        }
+        let mut cycles = self.billing_cycles.lock().unwrap();
+        for (device, cycle) in &snapshot.billing_cycle {
+            cycles.insert(device.clone(), *cycle);
+        }
+        let mut night_usages = self.night_usages.lock().unwrap();
+        for (device, usage) in &snapshot.night_usage {
+            night_usages.insert(device.clone(), *usage);
+        }
+
+        self.readings.restore(&snapshot.counters);
+        self.readings.restore_aggregate(&snapshot.aggregate_usage);
+        let mut water_totals = self.water_totals.lock().unwrap();
+        for sample in &snapshot.counters {
+            if sample.name == "homewizard_water_total_m3"
+                && let Some(device) = sample.labels.get("device")
+            {
+                water_totals.insert(device.clone(), sample.value);
+            }
+            let Some((counter, label_names)) =
+                self.counter_vecs().into_iter().find_map(|counter| {
+                    let desc = counter.desc()[0];
+                    (desc.fq_name == sample.name).then(|| (counter, desc.variable_labels.clone()))
+                })
+            else {
+                continue;
+            };
+            let values: Option<Vec<&str>> = label_names
+                .iter()
+                .map(|name| sample.labels.get(name).map(String::as_str))
+                .collect();
+            if let Some(values) = values {
+                set_counter(counter, &values, sample.value);
+            }
+        }
+    }
+
+    fn counter_vecs(&self) -> [&CounterVec; 7] {
+        [
+            &self.usage_events,
+            &self.cost_total,
+            &self.meter_resets,
+            &self.cumulative_water,
+            &self.scrapes_total,
+            &self.scrape_errors_total,
+            &self.fetch_attempts_total,
+        ]
+    }
+
+    pub fn set_cloud_enabled(&self, device: &str, enabled: bool) {
+        self.cloud_enabled
+            .with_label_values(&[device])
+            .set(f64::from(u8::from(enabled)));
+    }
+
+    pub fn set_device_info(&self, device: &str, info: &HomeWizardDeviceInfo) {
+        let values = DeviceInfoLabels {
+            product_type: info.product_type.clone(),
+            product_name: info.product_name.clone(),
+            serial: info.serial.clone(),
+            firmware_version: info.firmware_version.clone(),
+            api_version: info.api_version.clone(),
+        };
+
+        let mut device_infos = self.device_infos.lock().unwrap();
+        if let Some(previous) = device_infos.insert(device.to_string(), values.clone())
+            && previous != values
+        {
+            let _ = self
+                .device_info
+                .remove_label_values(&previous.label_values(device));
+        }
+
+        self.device_info
+            .with_label_values(&values.label_values(device))
+            .set(1.0);
+    }
+
+    /// Drop the reading series of a device that has not answered for too long, so
+    /// alerts on absent data fire instead of the last values being served forever.
+    ///
+    /// The exporter's own metrics and the device info are kept; the next successful
+    /// poll brings the readings back.
+    pub fn mark_stale(&self, device: &str) {
+        self.readings.remove(device);
+        for gauge in [
+            &self.usage_today_gallons,
+            &self.usage_today,
+            &self.peak_flow,
+            &self.cost_today,
+            &self.budget_used,
+            &self.budget_remaining,
+            &self.budget_used_percent,
+            &self.night_usage,
+            &self.leak_suspected,
+            &self.leak_duration,
+        ] {
+            let _ = gauge.remove_label_values(&[device]);
+        }
+        self.remove_flow_averages(device);
+        for source in [DataSource::Local, DataSource::Cloud] {
+            let _ = self
+                .data_source
+                .remove_label_values(&[device, source.as_str()]);
+        }
+        self.stale.with_label_values(&[device]).set(1.0);
+    }
+
+    /// Drop the series and derived state of a device that is no longer configured.
+    pub fn remove_device(&self, device: &str) {
+        self.mark_stale(device);
+        for gauge in [
+            &self.stale,
+            &self.up,
+            &self.breaker_open,
+            &self.last_successful_poll,
+            &self.cloud_enabled,
+        ] {
+            let _ = gauge.remove_label_values(&[device]);
+        }
+        for histogram in [&self.flow_histogram, &self.poll_duration] {
+            let _ = histogram.remove_label_values(&[device]);
+        }
+        for counter in self.counter_vecs() {
+            if counter.desc()[0].variable_labels == ["device"] {
+                let _ = counter.remove_label_values(&[device]);
+            }
+        }
+        if let Some(values) = self.device_infos.lock().unwrap().remove(device) {
+            let _ = self
+                .device_info
+                .remove_label_values(&values.label_values(device));
+        }
+
+        self.flowing.lock().unwrap().remove(device);
+        self.water_totals.lock().unwrap().remove(device);
+        self.peak_flows.lock().unwrap().remove(device);
+        self.billing_cycles.lock().unwrap().remove(device);
+        self.night_usages.lock().unwrap().remove(device);
+        self.daily_baselines.lock().unwrap().remove(device);
+    }
+
+    /// Record where the latest reading of `device` came from.
+    pub fn set_source(&self, device: &str, source: DataSource) {
+        for other in [DataSource::Local, DataSource::Cloud] {
+            if other != source {
+                let _ = self
+                    .data_source
+                    .remove_label_values(&[device, other.as_str()]);
+            }
+        }
+        self.data_source
+            .with_label_values(&[device, source.as_str()])
+            .set(1.0);
+    }
+
+    /// The exported metric families, after `--metrics-include`/`--metrics-exclude`.
+    pub fn families(&self) -> Vec<MetricFamily> {
+        let mut families = self.registry.gather();
+        families.retain(|family| self.filter.allows(family.name()));
+
+        let labels = self.labels.read().unwrap();
+        if !labels.is_empty() {
+            let pairs: Vec<LabelPair> = labels
+                .iter()
+                .map(|(name, value)| {
+                    let mut pair = LabelPair::default();
+                    pair.set_name(name.clone());
+                    pair.set_value(value.clone());
+                    pair
+                })
+                .collect();
+            for metric in families.iter_mut().flat_map(|family| family.mut_metric()) {
+                let mut metric_labels = metric.take_label();
+                metric_labels.extend(pairs.iter().cloned());
+                metric.set_label(metric_labels);
+            }
+        }
+        families
+    }
+
     pub fn gather(&self) -> Result<String> {
         let encoder = TextEncoder::new();
-        let metric_families = self.registry.gather();
+        let metric_families = self.families();
         let mut buffer = Vec::new();
         encoder.encode(&metric_families, &mut buffer)?;
         Ok(String::from_utf8(buffer)?)
     }
 }
 
+fn flow_histogram(buckets: &[f64]) -> Result<HistogramVec> {
+    if !buckets.windows(2).all(|pair| pair[0] < pair[1]) {
+        bail!("Flow buckets must be in increasing order: {buckets:?}");
+    }
+    Ok(HistogramVec::new(
+        HistogramOpts::new(
+            "homewizard_water_flow_lpm",
+            "Observed nonzero water flow in liters per minute, one sample per poll",
+        )
+        .buckets(buckets.to_vec()),
+        &["device"],
+    )?)
+}
+
+/// Set a counter to the device's own running total.
+pub(crate) fn set_counter(counter: &CounterVec, labels: &[&str], value: f64) {
+    let counter = counter.with_label_values(labels);
+    counter.reset();
+    counter.inc_by(value);
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cloud::DataSource;
     use crate::homewizard::HomeWizardWaterData;
 
     fn create_test_data() -> HomeWizardWaterData {
         HomeWizardWaterData {
             wifi_ssid: "TestNetwork".to_string(),
-            wifi_strength: 75.5,
+            wifi_strength: Some(75.5),
             total_liter_m3: 1234.567,
-            active_liter_lpm: 15.5,
+            active_liter_lpm: Some(15.5),
             total_liter_offset_m3: 100.0,
+            wifi_rssi_db: None,
+            ..Default::default()
         }
     }
 
@@ -119,7 +1418,7 @@ mod tests {
         let metrics = Metrics::new().unwrap();
         let data = create_test_data();
 
-        let result = metrics.update(&data);
+        let result = metrics.update("meter", &data);
         assert!(result.is_ok());
     }
 
@@ -128,7 +1427,7 @@ mod tests {
         let metrics = Metrics::new().unwrap();
         let data = create_test_data();
 
-        metrics.update(&data).unwrap();
+        metrics.update("meter", &data).unwrap();
         let result = metrics.gather();
         assert!(result.is_ok());
 
@@ -138,6 +1437,10 @@ mod tests {
         assert!(output.contains("homewizard_water_offset_m3"));
         assert!(output.contains("homewizard_water_wifi_strength_percent"));
         assert!(output.contains("homewizard_water_meter_info"));
+        assert!(output.contains(&format!(
+            "homewizard_exporter_build_info{{build_date=\"{}\",git_sha=\"{}\",rustc=\"{}\",version=\"{}\"}} 1",
+            BUILD_INFO.build_date, BUILD_INFO.git_sha, BUILD_INFO.rustc, BUILD_INFO.version
+        )));
     }
 
     #[test]
@@ -145,12 +1448,12 @@ mod tests {
         let metrics = Metrics::new().unwrap();
         let data = create_test_data();
 
-        metrics.update(&data).unwrap();
+        metrics.update("meter", &data).unwrap();
         let output = metrics.gather().unwrap();
 
-        assert!(output.contains("homewizard_water_total_m3 1234.567"));
-        assert!(output.contains("homewizard_water_active_flow_lpm 15.5"));
-        assert!(output.contains("homewizard_water_offset_m3 100"));
+        assert!(output.contains("homewizard_water_total_m3{device=\"meter\"} 1234.567"));
+        assert!(output.contains("homewizard_water_active_flow_lpm{device=\"meter\"} 15.5"));
+        assert!(output.contains("homewizard_water_offset_m3{device=\"meter\"} 100"));
     }
 
     #[test]
@@ -158,10 +1461,10 @@ mod tests {
         let metrics = Metrics::new().unwrap();
         let data = create_test_data();
 
-        metrics.update(&data).unwrap();
+        metrics.update("meter", &data).unwrap();
         let output = metrics.gather().unwrap();
 
-        assert!(output.contains("homewizard_water_wifi_strength_percent 75.5"));
+        assert!(output.contains("homewizard_water_wifi_strength_percent{device=\"meter\"} 75.5"));
     }
 
     #[test]
@@ -169,10 +1472,14 @@ mod tests {
         let metrics = Metrics::new().unwrap();
         let data = create_test_data();
 
-        metrics.update(&data).unwrap();
+        metrics.update("meter", &data).unwrap();
         let output = metrics.gather().unwrap();
 
-        assert!(output.contains("homewizard_water_meter_info{wifi_ssid=\"TestNetwork\"} 1"));
+        assert!(
+            output.contains(
+                "homewizard_water_meter_info{device=\"meter\",wifi_ssid=\"TestNetwork\"} 1"
+            )
+        );
     }
 
     #[test]
@@ -180,17 +1487,17 @@ mod tests {
         let metrics = Metrics::new().unwrap();
         let mut data = create_test_data();
         data.total_liter_m3 = 0.0;
-        data.active_liter_lpm = 0.0;
+        data.active_liter_lpm = Some(0.0);
         data.total_liter_offset_m3 = 0.0;
-        data.wifi_strength = 0.0;
+        data.wifi_strength = Some(0.0);
 
-        metrics.update(&data).unwrap();
+        metrics.update("meter", &data).unwrap();
         let output = metrics.gather().unwrap();
 
-        assert!(output.contains("homewizard_water_total_m3 0"));
-        assert!(output.contains("homewizard_water_active_flow_lpm 0"));
-        assert!(output.contains("homewizard_water_offset_m3 0"));
-        assert!(output.contains("homewizard_water_wifi_strength_percent 0"));
+        assert!(output.contains("homewizard_water_total_m3{device=\"meter\"} 0"));
+        assert!(output.contains("homewizard_water_active_flow_lpm{device=\"meter\"} 0"));
+        assert!(output.contains("homewizard_water_offset_m3{device=\"meter\"} 0"));
+        assert!(output.contains("homewizard_water_wifi_strength_percent{device=\"meter\"} 0"));
     }
 
     #[test]
@@ -199,15 +1506,15 @@ mod tests {
         let mut data = create_test_data();
 
         // First update
-        metrics.update(&data).unwrap();
+        metrics.update("meter", &data).unwrap();
         let output1 = metrics.gather().unwrap();
-        assert!(output1.contains("homewizard_water_active_flow_lpm 15.5"));
+        assert!(output1.contains("homewizard_water_active_flow_lpm{device=\"meter\"} 15.5"));
 
         // Second update with different values
-        data.active_liter_lpm = 25.0;
-        metrics.update(&data).unwrap();
+        data.active_liter_lpm = Some(25.0);
+        metrics.update("meter", &data).unwrap();
         let output2 = metrics.gather().unwrap();
-        assert!(output2.contains("homewizard_water_active_flow_lpm 25"));
+        assert!(output2.contains("homewizard_water_active_flow_lpm{device=\"meter\"} 25"));
     }
 
     #[test]
@@ -215,15 +1522,15 @@ mod tests {
         let metrics = Metrics::new().unwrap();
         let mut data = create_test_data();
         data.total_liter_m3 = 999999.999;
-        data.active_liter_lpm = 999.0;
+        data.active_liter_lpm = Some(999.0);
         data.total_liter_offset_m3 = 500.0;
 
-        metrics.update(&data).unwrap();
+        metrics.update("meter", &data).unwrap();
         let output = metrics.gather().unwrap();
 
-        assert!(output.contains("homewizard_water_total_m3 999999.999"));
-        assert!(output.contains("homewizard_water_active_flow_lpm 999"));
-        assert!(output.contains("homewizard_water_offset_m3 500"));
+        assert!(output.contains("homewizard_water_total_m3{device=\"meter\"} 999999.999"));
+        assert!(output.contains("homewizard_water_active_flow_lpm{device=\"meter\"} 999"));
+        assert!(output.contains("homewizard_water_offset_m3{device=\"meter\"} 500"));
     }
 
     #[test]
@@ -232,22 +1539,24 @@ mod tests {
         let mut data = create_test_data();
         data.wifi_ssid = "DifferentNetwork".to_string();
 
-        metrics.update(&data).unwrap();
+        metrics.update("meter", &data).unwrap();
         let output = metrics.gather().unwrap();
 
-        assert!(output.contains("homewizard_water_meter_info{wifi_ssid=\"DifferentNetwork\"} 1"));
+        assert!(output.contains(
+            "homewizard_water_meter_info{device=\"meter\",wifi_ssid=\"DifferentNetwork\"} 1"
+        ));
     }
 
     #[test]
     fn test_metrics_with_high_flow_rate() {
         let metrics = Metrics::new().unwrap();
         let mut data = create_test_data();
-        data.active_liter_lpm = 1000.0;
+        data.active_liter_lpm = Some(1000.0);
 
-        metrics.update(&data).unwrap();
+        metrics.update("meter", &data).unwrap();
         let output = metrics.gather().unwrap();
 
-        assert!(output.contains("homewizard_water_active_flow_lpm 1000"));
+        assert!(output.contains("homewizard_water_active_flow_lpm{device=\"meter\"} 1000"));
     }
 
     #[test]
@@ -256,22 +1565,22 @@ mod tests {
         let mut data = create_test_data();
         data.total_liter_offset_m3 = -50.0;
 
-        metrics.update(&data).unwrap();
+        metrics.update("meter", &data).unwrap();
         let output = metrics.gather().unwrap();
 
-        assert!(output.contains("homewizard_water_offset_m3 -50"));
+        assert!(output.contains("homewizard_water_offset_m3{device=\"meter\"} -50"));
     }
 
     #[test]
     fn test_metrics_with_weak_wifi() {
         let metrics = Metrics::new().unwrap();
         let mut data = create_test_data();
-        data.wifi_strength = 10.0;
+        data.wifi_strength = Some(10.0);
 
-        metrics.update(&data).unwrap();
+        metrics.update("meter", &data).unwrap();
         let output = metrics.gather().unwrap();
 
-        assert!(output.contains("homewizard_water_wifi_strength_percent 10"));
+        assert!(output.contains("homewizard_water_wifi_strength_percent{device=\"meter\"} 10"));
     }
 
     #[test]
@@ -279,14 +1588,1011 @@ mod tests {
         let metrics = Metrics::new().unwrap();
         let mut data = create_test_data();
         data.total_liter_m3 = 123.456;
-        data.active_liter_lpm = 7.89;
+        data.active_liter_lpm = Some(7.89);
         data.total_liter_offset_m3 = 12.34;
 
-        metrics.update(&data).unwrap();
+        metrics.update("meter", &data).unwrap();
         let output = metrics.gather().unwrap();
 
-        assert!(output.contains("homewizard_water_total_m3 123.456"));
-        assert!(output.contains("homewizard_water_active_flow_lpm 7.89"));
-        assert!(output.contains("homewizard_water_offset_m3 12.34"));
+        assert!(output.contains("homewizard_water_total_m3{device=\"meter\"} 123.456"));
+        assert!(output.contains("homewizard_water_active_flow_lpm{device=\"meter\"} 7.89"));
+        assert!(output.contains("homewizard_water_offset_m3{device=\"meter\"} 12.34"));
+    }
+
+    #[test]
+    fn test_metrics_multiple_devices_share_registry() {
+        let metrics = Metrics::new().unwrap();
+        let mut data = create_test_data();
+
+        metrics.update("kitchen", &data).unwrap();
+        data.total_liter_m3 = 42.0;
+        metrics.update("garden", &data).unwrap();
+        let output = metrics.gather().unwrap();
+
+        assert!(output.contains("homewizard_water_total_m3{device=\"kitchen\"} 1234.567"));
+        assert!(output.contains("homewizard_water_total_m3{device=\"garden\"} 42"));
+        // One HELP/TYPE header per family, no matter how many devices
+        assert_eq!(
+            output.matches("# TYPE homewizard_water_total_m3").count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_metrics_meter_info_replaced_on_ssid_change() {
+        let metrics = Metrics::new().unwrap();
+        let mut data = create_test_data();
+
+        metrics.update("meter", &data).unwrap();
+        data.wifi_ssid = "NewNetwork".to_string();
+        metrics.update("meter", &data).unwrap();
+        let output = metrics.gather().unwrap();
+
+        assert!(!output.contains("wifi_ssid=\"TestNetwork\""));
+        assert!(
+            output.contains(
+                "homewizard_water_meter_info{device=\"meter\",wifi_ssid=\"NewNetwork\"} 1"
+            )
+        );
+    }
+
+    #[test]
+    fn test_metrics_ssid_change_keeps_other_devices() {
+        let metrics = Metrics::new().unwrap();
+        let mut data = create_test_data();
+
+        metrics.update("kitchen", &data).unwrap();
+        metrics.update("garden", &data).unwrap();
+        data.wifi_ssid = "NewNetwork".to_string();
+        metrics.update("garden", &data).unwrap();
+        let output = metrics.gather().unwrap();
+
+        assert!(output.contains(
+            "homewizard_water_meter_info{device=\"kitchen\",wifi_ssid=\"TestNetwork\"} 1"
+        ));
+        assert!(
+            output.contains(
+                "homewizard_water_meter_info{device=\"garden\",wifi_ssid=\"NewNetwork\"} 1"
+            )
+        );
+    }
+
+    #[test]
+    fn test_metrics_data_source_switches() {
+        let metrics = Metrics::new().unwrap();
+
+        metrics.set_source("meter", DataSource::Cloud);
+        let output = metrics.gather().unwrap();
+        assert!(
+            output.contains("homewizard_water_data_source{device=\"meter\",source=\"cloud\"} 1")
+        );
+
+        metrics.set_source("meter", DataSource::Local);
+        let output = metrics.gather().unwrap();
+        assert!(
+            output.contains("homewizard_water_data_source{device=\"meter\",source=\"local\"} 1")
+        );
+        assert!(!output.contains("source=\"cloud\""));
+    }
+
+    #[test]
+    fn test_metrics_usage_today() {
+        let metrics = Metrics::new().unwrap();
+        let today = NaiveDate::from_ymd_opt(2025, 1, 23).unwrap();
+
+        metrics.update_daily_usage("meter", 100.0, today);
+        metrics.update_daily_usage("meter", 100.25, today);
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_usage_today_m3{device=\"meter\"} 0.25"));
+
+        // Midnight: the next reading starts a new day
+        metrics.update_daily_usage("meter", 100.5, today.succ_opt().unwrap());
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_usage_today_m3{device=\"meter\"} 0\n"));
+    }
+
+    #[test]
+    fn test_metrics_usage_today_after_meter_reset() {
+        let metrics = Metrics::new().unwrap();
+        let today = NaiveDate::from_ymd_opt(2025, 1, 23).unwrap();
+
+        metrics.update_daily_usage("meter", 100.0, today);
+        metrics.update_daily_usage("meter", 1.0, today);
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_usage_today_m3{device=\"meter\"} 0\n"));
+    }
+
+    #[test]
+    fn test_metrics_snapshot_restore() {
+        let today = NaiveDate::from_ymd_opt(2025, 1, 23).unwrap();
+        let metrics = Metrics::new().unwrap();
+        metrics.update_daily_usage("meter", 100.0, today);
+        let snapshot = metrics.snapshot();
+
+        // A restarted exporter keeps counting from the saved baseline
+        let restarted = Metrics::new().unwrap();
+        restarted.restore(&snapshot);
+        restarted.update_daily_usage("meter", 100.5, today);
+        let output = restarted.gather().unwrap();
+        assert!(output.contains("homewizard_water_usage_today_m3{device=\"meter\"} 0.5"));
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("homewizard_up", "homewizard_up"));
+        assert!(!glob_match("homewizard_up", "homewizard_upx"));
+        assert!(glob_match(
+            "homewizard_water_wifi_*",
+            "homewizard_water_wifi_strength_percent"
+        ));
+        assert!(glob_match("*_info", "homewizard_water_device_info"));
+        assert!(glob_match(
+            "homewizard_*_total",
+            "homewizard_exporter_scrapes_total"
+        ));
+        assert!(!glob_match(
+            "homewizard_*_total",
+            "homewizard_water_total_m3"
+        ));
+        assert!(glob_match("*", "anything"));
+    }
+
+    #[test]
+    fn test_metric_filter() {
+        let filter = MetricFilter {
+            include: vec![],
+            exclude: vec![
+                "homewizard_water_wifi_*".to_string(),
+                "homewizard_water_offset_m3".to_string(),
+            ],
+        };
+        assert!(filter.allows("homewizard_water_total_m3"));
+        assert!(!filter.allows("homewizard_water_wifi_strength_percent"));
+        assert!(!filter.allows("homewizard_water_offset_m3"));
+
+        let filter = MetricFilter {
+            include: vec!["homewizard_water_*".to_string()],
+            exclude: vec!["homewizard_water_meter_info".to_string()],
+        };
+        assert!(filter.allows("homewizard_water_total_m3"));
+        assert!(!filter.allows("homewizard_up"));
+        assert!(!filter.allows("homewizard_water_meter_info"));
+    }
+
+    #[test]
+    fn test_metrics_filtered_output() {
+        let metrics = Metrics::new().unwrap().with_filter(MetricFilter {
+            include: vec![],
+            exclude: vec!["homewizard_water_wifi_*".to_string()],
+        });
+        metrics.update("meter", &create_test_data()).unwrap();
+        let output = metrics.gather().unwrap();
+
+        assert!(output.contains("homewizard_water_total_m3{device=\"meter\"} 1234.567"));
+        assert!(!output.contains("homewizard_water_wifi_strength_percent"));
+        // Filtered families are still persisted
+        assert!(!metrics.snapshot().counters.is_empty());
+    }
+
+    #[test]
+    fn test_metrics_with_labels() {
+        let labels = HashMap::from([("site".to_string(), "home1".to_string())]);
+        let metrics = Metrics::with_labels(labels).unwrap();
+        metrics.update("meter", &create_test_data()).unwrap();
+        metrics.record_poll_success("meter");
+        let output = metrics.gather().unwrap();
+
+        assert!(
+            output.contains("homewizard_water_total_m3{device=\"meter\",site=\"home1\"} 1234.567")
+        );
+        assert!(output.contains("homewizard_up{device=\"meter\",site=\"home1\"} 1"));
+    }
+
+    #[test]
+    fn test_metrics_label_clashing_with_device_is_rejected() {
+        let labels = HashMap::from([("device".to_string(), "x".to_string())]);
+        assert!(Metrics::with_labels(labels).is_err());
+    }
+
+    #[test]
+    fn test_metrics_set_labels() {
+        let metrics =
+            Metrics::with_labels(HashMap::from([("site".to_string(), "home1".to_string())]))
+                .unwrap();
+        metrics.record_poll_success("meter");
+
+        metrics
+            .set_labels(HashMap::from([
+                ("site".to_string(), "home2".to_string()),
+                ("floor".to_string(), "1".to_string()),
+            ]))
+            .unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_up{device=\"meter\",floor=\"1\",site=\"home2\"} 1"));
+
+        let clash = HashMap::from([("device".to_string(), "x".to_string())]);
+        assert!(metrics.set_labels(clash).is_err());
+        metrics.set_labels(HashMap::new()).unwrap();
+        assert!(
+            metrics
+                .gather()
+                .unwrap()
+                .contains("homewizard_up{device=\"meter\"} 1")
+        );
+    }
+
+    #[test]
+    fn test_metrics_remove_device() {
+        let metrics = Metrics::new().unwrap();
+        for device in ["kitchen", "garden"] {
+            metrics.update(device, &create_test_data()).unwrap();
+            metrics.record_poll_success(device);
+        }
+
+        metrics.remove_device("garden");
+        let output = metrics.gather().unwrap();
+        assert!(!output.contains("device=\"garden\""), "{output}");
+        assert!(output.contains("homewizard_up{device=\"kitchen\"} 1"));
+        assert!(output.contains("homewizard_water_total_m3{device=\"kitchen\"}"));
+    }
+
+    #[test]
+    fn test_metrics_wifi_rssi() {
+        let metrics = Metrics::new().unwrap();
+        metrics.update("meter", &create_test_data()).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_wifi_rssi_dbm{device=\"meter\"} -62.25"));
+
+        let mut data = create_test_data();
+        data.wifi_rssi_db = Some(-71.0);
+        metrics.update("meter", &data).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_wifi_rssi_dbm{device=\"meter\"} -71"));
+    }
+
+    #[test]
+    fn test_metrics_wifi_disabled() {
+        let metrics = Metrics::new().unwrap().with_wifi_metrics(false);
+        metrics.update("meter", &create_test_data()).unwrap();
+        let output = metrics.gather().unwrap();
+
+        assert!(output.contains("homewizard_water_total_m3{device=\"meter\"}"));
+        assert!(!output.contains("homewizard_water_wifi_"));
+        assert!(!output.contains("homewizard_water_meter_info{"));
+    }
+
+    #[test]
+    fn test_metrics_net_total() {
+        let metrics = Metrics::new().unwrap();
+        metrics.update("meter", &create_test_data()).unwrap();
+        assert!(
+            !metrics
+                .gather()
+                .unwrap()
+                .contains("homewizard_water_net_total_m3{")
+        );
+
+        let metrics = Metrics::new().unwrap().with_net_total(Some(0.0));
+        metrics.update("meter", &create_test_data()).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_net_total_m3{device=\"meter\"} 1134.567"));
+
+        let metrics = Metrics::new().unwrap().with_net_total(Some(-1134.0));
+        metrics.update("meter", &create_test_data()).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_net_total_m3{device=\"meter\"} 0.567"));
+
+        // Never negative
+        let metrics = Metrics::new().unwrap().with_net_total(Some(-2000.0));
+        metrics.update("meter", &create_test_data()).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_net_total_m3{device=\"meter\"} 0\n"));
+    }
+
+    #[test]
+    fn test_metrics_us_units() {
+        let metrics = Metrics::new().unwrap();
+        metrics.update("meter", &create_test_data()).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(!output.contains("gallons"));
+        assert!(!output.contains("_gpm"));
+
+        let metrics = Metrics::new().unwrap().with_units(Units::Us);
+        let mut data = create_test_data();
+        data.total_liter_m3 = 2.0;
+        data.active_liter_lpm = Some(10.0);
+        metrics.update("meter", &data).unwrap();
+        let output = metrics.gather().unwrap();
+
+        // The metric series stay
+        assert!(output.contains("homewizard_water_total_m3{device=\"meter\"} 2"));
+        assert!(output.contains("homewizard_water_total_gallons{device=\"meter\"} 528.344104"));
+        assert!(output.contains("homewizard_water_active_flow_gpm{device=\"meter\"} 2.64172052"));
+        assert!(output.contains("homewizard_water_usage_today_gallons{device=\"meter\"} 0"));
+    }
+
+    #[test]
+    fn test_metrics_flow_histogram() {
+        let metrics = Metrics::new().unwrap();
+        let mut data = create_test_data();
+        metrics.update("meter", &data).unwrap();
+        data.active_liter_lpm = Some(0.0);
+        metrics.update("meter", &data).unwrap();
+        let output = metrics.gather().unwrap();
+
+        assert!(output.contains("homewizard_water_flow_lpm_bucket{device=\"meter\",le=\"15\"} 0"));
+        assert!(output.contains("homewizard_water_flow_lpm_bucket{device=\"meter\",le=\"20\"} 1"));
+        assert!(output.contains("homewizard_water_flow_lpm_count{device=\"meter\"} 1"));
+    }
+
+    #[test]
+    fn test_metrics_custom_flow_buckets() {
+        let metrics = Metrics::new()
+            .unwrap()
+            .with_flow_buckets(&[5.0, 50.0])
+            .unwrap();
+        metrics.update("meter", &create_test_data()).unwrap();
+        let output = metrics.gather().unwrap();
+
+        assert!(output.contains("homewizard_water_flow_lpm_bucket{device=\"meter\",le=\"5\"} 0"));
+        assert!(output.contains("homewizard_water_flow_lpm_bucket{device=\"meter\",le=\"50\"} 1"));
+        assert!(!output.contains("le=\"0.5\""));
+
+        assert!(
+            Metrics::new()
+                .unwrap()
+                .with_flow_buckets(&[5.0, 1.0])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_metrics_flow_averages() {
+        let metrics = Metrics::new().unwrap();
+        let start = Instant::now();
+
+        metrics.update_flow_averages("meter", 12.0, start);
+        metrics.update_flow_averages("meter", 0.0, start + Duration::from_secs(60));
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_flow_avg_lpm{device=\"meter\",window=\"1m\"} 0"));
+        assert!(output.contains("homewizard_water_flow_avg_lpm{device=\"meter\",window=\"5m\"} 6"));
+        assert!(
+            output.contains("homewizard_water_flow_avg_lpm{device=\"meter\",window=\"15m\"} 6")
+        );
+
+        metrics.mark_stale("meter");
+        let output = metrics.gather().unwrap();
+        assert!(!output.contains("homewizard_water_flow_avg_lpm{"));
+    }
+
+    #[test]
+    fn test_metrics_peak_flow() {
+        let metrics = Metrics::new().unwrap();
+        let today = NaiveDate::from_ymd_opt(2025, 1, 23).unwrap();
+
+        for flow in [3.0, 12.5, 0.0, 8.0] {
+            metrics.update_peak_flow("meter", flow, today);
+        }
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_peak_flow_lpm{device=\"meter\"} 12.5"));
+
+        // Midnight starts over
+        metrics.update_peak_flow("meter", 0.0, today.succ_opt().unwrap());
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_peak_flow_lpm{device=\"meter\"} 0\n"));
+    }
+
+    #[test]
+    fn test_metrics_peak_flow_restored() {
+        let today = NaiveDate::from_ymd_opt(2025, 1, 23).unwrap();
+        let metrics = Metrics::new().unwrap();
+        metrics.update_peak_flow("meter", 12.5, today);
+
+        let restarted = Metrics::new().unwrap();
+        restarted.restore(&metrics.snapshot());
+        restarted.update_peak_flow("meter", 2.0, today);
+        let output = restarted.gather().unwrap();
+        assert!(output.contains("homewizard_water_peak_flow_lpm{device=\"meter\"} 12.5"));
+    }
+
+    #[test]
+    fn test_metrics_usage_events() {
+        let metrics = Metrics::new().unwrap();
+        // Already flowing on the first reading: not counted
+        for flow in [2.0, 0.0, 6.5, 8.0, 0.0, 0.0, 1.0] {
+            metrics.count_usage_event("meter", flow);
+        }
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_usage_events_total{device=\"meter\"} 2"));
+    }
+
+    #[test]
+    fn test_metrics_cost() {
+        let metrics = Metrics::new().unwrap().with_pricing(Some(Pricing {
+            per_m3: 2.0,
+            standing_charge: 0.25,
+        }));
+        let today = NaiveDate::from_ymd_opt(2025, 1, 23).unwrap();
+
+        // The first reading starts tracking; only the standing charge counts
+        metrics.update_totals("meter", 100.0, today);
+        metrics.update_totals("meter", 100.5, today);
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_cost_estimate_total{device=\"meter\"} 1.25"));
+        assert!(output.contains("homewizard_water_cost_today{device=\"meter\"} 1.25"));
+
+        // The standing charge is added once per day
+        metrics.update_totals("meter", 101.0, today.succ_opt().unwrap());
+        metrics.update_totals("meter", 101.0, today.succ_opt().unwrap());
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_cost_estimate_total{device=\"meter\"} 2.5"));
+        assert!(output.contains("homewizard_water_cost_today{device=\"meter\"} 0.25"));
+    }
+
+    #[test]
+    fn test_budget_cycle_start() {
+        let budget = Budget {
+            monthly_m3: 10.0,
+            cycle_start_day: 15,
+        };
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+
+        assert_eq!(budget.cycle_start(date(2025, 3, 15)), date(2025, 3, 15));
+        assert_eq!(budget.cycle_start(date(2025, 3, 31)), date(2025, 3, 15));
+        assert_eq!(budget.cycle_start(date(2025, 3, 14)), date(2025, 2, 15));
+        assert_eq!(budget.cycle_start(date(2025, 1, 1)), date(2024, 12, 15));
+    }
+
+    #[test]
+    fn test_metrics_budget() {
+        let metrics = Metrics::new().unwrap().with_budget(Some(Budget {
+            monthly_m3: 10.0,
+            cycle_start_day: 15,
+        }));
+        let date = |m, d| NaiveDate::from_ymd_opt(2025, m, d).unwrap();
+
+        metrics.update_totals("meter", 100.0, date(1, 20));
+        metrics.update_totals("meter", 102.0, date(1, 25));
+        // A meter reset does not lose the water used so far
+        metrics.update_totals("meter", 0.5, date(2, 1));
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_budget_used_m3{device=\"meter\"} 2.5"));
+        assert!(output.contains("homewizard_water_budget_remaining_m3{device=\"meter\"} 7.5"));
+        assert!(output.contains("homewizard_water_budget_used_percent{device=\"meter\"} 25"));
+
+        // The next cycle starts on the 15th
+        metrics.update_totals("meter", 1.0, date(2, 15));
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_budget_used_m3{device=\"meter\"} 0\n"));
+    }
+
+    fn at(day: u32, time: &str) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2025, 1, day)
+            .unwrap()
+            .and_time(NaiveTime::parse_from_str(time, "%H:%M").unwrap())
+    }
+
+    #[test]
+    fn test_quiet_hours() {
+        let quiet: QuietHours = "01:00-05:00".parse().unwrap();
+        assert_eq!(
+            quiet.night_of(at(23, "01:00")),
+            Some(at(23, "00:00").date())
+        );
+        assert_eq!(
+            quiet.night_of(at(23, "04:59")),
+            Some(at(23, "00:00").date())
+        );
+        assert_eq!(quiet.night_of(at(23, "05:00")), None);
+        assert_eq!(quiet.night_of(at(23, "00:59")), None);
+
+        // Wrapping past midnight belongs to the night it started
+        let quiet: QuietHours = "23:00-05:00".parse().unwrap();
+        assert_eq!(
+            quiet.night_of(at(23, "23:30")),
+            Some(at(23, "00:00").date())
+        );
+        assert_eq!(
+            quiet.night_of(at(24, "02:00")),
+            Some(at(23, "00:00").date())
+        );
+        assert_eq!(quiet.night_of(at(24, "12:00")), None);
+
+        assert!("01:00".parse::<QuietHours>().is_err());
+        assert!("25:00-05:00".parse::<QuietHours>().is_err());
+        assert!("05:00-05:00".parse::<QuietHours>().is_err());
+    }
+
+    #[test]
+    fn test_metrics_night_usage() {
+        let metrics = Metrics::new()
+            .unwrap()
+            .with_quiet_hours(Some("01:00-05:00".parse().unwrap()));
+
+        metrics.update_night_usage("meter", 0.5, at(22, "20:00"));
+        let output = metrics.gather().unwrap();
+        assert!(!output.contains("homewizard_water_night_usage_m3{"));
+
+        metrics.update_night_usage("meter", 0.01, at(23, "01:30"));
+        metrics.update_night_usage("meter", 0.02, at(23, "04:00"));
+        // Daytime use does not count, and last night's total stays
+        metrics.update_night_usage("meter", 1.0, at(23, "08:00"));
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_night_usage_m3{device=\"meter\"} 0.03"));
+
+        metrics.update_night_usage("meter", 0.0, at(24, "01:00"));
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_night_usage_m3{device=\"meter\"} 0\n"));
+    }
+
+    #[test]
+    fn test_metrics_cost_without_pricing() {
+        let metrics = Metrics::new().unwrap();
+        metrics.update("meter", &create_test_data()).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(!output.contains("homewizard_water_cost"));
+    }
+
+    #[test]
+    fn test_metrics_leak() {
+        let metrics = Metrics::new()
+            .unwrap()
+            .with_leak_after(Duration::from_secs(1800));
+        let start = Instant::now();
+
+        metrics.update_leak("meter", 1.5, start);
+        metrics.update_leak("meter", 1.5, start + Duration::from_secs(600));
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_leak_duration_seconds{device=\"meter\"} 600"));
+        assert!(output.contains("homewizard_water_leak_suspected{device=\"meter\"} 0"));
+
+        metrics.update_leak("meter", 1.5, start + Duration::from_secs(1800));
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_leak_suspected{device=\"meter\"} 1"));
+
+        metrics.update_leak("meter", 0.0, start + Duration::from_secs(1860));
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_leak_duration_seconds{device=\"meter\"} 0"));
+        assert!(output.contains("homewizard_water_leak_suspected{device=\"meter\"} 0"));
+    }
+
+    #[test]
+    fn test_metrics_events() {
+        let events = Events::new();
+        let mut received = events.subscribe();
+        let metrics = Metrics::new()
+            .unwrap()
+            .with_leak_after(Duration::from_secs(1800))
+            .with_events(events);
+        let start = Instant::now();
+
+        // One event per leak, not per poll
+        for minutes in [0, 30, 31] {
+            metrics.update_leak("meter", 1.5, start + Duration::from_secs(minutes * 60));
+        }
+        metrics.update("meter", &water_data(100.0)).unwrap();
+        metrics.update("meter", &water_data(0.25)).unwrap();
+
+        let kinds: Vec<EventKind> = std::iter::from_fn(|| received.try_recv().ok())
+            .map(|event| event.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                EventKind::LeakSuspected {
+                    flow_lpm: 1.5,
+                    flowing_seconds: 1800
+                },
+                EventKind::CounterReset {
+                    previous_m3: 100.0,
+                    total_m3: 0.25
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_metrics_leak_detection_disabled() {
+        let metrics = Metrics::new().unwrap();
+        metrics.update("meter", &create_test_data()).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(!output.contains("homewizard_water_leak_suspected{"));
+    }
+
+    fn water_data(total: f64) -> HomeWizardWaterData {
+        HomeWizardWaterData {
+            total_liter_m3: total,
+            ..create_test_data()
+        }
+    }
+
+    #[test]
+    fn test_metrics_meter_reset() {
+        let metrics = Metrics::new().unwrap();
+        metrics.update("meter", &water_data(100.0)).unwrap();
+        metrics.update("meter", &water_data(100.5)).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_meter_resets_total{device=\"meter\"} 0"));
+        assert!(!output.contains("homewizard_water_cumulative_total_m3{"));
+
+        metrics.update("meter", &water_data(0.25)).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_meter_resets_total{device=\"meter\"} 1"));
+        assert!(output.contains("homewizard_water_total_m3{device=\"meter\"} 0.25"));
+    }
+
+    #[test]
+    fn test_metrics_cumulative_total() {
+        let metrics = Metrics::new().unwrap().with_cumulative_total(true);
+        metrics.update("meter", &water_data(100.0)).unwrap();
+        metrics.update("meter", &water_data(100.5)).unwrap();
+        // Replaced meter starting from zero
+        metrics.update("meter", &water_data(0.25)).unwrap();
+        metrics.update("meter", &water_data(1.0)).unwrap();
+        let output = metrics.gather().unwrap();
+
+        assert!(output.contains("homewizard_water_cumulative_total_m3{device=\"meter\"} 101.5"));
+    }
+
+    #[test]
+    fn test_metrics_meter_reset_across_restart() {
+        let metrics = Metrics::new().unwrap().with_cumulative_total(true);
+        metrics.update("meter", &water_data(100.0)).unwrap();
+        let snapshot = metrics.snapshot();
+
+        // The meter was replaced while the exporter was down
+        let restarted = Metrics::new().unwrap().with_cumulative_total(true);
+        restarted.restore(&snapshot);
+        restarted.update("meter", &water_data(2.0)).unwrap();
+        let output = restarted.gather().unwrap();
+
+        assert!(output.contains("homewizard_water_meter_resets_total{device=\"meter\"} 1"));
+        assert!(output.contains("homewizard_water_cumulative_total_m3{device=\"meter\"} 102"));
+    }
+
+    #[test]
+    fn test_metrics_snapshot_restores_counters() {
+        let metrics = Metrics::new().unwrap();
+        metrics.update("meter", &create_test_data()).unwrap();
+        metrics.record_poll_success("meter");
+        metrics.record_poll_success("meter");
+        metrics.record_poll_failure("meter", "timeout", false);
+        let snapshot = metrics.snapshot();
+
+        let restarted = Metrics::new().unwrap();
+        restarted.restore(&snapshot);
+        restarted.record_poll_success("meter");
+        let output = restarted.gather().unwrap();
+
+        assert!(output.contains("homewizard_water_total_m3{device=\"meter\"} 1234.567"));
+        assert!(output.contains("homewizard_exporter_scrapes_total{device=\"meter\"} 4"));
+        assert!(output.contains(
+            "homewizard_exporter_scrape_errors_total{device=\"meter\",reason=\"timeout\"} 1"
+        ));
+    }
+
+    #[test]
+    fn test_metrics_device_info_replaced_on_firmware_update() {
+        let metrics = Metrics::new().unwrap();
+        let mut info = HomeWizardDeviceInfo {
+            product_type: "HWE-WTR".to_string(),
+            product_name: "Watermeter".to_string(),
+            serial: "5c2fafabcdef".to_string(),
+            firmware_version: "2.03".to_string(),
+            api_version: "v1".to_string(),
+        };
+
+        metrics.set_device_info("meter", &info);
+        info.firmware_version = "2.05".to_string();
+        metrics.set_device_info("meter", &info);
+        let output = metrics.gather().unwrap();
+
+        assert!(output.contains(
+            "homewizard_water_device_info{api_version=\"v1\",device=\"meter\",firmware_version=\"2.05\",product_name=\"Watermeter\",product_type=\"HWE-WTR\",serial=\"5c2fafabcdef\"} 1"
+        ));
+        assert!(!output.contains("firmware_version=\"2.03\""));
+    }
+
+    #[test]
+    fn test_metrics_serial_only_on_info() {
+        let metrics = Metrics::new().unwrap();
+        metrics.update("kitchen", &create_test_data()).unwrap();
+        let before = metrics.gather().unwrap();
+
+        metrics.set_device_info(
+            "kitchen",
+            &HomeWizardDeviceInfo {
+                product_type: "HWE-WTR".to_string(),
+                product_name: "Watermeter".to_string(),
+                serial: "5c2fafabcdef".to_string(),
+                firmware_version: "2.03".to_string(),
+                api_version: "v1".to_string(),
+            },
+        );
+        let output = metrics.gather().unwrap();
+        // The reading series keep their labels once the device info is known
+        assert!(before.contains("homewizard_water_total_m3{device=\"kitchen\"} 1234.567"));
+        assert!(output.contains("homewizard_water_total_m3{device=\"kitchen\"} 1234.567"));
+        let serials: Vec<_> = output
+            .lines()
+            .filter(|line| line.contains("serial="))
+            .collect();
+        assert_eq!(serials.len(), 1);
+        assert!(serials[0].starts_with("homewizard_water_device_info{"));
+
+        metrics.remove_device("kitchen");
+        assert!(!metrics.gather().unwrap().contains("serial="));
+    }
+
+    #[test]
+    fn test_metrics_update_p1() {
+        let metrics = Metrics::new().unwrap();
+        let data = HomeWizardP1Data {
+            total_power_import_kwh: 13779.338,
+            total_power_export_kwh: Some(12.5),
+            active_power_w: Some(-543.0),
+            active_voltage_l1_v: Some(230.1),
+            total_gas_m3: Some(2569.646),
+            ..Default::default()
+        };
+
+        metrics.update_reading("p1", &Reading::P1(data)).unwrap();
+        let output = metrics.gather().unwrap();
+
+        assert!(output.contains("homewizard_p1_energy_import_kwh{device=\"p1\"} 13779.338"));
+        assert!(output.contains("homewizard_p1_energy_export_kwh{device=\"p1\"} 12.5"));
+        assert!(output.contains("homewizard_p1_active_power_w{device=\"p1\"} -543"));
+        assert!(output.contains("homewizard_p1_voltage_v{device=\"p1\",phase=\"l1\"} 230.1"));
+        assert!(!output.contains("phase=\"l2\""));
+        assert!(output.contains("homewizard_p1_gas_m3{device=\"p1\"} 2569.646"));
+        // A P1 meter has no water series
+        assert!(!output.contains("homewizard_water_total_m3{"));
+
+        // A meter leaving out the export total gets no export series, not a zero
+        let data = HomeWizardP1Data {
+            total_power_import_kwh: 100.0,
+            ..Default::default()
+        };
+        metrics.update_reading("grid", &Reading::P1(data)).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_p1_energy_import_kwh{device=\"grid\"} 100"));
+        assert!(!output.contains("homewizard_p1_energy_export_kwh{device=\"grid\"}"));
+    }
+
+    #[test]
+    fn test_metrics_update_energy_socket() {
+        let metrics = Metrics::new().unwrap();
+        let data = HomeWizardEnergySocketData {
+            wifi_strength: Some(92.0),
+            total_power_import_kwh: 30.511,
+            active_power_w: Some(543.2),
+            power_on: true,
+            ..Default::default()
+        };
+
+        metrics
+            .update_reading("heater", &Reading::EnergySocket(data.clone()))
+            .unwrap();
+        let output = metrics.gather().unwrap();
+
+        assert!(
+            output.contains("homewizard_energy_socket_energy_import_kwh{device=\"heater\"} 30.511")
+        );
+        assert!(
+            output.contains("homewizard_energy_socket_active_power_w{device=\"heater\"} 543.2")
+        );
+        assert!(output.contains("homewizard_energy_socket_switch_state{device=\"heater\"} 1"));
+        assert!(output.contains("homewizard_water_wifi_strength_percent{device=\"heater\"} 92"));
+        // No export total reported, so no export series
+        assert!(!output.contains("homewizard_energy_socket_energy_export_kwh{"));
+
+        let switched_off = HomeWizardEnergySocketData {
+            power_on: false,
+            ..data
+        };
+        metrics
+            .update_reading("heater", &Reading::EnergySocket(switched_off))
+            .unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_energy_socket_switch_state{device=\"heater\"} 0"));
+    }
+
+    #[test]
+    fn test_metrics_update_kwh() {
+        let metrics = Metrics::new().unwrap();
+        let data = HomeWizardKwhData {
+            total_power_import_kwh: 1200.5,
+            active_power_w: Some(700.0),
+            active_power_l1_w: Some(300.0),
+            active_power_l2_w: Some(400.0),
+            active_voltage_l1_v: Some(230.0),
+            active_voltage_l2_v: Some(231.0),
+            active_current_l2_a: Some(1.7),
+            ..Default::default()
+        };
+
+        metrics
+            .update_reading("heatpump", &Reading::Kwh(data))
+            .unwrap();
+        let output = metrics.gather().unwrap();
+
+        assert!(output.contains("homewizard_kwh_energy_import_kwh{device=\"heatpump\"} 1200.5"));
+        assert!(output.contains("homewizard_kwh_active_power_w{device=\"heatpump\"} 700"));
+        assert!(
+            output.contains("homewizard_kwh_phase_power_w{device=\"heatpump\",phase=\"l2\"} 400")
+        );
+        assert!(output.contains("homewizard_kwh_voltage_v{device=\"heatpump\",phase=\"l1\"} 230"));
+        assert!(output.contains("homewizard_kwh_current_a{device=\"heatpump\",phase=\"l2\"} 1.7"));
+        assert!(!output.contains("homewizard_kwh_current_a{device=\"heatpump\",phase=\"l1\"}"));
+        assert!(!output.contains("phase=\"l3\""));
+        // No export total reported, so no export series
+        assert!(!output.contains("homewizard_kwh_energy_export_kwh{"));
+    }
+
+    #[test]
+    fn test_metrics_update_without_flow() {
+        let metrics = Metrics::new().unwrap();
+        metrics.update("battery", &create_test_data()).unwrap();
+
+        let data = HomeWizardWaterData {
+            wifi_ssid: String::new(),
+            wifi_strength: None,
+            active_liter_lpm: None,
+            ..create_test_data()
+        };
+        metrics.update("battery", &data).unwrap();
+        let output = metrics.gather().unwrap();
+
+        assert!(!output.contains("homewizard_water_active_flow_lpm{"));
+        assert!(output.contains("homewizard_water_total_m3{device=\"battery\"} 1234.567"));
+        // The last known signal strength and SSID are kept
+        assert!(output.contains("homewizard_water_wifi_strength_percent{device=\"battery\"} 75.5"));
+        assert!(output.contains("wifi_ssid=\"TestNetwork\""));
+    }
+
+    #[test]
+    fn test_poll_self_metrics() {
+        let metrics = Metrics::new().unwrap();
+
+        metrics.record_poll_success("kitchen");
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_up{device=\"kitchen\"} 1"));
+        assert!(output.contains("homewizard_exporter_scrapes_total{device=\"kitchen\"} 1"));
+
+        metrics.record_poll_failure("kitchen", "timeout", false);
+        metrics.record_poll_failure("kitchen", "timeout", false);
+        metrics.record_poll_failure("kitchen", "connect", true);
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_up{device=\"kitchen\"} 1"));
+        assert!(output.contains("homewizard_exporter_scrapes_total{device=\"kitchen\"} 4"));
+        assert!(output.contains(
+            "homewizard_exporter_scrape_errors_total{device=\"kitchen\",reason=\"timeout\"} 2"
+        ));
+        assert!(output.contains(
+            "homewizard_exporter_scrape_errors_total{device=\"kitchen\",reason=\"connect\"} 1"
+        ));
+
+        metrics.record_poll_failure("kitchen", "timeout", false);
+        assert!(
+            metrics
+                .gather()
+                .unwrap()
+                .contains("homewizard_up{device=\"kitchen\"} 0")
+        );
+    }
+
+    #[test]
+    fn test_poll_duration_and_last_success() {
+        let metrics = Metrics::new().unwrap();
+
+        metrics.record_fetch_attempts("kitchen", 1);
+        metrics.record_fetch_attempts("kitchen", 3);
+        metrics.observe_poll_duration("kitchen", Duration::from_millis(40));
+        metrics.observe_poll_duration("kitchen", Duration::from_secs(5));
+        metrics.record_poll_success("kitchen");
+        let output = metrics.gather().unwrap();
+
+        assert!(output.contains(
+            "homewizard_exporter_poll_duration_seconds_bucket{device=\"kitchen\",le=\"0.05\"} 1"
+        ));
+        assert!(
+            output
+                .contains("homewizard_exporter_poll_duration_seconds_count{device=\"kitchen\"} 2")
+        );
+        assert!(output.contains("homewizard_exporter_fetch_attempts_total{device=\"kitchen\"} 4"));
+
+        let timestamp = metrics
+            .last_successful_poll
+            .with_label_values(&["kitchen"])
+            .get();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+        assert!((now - timestamp).abs() < 60.0);
+    }
+
+    #[test]
+    fn test_extra_metric_named_like_another_metric() {
+        let extra = ExtraMetric {
+            field: "total".to_string(),
+            metric: "homewizard_water_total_m3".to_string(),
+            kind: crate::collector::ExtraMetricKind::Counter,
+        };
+        assert!(
+            Metrics::new()
+                .unwrap()
+                .with_extra_metrics(&[extra])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_cloud_enabled_gauge() {
+        let metrics = Metrics::new().unwrap();
+
+        metrics.set_cloud_enabled("kitchen", false);
+        assert!(
+            metrics
+                .gather()
+                .unwrap()
+                .contains("homewizard_water_cloud_enabled{device=\"kitchen\"} 0")
+        );
+
+        metrics.remove_device("kitchen");
+        assert!(
+            !metrics
+                .gather()
+                .unwrap()
+                .contains("homewizard_water_cloud_enabled")
+        );
+    }
+
+    #[test]
+    fn test_breaker_open_gauge() {
+        let metrics = Metrics::new().unwrap();
+
+        metrics.set_breaker_open("kitchen", true);
+        assert!(
+            metrics
+                .gather()
+                .unwrap()
+                .contains("homewizard_exporter_circuit_breaker_open{device=\"kitchen\"} 1")
+        );
+
+        metrics.set_breaker_open("kitchen", false);
+        assert!(
+            metrics
+                .gather()
+                .unwrap()
+                .contains("homewizard_exporter_circuit_breaker_open{device=\"kitchen\"} 0")
+        );
+    }
+
+    #[test]
+    fn test_mark_stale_drops_readings() {
+        let metrics = Metrics::new().unwrap();
+        metrics.update("kitchen", &create_test_data()).unwrap();
+        metrics.update("garden", &create_test_data()).unwrap();
+        metrics.set_source("kitchen", DataSource::Local);
+        metrics.record_poll_success("kitchen");
+
+        metrics.mark_stale("kitchen");
+        let output = metrics.gather().unwrap();
+
+        assert!(!output.contains("homewizard_water_total_m3{device=\"kitchen\"}"));
+        assert!(!output.contains("homewizard_water_active_flow_lpm{device=\"kitchen\"}"));
+        assert!(!output.contains("homewizard_water_meter_info{device=\"kitchen\""));
+        assert!(!output.contains("homewizard_water_data_source{device=\"kitchen\""));
+        assert!(output.contains("homewizard_exporter_stale{device=\"kitchen\"} 1"));
+        assert!(output.contains("homewizard_up{device=\"kitchen\"} 1"));
+        // Other devices are untouched
+        assert!(output.contains("homewizard_water_total_m3{device=\"garden\"} 1234.567"));
+
+        metrics.update("kitchen", &create_test_data()).unwrap();
+        metrics.record_poll_success("kitchen");
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_water_total_m3{device=\"kitchen\"} 1234.567"));
+        assert!(output.contains("homewizard_exporter_stale{device=\"kitchen\"} 0"));
     }
 }
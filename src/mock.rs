@@ -0,0 +1,399 @@
+use crate::homewizard::{
+    DeviceType, HomeWizardEnergySocketData, HomeWizardKwhData, HomeWizardP1Data,
+    HomeWizardWaterData, Reading,
+};
+use crate::simulate::Simulator;
+use anyhow::{Context, Result, bail};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde_json::{Map, Value, json};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// One scripted answer of the mock device.
+///
+/// Written as a JSON object: `status` and `delay_ms` shape the response, every
+/// other field is merged into the data the device reports.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Step {
+    pub data: Map<String, Value>,
+    pub status: Option<u16>,
+    pub delay: Option<Duration>,
+    /// Answer this many requests the same way
+    pub repeat: u32,
+}
+
+impl Step {
+    pub fn from_json(value: Value) -> Result<Self> {
+        let Value::Object(mut data) = value else {
+            bail!("a step must be a JSON object, got {value}");
+        };
+        let mut number = |field: &str| -> Result<Option<u64>> {
+            match data.remove(field) {
+                None => Ok(None),
+                Some(value) => value
+                    .as_u64()
+                    .map(Some)
+                    .with_context(|| format!("`{field}` must be a positive integer")),
+            }
+        };
+        let status = number("status")?
+            .map(|status| {
+                StatusCode::from_u16(status as u16)
+                    .map(|_| status as u16)
+                    .with_context(|| format!("invalid HTTP status {status}"))
+            })
+            .transpose()?;
+        let delay = number("delay_ms")?.map(Duration::from_millis);
+        let repeat = number("repeat")?.unwrap_or(1).max(1) as u32;
+        Ok(Self {
+            data,
+            status,
+            delay,
+            repeat,
+        })
+    }
+}
+
+/// Parse a script: a JSON array of steps, or one step per line.
+pub fn parse_script(text: &str) -> Result<Vec<Step>> {
+    let values: Vec<Value> = match serde_json::from_str(text) {
+        Ok(Value::Array(values)) => values,
+        Ok(value) => vec![value],
+        Err(_) => text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(number, line)| {
+                serde_json::from_str(line).with_context(|| format!("line {}", number + 1))
+            })
+            .collect::<Result<_>>()?,
+    };
+    values.into_iter().map(Step::from_json).collect()
+}
+
+pub fn load_script(path: &Path) -> Result<Vec<Step>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    parse_script(&text).with_context(|| format!("invalid script {}", path.display()))
+}
+
+/// Data a device of this type reports when nothing else is scripted.
+fn default_data(device_type: DeviceType) -> Map<String, Value> {
+    let reading = match device_type {
+        DeviceType::Water => Reading::Water(HomeWizardWaterData {
+            wifi_ssid: "Mock".to_string(),
+            wifi_strength: Some(100.0),
+            total_liter_m3: 123.456,
+            active_liter_lpm: Some(0.0),
+            ..Default::default()
+        }),
+        DeviceType::P1 => Reading::P1(HomeWizardP1Data {
+            wifi_ssid: Some("Mock".to_string()),
+            wifi_strength: Some(100.0),
+            total_power_import_kwh: 1234.567,
+            total_power_export_kwh: Some(123.456),
+            active_power_w: Some(350.0),
+            active_voltage_l1_v: Some(230.0),
+            total_gas_m3: Some(456.789),
+            ..Default::default()
+        }),
+        DeviceType::EnergySocket => Reading::EnergySocket(HomeWizardEnergySocketData {
+            wifi_ssid: Some("Mock".to_string()),
+            wifi_strength: Some(100.0),
+            total_power_import_kwh: 12.345,
+            active_power_w: Some(60.0),
+            power_on: true,
+            ..Default::default()
+        }),
+        DeviceType::Kwh => Reading::Kwh(HomeWizardKwhData {
+            wifi_ssid: Some("Mock".to_string()),
+            wifi_strength: Some(100.0),
+            total_power_import_kwh: 234.567,
+            total_power_export_kwh: Some(0.0),
+            active_power_w: Some(500.0),
+            active_voltage_v: Some(230.0),
+            active_current_a: Some(2.17),
+            ..Default::default()
+        }),
+    };
+    match serde_json::to_value(reading) {
+        Ok(Value::Object(data)) => data,
+        _ => Map::new(),
+    }
+}
+
+struct MockState {
+    /// What the device reports; script steps are merged into it
+    data: Map<String, Value>,
+    script: VecDeque<Step>,
+    /// Steps to start over with when the script runs out, with `--loop`
+    looped: Option<Vec<Step>>,
+    requests: u64,
+}
+
+/// A fake HomeWizard device answering the local API v1 (`mock-device`).
+///
+/// Each data request takes the next step of the script; once it runs out the
+/// device keeps reporting the last data. `PUT /mock` replaces the script with a
+/// single step and `GET /mock` tells how many requests were answered.
+pub struct MockDevice {
+    device_type: DeviceType,
+    simulator: Option<Simulator>,
+    state: Mutex<MockState>,
+}
+
+impl MockDevice {
+    pub fn new(device_type: DeviceType) -> Self {
+        Self {
+            device_type,
+            simulator: None,
+            state: Mutex::new(MockState {
+                data: default_data(device_type),
+                script: VecDeque::new(),
+                looped: None,
+                requests: 0,
+            }),
+        }
+    }
+
+    /// Answer with `script`, from the first step on; with `repeat`, start over at the end.
+    pub fn with_script(self, script: Vec<Step>, repeat: bool) -> Self {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.looped = repeat.then(|| script.clone());
+            state.script = script.into();
+        }
+        self
+    }
+
+    /// Report simulated readings, with script steps merged on top (`--simulate`).
+    pub fn with_simulator(mut self, simulator: Simulator) -> Self {
+        self.simulator = Some(simulator);
+        self
+    }
+
+    /// The next answer to a data request.
+    fn next(&self) -> Step {
+        let mut state = self.state.lock().unwrap();
+        state.requests += 1;
+        if state.script.is_empty()
+            && let Some(looped) = &state.looped
+        {
+            state.script = looped.clone().into();
+        }
+
+        let mut step = match state.script.front_mut() {
+            Some(step) if step.repeat > 1 => {
+                step.repeat -= 1;
+                step.clone()
+            }
+            Some(_) => state.script.pop_front().unwrap_or_default(),
+            None => Step::default(),
+        };
+
+        if let Some(simulator) = &self.simulator
+            && let Ok(Value::Object(data)) =
+                serde_json::to_value(simulator.reading(self.device_type))
+        {
+            state.data.extend(data);
+        }
+        // Failed answers leave the data alone, as a device would
+        if step.status.is_none_or(|status| status < 400) {
+            let data = std::mem::take(&mut step.data);
+            state.data.extend(data);
+            step.data = state.data.clone();
+        }
+        step
+    }
+
+    fn set(&self, step: Step) {
+        let mut state = self.state.lock().unwrap();
+        state.script = VecDeque::from([step]);
+        state.looped = None;
+    }
+
+    fn info(&self) -> Value {
+        let (product_type, product_name) = match self.device_type {
+            DeviceType::Water => ("HWE-WTR", "Watermeter"),
+            DeviceType::P1 => ("HWE-P1", "P1 Meter"),
+            DeviceType::EnergySocket => ("HWE-SKT", "Energy Socket"),
+            DeviceType::Kwh => ("HWE-KWH1", "kWh meter"),
+        };
+        json!({
+            "product_type": product_type,
+            "product_name": format!("{product_name} (mock)"),
+            "serial": "5c2fafe00000",
+            "firmware_version": "mock",
+            "api_version": "v1",
+        })
+    }
+}
+
+/// Routes of the mock device.
+pub fn router(device: Arc<MockDevice>) -> Router {
+    Router::new()
+        .route("/api", get(info_handler))
+        .route("/api/v1/data", get(data_handler))
+        .route("/api/v1/state", get(state_handler))
+        .route("/mock", get(mock_handler).put(set_handler))
+        .with_state(device)
+}
+
+async fn info_handler(State(device): State<Arc<MockDevice>>) -> Json<Value> {
+    Json(device.info())
+}
+
+async fn data_handler(State(device): State<Arc<MockDevice>>) -> Response {
+    let step = device.next();
+    if let Some(delay) = step.delay {
+        tokio::time::sleep(delay).await;
+    }
+    let status = step
+        .status
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .unwrap_or(StatusCode::OK);
+    if status.is_success() {
+        (status, Json(Value::Object(step.data))).into_response()
+    } else {
+        status.into_response()
+    }
+}
+
+async fn state_handler(State(device): State<Arc<MockDevice>>) -> Json<Value> {
+    let state = device.state.lock().unwrap();
+    let power_on = state
+        .data
+        .get("power_on")
+        .and_then(Value::as_bool)
+        .unwrap_or(true);
+    Json(json!({"power_on": power_on, "switch_lock": false, "brightness": 255}))
+}
+
+async fn mock_handler(State(device): State<Arc<MockDevice>>) -> Json<Value> {
+    let state = device.state.lock().unwrap();
+    Json(json!({
+        "requests": state.requests,
+        "remaining_steps": state.script.len(),
+        "data": state.data,
+    }))
+}
+
+async fn set_handler(
+    State(device): State<Arc<MockDevice>>,
+    Json(value): Json<Value>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let step = Step::from_json(value).map_err(|e| (StatusCode::BAD_REQUEST, format!("{e:#}")))?;
+    device.set(step);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::homewizard::HomeWizardClient;
+    use tokio::net::TcpListener;
+
+    async fn start(device: MockDevice) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router(Arc::new(device))).await });
+        format!("http://{address}")
+    }
+
+    fn client(url: &str) -> HomeWizardClient {
+        HomeWizardClient::new(format!("{url}/api/v1/data"), Duration::from_secs(5)).unwrap()
+    }
+
+    #[test]
+    fn test_parse_script() {
+        let steps = parse_script(
+            "{\"total_liter_m3\": 1.5}\n\n{\"status\": 503, \"repeat\": 2}\n{\"delay_ms\": 10}\n",
+        )
+        .unwrap();
+        assert_eq!(steps.len(), 3);
+        assert_eq!(steps[0].data["total_liter_m3"], 1.5);
+        assert_eq!(steps[1].status, Some(503));
+        assert_eq!(steps[1].repeat, 2);
+        assert_eq!(steps[2].delay, Some(Duration::from_millis(10)));
+
+        assert_eq!(
+            parse_script("[{\"active_liter_lpm\": 3}, {}]")
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(parse_script("{\"status\": 1000}").is_err());
+        assert!(parse_script("[1]").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_scripted_device() {
+        let script = parse_script(
+            r#"[{"total_liter_m3": 10.0, "active_liter_lpm": 4.5}, {"status": 503}, {"total_liter_m3": 10.25}]"#,
+        )
+        .unwrap();
+        let url = start(MockDevice::new(DeviceType::Water).with_script(script, false)).await;
+        let client = client(&url);
+
+        let data = client.fetch_data().await.unwrap();
+        assert_eq!(data.total_liter_m3, 10.0);
+        assert_eq!(data.active_liter_lpm, Some(4.5));
+        assert_eq!(data.wifi_ssid, "Mock");
+        assert!(client.fetch_data().await.is_err());
+        let data = client.fetch_data().await.unwrap();
+        assert_eq!(data.total_liter_m3, 10.25);
+        // Fields not in the step keep their last value
+        assert_eq!(data.active_liter_lpm, Some(4.5));
+        // The last data stays once the script ran out
+        assert_eq!(client.fetch_data().await.unwrap().total_liter_m3, 10.25);
+
+        let info = client.fetch_device_info().await.unwrap();
+        assert_eq!(info.product_type, "HWE-WTR");
+
+        let http = reqwest::Client::new();
+        let response = http
+            .put(format!("{url}/mock"))
+            .json(&json!({"total_liter_m3": 11.0}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(client.fetch_data().await.unwrap().total_liter_m3, 11.0);
+
+        let status: Value = http
+            .get(format!("{url}/mock"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(status["requests"], 5);
+    }
+
+    #[tokio::test]
+    async fn test_looped_script_and_device_types() {
+        let script =
+            parse_script(r#"[{"active_power_w": 100.0}, {"active_power_w": 200.0}]"#).unwrap();
+        let url = start(MockDevice::new(DeviceType::EnergySocket).with_script(script, true)).await;
+        let client = client(&url).with_device_type(DeviceType::EnergySocket);
+
+        let mut powers = Vec::new();
+        for _ in 0..3 {
+            match client.fetch_reading().await.unwrap() {
+                Reading::EnergySocket(data) => {
+                    assert!(data.power_on);
+                    powers.push(data.active_power_w);
+                }
+                other => panic!("unexpected reading {other:?}"),
+            }
+        }
+        assert_eq!(powers, [Some(100.0), Some(200.0), Some(100.0)]);
+    }
+}
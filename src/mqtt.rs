@@ -0,0 +1,673 @@
+use crate::homeassistant::Discovery;
+use crate::readings::TimedReading;
+use crate::tls::{self, ClientStream};
+use anyhow::{Context, Result, bail};
+use std::collections::VecDeque;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc};
+use tokio_rustls::TlsConnector;
+use tracing::{debug, info, warn};
+
+/// Messages waiting to be published; newer ones are dropped while it is full.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// How long the broker gets to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Waits between reconnection attempts, doubling up to the maximum.
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(60);
+
+/// Largest packet accepted from the broker, which only sends acknowledgements.
+const MAX_PACKET_LEN: usize = 64 * 1024;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+const DISCONNECT: u8 = 0xe0;
+
+/// Payloads of the availability topic.
+pub const ONLINE: &str = "online";
+pub const OFFLINE: &str = "offline";
+
+/// A message to publish. QoS 0 and 1 are supported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: u8,
+    pub retain: bool,
+}
+
+/// Where and how to connect (`--mqtt-url` and friends).
+#[derive(Clone)]
+pub struct MqttOptions {
+    pub host: String,
+    pub port: u16,
+    /// Set for `mqtts://` URLs
+    pub tls: Option<TlsConnector>,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub keep_alive: Duration,
+    /// Retained topic set to `online` on connecting and `offline` (by the broker,
+    /// as the will) when the connection is lost
+    pub availability_topic: Option<String>,
+}
+
+impl MqttOptions {
+    /// Options for a `mqtt://host[:1883]` or `mqtts://host[:8883]` URL; `ca_file`
+    /// replaces the public CAs for `mqtts`.
+    pub fn from_url(url: &str, ca_file: Option<&Path>) -> Result<Self> {
+        let parsed = reqwest::Url::parse(url).context("invalid MQTT URL")?;
+        let (tls, default_port) = match parsed.scheme() {
+            "mqtt" | "tcp" => (None, 1883),
+            "mqtts" | "ssl" => (Some(crate::tls::connector(ca_file)?), 8883),
+            scheme => bail!("unsupported MQTT URL scheme `{scheme}`, use mqtt:// or mqtts://"),
+        };
+        let host = parsed
+            .host_str()
+            .filter(|host| !host.is_empty())
+            .context("MQTT URL has no host")?;
+        Ok(Self {
+            // IPv6 addresses come bracketed
+            host: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            port: parsed.port().unwrap_or(default_port),
+            tls,
+            client_id: format!("homewizard-water-exporter-{:08x}", fastrand::u32(..)),
+            username: None,
+            password: None,
+            keep_alive: Duration::from_secs(30),
+            availability_topic: None,
+        })
+    }
+}
+
+/// The topic of a device's readings: `{device}` and `{type}` in `template` are replaced.
+pub fn topic(template: &str, device: &str, device_type: &str) -> String {
+    // Wildcards and separators in a device name would change the topic structure
+    let device: String = device
+        .chars()
+        .map(|c| if matches!(c, '/' | '+' | '#') { '_' } else { c })
+        .collect();
+    template
+        .replace("{device}", &device)
+        .replace("{type}", device_type)
+}
+
+/// Messages published again on every connection, so a broker that lost its
+/// retained messages gets them back.
+type OnConnect = Arc<Mutex<Vec<Message>>>;
+
+/// Publishes messages to an MQTT 3.1.1 broker from a background task, reconnecting
+/// as needed. QoS 1 messages not acknowledged before a disconnect are sent again.
+#[derive(Clone)]
+pub struct MqttClient {
+    queue: mpsc::Sender<Message>,
+    on_connect: OnConnect,
+}
+
+impl MqttClient {
+    pub fn start(options: MqttOptions) -> Self {
+        let (queue, messages) = mpsc::channel(QUEUE_CAPACITY);
+        let on_connect = OnConnect::default();
+        if let Some(topic) = &options.availability_topic {
+            on_connect.lock().unwrap().push(Message {
+                topic: topic.clone(),
+                payload: ONLINE.into(),
+                qos: 1,
+                retain: true,
+            });
+        }
+        tokio::spawn(run(options, messages, on_connect.clone()));
+        Self { queue, on_connect }
+    }
+
+    pub fn publish(&self, message: Message) {
+        if self.queue.try_send(message).is_err() {
+            warn!("MQTT queue is full, dropping a message");
+        }
+    }
+
+    /// Publish `message` now and again after every reconnection, replacing an
+    /// earlier one on the same topic.
+    pub fn publish_on_connect(&self, message: Message) {
+        {
+            let mut on_connect = self.on_connect.lock().unwrap();
+            on_connect.retain(|m| m.topic != message.topic);
+            on_connect.push(message.clone());
+        }
+        self.publish(message);
+    }
+
+    /// Publish every recorded reading as JSON to its device's topic, preceded by
+    /// the device's Home Assistant `discovery` configuration.
+    pub fn publish_readings(
+        &self,
+        mut readings: broadcast::Receiver<TimedReading>,
+        topic_template: String,
+        qos: u8,
+        retain: bool,
+        discovery: Option<Discovery>,
+    ) {
+        let client = self.clone();
+        tokio::spawn(async move {
+            let mut announced = std::collections::HashSet::new();
+            loop {
+                match readings.recv().await {
+                    Ok(reading) => match serde_json::to_vec(&reading) {
+                        Ok(payload) => {
+                            let topic =
+                                topic(&topic_template, &reading.device, reading.device_type);
+                            if let Some(discovery) = &discovery
+                                && announced.insert((reading.device.clone(), reading.device_type))
+                            {
+                                for message in
+                                    discovery.messages(&reading.device, &reading.reading, &topic)
+                                {
+                                    client.publish_on_connect(message);
+                                }
+                            }
+                            client.publish(Message {
+                                topic,
+                                payload,
+                                qos,
+                                retain,
+                            })
+                        }
+                        Err(e) => warn!("Failed to serialize reading for MQTT: {}", e),
+                    },
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("MQTT publisher fell behind, skipped {} readings", skipped)
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+}
+
+/// Connect, publish until the connection fails, and start over.
+async fn run(options: MqttOptions, mut messages: mpsc::Receiver<Message>, on_connect: OnConnect) {
+    let mut session = Session::default();
+    let mut delay = RECONNECT_MIN;
+    loop {
+        let result = match connect(&options).await {
+            Ok(stream) => {
+                info!("Connected to MQTT broker {}:{}", options.host, options.port);
+                delay = RECONNECT_MIN;
+                session
+                    .run(stream, &options, &mut messages, &on_connect)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => return,
+            Err(e) => warn!(
+                "MQTT connection to {}:{} failed, retrying in {:?}: {:#}",
+                options.host, options.port, delay, e
+            ),
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(RECONNECT_MAX);
+    }
+}
+
+async fn connect(options: &MqttOptions) -> Result<Box<dyn ClientStream>> {
+    tls::connect(
+        &options.host,
+        options.port,
+        options.tls.as_ref(),
+        CONNECT_TIMEOUT,
+    )
+    .await
+}
+
+/// State that outlives a connection.
+#[derive(Default)]
+struct Session {
+    last_packet_id: u16,
+    /// QoS 1 messages sent but not acknowledged yet
+    unacked: VecDeque<(u16, Message)>,
+}
+
+impl Session {
+    fn next_packet_id(&mut self) -> u16 {
+        // 0 is not a valid packet identifier
+        self.last_packet_id = self.last_packet_id.checked_add(1).unwrap_or(1);
+        self.last_packet_id
+    }
+
+    /// Write a PUBLISH, keeping QoS 1 messages until they are acknowledged.
+    async fn publish<W: AsyncWrite + Unpin>(
+        &mut self,
+        writer: &mut W,
+        message: Message,
+    ) -> io::Result<()> {
+        let id = (message.qos > 0).then(|| self.next_packet_id());
+        let packet = encode_publish(&message, id, false);
+        if let Some(id) = id {
+            self.unacked.push_back((id, message));
+        }
+        writer.write_all(&packet).await
+    }
+
+    /// Publish from `messages` over `stream`; returns `Ok` once the queue is closed.
+    async fn run(
+        &mut self,
+        stream: Box<dyn ClientStream>,
+        options: &MqttOptions,
+        messages: &mut mpsc::Receiver<Message>,
+        on_connect: &OnConnect,
+    ) -> Result<()> {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        writer.write_all(&encode_connect(options)).await?;
+        let (kind, body) = tokio::time::timeout(CONNECT_TIMEOUT, read_packet(&mut reader))
+            .await
+            .context("no CONNACK from the broker")??;
+        if kind != CONNACK || body.len() != 2 {
+            bail!("expected CONNACK, got packet type {:#x}", kind);
+        }
+        if body[1] != 0 {
+            bail!("broker refused the connection: {}", connack_error(body[1]));
+        }
+
+        let on_connect = on_connect.lock().unwrap().clone();
+        // Those are published again below anyway
+        self.unacked
+            .retain(|(_, message)| !on_connect.contains(message));
+        for (id, message) in &self.unacked {
+            writer
+                .write_all(&encode_publish(message, Some(*id), true))
+                .await?;
+        }
+        for message in on_connect {
+            self.publish(&mut writer, message).await?;
+        }
+
+        // Reading a packet is not cancel safe, so it gets its own task
+        let (packets_tx, mut packets) = mpsc::channel(8);
+        let reader_task = tokio::spawn(async move {
+            loop {
+                let packet = read_packet(&mut reader).await;
+                let failed = packet.is_err();
+                if packets_tx.send(packet).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        let mut pings = tokio::time::interval_at(
+            tokio::time::Instant::now() + options.keep_alive,
+            options.keep_alive,
+        );
+        let mut answered = true;
+        let result = loop {
+            tokio::select! {
+                message = messages.recv() => {
+                    let Some(message) = message else {
+                        // The broker drops the will on a clean disconnect
+                        if let Some(topic) = &options.availability_topic {
+                            let offline = Message {
+                                topic: topic.clone(),
+                                payload: OFFLINE.into(),
+                                qos: 0,
+                                retain: true,
+                            };
+                            let _ = writer.write_all(&encode_publish(&offline, None, false)).await;
+                        }
+                        let _ = writer.write_all(&[DISCONNECT, 0]).await;
+                        break Ok(());
+                    };
+                    if let Err(e) = self.publish(&mut writer, message).await {
+                        break Err(e.into());
+                    }
+                }
+                packet = packets.recv() => match packet {
+                    Some(Ok((PUBACK, body))) if body.len() == 2 => {
+                        let id = u16::from_be_bytes([body[0], body[1]]);
+                        self.unacked.retain(|(unacked, _)| *unacked != id);
+                    }
+                    Some(Ok((PINGRESP, _))) => answered = true,
+                    Some(Ok((kind, _))) => debug!("Ignoring MQTT packet type {:#x}", kind),
+                    Some(Err(e)) => break Err(e.into()),
+                    None => break Err(anyhow::anyhow!("connection closed")),
+                },
+                _ = pings.tick() => {
+                    if !answered {
+                        break Err(anyhow::anyhow!("broker stopped answering pings"));
+                    }
+                    answered = false;
+                    if let Err(e) = writer.write_all(&[PINGREQ, 0]).await {
+                        break Err(e.into());
+                    }
+                }
+            }
+        };
+        reader_task.abort();
+        result
+    }
+}
+
+fn connack_error(code: u8) -> &'static str {
+    match code {
+        1 => "unacceptable protocol version",
+        2 => "client identifier rejected",
+        3 => "server unavailable",
+        4 => "bad user name or password",
+        5 => "not authorized",
+        _ => "unknown reason",
+    }
+}
+
+fn put_string(buf: &mut Vec<u8>, value: &[u8]) {
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+}
+
+/// A packet with its fixed header: the type and flags byte, then the remaining length.
+fn packet(first: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![first];
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn encode_connect(options: &MqttOptions) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_string(&mut body, b"MQTT");
+    body.push(4); // protocol level 3.1.1
+    let mut flags = 0x02; // clean session
+    if options.availability_topic.is_some() {
+        flags |= 0x04 | 0x08 | 0x20; // will, with QoS 1, retained
+    }
+    if options.username.is_some() {
+        flags |= 0x80;
+    }
+    if options.password.is_some() {
+        flags |= 0x40;
+    }
+    body.push(flags);
+    body.extend_from_slice(
+        &(options.keep_alive.as_secs().min(u16::MAX.into()) as u16).to_be_bytes(),
+    );
+    put_string(&mut body, options.client_id.as_bytes());
+    if let Some(topic) = &options.availability_topic {
+        put_string(&mut body, topic.as_bytes());
+        put_string(&mut body, OFFLINE.as_bytes());
+    }
+    if let Some(username) = &options.username {
+        put_string(&mut body, username.as_bytes());
+    }
+    if let Some(password) = &options.password {
+        put_string(&mut body, password.as_bytes());
+    }
+    packet(CONNECT, &body)
+}
+
+fn encode_publish(message: &Message, packet_id: Option<u16>, dup: bool) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_string(&mut body, message.topic.as_bytes());
+    if let Some(id) = packet_id {
+        body.extend_from_slice(&id.to_be_bytes());
+    }
+    body.extend_from_slice(&message.payload);
+    let flags = (u8::from(dup) << 3) | (message.qos.min(1) << 1) | u8::from(message.retain);
+    packet(PUBLISH | flags, &body)
+}
+
+/// The packet type (the high nibble of the first byte) and the body of a packet.
+async fn read_packet<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let first = reader.read_u8().await?;
+    let mut len = 0usize;
+    for shift in [0, 7, 14, 21] {
+        let byte = reader.read_u8().await?;
+        len |= usize::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if shift == 21 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "malformed remaining length",
+            ));
+        }
+    }
+    if len > MAX_PACKET_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("packet of {len} bytes is too large"),
+        ));
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).await?;
+    Ok((first & 0xf0, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn options(port: u16) -> MqttOptions {
+        MqttOptions {
+            client_id: "test".to_string(),
+            ..MqttOptions::from_url(&format!("mqtt://127.0.0.1:{port}"), None).unwrap()
+        }
+    }
+
+    fn message(topic: &str, qos: u8) -> Message {
+        Message {
+            topic: topic.to_string(),
+            payload: b"42".to_vec(),
+            qos,
+            retain: false,
+        }
+    }
+
+    /// Accept a client and answer its CONNECT, returning the CONNECT body.
+    async fn accept(listener: &TcpListener) -> (TcpStream, Vec<u8>) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (kind, body) = read_packet(&mut stream).await.unwrap();
+        assert_eq!(kind, CONNECT);
+        stream.write_all(&[CONNACK, 2, 0, 0]).await.unwrap();
+        (stream, body)
+    }
+
+    #[test]
+    fn test_from_url() {
+        let options = MqttOptions::from_url("mqtt://broker.local", None).unwrap();
+        assert_eq!(
+            (options.host.as_str(), options.port),
+            ("broker.local", 1883)
+        );
+        assert!(options.tls.is_none());
+        assert!(options.client_id.starts_with("homewizard-water-exporter-"));
+
+        let options = MqttOptions::from_url("mqtts://[::1]:8884", None).unwrap();
+        assert_eq!((options.host.as_str(), options.port), ("::1", 8884));
+        assert!(options.tls.is_some());
+
+        assert!(MqttOptions::from_url("http://broker.local", None).is_err());
+        assert!(MqttOptions::from_url("broker.local:1883", None).is_err());
+    }
+
+    #[test]
+    fn test_topic() {
+        assert_eq!(
+            topic("homewizard/{device}", "kitchen", "water"),
+            "homewizard/kitchen"
+        );
+        assert_eq!(
+            topic("home/{type}/{device}/state", "a/b+#", "p1"),
+            "home/p1/a_b__/state"
+        );
+    }
+
+    #[test]
+    fn test_encode_connect() {
+        let mut options = options(1883);
+        options.username = Some("user".to_string());
+        options.password = Some("pw".to_string());
+        assert_eq!(
+            encode_connect(&options),
+            b"\x10\x1a\x00\x04MQTT\x04\xc2\x00\x1e\x00\x04test\x00\x04user\x00\x02pw"
+        );
+    }
+
+    #[test]
+    fn test_encode_publish() {
+        assert_eq!(
+            encode_publish(&message("a/b", 0), None, false),
+            b"\x30\x07\x00\x03a/b42"
+        );
+        let mut retained = message("a/b", 1);
+        retained.retain = true;
+        assert_eq!(
+            encode_publish(&retained, Some(7), true),
+            b"\x3b\x09\x00\x03a/b\x00\x0742"
+        );
+        // Remaining lengths over 127 take more bytes
+        let long = Message {
+            payload: vec![0; 200],
+            ..message("t", 0)
+        };
+        assert_eq!(
+            &encode_publish(&long, None, false)[..3],
+            &[0x30, 0xcb, 0x01]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_packet() {
+        let packet = packet(PUBACK, &[0, 7]);
+        assert_eq!(
+            read_packet(&mut packet.as_slice()).await.unwrap(),
+            (PUBACK, vec![0, 7])
+        );
+        let huge = [PUBLISH, 0xff, 0xff, 0xff, 0x7f];
+        assert!(read_packet(&mut huge.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = MqttClient::start(options(listener.local_addr().unwrap().port()));
+        let (mut broker, connect) = accept(&listener).await;
+        assert!(connect.ends_with(b"\x00\x04test"));
+
+        client.publish(message("homewizard/kitchen", 0));
+        let (kind, body) = read_packet(&mut broker).await.unwrap();
+        assert_eq!(kind, PUBLISH);
+        assert_eq!(body, b"\x00\x12homewizard/kitchen42");
+
+        client.publish(message("homewizard/kitchen", 1));
+        let (_, body) = read_packet(&mut broker).await.unwrap();
+        assert_eq!(&body[20..], b"\x00\x0142");
+        broker.write_all(&[PUBACK, 2, 0, 1]).await.unwrap();
+
+        // Closing the queue disconnects cleanly
+        drop(client);
+        let (kind, _) = read_packet(&mut broker).await.unwrap();
+        assert_eq!(kind, DISCONNECT);
+    }
+
+    #[tokio::test]
+    async fn test_resends_unacknowledged() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = MqttClient::start(options(listener.local_addr().unwrap().port()));
+        let (mut broker, _) = accept(&listener).await;
+        client.publish(message("t", 1));
+        assert_eq!(broker.read_u8().await.unwrap(), PUBLISH | 0x02);
+        // The broker goes away before acknowledging
+        drop(broker);
+
+        let (mut broker, _) = accept(&listener).await;
+        // Sent again with the DUP flag
+        assert_eq!(broker.read_u8().await.unwrap(), PUBLISH | 0x08 | 0x02);
+    }
+
+    #[test]
+    fn test_encode_connect_will() {
+        let mut options = options(1883);
+        options.availability_topic = Some("hw/status".to_string());
+        assert_eq!(
+            encode_connect(&options),
+            b"\x10\x24\x00\x04MQTT\x04\x2e\x00\x1e\x00\x04test\x00\x09hw/status\x00\x07offline"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_availability() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut options = options(listener.local_addr().unwrap().port());
+        options.availability_topic = Some("hw/status".to_string());
+        let client = MqttClient::start(options);
+        let (mut broker, _) = accept(&listener).await;
+        let (kind, body) = read_packet(&mut broker).await.unwrap();
+        assert_eq!(kind, PUBLISH);
+        assert_eq!(body, b"\x00\x09hw/status\x00\x01online");
+
+        client.publish_on_connect(message("config", 1));
+        let (_, body) = read_packet(&mut broker).await.unwrap();
+        assert_eq!(body, b"\x00\x06config\x00\x0242");
+        drop(broker);
+
+        // Both are published again after reconnecting
+        let (mut broker, _) = accept(&listener).await;
+        let mut topics = Vec::new();
+        for _ in 0..2 {
+            let (_, body) = read_packet(&mut broker).await.unwrap();
+            let len = usize::from(body[1]);
+            topics.push(String::from_utf8(body[2..2 + len].to_vec()).unwrap());
+        }
+        assert_eq!(topics, ["hw/status", "config"]);
+        assert_eq!(client.on_connect.lock().unwrap().len(), 2);
+
+        // Going away cleanly sets the topic to offline
+        drop(client);
+        let (kind, body) = read_packet(&mut broker).await.unwrap();
+        assert_eq!(kind, PUBLISH);
+        assert!(body.ends_with(b"offline"));
+    }
+
+    #[tokio::test]
+    async fn test_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_packet(&mut stream).await.unwrap();
+            stream.write_all(&[CONNACK, 2, 0, 4]).await.unwrap();
+        });
+        let stream = connect(&options(port)).await.unwrap();
+        let (_tx, mut messages) = mpsc::channel(1);
+        let error = Session::default()
+            .run(stream, &options(port), &mut messages, &OnConnect::default())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("bad user name or password"));
+    }
+}
@@ -0,0 +1,764 @@
+use crate::build_info::BUILD_INFO;
+use crate::readings::TimedReading;
+use crate::tls::{self, ClientStream};
+use anyhow::{Context, Result, bail};
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::sync::{broadcast, mpsc};
+use tokio_rustls::TlsConnector;
+use tracing::{debug, info, warn};
+
+/// Messages waiting to be published; newer ones are dropped while it is full.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// How long the server gets to accept a connection and answer requests.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Waits between reconnection attempts, doubling up to the maximum.
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(60);
+
+/// How often the server is pinged to notice a dead connection.
+const PING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Largest message accepted from the server, which only sends acknowledgements.
+const MAX_PAYLOAD: usize = 1024 * 1024;
+
+/// A message to publish.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub subject: String,
+    pub payload: Vec<u8>,
+}
+
+/// Where and how to connect (`--nats-url` and friends).
+#[derive(Clone)]
+pub struct NatsOptions {
+    pub host: String,
+    pub port: u16,
+    /// Set for `tls://` URLs
+    pub tls: Option<TlsConnector>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub token: Option<String>,
+    /// JetStream stream that must acknowledge every message, created when missing
+    pub stream: Option<String>,
+    /// Subjects the stream is created with
+    pub stream_subjects: Vec<String>,
+}
+
+impl NatsOptions {
+    /// Options for a `nats://[user:password@|token@]host[:4222]` or `tls://` URL;
+    /// `ca_file` replaces the public CAs for `tls`.
+    pub fn from_url(url: &str, ca_file: Option<&Path>) -> Result<Self> {
+        let parsed = reqwest::Url::parse(url).context("invalid NATS URL")?;
+        let tls = match parsed.scheme() {
+            "nats" => None,
+            "tls" => Some(tls::connector(ca_file)?),
+            scheme => bail!("unsupported NATS URL scheme `{scheme}`, use nats:// or tls://"),
+        };
+        let host = parsed
+            .host_str()
+            .filter(|host| !host.is_empty())
+            .context("NATS URL has no host")?;
+        let decode = |text: &str| {
+            percent_decode(text)
+                .filter(|text| !text.is_empty())
+                .context("invalid credentials in NATS URL")
+        };
+        let (user, password, token) = match (parsed.username(), parsed.password()) {
+            ("", _) => (None, None, None),
+            (user, Some(password)) => (Some(decode(user)?), Some(decode(password)?), None),
+            (token, None) => (None, None, Some(decode(token)?)),
+        };
+        Ok(Self {
+            host: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            port: parsed.port().unwrap_or(4222),
+            tls,
+            user,
+            password,
+            token,
+            stream: None,
+            stream_subjects: Vec::new(),
+        })
+    }
+}
+
+fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// The subject of a device's readings: `{device}` and `{type}` in `template` are replaced.
+pub fn subject(template: &str, device: &str, device_type: &str) -> String {
+    // Separators and wildcards in a device name would change the subject structure
+    let device: String = device
+        .chars()
+        .map(|c| {
+            if matches!(c, '.' | '*' | '>') || c.is_whitespace() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    template
+        .replace("{device}", &device)
+        .replace("{type}", device_type)
+}
+
+/// The subjects a stream needs to capture every device's subject.
+pub fn stream_subjects(template: &str) -> Vec<String> {
+    vec![template.replace("{device}", "*").replace("{type}", "*")]
+}
+
+/// Publishes messages to a NATS server from a background task, reconnecting as
+/// needed. With a JetStream stream, messages not acknowledged before a disconnect
+/// are published again.
+#[derive(Clone)]
+pub struct NatsClient {
+    queue: mpsc::Sender<Message>,
+}
+
+impl NatsClient {
+    pub fn start(options: NatsOptions) -> Self {
+        let (queue, messages) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(run(options, messages));
+        Self { queue }
+    }
+
+    pub fn publish(&self, message: Message) {
+        if self.queue.try_send(message).is_err() {
+            warn!("NATS queue is full, dropping a message");
+        }
+    }
+
+    /// Publish every recorded reading as JSON to its device's subject.
+    pub fn publish_readings(
+        &self,
+        mut readings: broadcast::Receiver<TimedReading>,
+        subject_template: String,
+    ) {
+        let client = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match readings.recv().await {
+                    Ok(reading) => match serde_json::to_vec(&reading) {
+                        Ok(payload) => client.publish(Message {
+                            subject: subject(
+                                &subject_template,
+                                &reading.device,
+                                reading.device_type,
+                            ),
+                            payload,
+                        }),
+                        Err(e) => warn!("Failed to serialize reading for NATS: {}", e),
+                    },
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("NATS publisher fell behind, skipped {} readings", skipped)
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+}
+
+/// Connect, publish until the connection fails, and start over.
+async fn run(options: NatsOptions, mut messages: mpsc::Receiver<Message>) {
+    let mut session = Session::new();
+    let mut delay = RECONNECT_MIN;
+    loop {
+        let result = match connect(&options).await {
+            Ok(stream) => {
+                info!("Connected to NATS server {}:{}", options.host, options.port);
+                delay = RECONNECT_MIN;
+                session.run(stream, &options, &mut messages).await
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => return,
+            Err(e) => warn!(
+                "NATS connection to {}:{} failed, retrying in {:?}: {:#}",
+                options.host, options.port, delay, e
+            ),
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(RECONNECT_MAX);
+    }
+}
+
+/// Connect and read the server's `INFO`; TLS starts after it.
+async fn connect(options: &NatsOptions) -> Result<Box<dyn ClientStream>> {
+    let mut stream = tls::connect(&options.host, options.port, None, CONNECT_TIMEOUT).await?;
+    let info = tokio::time::timeout(CONNECT_TIMEOUT, read_line(&mut stream))
+        .await
+        .context("no INFO from the server")??;
+    if !info.starts_with("INFO ") {
+        bail!("expected INFO, got {info:?}");
+    }
+    match &options.tls {
+        Some(connector) => tls::upgrade(stream, &options.host, connector, CONNECT_TIMEOUT).await,
+        None => Ok(stream),
+    }
+}
+
+/// A line read a byte at a time, so nothing after it is consumed before TLS starts.
+async fn read_line<R: AsyncRead + Unpin>(reader: &mut R) -> Result<String> {
+    let mut line = Vec::new();
+    while !line.ends_with(b"\r\n") {
+        if line.len() > MAX_PAYLOAD {
+            bail!("line too long");
+        }
+        line.push(reader.read_u8().await?);
+    }
+    line.truncate(line.len() - 2);
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+/// What the server sent.
+#[derive(Debug, PartialEq)]
+enum Op {
+    Msg { subject: String, payload: Vec<u8> },
+    Ping,
+    Pong,
+    Err(String),
+    Other(String),
+}
+
+async fn read_op<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Op> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        bail!("connection closed");
+    }
+    let line = line.trim_end();
+    let (verb, args) = line.split_once(' ').unwrap_or((line, ""));
+    Ok(match verb.to_ascii_uppercase().as_str() {
+        "MSG" => {
+            // MSG <subject> <sid> [reply-to] <#bytes>
+            let fields: Vec<&str> = args.split_whitespace().collect();
+            let (Some(subject), Some(len)) = (fields.first(), fields.last()) else {
+                bail!("invalid MSG: {line}");
+            };
+            let len: usize = len
+                .parse()
+                .with_context(|| format!("invalid MSG: {line}"))?;
+            if len > MAX_PAYLOAD {
+                bail!("message of {len} bytes is too large");
+            }
+            let mut payload = vec![0; len + 2];
+            reader.read_exact(&mut payload).await?;
+            payload.truncate(len);
+            Op::Msg {
+                subject: subject.to_string(),
+                payload,
+            }
+        }
+        "PING" => Op::Ping,
+        "PONG" => Op::Pong,
+        "-ERR" => Op::Err(args.trim_matches('\'').to_string()),
+        _ => Op::Other(line.to_string()),
+    })
+}
+
+fn encode_connect(options: &NatsOptions) -> Vec<u8> {
+    let mut connect = json!({
+        "verbose": false,
+        "pedantic": false,
+        "tls_required": options.tls.is_some(),
+        "name": "homewizard-water-exporter",
+        "lang": "rust",
+        "version": BUILD_INFO.version,
+        "protocol": 1,
+    });
+    let fields = connect.as_object_mut().expect("an object");
+    for (key, value) in [
+        ("user", &options.user),
+        ("pass", &options.password),
+        ("auth_token", &options.token),
+    ] {
+        if let Some(value) = value {
+            fields.insert(key.into(), value.as_str().into());
+        }
+    }
+    format!("CONNECT {connect}\r\nPING\r\n").into_bytes()
+}
+
+fn encode_publish(subject: &str, reply: Option<&str>, payload: &[u8]) -> Vec<u8> {
+    let mut command = match reply {
+        Some(reply) => format!("PUB {subject} {reply} {}\r\n", payload.len()),
+        None => format!("PUB {subject} {}\r\n", payload.len()),
+    }
+    .into_bytes();
+    command.extend_from_slice(payload);
+    command.extend_from_slice(b"\r\n");
+    command
+}
+
+/// State that outlives a connection.
+struct Session {
+    /// Prefix of the reply subjects of JetStream acknowledgements
+    inbox: String,
+    next_id: u64,
+    /// Messages published to the stream but not acknowledged yet, by reply id
+    unacked: BTreeMap<u64, Message>,
+}
+
+impl Session {
+    fn new() -> Self {
+        Self {
+            inbox: format!("_INBOX.{:016x}", fastrand::u64(..)),
+            next_id: 0,
+            unacked: BTreeMap::new(),
+        }
+    }
+
+    fn reply(&mut self) -> (u64, String) {
+        self.next_id += 1;
+        (self.next_id, format!("{}.{}", self.inbox, self.next_id))
+    }
+
+    /// Publish from `messages` over `stream`; returns `Ok` once the queue is closed.
+    async fn run(
+        &mut self,
+        stream: Box<dyn ClientStream>,
+        options: &NatsOptions,
+        messages: &mut mpsc::Receiver<Message>,
+    ) -> Result<()> {
+        let (reader, mut writer) = tokio::io::split(stream);
+        writer.write_all(&encode_connect(options)).await?;
+        writer
+            .write_all(format!("SUB {}.* 1\r\n", self.inbox).as_bytes())
+            .await?;
+
+        // Reading an operation is not cancel safe, so it gets its own task
+        let (ops_tx, mut ops) = mpsc::channel(64);
+        let reader_task = tokio::spawn(async move {
+            let mut reader = BufReader::new(reader);
+            loop {
+                let op = read_op(&mut reader).await;
+                let failed = op.is_err();
+                if ops_tx.send(op).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        let result = async {
+            // The PING after CONNECT is answered once the server accepted it
+            loop {
+                match tokio::time::timeout(CONNECT_TIMEOUT, ops.recv()).await {
+                    Ok(Some(Ok(Op::Pong))) => break,
+                    Ok(Some(Ok(Op::Err(error)))) => bail!("server refused the connection: {error}"),
+                    Ok(Some(Ok(_))) => {}
+                    Ok(Some(Err(e))) => return Err(e),
+                    Ok(None) => bail!("connection closed"),
+                    Err(_) => bail!("no answer to CONNECT"),
+                }
+            }
+            if let Some(stream) = &options.stream {
+                self.ensure_stream(&mut writer, &mut ops, stream, &options.stream_subjects)
+                    .await?;
+            }
+            self.publish_loop(&mut writer, &mut ops, options, messages)
+                .await
+        }
+        .await;
+        reader_task.abort();
+        result
+    }
+
+    /// Create the stream unless it exists.
+    async fn ensure_stream<W: AsyncWrite + Unpin>(
+        &mut self,
+        writer: &mut W,
+        ops: &mut mpsc::Receiver<Result<Op>>,
+        stream: &str,
+        subjects: &[String],
+    ) -> Result<()> {
+        let info = self
+            .request(writer, ops, &format!("$JS.API.STREAM.INFO.{stream}"), b"")
+            .await?;
+        match info["error"]["code"].as_u64() {
+            None => return Ok(()),
+            Some(404) => {}
+            Some(_) => bail!(
+                "JetStream stream {stream}: {}",
+                info["error"]["description"]
+            ),
+        }
+        let config = json!({"name": stream, "subjects": subjects, "storage": "file"});
+        let created = self
+            .request(
+                writer,
+                ops,
+                &format!("$JS.API.STREAM.CREATE.{stream}"),
+                config.to_string().as_bytes(),
+            )
+            .await?;
+        if !created["error"].is_null() {
+            bail!(
+                "failed to create JetStream stream {stream}: {}",
+                created["error"]["description"]
+            );
+        }
+        info!(
+            "Created JetStream stream {} for {}",
+            stream,
+            subjects.join(", ")
+        );
+        Ok(())
+    }
+
+    /// Send a JetStream API request and wait for its JSON response.
+    async fn request<W: AsyncWrite + Unpin>(
+        &mut self,
+        writer: &mut W,
+        ops: &mut mpsc::Receiver<Result<Op>>,
+        subject: &str,
+        payload: &[u8],
+    ) -> Result<Value> {
+        let (_, reply) = self.reply();
+        writer
+            .write_all(&encode_publish(subject, Some(&reply), payload))
+            .await?;
+        loop {
+            match tokio::time::timeout(CONNECT_TIMEOUT, ops.recv()).await {
+                Ok(Some(Ok(Op::Msg { subject, payload }))) if subject == reply => {
+                    return serde_json::from_slice(&payload)
+                        .context("invalid JetStream API response");
+                }
+                Ok(Some(Ok(Op::Ping))) => writer.write_all(b"PONG\r\n").await?,
+                Ok(Some(Ok(Op::Err(error)))) => bail!("server error: {error}"),
+                Ok(Some(Ok(_))) => {}
+                Ok(Some(Err(e))) => return Err(e),
+                Ok(None) => bail!("connection closed"),
+                Err(_) => bail!("no response to {subject}; is JetStream enabled?"),
+            }
+        }
+    }
+
+    async fn publish_loop<W: AsyncWrite + Unpin>(
+        &mut self,
+        writer: &mut W,
+        ops: &mut mpsc::Receiver<Result<Op>>,
+        options: &NatsOptions,
+        messages: &mut mpsc::Receiver<Message>,
+    ) -> Result<()> {
+        let jetstream = options.stream.is_some();
+        for (id, message) in &self.unacked {
+            let reply = format!("{}.{id}", self.inbox);
+            writer
+                .write_all(&encode_publish(
+                    &message.subject,
+                    Some(&reply),
+                    &message.payload,
+                ))
+                .await?;
+        }
+
+        let mut pings =
+            tokio::time::interval_at(tokio::time::Instant::now() + PING_INTERVAL, PING_INTERVAL);
+        let mut answered = true;
+        loop {
+            tokio::select! {
+                message = messages.recv() => {
+                    let Some(message) = message else {
+                        let _ = writer.flush().await;
+                        return Ok(());
+                    };
+                    let packet = if jetstream {
+                        let (id, reply) = self.reply();
+                        let packet = encode_publish(&message.subject, Some(&reply), &message.payload);
+                        self.unacked.insert(id, message);
+                        packet
+                    } else {
+                        encode_publish(&message.subject, None, &message.payload)
+                    };
+                    writer.write_all(&packet).await?;
+                }
+                op = ops.recv() => match op {
+                    Some(Ok(Op::Msg { subject, payload })) => self.acknowledged(&subject, &payload),
+                    Some(Ok(Op::Ping)) => writer.write_all(b"PONG\r\n").await?,
+                    Some(Ok(Op::Pong)) => answered = true,
+                    Some(Ok(Op::Err(error))) => warn!("NATS server error: {}", error),
+                    Some(Ok(Op::Other(line))) => debug!("Ignoring NATS operation {:?}", line),
+                    Some(Err(e)) => return Err(e),
+                    None => bail!("connection closed"),
+                },
+                _ = pings.tick() => {
+                    if !answered {
+                        bail!("server stopped answering pings");
+                    }
+                    answered = false;
+                    writer.write_all(b"PING\r\n").await?;
+                }
+            }
+        }
+    }
+
+    /// Handle a JetStream acknowledgement; rejected messages are dropped.
+    fn acknowledged(&mut self, subject: &str, payload: &[u8]) {
+        let Some(id) = subject
+            .strip_prefix(&self.inbox)
+            .and_then(|id| id.strip_prefix('.'))
+            .and_then(|id| id.parse().ok())
+        else {
+            return;
+        };
+        let Some(message) = self.unacked.remove(&id) else {
+            return;
+        };
+        let ack: Value = serde_json::from_slice(payload).unwrap_or(Value::Null);
+        if !ack["error"].is_null() {
+            warn!(
+                "JetStream rejected a message to {}: {}",
+                message.subject, ack["error"]["description"]
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn options(port: u16) -> NatsOptions {
+        NatsOptions::from_url(&format!("nats://127.0.0.1:{port}"), None).unwrap()
+    }
+
+    fn message(subject: &str) -> Message {
+        Message {
+            subject: subject.to_string(),
+            payload: b"42".to_vec(),
+        }
+    }
+
+    /// A server connection: line reader and writer halves.
+    struct Server {
+        reader: BufReader<tokio::io::ReadHalf<TcpStream>>,
+        writer: tokio::io::WriteHalf<TcpStream>,
+    }
+
+    impl Server {
+        /// Accept a client and answer its CONNECT, returning the CONNECT options.
+        async fn accept(listener: &TcpListener) -> (Self, Value) {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream
+                .write_all(b"INFO {\"server_id\":\"test\"}\r\n")
+                .await
+                .unwrap();
+            let (reader, writer) = tokio::io::split(stream);
+            let mut server = Self {
+                reader: BufReader::new(reader),
+                writer,
+            };
+            let connect = server.line().await;
+            let connect: Value =
+                serde_json::from_str(connect.strip_prefix("CONNECT ").unwrap()).unwrap();
+            assert_eq!(server.line().await, "PING");
+            server.writer.write_all(b"PONG\r\n").await.unwrap();
+            assert!(server.line().await.starts_with("SUB _INBOX."));
+            (server, connect)
+        }
+
+        async fn line(&mut self) -> String {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).await.unwrap();
+            line.trim_end().to_string()
+        }
+
+        /// Read a PUB, returning its subject, reply subject and payload.
+        async fn publish(&mut self) -> (String, Option<String>, String) {
+            let line = self.line().await;
+            let fields: Vec<&str> = line.split(' ').collect();
+            assert_eq!(fields[0], "PUB", "{line}");
+            let payload = self.line().await;
+            assert_eq!(
+                fields.last().unwrap().parse::<usize>().unwrap(),
+                payload.len()
+            );
+            let reply = (fields.len() == 4).then(|| fields[2].to_string());
+            (fields[1].to_string(), reply, payload)
+        }
+
+        async fn reply(&mut self, subject: &str, payload: &str) {
+            let msg = format!("MSG {subject} 1 {}\r\n{payload}\r\n", payload.len());
+            self.writer.write_all(msg.as_bytes()).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn test_from_url() {
+        let options = NatsOptions::from_url("nats://nats.local", None).unwrap();
+        assert_eq!((options.host.as_str(), options.port), ("nats.local", 4222));
+        assert!(options.tls.is_none());
+        assert_eq!((options.user, options.token), (None, None));
+
+        let options = NatsOptions::from_url("tls://exporter:p%40ss@[::1]:4443", None).unwrap();
+        assert_eq!((options.host.as_str(), options.port), ("::1", 4443));
+        assert!(options.tls.is_some());
+        assert_eq!(options.user.as_deref(), Some("exporter"));
+        assert_eq!(options.password.as_deref(), Some("p@ss"));
+
+        let options = NatsOptions::from_url("nats://s3cr3t@nats.local", None).unwrap();
+        assert_eq!(options.token.as_deref(), Some("s3cr3t"));
+
+        assert!(NatsOptions::from_url("http://nats.local", None).is_err());
+        assert!(NatsOptions::from_url("nats.local:4222", None).is_err());
+    }
+
+    #[test]
+    fn test_subjects() {
+        assert_eq!(
+            subject("homewizard.{device}", "kitchen", "water"),
+            "homewizard.kitchen"
+        );
+        assert_eq!(
+            subject("home.{type}.{device}", "a.b *>", "p1"),
+            "home.p1.a_b___"
+        );
+        assert_eq!(
+            stream_subjects("home.{type}.{device}.reading"),
+            ["home.*.*.reading"]
+        );
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut options = options(4222);
+        options.user = Some("exporter".to_string());
+        options.password = Some("secret".to_string());
+        let connect = String::from_utf8(encode_connect(&options)).unwrap();
+        let (connect, ping) = connect.split_once("\r\n").unwrap();
+        assert_eq!(ping, "PING\r\n");
+        let connect: Value =
+            serde_json::from_str(connect.strip_prefix("CONNECT ").unwrap()).unwrap();
+        assert_eq!(connect["user"], "exporter");
+        assert_eq!(connect["pass"], "secret");
+        assert_eq!(connect["verbose"], false);
+        assert!(connect.get("auth_token").is_none());
+
+        assert_eq!(
+            encode_publish("homewizard.kitchen", None, b"42"),
+            b"PUB homewizard.kitchen 2\r\n42\r\n"
+        );
+        assert_eq!(
+            encode_publish("a", Some("_INBOX.x.1"), b""),
+            b"PUB a _INBOX.x.1 0\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_op() {
+        let mut input: &[u8] =
+            b"MSG _INBOX.x.1 1 5\r\nhello\r\nPING\r\n-ERR 'Authorization Violation'\r\n+OK\r\n";
+        assert_eq!(
+            read_op(&mut input).await.unwrap(),
+            Op::Msg {
+                subject: "_INBOX.x.1".to_string(),
+                payload: b"hello".to_vec()
+            }
+        );
+        assert_eq!(read_op(&mut input).await.unwrap(), Op::Ping);
+        assert_eq!(
+            read_op(&mut input).await.unwrap(),
+            Op::Err("Authorization Violation".to_string())
+        );
+        assert_eq!(
+            read_op(&mut input).await.unwrap(),
+            Op::Other("+OK".to_string())
+        );
+        assert!(read_op(&mut input).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = NatsClient::start(options(listener.local_addr().unwrap().port()));
+        let (mut server, connect) = Server::accept(&listener).await;
+        assert_eq!(connect["name"], "homewizard-water-exporter");
+
+        client.publish(message("homewizard.kitchen"));
+        assert_eq!(
+            server.publish().await,
+            ("homewizard.kitchen".to_string(), None, "42".to_string())
+        );
+
+        // Pings from the server are answered
+        server.writer.write_all(b"PING\r\n").await.unwrap();
+        assert_eq!(server.line().await, "PONG");
+    }
+
+    #[tokio::test]
+    async fn test_jetstream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut options = options(listener.local_addr().unwrap().port());
+        options.stream = Some("HOMEWIZARD".to_string());
+        options.stream_subjects = stream_subjects("homewizard.{device}");
+        let client = NatsClient::start(options);
+        let (mut server, _) = Server::accept(&listener).await;
+
+        // The stream does not exist yet
+        let (subject, reply, _) = server.publish().await;
+        assert_eq!(subject, "$JS.API.STREAM.INFO.HOMEWIZARD");
+        server
+            .reply(
+                &reply.unwrap(),
+                "{\"error\":{\"code\":404,\"description\":\"stream not found\"}}",
+            )
+            .await;
+        let (subject, reply, config) = server.publish().await;
+        assert_eq!(subject, "$JS.API.STREAM.CREATE.HOMEWIZARD");
+        let config: Value = serde_json::from_str(&config).unwrap();
+        assert_eq!(config["subjects"], json!(["homewizard.*"]));
+        server
+            .reply(&reply.unwrap(), "{\"config\":{\"name\":\"HOMEWIZARD\"}}")
+            .await;
+
+        client.publish(message("homewizard.kitchen"));
+        client.publish(message("homewizard.garden"));
+        let (_, first, _) = server.publish().await;
+        let (_, second, _) = server.publish().await;
+        server
+            .reply(&first.unwrap(), "{\"stream\":\"HOMEWIZARD\",\"seq\":1}")
+            .await;
+        // Make sure the acknowledgement was handled before disconnecting
+        server.writer.write_all(b"PING\r\n").await.unwrap();
+        assert_eq!(server.line().await, "PONG");
+        drop(server);
+
+        // Only the unacknowledged message is published again
+        let (mut server, _) = Server::accept(&listener).await;
+        let (subject, reply, _) = server.publish().await;
+        assert_eq!(subject, "$JS.API.STREAM.INFO.HOMEWIZARD");
+        server
+            .reply(&reply.unwrap(), "{\"config\":{\"name\":\"HOMEWIZARD\"}}")
+            .await;
+        let (subject, reply, _) = server.publish().await;
+        assert_eq!(subject, "homewizard.garden");
+        assert_eq!(reply, second);
+    }
+}
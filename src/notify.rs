@@ -0,0 +1,489 @@
+use crate::events::{Event, EventKind};
+use crate::metrics::Metrics;
+use crate::smtp::{Mailer, Reply};
+use anyhow::{Context, Result, bail};
+use reqwest::StatusCode;
+use serde::Serialize;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+
+/// Deliveries attempted per event and channel before giving up.
+const ATTEMPTS: u32 = 3;
+
+/// Wait before the first retry, doubling after each.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// What a channel sends its events to, which decides the shape of the request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Service {
+    /// The event as JSON (`--webhook-url`)
+    Webhook,
+    /// An ntfy topic URL (`--ntfy-url`)
+    Ntfy,
+    /// A Slack incoming webhook (`--slack-webhook-url`)
+    Slack,
+    /// A Discord webhook (`--discord-webhook-url`)
+    Discord,
+    /// Mail through an SMTP server (`--smtp-host`)
+    Email,
+}
+
+impl Service {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Webhook => "webhook",
+            Self::Ntfy => "ntfy",
+            Self::Slack => "slack",
+            Self::Discord => "discord",
+            Self::Email => "email",
+        }
+    }
+}
+
+/// The JSON body of a webhook: the event with a readable message.
+#[derive(Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    event: &'a Event,
+    message: String,
+}
+
+/// One destination of events.
+#[derive(Debug, Clone)]
+pub struct Channel {
+    pub service: Service,
+    target: Target,
+    /// Names of the events to send; all of them when empty
+    pub events: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+enum Target {
+    Http {
+        url: reqwest::Url,
+        /// Sent as a bearer token (ntfy access tokens)
+        token: Option<String>,
+    },
+    Email(Arc<Mailer>),
+}
+
+impl Channel {
+    /// A channel to an HTTP service.
+    pub fn new(service: Service, url: &str, events: &[String]) -> Result<Self> {
+        let parsed = reqwest::Url::parse(url)
+            .with_context(|| format!("invalid {} URL {url}", service.as_str()))?;
+        if !matches!(parsed.scheme(), "http" | "https") {
+            bail!("{} URL {url} must use http or https", service.as_str());
+        }
+        validate_events(events)?;
+        Ok(Self {
+            service,
+            target: Target::Http {
+                url: parsed,
+                token: None,
+            },
+            events: events.to_vec(),
+        })
+    }
+
+    pub fn email(mailer: Mailer, events: &[String]) -> Result<Self> {
+        validate_events(events)?;
+        Ok(Self {
+            service: Service::Email,
+            target: Target::Email(Arc::new(mailer)),
+            events: events.to_vec(),
+        })
+    }
+
+    /// Authenticate HTTP requests with a bearer token.
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        if let Target::Http { token: current, .. } = &mut self.target {
+            *current = token;
+        }
+        self
+    }
+
+    pub fn token(&self) -> Option<&str> {
+        match &self.target {
+            Target::Http { token, .. } => token.as_deref(),
+            Target::Email(_) => None,
+        }
+    }
+
+    /// The service and host, for logs; Slack and Discord URLs contain their secret.
+    fn describe(&self) -> String {
+        let host = match &self.target {
+            Target::Http { url, .. } => url.host_str().unwrap_or_default(),
+            Target::Email(mailer) => &mailer.host,
+        };
+        format!("{} ({host})", self.service.as_str())
+    }
+
+    pub fn wants(&self, event: &Event) -> bool {
+        self.events.is_empty() || self.events.iter().any(|name| name == event.kind.name())
+    }
+
+    /// Send the event once; on failure, returns whether trying again may help.
+    async fn send(
+        &self,
+        client: &reqwest::Client,
+        event: &Event,
+    ) -> std::result::Result<(), (bool, String)> {
+        let (url, token) = match &self.target {
+            Target::Http { url, token } => (url, token),
+            Target::Email(mailer) => {
+                return mailer
+                    .send(&event.title(), &email_body(event))
+                    .await
+                    .map_err(|e| {
+                        let permanent = e.downcast_ref::<Reply>().is_some_and(Reply::is_permanent);
+                        (!permanent, format!("{e:#}"))
+                    });
+            }
+        };
+        let request = client.post(url.clone());
+        let request = match token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        };
+        match self.request(request, event).send().await {
+            Ok(response) if response.status().is_success() => Ok(()),
+            // Other client errors will not go away by asking again
+            Ok(response) => {
+                let status = response.status();
+                let body = response.text().await.unwrap_or_default();
+                Err((
+                    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+                    format!("{status}: {}", body.trim()),
+                ))
+            }
+            Err(e) => Err((true, e.to_string())),
+        }
+    }
+
+    fn request(&self, request: reqwest::RequestBuilder, event: &Event) -> reqwest::RequestBuilder {
+        match self.service {
+            Service::Ntfy => {
+                let (priority, tags) = match event.kind {
+                    EventKind::DeviceOffline { .. } => ("high", "warning"),
+                    EventKind::DeviceRecovered => ("default", "white_check_mark"),
+                    EventKind::LeakSuspected { .. } => ("urgent", "droplet,rotating_light"),
+                    EventKind::CounterReset { .. } => ("default", "arrows_counterclockwise"),
+                };
+                request
+                    .header("Title", event.title())
+                    .header("Priority", priority)
+                    .header("Tags", tags)
+                    .body(event.message())
+            }
+            Service::Slack => request.json(&json!({
+                "text": format!("*{}*\n{}", event.title(), event.message()),
+            })),
+            Service::Discord => request.json(&json!({
+                "content": format!("**{}**\n{}", event.title(), event.message()),
+            })),
+            Service::Webhook | Service::Email => request.json(&Payload {
+                event,
+                message: event.message(),
+            }),
+        }
+    }
+}
+
+fn validate_events(events: &[String]) -> Result<()> {
+    if let Some(unknown) = events
+        .iter()
+        .find(|name| !EventKind::NAMES.contains(&name.as_str()))
+    {
+        bail!(
+            "unknown event `{unknown}`, expected one of {}",
+            EventKind::NAMES.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// The text of an email: the message, then the details of the event.
+fn email_body(event: &Event) -> String {
+    let time = chrono::DateTime::from_timestamp(event.timestamp as i64, 0)
+        .map(|time| time.with_timezone(&chrono::Local).to_rfc2822())
+        .unwrap_or_default();
+    format!(
+        "{}\n\nDevice: {}\nEvent: {}\nTime: {time}\n\n-- \nhomewizard-water-exporter\n",
+        event.message(),
+        event.device,
+        event.kind.name()
+    )
+}
+
+/// Sends events to webhooks, chat services and mailboxes as they happen.
+pub struct Notifications {
+    client: reqwest::Client,
+    channels: Vec<Channel>,
+    retry_delay: Duration,
+}
+
+impl Notifications {
+    pub fn new(channels: Vec<Channel>, timeout: Duration) -> Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::builder().timeout(timeout).build()?,
+            channels,
+            retry_delay: RETRY_DELAY,
+        })
+    }
+
+    /// Deliver the event, retrying server errors, rate limits and failed
+    /// connections; returns whether it arrived.
+    async fn deliver(&self, channel: &Channel, event: &Event) -> bool {
+        let mut delay = self.retry_delay;
+        for attempt in 1..=ATTEMPTS {
+            let (retry, error) = match channel.send(&self.client, event).await {
+                Ok(()) => {
+                    debug!(
+                        "Delivered {} event to {}",
+                        event.kind.name(),
+                        channel.describe()
+                    );
+                    return true;
+                }
+                Err(failure) => failure,
+            };
+            if !retry || attempt == ATTEMPTS {
+                warn!(
+                    "Failed to deliver {} event to {} after {} attempts: {}",
+                    event.kind.name(),
+                    channel.describe(),
+                    attempt,
+                    error
+                );
+                return false;
+            }
+            debug!(
+                "Delivery to {} failed, retrying in {:?}: {}",
+                channel.describe(),
+                delay,
+                error
+            );
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+        false
+    }
+
+    /// Deliver events in the background, each to every channel that wants it at
+    /// once; failed deliveries are counted in
+    /// `homewizard_exporter_notification_failures_total`.
+    pub fn start(self, mut events: broadcast::Receiver<Event>, metrics: Arc<Metrics>) {
+        let notifications = Arc::new(self);
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => Arc::new(event),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Notifications fell behind, skipped {} events", skipped);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                for index in 0..notifications.channels.len() {
+                    if !notifications.channels[index].wants(&event) {
+                        continue;
+                    }
+                    let (notifications, metrics, event) =
+                        (notifications.clone(), metrics.clone(), event.clone());
+                    tokio::spawn(async move {
+                        let channel = &notifications.channels[index];
+                        if !notifications.deliver(channel, &event).await {
+                            metrics.record_notification_failure(
+                                channel.service.as_str(),
+                                event.kind.name(),
+                            );
+                        }
+                    });
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Events;
+    use wiremock::matchers::{body_json, body_string, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn event() -> Event {
+        Event {
+            kind: EventKind::DeviceOffline {
+                error: "timed out".to_string(),
+            },
+            device: "kitchen".to_string(),
+            timestamp: 1_700_000_000,
+        }
+    }
+
+    fn notifications(channels: Vec<Channel>) -> Notifications {
+        let mut notifications = Notifications::new(channels, Duration::from_secs(5)).unwrap();
+        notifications.retry_delay = Duration::from_millis(10);
+        notifications
+    }
+
+    fn channel(service: Service, url: String) -> Channel {
+        Channel::new(service, &url, &[]).unwrap()
+    }
+
+    #[test]
+    fn test_channel() {
+        let url = "https://example.com/hook";
+        assert!(Channel::new(Service::Webhook, "ftp://example.com/hook", &[]).is_err());
+        assert!(Channel::new(Service::Slack, "example.com/hook", &[]).is_err());
+        assert!(Channel::new(Service::Ntfy, url, &["flood".to_string()]).is_err());
+
+        let channel = Channel::new(Service::Ntfy, url, &["leak-suspected".to_string()]).unwrap();
+        assert!(!channel.wants(&event()));
+        assert!(
+            Channel::new(Service::Ntfy, url, &[])
+                .unwrap()
+                .wants(&event())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_requests() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/hook"))
+            .and(body_json(json!({
+                "event": "device-offline",
+                "device": "kitchen",
+                "timestamp": 1_700_000_000,
+                "error": "timed out",
+                "message": "kitchen is offline: timed out",
+            })))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/water"))
+            .and(header("Authorization", "Bearer tk_secret"))
+            .and(header("Title", "kitchen: offline"))
+            .and(header("Priority", "high"))
+            .and(body_string("kitchen is offline: timed out"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/services/T0/B0/x"))
+            .and(body_json(json!({
+                "text": "*kitchen: offline*\nkitchen is offline: timed out",
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/webhooks/1/x"))
+            .and(body_json(json!({
+                "content": "**kitchen: offline**\nkitchen is offline: timed out",
+            })))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let ntfy = channel(Service::Ntfy, format!("{}/water", server.uri()))
+            .with_token(Some("tk_secret".to_string()));
+        let channels = vec![
+            channel(Service::Webhook, format!("{}/hook", server.uri())),
+            ntfy,
+            channel(Service::Slack, format!("{}/services/T0/B0/x", server.uri())),
+            channel(
+                Service::Discord,
+                format!("{}/api/webhooks/1/x", server.uri()),
+            ),
+        ];
+        let notifications = notifications(channels.clone());
+        for channel in &channels {
+            assert!(notifications.deliver(channel, &event()).await);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retries() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/flaky"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/flaky"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/gone"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let notifications = notifications(Vec::new());
+        let flaky = channel(Service::Webhook, format!("{}/flaky", server.uri()));
+        assert!(notifications.deliver(&flaky, &event()).await);
+        // Client errors are not retried
+        let gone = channel(Service::Webhook, format!("{}/gone", server.uri()));
+        assert!(!notifications.deliver(&gone, &event()).await);
+    }
+
+    #[tokio::test]
+    async fn test_start() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/slack"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/discord"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&server)
+            .await;
+
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let events = Events::new();
+        let slack = channel(Service::Slack, format!("{}/slack", server.uri()));
+        let discord = Channel::new(
+            Service::Discord,
+            &format!("{}/discord", server.uri()),
+            &["leak-suspected".to_string()],
+        )
+        .unwrap();
+        notifications(vec![slack, discord]).start(events.subscribe(), metrics.clone());
+        events.emit("kitchen", EventKind::DeviceRecovered);
+
+        for _ in 0..100 {
+            let output = metrics.gather().unwrap();
+            if output.contains(
+                "homewizard_exporter_notification_failures_total{channel=\"slack\",event=\"device-recovered\"} 1",
+            ) {
+                // Discord only gets leaks
+                let requests = server.received_requests().await.unwrap();
+                assert_eq!(requests.len(), 3);
+                assert!(requests.iter().all(|r| r.url.path() == "/slack"));
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("the failed delivery was not counted");
+    }
+}
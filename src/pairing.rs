@@ -0,0 +1,196 @@
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Name the token is registered under on the device.
+pub const USER_NAME: &str = concat!("local/", env!("CARGO_PKG_NAME"));
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    token: String,
+}
+
+/// Creates a v2 API user token on a device.
+///
+/// The device only accepts the request for 30 seconds after its button is
+/// pressed; until then it answers 403 `user:creation-not-enabled`.
+pub struct Pairing {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl Pairing {
+    /// `base_url` is the device root, e.g. `https://192.168.1.100`.
+    pub fn new(base_url: &str, timeout: Duration) -> Result<Self> {
+        // Same self-signed device certificate as the v2 poller accepts
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .danger_accept_invalid_certs(true)
+            .build()?;
+
+        Ok(Self {
+            client,
+            url: format!("{}/api/user", base_url.trim_end_matches('/')),
+        })
+    }
+
+    /// Try to create the user once; `None` while the button has not been pressed.
+    pub async fn try_create_user(&self) -> Result<Option<String>> {
+        let response = self
+            .client
+            .post(&self.url)
+            .header("X-Api-Version", "2")
+            .json(&serde_json::json!({ "name": USER_NAME }))
+            .send()
+            .await
+            .context("failed to reach the device")?;
+
+        if response.status() == reqwest::StatusCode::FORBIDDEN {
+            return Ok(None);
+        }
+        let response = response
+            .error_for_status()
+            .context("device rejected the pairing request")?;
+
+        Ok(Some(response.json::<TokenResponse>().await?.token))
+    }
+
+    /// Retry every `every` until the button is pressed or `timeout` passes.
+    pub async fn wait_for_token(&self, timeout: Duration, every: Duration) -> Result<String> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(token) = self.try_create_user().await? {
+                return Ok(token);
+            }
+            if Instant::now() + every > deadline {
+                bail!(
+                    "the device button was not pressed within {}s",
+                    timeout.as_secs()
+                );
+            }
+            tokio::time::sleep(every).await;
+        }
+    }
+}
+
+/// Store a token readable only by the current user.
+pub fn write_token(path: &Path, token: &str) -> Result<()> {
+    if let Some(parent) = path.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, format!("{token}\n"))
+        .with_context(|| format!("failed to write {}", path.display()))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+/// Read a token written by [`write_token`].
+pub fn read_token(path: &Path) -> Result<String> {
+    let token = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read token file {}", path.display()))?;
+    let token = token.trim();
+    if token.is_empty() {
+        bail!("token file {} is empty", path.display());
+    }
+    Ok(token.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_json, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn test_button_not_pressed() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/user"))
+            .respond_with(ResponseTemplate::new(403).set_body_json(serde_json::json!({
+                "error": "user:creation-not-enabled"
+            })))
+            .mount(&server)
+            .await;
+
+        let pairing = Pairing::new(&server.uri(), Duration::from_secs(5)).unwrap();
+        assert_eq!(pairing.try_create_user().await.unwrap(), None);
+
+        let result = pairing
+            .wait_for_token(Duration::from_millis(30), Duration::from_millis(10))
+            .await;
+        assert!(result.unwrap_err().to_string().contains("not pressed"));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_token() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/user"))
+            .respond_with(ResponseTemplate::new(403))
+            .up_to_n_times(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/user"))
+            .and(header("X-Api-Version", "2"))
+            .and(body_json(serde_json::json!({ "name": USER_NAME })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "token": "2D4B6A0F8C1E",
+                "name": USER_NAME
+            })))
+            .mount(&server)
+            .await;
+
+        let pairing = Pairing::new(&server.uri(), Duration::from_secs(5)).unwrap();
+        let token = pairing
+            .wait_for_token(Duration::from_secs(5), Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert_eq!(token, "2D4B6A0F8C1E");
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_error() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+
+        let pairing = Pairing::new(&server.uri(), Duration::from_secs(5)).unwrap();
+        assert!(pairing.try_create_user().await.is_err());
+    }
+
+    #[test]
+    fn test_write_and_read_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token");
+
+        write_token(&path, "2D4B6A0F8C1E").unwrap();
+        assert_eq!(read_token(&path).unwrap(), "2D4B6A0F8C1E");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn test_read_empty_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token");
+        std::fs::write(&path, "\n").unwrap();
+
+        assert!(read_token(&path).is_err());
+    }
+}
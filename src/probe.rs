@@ -0,0 +1,164 @@
+use crate::config::Config;
+use crate::devices::Device;
+use crate::homewizard::HomeWizardClient;
+use crate::metrics::Metrics;
+use crate::resolver::CachingResolver;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+/// Serves `/probe?target=`, the Prometheus multi-target exporter pattern.
+///
+/// Each target gets its own client and registry, created on the first probe
+/// and reused afterwards, so counters and the daily usage baseline carry over
+/// between scrapes like they do for polled devices.
+pub struct Prober {
+    config: Config,
+    token: Option<String>,
+    targets: Mutex<HashMap<String, Arc<ProbeTarget>>>,
+}
+
+struct ProbeTarget {
+    device: Device,
+    client: HomeWizardClient,
+    metrics: Metrics,
+}
+
+impl Prober {
+    pub fn new(config: Config) -> Result<Self> {
+        let token = config.device_token()?;
+        Ok(Self {
+            config,
+            token,
+            targets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Fetch the target and render its metrics. `target` takes the same
+    /// `[type:][alias=]address` form as `--host`.
+    pub async fn probe(&self, target: &str) -> Result<String> {
+        let target = self.target(target).await?;
+        let name = target.device.name();
+
+        match target.client.fetch_device_info().await {
+            Ok(info) => target.metrics.set_device_info(name, &info),
+            Err(e) => warn!("Failed to fetch device info from {}: {}", name, e),
+        }
+
+        let started = Instant::now();
+        let result = target.client.fetch_reading().await;
+        target
+            .metrics
+            .record_fetch_attempts(name, target.client.last_attempts());
+        target
+            .metrics
+            .observe_poll_duration(name, started.elapsed());
+
+        match result {
+            Ok(reading) => {
+                target.metrics.record_poll_success(name);
+                target.metrics.update_reading(name, &reading)?;
+            }
+            Err(e) => {
+                warn!("Failed to probe HomeWizard {}: {}", name, e);
+                target.metrics.record_poll_failure(name, e.reason(), false);
+            }
+        }
+
+        target.metrics.gather()
+    }
+
+    async fn target(&self, spec: &str) -> Result<Arc<ProbeTarget>> {
+        let mut targets = self.targets.lock().await;
+        if let Some(target) = targets.get(spec) {
+            return Ok(target.clone());
+        }
+
+        let device = Device::parse(spec);
+        let timeout = self.config.http_timeout;
+        let api_version = self.config.api_version_for(&device.address).await;
+        let url = api_version.data_url(&device.address);
+        // Building the client loads the TLS root store
+        let client = tokio::task::spawn_blocking(move || {
+            HomeWizardClient::with_api_version(url, timeout, CachingResolver::new(), api_version)
+        })
+        .await??
+        .with_device_type(device.device_type)
+        .with_retry(self.config.retry_policy());
+        let client = match &self.token {
+            Some(token) => client.with_token(token.clone()),
+            None => client,
+        };
+
+        let target = Arc::new(ProbeTarget {
+            device,
+            client,
+            metrics: Metrics::with_labels(self.config.labels.iter().cloned().collect())?
+                .with_cumulative_total(self.config.cumulative_total)
+                .with_leak_after(self.config.leak_after)
+                .with_pricing(self.config.pricing())
+                .with_filter(self.config.metric_filter())
+                .with_net_total(self.config.net_total_calibration())
+                .with_wifi_metrics(!self.config.disable_wifi_metrics)
+                .with_units(self.config.units)
+                .with_budget(self.config.budget())
+                .with_quiet_hours(self.config.quiet_hours)
+                .with_flow_average_windows(self.config.flow_average_windows.clone())
+                .with_flow_buckets(&self.config.flow_buckets)?
+                .with_extra_metrics(&self.config.extra_metrics)?,
+        });
+        targets.insert(spec.to_string(), target.clone());
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn prober() -> Prober {
+        let config = Config::try_parse_from(["homewizard-water-exporter", "--probe"]).unwrap();
+        Prober::new(config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_probe_target() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/data"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "wifi_ssid": "TestNetwork",
+                "wifi_strength": 80,
+                "total_liter_m3": 42.5,
+                "active_liter_lpm": 3.0,
+                "total_liter_offset_m3": 0
+            })))
+            .mount(&server)
+            .await;
+
+        let target = server.address().to_string();
+        let prober = prober();
+        let output = prober.probe(&format!("garden={target}")).await.unwrap();
+
+        assert!(output.contains("homewizard_water_total_m3{device=\"garden\"} 42.5"));
+        assert!(output.contains("homewizard_up{device=\"garden\"} 1"));
+
+        // The registry is kept per target
+        let output = prober.probe(&format!("garden={target}")).await.unwrap();
+        assert!(output.contains("homewizard_exporter_scrapes_total{device=\"garden\"} 2"));
+    }
+
+    #[tokio::test]
+    async fn test_probe_unreachable_target() {
+        let output = prober().probe("127.0.0.1:1").await.unwrap();
+
+        assert!(output.contains("homewizard_up{device=\"127.0.0.1:1\"} 0"));
+        assert!(!output.contains("homewizard_water_total_m3{"));
+    }
+}
@@ -0,0 +1,460 @@
+use crate::cloud::DataSource;
+#[cfg(feature = "sqlite")]
+use crate::history::HistoryDb;
+use crate::homewizard::{DeviceType, Reading};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+#[cfg(feature = "sqlite")]
+use std::path::Path;
+#[cfg(feature = "sqlite")]
+use std::sync::Mutex;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+#[cfg(feature = "sqlite")]
+use tracing::warn;
+
+/// A device's reading with when and where it was fetched, as served by `/json`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TimedReading {
+    pub device: String,
+    #[serde(rename = "type")]
+    pub device_type: &'static str,
+    /// Unix seconds
+    pub timestamp: u64,
+    pub source: &'static str,
+    pub reading: Reading,
+}
+
+/// A [`TimedReading`] as read back from the history database.
+#[derive(Deserialize)]
+pub(crate) struct StoredReading {
+    pub device: String,
+    #[serde(rename = "type")]
+    pub device_type: String,
+    pub timestamp: u64,
+    pub source: String,
+    pub reading: serde_json::Value,
+}
+
+impl TryFrom<StoredReading> for TimedReading {
+    type Error = anyhow::Error;
+
+    fn try_from(stored: StoredReading) -> Result<Self> {
+        let device_type = DeviceType::from_name(&stored.device_type)
+            .with_context(|| format!("unknown device type {:?}", stored.device_type))?;
+        // The reading is untagged, so the type decides which schema to read it as
+        let reading = match device_type {
+            DeviceType::Water => Reading::Water(serde_json::from_value(stored.reading)?),
+            DeviceType::P1 => Reading::P1(serde_json::from_value(stored.reading)?),
+            DeviceType::EnergySocket => {
+                Reading::EnergySocket(serde_json::from_value(stored.reading)?)
+            }
+            DeviceType::Kwh => Reading::Kwh(serde_json::from_value(stored.reading)?),
+        };
+        let source = match stored.source.as_str() {
+            "cloud" => DataSource::Cloud,
+            _ => DataSource::Local,
+        };
+        Ok(Self {
+            device: stored.device,
+            device_type: device_type.as_str(),
+            timestamp: stored.timestamp,
+            source: source.as_str(),
+            reading,
+        })
+    }
+}
+
+/// How many readings a slow `/events` client may fall behind before it misses some.
+const EVENT_BUFFER: usize = 64;
+
+/// The latest reading of every device and, with `--history-retention`, the
+/// readings before it; shared between the pollers and the server.
+#[derive(Debug, Clone)]
+pub struct Readings {
+    latest: Arc<RwLock<BTreeMap<String, TimedReading>>>,
+    history: Arc<RwLock<VecDeque<TimedReading>>>,
+    retention: Duration,
+    #[cfg(feature = "sqlite")]
+    db: Option<Arc<Mutex<HistoryDb>>>,
+    events: broadcast::Sender<TimedReading>,
+}
+
+impl Default for Readings {
+    fn default() -> Self {
+        Self {
+            latest: Arc::default(),
+            history: Arc::default(),
+            retention: Duration::ZERO,
+            #[cfg(feature = "sqlite")]
+            db: None,
+            events: broadcast::channel(EVENT_BUFFER).0,
+        }
+    }
+}
+
+impl Readings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every reading recorded from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<TimedReading> {
+        self.events.subscribe()
+    }
+
+    /// Keep the readings of the last `retention` for [`Readings::history`]; zero keeps none.
+    pub fn with_history(mut self, retention: Duration) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Persist the history in the SQLite database at `path`, starting from the
+    /// readings in it that are still within the retention. Requires
+    /// [`Readings::with_history`].
+    #[cfg(feature = "sqlite")]
+    pub fn with_history_db(self, path: &Path) -> Result<Self> {
+        self.with_history_db_at(path, unix_now())
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn with_history_db_at(mut self, path: &Path, now: u64) -> Result<Self> {
+        let cutoff = now.saturating_sub(self.retention.as_secs());
+        let db = HistoryDb::open(path)?;
+        db.prune(cutoff)?;
+        let history = db.load(cutoff)?;
+
+        let mut latest = self.latest.write().unwrap();
+        for reading in &history {
+            latest.insert(reading.device.clone(), reading.clone());
+        }
+        drop(latest);
+
+        self.db = Some(Arc::new(Mutex::new(db)));
+        *self.history.write().unwrap() = history.into();
+        Ok(self)
+    }
+
+    pub fn keeps_history(&self) -> bool {
+        !self.retention.is_zero()
+    }
+
+    pub fn record(&self, device: &str, reading: &Reading, source: DataSource) {
+        self.record_at(device, reading, source, unix_now());
+    }
+
+    fn record_at(&self, device: &str, reading: &Reading, source: DataSource, timestamp: u64) {
+        let reading = TimedReading {
+            device: device.to_string(),
+            device_type: reading.device_type().as_str(),
+            timestamp,
+            source: source.as_str(),
+            reading: reading.clone(),
+        };
+        if self.keeps_history() {
+            let mut history = self.history.write().unwrap();
+            history.push_back(reading.clone());
+            let cutoff = timestamp.saturating_sub(self.retention.as_secs());
+            while history
+                .front()
+                .is_some_and(|oldest| oldest.timestamp < cutoff)
+            {
+                history.pop_front();
+            }
+            #[cfg(feature = "sqlite")]
+            if let Some(db) = &self.db
+                && let Err(e) = db.lock().unwrap().insert(&reading, cutoff)
+            {
+                warn!("Failed to write history database: {:#}", e);
+            }
+        }
+        self.latest
+            .write()
+            .unwrap()
+            .insert(device.to_string(), reading.clone());
+        // Fails only when nobody is subscribed
+        let _ = self.events.send(reading);
+    }
+
+    /// Readings since `since` (Unix seconds), oldest first, of one device or all.
+    pub fn history(&self, since: u64, device: Option<&str>) -> Vec<TimedReading> {
+        let history = self.history.read().unwrap();
+        // Readings are appended in time order
+        let start = history.partition_point(|reading| reading.timestamp < since);
+        history
+            .range(start..)
+            .filter(|reading| device.is_none_or(|device| reading.device == device))
+            .cloned()
+            .collect()
+    }
+
+    /// Latest readings, by device name.
+    pub fn latest(&self) -> Vec<TimedReading> {
+        self.latest.read().unwrap().values().cloned().collect()
+    }
+
+    pub fn get(&self, device: &str) -> Option<TimedReading> {
+        self.latest.read().unwrap().get(device).cloned()
+    }
+
+    pub fn remove_device(&self, device: &str) {
+        self.latest.write().unwrap().remove(device);
+        let mut history = self.history.write().unwrap();
+        history.retain(|reading| reading.device != device);
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.db
+            && let Err(e) = db.lock().unwrap().remove_device(device)
+        {
+            warn!("Failed to write history database: {:#}", e);
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "sqlite")]
+    use crate::homewizard::HomeWizardEnergySocketData;
+    use crate::homewizard::HomeWizardWaterData;
+
+    fn water(total: f64) -> Reading {
+        Reading::Water(HomeWizardWaterData {
+            total_liter_m3: total,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_latest_reading_per_device() {
+        let readings = Readings::new();
+        assert!(readings.latest().is_empty());
+
+        readings.record("kitchen", &water(1.0), DataSource::Local);
+        readings.record("garden", &water(5.0), DataSource::Cloud);
+        readings.record("kitchen", &water(1.5), DataSource::Local);
+
+        let latest = readings.latest();
+        assert_eq!(latest.len(), 2);
+        assert_eq!(latest[0].device, "garden");
+        assert_eq!(latest[0].source, "cloud");
+        assert_eq!(readings.get("kitchen").unwrap().reading, water(1.5));
+        assert_eq!(readings.get("kitchen").unwrap().device_type, "water");
+        assert!(readings.get("kitchen").unwrap().timestamp > 0);
+        assert_eq!(readings.get("attic"), None);
+
+        readings.remove_device("kitchen");
+        assert_eq!(readings.get("kitchen"), None);
+    }
+
+    #[test]
+    fn test_no_history_by_default() {
+        let readings = Readings::new();
+        readings.record("kitchen", &water(1.0), DataSource::Local);
+
+        assert!(!readings.keeps_history());
+        assert!(readings.history(0, None).is_empty());
+    }
+
+    #[test]
+    fn test_history() {
+        let readings = Readings::new().with_history(Duration::from_secs(3600));
+        readings.record_at("kitchen", &water(1.0), DataSource::Local, 10_000);
+        readings.record_at("garden", &water(5.0), DataSource::Local, 10_060);
+        readings.record_at("kitchen", &water(1.1), DataSource::Local, 10_120);
+
+        let all = readings.history(0, None);
+        let timestamps: Vec<u64> = all.iter().map(|reading| reading.timestamp).collect();
+        assert_eq!(timestamps, vec![10_000, 10_060, 10_120]);
+
+        assert_eq!(readings.history(10_060, None).len(), 2);
+        let kitchen = readings.history(0, Some("kitchen"));
+        assert_eq!(kitchen.len(), 2);
+        assert_eq!(kitchen[1].reading, water(1.1));
+
+        readings.remove_device("kitchen");
+        assert_eq!(readings.history(0, None).len(), 1);
+    }
+
+    #[test]
+    fn test_history_retention() {
+        let readings = Readings::new().with_history(Duration::from_secs(60));
+        readings.record_at("kitchen", &water(1.0), DataSource::Local, 10_000);
+        readings.record_at("kitchen", &water(1.1), DataSource::Local, 10_030);
+        readings.record_at("kitchen", &water(1.2), DataSource::Local, 10_070);
+
+        let kept: Vec<u64> = readings
+            .history(0, None)
+            .iter()
+            .map(|reading| reading.timestamp)
+            .collect();
+        assert_eq!(kept, vec![10_030, 10_070]);
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn socket(power_on: bool) -> Reading {
+        Reading::EnergySocket(HomeWizardEnergySocketData {
+            total_power_import_kwh: 12.5,
+            active_power_w: Some(40.0),
+            power_on,
+            ..Default::default()
+        })
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn rows(path: &Path) -> i64 {
+        rusqlite::Connection::open(path)
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM readings", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn test_history_db_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.db");
+        let retention = Duration::from_secs(3600);
+
+        let readings = Readings::new()
+            .with_history(retention)
+            .with_history_db_at(&path, 10_000)
+            .unwrap();
+        readings.record_at("kitchen", &water(1.0), DataSource::Local, 10_000);
+        readings.record_at("socket", &socket(true), DataSource::Cloud, 10_060);
+        readings.record_at("kitchen", &water(1.1), DataSource::Local, 10_120);
+        let before = readings.history(0, None);
+        drop(readings);
+
+        let restarted = Readings::new()
+            .with_history(retention)
+            .with_history_db_at(&path, 10_200)
+            .unwrap();
+        assert_eq!(restarted.history(0, None), before);
+        assert_eq!(restarted.get("kitchen").unwrap().reading, water(1.1));
+        assert_eq!(restarted.get("socket").unwrap().reading, socket(true));
+        assert_eq!(restarted.get("socket").unwrap().source, "cloud");
+        drop(restarted);
+
+        // Only what is still within the retention is loaded, and the rest deleted
+        let later = Readings::new()
+            .with_history(retention)
+            .with_history_db_at(&path, 13_650)
+            .unwrap();
+        let kept: Vec<u64> = later.history(0, None).iter().map(|r| r.timestamp).collect();
+        assert_eq!(kept, vec![10_060, 10_120]);
+        assert_eq!(rows(&path), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn test_history_db_columns() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.db");
+        let readings = Readings::new()
+            .with_history(Duration::from_secs(3600))
+            .with_history_db_at(&path, 10_000)
+            .unwrap();
+        readings.record_at("kitchen", &water(1.5), DataSource::Local, 10_000);
+        readings.record_at("socket", &socket(true), DataSource::Local, 10_010);
+
+        let rows: Vec<(String, i64, Option<f64>)> = rusqlite::Connection::open(&path)
+            .unwrap()
+            .prepare("SELECT device, timestamp, total_m3 FROM readings ORDER BY timestamp")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            [
+                ("kitchen".to_string(), 10_000, Some(1.5)),
+                ("socket".to_string(), 10_010, None)
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn test_history_db_skips_unreadable_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.db");
+        drop(HistoryDb::open(&path).unwrap());
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                r#"INSERT INTO readings (device, type, timestamp, source, reading) VALUES
+                    ('kitchen', 'water', 10000, 'local', '{"total_liter_m3":1.0}'),
+                    ('attic', 'toaster', 10010, 'local', '{}'),
+                    ('kitchen', 'water', 10020, 'local', '{"total_liter_m3"');"#,
+            )
+            .unwrap();
+
+        let readings = Readings::new()
+            .with_history(Duration::from_secs(3600))
+            .with_history_db_at(&path, 10_100)
+            .unwrap();
+        assert_eq!(readings.history(0, None).len(), 1);
+        assert_eq!(readings.get("kitchen").unwrap().reading, water(1.0));
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn test_history_db_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.db");
+        let readings = Readings::new()
+            .with_history(Duration::from_secs(60))
+            .with_history_db_at(&path, 10_000)
+            .unwrap();
+
+        for i in 0..100 {
+            readings.record_at("kitchen", &water(1.0), DataSource::Local, 10_000 + i * 10);
+        }
+        assert_eq!(readings.history(0, None).len(), 7);
+        assert_eq!(rows(&path), 7);
+
+        readings.remove_device("kitchen");
+        assert_eq!(rows(&path), 0);
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let readings = Readings::new();
+        readings.record("kitchen", &water(1.0), DataSource::Local);
+
+        let mut events = readings.subscribe();
+        readings.record("garden", &water(5.0), DataSource::Local);
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.device, "garden");
+        assert_eq!(event.reading, water(5.0));
+        assert!(events.try_recv().is_err());
+
+        // Clones share the channel
+        readings
+            .clone()
+            .record("kitchen", &water(1.1), DataSource::Local);
+        assert_eq!(events.try_recv().unwrap().device, "kitchen");
+    }
+
+    #[test]
+    fn test_serialization() {
+        let readings = Readings::new();
+        readings.record("kitchen", &water(42.5), DataSource::Local);
+        let json = serde_json::to_value(readings.get("kitchen").unwrap()).unwrap();
+
+        assert_eq!(json["device"], "kitchen");
+        assert_eq!(json["type"], "water");
+        assert_eq!(json["source"], "local");
+        assert_eq!(json["reading"]["total_liter_m3"], 42.5);
+    }
+}
@@ -0,0 +1,302 @@
+use crate::homewizard::DeviceType;
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// One device response, a line of the recording.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub timestamp: DateTime<Utc>,
+    pub device: String,
+    #[serde(rename = "type")]
+    pub device_type: String,
+    /// Path of the endpoint, such as `/api/v1/data`
+    pub path: String,
+    pub status: u16,
+    /// The response as the device sent it; a string if it was not valid JSON
+    #[serde(default)]
+    pub body: Value,
+}
+
+/// Appends every device response as a JSON line to a file (`--record`).
+pub struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(
+        &self,
+        device: &str,
+        device_type: DeviceType,
+        path: &str,
+        status: u16,
+        body: &Value,
+    ) -> io::Result<()> {
+        let entry = Entry {
+            timestamp: Utc::now(),
+            device: device.to_string(),
+            device_type: device_type.as_str().to_string(),
+            path: path.to_string(),
+            status,
+            body: body.clone(),
+        };
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())?;
+        file.flush()
+    }
+}
+
+/// Parse the body of a response as JSON, keeping anything else as a string.
+pub fn body(bytes: &[u8]) -> Value {
+    serde_json::from_slice(bytes)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(bytes).into_owned()))
+}
+
+/// Read the entries of a recording, in the order they were recorded.
+pub fn load(path: &Path) -> Result<Vec<Entry>> {
+    let file = File::open(path)?;
+    let mut entries = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line)
+            .with_context(|| format!("invalid entry on line {}", number + 1))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// The devices of a recording as `--host` entries (`type:name`), in order of appearance.
+pub fn devices(entries: &[Entry]) -> Vec<String> {
+    let mut devices: Vec<String> = Vec::new();
+    for entry in entries {
+        let spec = format!("{}:{}", entry.device_type, entry.device);
+        if !devices.contains(&spec) {
+            devices.push(spec);
+        }
+    }
+    devices
+}
+
+/// Serves the responses of a recording in place of the devices (`--replay`).
+///
+/// The recording plays back from when the replay is created, `speed` times as
+/// fast as it was recorded; a request gets the last response recorded for its
+/// device and path by then, and after the end of the recording the last one.
+pub struct Replay {
+    responses: HashMap<(String, String), Vec<Entry>>,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    speed: f64,
+    started: Instant,
+    finished: AtomicBool,
+}
+
+impl Replay {
+    pub fn new(entries: Vec<Entry>, speed: f64) -> Result<Self> {
+        let (Some(start), Some(end)) = (
+            entries.iter().map(|entry| entry.timestamp).min(),
+            entries.iter().map(|entry| entry.timestamp).max(),
+        ) else {
+            bail!("the recording is empty");
+        };
+        let mut responses: HashMap<_, Vec<Entry>> = HashMap::new();
+        for entry in entries {
+            responses
+                .entry((entry.device.clone(), entry.path.clone()))
+                .or_default()
+                .push(entry);
+        }
+        for entries in responses.values_mut() {
+            entries.sort_by_key(|entry| entry.timestamp);
+        }
+        Ok(Self {
+            responses,
+            start,
+            end,
+            speed,
+            started: Instant::now(),
+            finished: AtomicBool::new(false),
+        })
+    }
+
+    /// The response of `device` to a request for `path` now, if it was ever recorded.
+    pub fn response(&self, device: &str, path: &str) -> Option<&Entry> {
+        let at = self.position(self.started.elapsed());
+        if at >= self.end && !self.finished.swap(true, Ordering::Relaxed) {
+            tracing::info!("Reached the end of the recording, repeating the last responses");
+        }
+        self.response_at(device, path, at)
+    }
+
+    /// Time in the recording after playing it for `elapsed`.
+    fn position(&self, elapsed: Duration) -> DateTime<Utc> {
+        let offset = elapsed.mul_f64(self.speed);
+        self.start + chrono::Duration::from_std(offset).unwrap_or(chrono::Duration::MAX)
+    }
+
+    fn response_at(&self, device: &str, path: &str, at: DateTime<Utc>) -> Option<&Entry> {
+        let entries = self
+            .responses
+            .get(&(device.to_string(), path.to_string()))?;
+        // Before its first response the device answers with that one
+        let index = entries.partition_point(|entry| entry.timestamp <= at);
+        entries.get(index.saturating_sub(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::homewizard::HomeWizardClient;
+    use serde_json::json;
+    use std::sync::Arc;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn entry(seconds: i64, device: &str, path: &str, body: Value) -> Entry {
+        Entry {
+            timestamp: DateTime::from_timestamp(1_700_000_000 + seconds, 0).unwrap(),
+            device: device.to_string(),
+            device_type: "water".to_string(),
+            path: path.to_string(),
+            status: 200,
+            body,
+        }
+    }
+
+    #[test]
+    fn test_entry_format() {
+        let line = r#"{"timestamp":"2023-11-14T22:13:20Z","device":"kitchen","type":"water","path":"/api/v1/data","status":200,"body":{"total_liter_m3":1.5}}"#;
+        let parsed: Entry = serde_json::from_str(line).unwrap();
+        assert_eq!(
+            parsed,
+            entry(0, "kitchen", "/api/v1/data", json!({"total_liter_m3": 1.5}))
+        );
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), line);
+    }
+
+    #[test]
+    fn test_body() {
+        assert_eq!(body(br#"{"a": 1}"#), json!({"a": 1}));
+        assert_eq!(body(b"Not Found"), json!("Not Found"));
+    }
+
+    #[test]
+    fn test_devices() {
+        let mut p1 = entry(1, "grid", "/api/v1/data", Value::Null);
+        p1.device_type = "p1".to_string();
+        let entries = [
+            entry(0, "kitchen", "/api", Value::Null),
+            p1,
+            entry(2, "kitchen", "/api/v1/data", Value::Null),
+        ];
+        assert_eq!(devices(&entries), ["water:kitchen", "p1:grid"]);
+    }
+
+    #[test]
+    fn test_replay_timing() {
+        let entries = vec![
+            entry(0, "kitchen", "/api/v1/data", json!(1)),
+            entry(60, "kitchen", "/api/v1/data", json!(2)),
+            entry(120, "kitchen", "/api/v1/data", json!(3)),
+            entry(30, "garden", "/api/v1/data", json!(10)),
+        ];
+        let replay = Replay::new(entries, 10.0).unwrap();
+        let body = |device, elapsed| {
+            let at = replay.position(Duration::from_secs(elapsed));
+            replay
+                .response_at(device, "/api/v1/data", at)
+                .map(|entry| entry.body.clone())
+        };
+
+        assert_eq!(body("kitchen", 0), Some(json!(1)));
+        // Ten times as fast: a minute of recording every six seconds
+        assert_eq!(body("kitchen", 5), Some(json!(1)));
+        assert_eq!(body("kitchen", 6), Some(json!(2)));
+        assert_eq!(body("kitchen", 100), Some(json!(3)));
+        // Before its first response a device answers with that one
+        assert_eq!(body("garden", 0), Some(json!(10)));
+        assert_eq!(body("cellar", 0), None);
+        assert_eq!(
+            replay.response_at("kitchen", "/api", replay.start).cloned(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_replay_empty() {
+        assert!(Replay::new(Vec::new(), 1.0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/data"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "total_liter_m3": 12.5,
+                "active_liter_lpm": 3.0,
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("recording.jsonl");
+        let recorder = Arc::new(Recorder::open(&file).unwrap());
+        let client = HomeWizardClient::new(
+            format!("{}/api/v1/data", server.uri()),
+            Duration::from_secs(5),
+        )
+        .unwrap()
+        .with_recorder(recorder, "kitchen");
+        let recorded = client.fetch_data().await.unwrap();
+        assert!(client.fetch_device_info().await.is_err());
+
+        let entries = load(&file).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].device, "kitchen");
+        assert_eq!(entries[0].path, "/api/v1/data");
+        assert_eq!(entries[0].body["total_liter_m3"], 12.5);
+        assert_eq!((entries[1].path.as_str(), entries[1].status), ("/api", 503));
+
+        // The replaying client never contacts the device
+        drop(server);
+        let replay = Arc::new(Replay::new(entries, 1.0).unwrap());
+        let client = HomeWizardClient::new(
+            "http://kitchen/api/v1/data".to_string(),
+            Duration::from_secs(5),
+        )
+        .unwrap()
+        .with_replay(replay, "kitchen");
+        assert_eq!(client.fetch_data().await.unwrap(), recorded);
+        assert!(matches!(
+            client.fetch_device_info().await,
+            Err(crate::homewizard::HomeWizardError::HttpStatus(status)) if status == 503
+        ));
+    }
+}
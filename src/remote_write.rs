@@ -0,0 +1,637 @@
+use anyhow::{Context, Result, bail};
+use prometheus::proto::{MetricFamily, MetricType};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// Number of buffered batches sent per request when replaying the WAL.
+const REPLAY_CHUNK: usize = 50;
+
+/// Pending pushes kept in memory before new samples are dropped.
+const QUEUE_CAPACITY: usize = 64;
+
+/// One series and its value at the batch timestamp.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Series {
+    /// Label pairs including `__name__`, sorted by name.
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+}
+
+/// All series sampled at the same instant.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Batch {
+    pub timestamp_ms: i64,
+    pub series: Vec<Series>,
+}
+
+impl Batch {
+    /// Flatten gathered metric families into remote-write series.
+    pub fn from_families(families: &[MetricFamily], timestamp_ms: i64) -> Self {
+        let mut series = Vec::new();
+
+        for family in families {
+            let name = family.name();
+            for metric in family.get_metric() {
+                let labels: Vec<(String, String)> = metric
+                    .get_label()
+                    .iter()
+                    .map(|label| (label.name().to_string(), label.value().to_string()))
+                    .collect();
+                let mut push = |suffix: &str, extra: Option<(&str, String)>, value: f64| {
+                    let mut labels = labels.clone();
+                    labels.push(("__name__".to_string(), format!("{name}{suffix}")));
+                    if let Some((label, label_value)) = extra {
+                        labels.push((label.to_string(), label_value));
+                    }
+                    labels.sort();
+                    series.push(Series { labels, value });
+                };
+
+                match family.get_field_type() {
+                    MetricType::COUNTER => push("", None, metric.get_counter().value()),
+                    MetricType::GAUGE => push("", None, metric.get_gauge().value()),
+                    MetricType::UNTYPED => push("", None, metric.untyped.value()),
+                    MetricType::HISTOGRAM => {
+                        let histogram = metric.get_histogram();
+                        for bucket in histogram.get_bucket() {
+                            push(
+                                "_bucket",
+                                Some(("le", bucket.upper_bound().to_string())),
+                                bucket.cumulative_count() as f64,
+                            );
+                        }
+                        push(
+                            "_bucket",
+                            Some(("le", "+Inf".to_string())),
+                            histogram.get_sample_count() as f64,
+                        );
+                        push("_sum", None, histogram.get_sample_sum());
+                        push("_count", None, histogram.get_sample_count() as f64);
+                    }
+                    MetricType::SUMMARY => {
+                        let summary = metric.get_summary();
+                        for quantile in summary.get_quantile() {
+                            push(
+                                "",
+                                Some(("quantile", quantile.quantile().to_string())),
+                                quantile.value(),
+                            );
+                        }
+                        push("_sum", None, summary.sample_sum());
+                        push("_count", None, summary.sample_count() as f64);
+                    }
+                }
+            }
+        }
+
+        Self {
+            timestamp_ms,
+            series,
+        }
+    }
+}
+
+pub fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+/// Value and timestamp in milliseconds.
+type Sample = (f64, i64);
+
+/// Encode batches as a Prometheus remote-write `WriteRequest` protobuf message.
+///
+/// Samples of the same series are merged into one time series in timestamp order.
+pub fn encode_write_request(batches: &[Batch]) -> Vec<u8> {
+    let mut timeseries: BTreeMap<&[(String, String)], Vec<Sample>> = BTreeMap::new();
+    for batch in batches {
+        for series in &batch.series {
+            timeseries
+                .entry(series.labels.as_slice())
+                .or_default()
+                .push((series.value, batch.timestamp_ms));
+        }
+    }
+
+    let mut request = Vec::new();
+    for (labels, samples) in timeseries {
+        let mut ts = Vec::new();
+        for (name, value) in labels {
+            let mut label = Vec::new();
+            put_bytes(&mut label, 1, name.as_bytes());
+            put_bytes(&mut label, 2, value.as_bytes());
+            put_bytes(&mut ts, 1, &label);
+        }
+        for (value, timestamp) in samples {
+            let mut sample = Vec::new();
+            put_key(&mut sample, 1, 1);
+            sample.extend_from_slice(&value.to_le_bytes());
+            put_key(&mut sample, 2, 0);
+            put_varint(&mut sample, timestamp as u64);
+            put_bytes(&mut ts, 2, &sample);
+        }
+        put_bytes(&mut request, 1, &ts);
+    }
+    request
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_key(buf: &mut Vec<u8>, field: u64, wire_type: u64) {
+    put_varint(buf, (field << 3) | wire_type);
+}
+
+fn put_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_key(buf, field, 2);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+/// Bounded write-ahead log of batches that could not be delivered yet.
+///
+/// Stored as one JSON batch per line. When the file would exceed `max_bytes`,
+/// the oldest batches are discarded first.
+pub struct Wal {
+    path: PathBuf,
+    max_bytes: u64,
+}
+
+impl Wal {
+    pub fn new(path: PathBuf, max_bytes: u64) -> Self {
+        Self { path, max_bytes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        std::fs::metadata(&self.path)
+            .map(|meta| meta.len() == 0)
+            .unwrap_or(true)
+    }
+
+    pub fn load(&self) -> Result<Vec<Batch>> {
+        let file = match std::fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).context("failed to open remote-write WAL"),
+        };
+
+        let mut batches = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            match serde_json::from_str(&line) {
+                Ok(batch) => batches.push(batch),
+                // A torn write after a crash only loses that one batch
+                Err(e) => warn!("Skipping corrupt remote-write WAL entry: {}", e),
+            }
+        }
+        Ok(batches)
+    }
+
+    pub fn append(&self, batch: &Batch) -> Result<()> {
+        let line = serde_json::to_string(batch)? + "\n";
+        let size = std::fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+
+        if size + line.len() as u64 > self.max_bytes {
+            let mut batches = self.load()?;
+            batches.push(batch.clone());
+            return self.store(&batches);
+        }
+
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .context("failed to open remote-write WAL")?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Replace the WAL contents, dropping the oldest batches beyond the size limit.
+    pub fn store(&self, batches: &[Batch]) -> Result<()> {
+        let mut lines = Vec::new();
+        let mut total = 0u64;
+        for batch in batches.iter().rev() {
+            let line = serde_json::to_string(batch)? + "\n";
+            if total + line.len() as u64 > self.max_bytes {
+                break;
+            }
+            total += line.len() as u64;
+            lines.push(line);
+        }
+
+        let dropped = batches.len() - lines.len();
+        if dropped > 0 {
+            warn!(
+                "Remote-write WAL is full, dropped {} oldest batches",
+                dropped
+            );
+        }
+
+        if lines.is_empty() {
+            return match std::fs::remove_file(&self.path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        let content: String = lines.into_iter().rev().collect();
+        std::fs::write(&tmp, content)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum SendError {
+    /// Network errors, 5xx and 429: keep the data and try again later.
+    Retryable(anyhow::Error),
+    /// The receiver rejected the data; retrying would fail the same way.
+    Rejected,
+}
+
+/// Pushes samples to a Prometheus remote-write endpoint.
+pub struct RemoteWriter {
+    client: reqwest::Client,
+    url: String,
+    bearer_token: Option<String>,
+    wal: Option<Wal>,
+}
+
+impl RemoteWriter {
+    pub fn new(
+        url: String,
+        bearer_token: Option<String>,
+        wal: Option<Wal>,
+        timeout: Duration,
+    ) -> Result<Self> {
+        let client = reqwest::Client::builder().timeout(timeout).build()?;
+        Ok(Self {
+            client,
+            url,
+            bearer_token,
+            wal,
+        })
+    }
+
+    /// Run the writer on a background task, pushing batches in the order they are queued.
+    pub fn start(self) -> mpsc::Sender<Batch> {
+        let (tx, mut rx) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(async move {
+            while let Some(batch) = rx.recv().await {
+                if let Err(e) = self.push(batch).await {
+                    warn!("Remote write failed: {}", e);
+                }
+            }
+        });
+        tx
+    }
+
+    /// Push a batch; with a WAL, undeliverable batches are buffered and replayed
+    /// in order before newer samples once the endpoint is reachable again.
+    pub async fn push(&self, batch: Batch) -> Result<()> {
+        let Some(wal) = &self.wal else {
+            return match self.send(std::slice::from_ref(&batch)).await {
+                Ok(()) | Err(SendError::Rejected) => Ok(()),
+                Err(SendError::Retryable(e)) => Err(e),
+            };
+        };
+
+        if wal.is_empty() {
+            match self.send(std::slice::from_ref(&batch)).await {
+                Ok(()) | Err(SendError::Rejected) => return Ok(()),
+                Err(SendError::Retryable(e)) => {
+                    wal.append(&batch)?;
+                    return Err(e.context("buffered samples in WAL"));
+                }
+            }
+        }
+
+        // Older samples are waiting; queue behind them to keep timestamps in order
+        wal.append(&batch)?;
+        self.replay(wal).await
+    }
+
+    async fn replay(&self, wal: &Wal) -> Result<()> {
+        let batches = wal.load()?;
+        let total = batches.len();
+
+        for (index, chunk) in batches.chunks(REPLAY_CHUNK).enumerate() {
+            match self.send(chunk).await {
+                Ok(()) | Err(SendError::Rejected) => {}
+                Err(SendError::Retryable(e)) => {
+                    wal.store(&batches[index * REPLAY_CHUNK..])?;
+                    return Err(e.context(format!(
+                        "{} batches remain buffered in WAL",
+                        total - index * REPLAY_CHUNK
+                    )));
+                }
+            }
+        }
+
+        wal.store(&[])?;
+        info!("Replayed {} buffered remote-write batches", total);
+        Ok(())
+    }
+
+    async fn send(&self, batches: &[Batch]) -> Result<(), SendError> {
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&encode_write_request(batches))
+            .map_err(|e| SendError::Retryable(e.into()))?;
+
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header("Content-Encoding", "snappy")
+            .header("Content-Type", "application/x-protobuf")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .body(body);
+        if let Some(token) = &self.bearer_token {
+            request = request.bearer_auth(token);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| SendError::Retryable(e.into()))?;
+        let status = response.status();
+
+        if status.is_success() {
+            debug!("Pushed {} batches to {}", batches.len(), self.url);
+            Ok(())
+        } else if status.is_server_error() || status.as_u16() == 429 {
+            Err(SendError::Retryable(anyhow::anyhow!(
+                "remote write endpoint returned {status}"
+            )))
+        } else {
+            let body = response.text().await.unwrap_or_default();
+            warn!(
+                "Remote write endpoint rejected samples ({}): {}",
+                status, body
+            );
+            Err(SendError::Rejected)
+        }
+    }
+}
+
+/// Validate the remote-write settings that can be checked without a network.
+pub fn validate_url(url: &str) -> Result<()> {
+    let parsed = reqwest::Url::parse(url).context("invalid remote-write URL")?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        bail!("remote-write URL must use http or https");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::homewizard::HomeWizardWaterData;
+    use crate::metrics::Metrics;
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn test_batch(timestamp_ms: i64, value: f64) -> Batch {
+        Batch {
+            timestamp_ms,
+            series: vec![Series {
+                labels: vec![
+                    (
+                        "__name__".to_string(),
+                        "homewizard_water_total_m3".to_string(),
+                    ),
+                    ("device".to_string(), "meter".to_string()),
+                ],
+                value,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_put_varint() {
+        let mut buf = Vec::new();
+        put_varint(&mut buf, 1);
+        put_varint(&mut buf, 300);
+        assert_eq!(buf, vec![0x01, 0xac, 0x02]);
+    }
+
+    #[test]
+    fn test_encode_write_request() {
+        let encoded = encode_write_request(&[Batch {
+            timestamp_ms: 1,
+            series: vec![Series {
+                labels: vec![("__name__".to_string(), "a".to_string())],
+                value: 1.0,
+            }],
+        }]);
+
+        let label = [
+            0x0a, 0x08, b'_', b'_', b'n', b'a', b'm', b'e', b'_', b'_', 0x12, 0x01, b'a',
+        ];
+        let mut expected_ts = vec![0x0a, label.len() as u8];
+        expected_ts.extend_from_slice(&label);
+        let mut sample = vec![0x09];
+        sample.extend_from_slice(&1.0f64.to_le_bytes());
+        sample.extend_from_slice(&[0x10, 0x01]);
+        expected_ts.push(0x12);
+        expected_ts.push(sample.len() as u8);
+        expected_ts.extend_from_slice(&sample);
+        let mut expected = vec![0x0a, expected_ts.len() as u8];
+        expected.extend_from_slice(&expected_ts);
+
+        assert_eq!(encoded, expected);
+    }
+
+    #[test]
+    fn test_encode_merges_samples_of_same_series() {
+        let single = encode_write_request(&[test_batch(1, 1.0)]);
+        let merged = encode_write_request(&[test_batch(1, 1.0), test_batch(2, 2.0)]);
+        // One extra sample (1 key + 8 value + 2 timestamp bytes, plus its header)
+        assert_eq!(merged.len(), single.len() + 13);
+    }
+
+    #[test]
+    fn test_batch_from_families() {
+        let metrics = Metrics::new().unwrap();
+        metrics
+            .update(
+                "meter",
+                &HomeWizardWaterData {
+                    wifi_ssid: "TestNetwork".to_string(),
+                    wifi_strength: Some(75.5),
+                    total_liter_m3: 1234.567,
+                    active_liter_lpm: Some(15.5),
+                    total_liter_offset_m3: 100.0,
+                    wifi_rssi_db: None,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let batch = Batch::from_families(&metrics.families(), 42);
+        assert_eq!(batch.timestamp_ms, 42);
+        assert!(batch.series.contains(&Series {
+            labels: vec![
+                (
+                    "__name__".to_string(),
+                    "homewizard_water_total_m3".to_string()
+                ),
+                ("device".to_string(), "meter".to_string()),
+            ],
+            value: 1234.567,
+        }));
+        // Including homewizard_exporter_build_info and the scheduler metrics
+        assert_eq!(batch.series.len(), 45);
+    }
+
+    #[test]
+    fn test_wal_append_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal = Wal::new(dir.path().join("wal.jsonl"), 1024 * 1024);
+        assert!(wal.is_empty());
+
+        wal.append(&test_batch(1, 1.0)).unwrap();
+        wal.append(&test_batch(2, 2.0)).unwrap();
+
+        assert!(!wal.is_empty());
+        assert_eq!(
+            wal.load().unwrap(),
+            vec![test_batch(1, 1.0), test_batch(2, 2.0)]
+        );
+
+        wal.store(&[]).unwrap();
+        assert!(wal.is_empty());
+    }
+
+    #[test]
+    fn test_wal_drops_oldest_when_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let line_len = serde_json::to_string(&test_batch(1, 1.0)).unwrap().len() as u64 + 1;
+        let wal = Wal::new(dir.path().join("wal.jsonl"), line_len * 2);
+
+        wal.append(&test_batch(1, 1.0)).unwrap();
+        wal.append(&test_batch(2, 2.0)).unwrap();
+        wal.append(&test_batch(3, 3.0)).unwrap();
+
+        assert_eq!(
+            wal.load().unwrap(),
+            vec![test_batch(2, 2.0), test_batch(3, 3.0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_push_sends_snappy_protobuf() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/write"))
+            .and(header("Content-Encoding", "snappy"))
+            .and(header("Content-Type", "application/x-protobuf"))
+            .and(header("Authorization", "Bearer secret"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let writer = RemoteWriter::new(
+            format!("{}/api/v1/write", server.uri()),
+            Some("secret".to_string()),
+            None,
+            Duration::from_secs(5),
+        )
+        .unwrap();
+
+        writer.push(test_batch(1, 1.0)).await.unwrap();
+        let requests = server.received_requests().await.unwrap();
+        let body = snap::raw::Decoder::new()
+            .decompress_vec(&requests[0].body)
+            .unwrap();
+        assert_eq!(body, encode_write_request(&[test_batch(1, 1.0)]));
+    }
+
+    #[tokio::test]
+    async fn test_push_buffers_and_replays() {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let writer = RemoteWriter::new(
+            format!("{}/api/v1/write", server.uri()),
+            None,
+            Some(Wal::new(dir.path().join("wal.jsonl"), 1024 * 1024)),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+
+        // Endpoint down: both batches end up in the WAL
+        let down = Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount_as_scoped(&server)
+            .await;
+        assert!(writer.push(test_batch(1, 1.0)).await.is_err());
+        assert!(writer.push(test_batch(2, 2.0)).await.is_err());
+        drop(down);
+        assert_eq!(writer.wal.as_ref().unwrap().load().unwrap().len(), 2);
+
+        // Endpoint back: buffered and new samples are sent together, oldest first
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        writer.push(test_batch(3, 3.0)).await.unwrap();
+
+        assert!(writer.wal.as_ref().unwrap().is_empty());
+        let requests = server.received_requests().await.unwrap();
+        let body = snap::raw::Decoder::new()
+            .decompress_vec(&requests.last().unwrap().body)
+            .unwrap();
+        assert_eq!(
+            body,
+            encode_write_request(&[test_batch(1, 1.0), test_batch(2, 2.0), test_batch(3, 3.0)])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rejected_samples_are_not_buffered() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400).set_body_string("out of order sample"))
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let writer = RemoteWriter::new(
+            server.uri(),
+            None,
+            Some(Wal::new(dir.path().join("wal.jsonl"), 1024 * 1024)),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+
+        assert!(writer.push(test_batch(1, 1.0)).await.is_ok());
+        assert!(writer.wal.as_ref().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_validate_url() {
+        assert!(validate_url("http://prometheus:9090/api/v1/write").is_ok());
+        assert!(validate_url("ftp://prometheus/api/v1/write").is_err());
+        assert!(validate_url("not a url").is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// DNS resolver that remembers the addresses of each host it resolved.
+///
+/// Hostnames like `homewizard.local` go through mDNS, which can take seconds per
+/// lookup. Resolving once (ideally while the rest of the exporter starts up) and
+/// reusing the result keeps those delays out of every poll. Entries are dropped
+/// with [`CachingResolver::invalidate`] when a connection fails, so a device
+/// that moved to another address is picked up again.
+///
+/// Clones share the same cache.
+#[derive(Debug, Default, Clone)]
+pub struct CachingResolver {
+    cache: Arc<Mutex<HashMap<String, Vec<SocketAddr>>>>,
+}
+
+impl CachingResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolve `host`, answering from the cache when possible.
+    pub async fn lookup(&self, host: &str) -> io::Result<Vec<SocketAddr>> {
+        if let Some(addrs) = self.cached(host) {
+            return Ok(addrs);
+        }
+
+        // The port is replaced by the one from the request URL, so any value works here.
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, 0)).await?.collect();
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no addresses found for {host}"),
+            ));
+        }
+
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(host.to_string(), addrs.clone());
+        Ok(addrs)
+    }
+
+    pub fn cached(&self, host: &str) -> Option<Vec<SocketAddr>> {
+        self.cache.lock().unwrap().get(host).cloned()
+    }
+
+    pub fn invalidate(&self, host: &str) {
+        self.cache.lock().unwrap().remove(host);
+    }
+}
+
+impl Resolve for CachingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.clone();
+        Box::pin(async move {
+            let addrs = resolver.lookup(name.as_str()).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lookup_caches_result() {
+        let resolver = CachingResolver::new();
+        assert!(resolver.cached("localhost").is_none());
+
+        let addrs = resolver.lookup("localhost").await.unwrap();
+        assert!(!addrs.is_empty());
+        assert_eq!(resolver.cached("localhost"), Some(addrs));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_ip_literal() {
+        let resolver = CachingResolver::new();
+        let addrs = resolver.lookup("127.0.0.1").await.unwrap();
+        assert_eq!(addrs[0].ip().to_string(), "127.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_removes_entry() {
+        let resolver = CachingResolver::new();
+        resolver.lookup("127.0.0.1").await.unwrap();
+
+        resolver.invalidate("127.0.0.1");
+        assert!(resolver.cached("127.0.0.1").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lookup_failure_is_not_cached() {
+        let resolver = CachingResolver::new();
+        let result = resolver
+            .lookup("invalid-host-that-does-not-exist.test")
+            .await;
+        assert!(result.is_err());
+        assert!(
+            resolver
+                .cached("invalid-host-that-does-not-exist.test")
+                .is_none()
+        );
+    }
+}
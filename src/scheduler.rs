@@ -0,0 +1,86 @@
+use crate::metrics::Metrics;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Limits how many devices are polled at once (`--max-concurrent-polls`).
+///
+/// Every device keeps its own schedule; when more polls are due than there are
+/// slots, the others wait their turn instead of all connecting in the same instant.
+pub struct PollScheduler {
+    slots: Semaphore,
+    metrics: Arc<Metrics>,
+}
+
+impl PollScheduler {
+    pub fn new(limit: usize, metrics: Arc<Metrics>) -> Self {
+        metrics.set_poll_concurrency_limit(limit);
+        Self {
+            slots: Semaphore::new(limit),
+            metrics,
+        }
+    }
+
+    /// Wait for a free slot; the poll holds it until the slot is dropped.
+    pub async fn slot(&self) -> PollSlot<'_> {
+        let queued = Instant::now();
+        self.metrics.poll_queued();
+        let permit = self
+            .slots
+            .acquire()
+            .await
+            .expect("the poll slots are never closed");
+        self.metrics.poll_started(queued.elapsed());
+        PollSlot {
+            _permit: permit,
+            metrics: &self.metrics,
+        }
+    }
+}
+
+/// A running poll's share of the limit.
+pub struct PollSlot<'a> {
+    _permit: SemaphorePermit<'a>,
+    metrics: &'a Metrics,
+}
+
+impl Drop for PollSlot<'_> {
+    fn drop(&mut self) {
+        self.metrics.poll_finished();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_limit_and_metrics() {
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let scheduler = Arc::new(PollScheduler::new(2, metrics.clone()));
+
+        let first = scheduler.slot().await;
+        let second = scheduler.slot().await;
+        let waiting = {
+            let scheduler = scheduler.clone();
+            tokio::spawn(async move {
+                let _slot = scheduler.slot().await;
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_exporter_poll_concurrency_limit 2"));
+        assert!(output.contains("homewizard_exporter_polls_in_flight 2"));
+        assert!(output.contains("homewizard_exporter_polls_waiting 1"));
+        assert!(!waiting.is_finished());
+
+        drop(first);
+        waiting.await.unwrap();
+        drop(second);
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("homewizard_exporter_polls_in_flight 0"));
+        assert!(output.contains("homewizard_exporter_polls_waiting 0"));
+        assert!(output.contains("homewizard_exporter_poll_queue_wait_seconds_count 3"));
+    }
+}
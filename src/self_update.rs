@@ -0,0 +1,371 @@
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::Path;
+
+pub const GITHUB_API_URL: &str = "https://api.github.com";
+const REPOSITORY: &str = "rvben/homewizard-water-exporter";
+const BINARY_NAME: &str = "homewizard-water-exporter";
+
+#[derive(Debug, Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+    pub assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Asset {
+    pub name: String,
+    pub browser_download_url: String,
+}
+
+impl Release {
+    /// Version without the leading `v` of the tag.
+    pub fn version(&self) -> &str {
+        self.tag_name.trim_start_matches('v')
+    }
+
+    fn asset(&self, name: &str) -> Option<&Asset> {
+        self.assets.iter().find(|asset| asset.name == name)
+    }
+}
+
+/// Outcome of a self-update run.
+#[derive(Debug, PartialEq)]
+pub enum UpdateStatus {
+    UpToDate { version: String },
+    Available { version: String },
+    Updated { version: String },
+}
+
+/// Updates the binary from the GitHub releases of this project.
+///
+/// Release archives are checked against the `.sha256` file published next to
+/// them before anything on disk is touched. This only catches a corrupted or
+/// truncated download: the checksum comes from the same release as the archive,
+/// so it does not prove who published it. Authenticity rests on the TLS
+/// connection to GitHub and on the integrity of the GitHub release itself.
+pub struct SelfUpdater {
+    client: reqwest::Client,
+    api_url: String,
+}
+
+impl SelfUpdater {
+    pub fn new(api_url: impl Into<String>) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .user_agent(concat!(
+                env!("CARGO_PKG_NAME"),
+                "/",
+                env!("CARGO_PKG_VERSION")
+            ))
+            .build()?;
+
+        Ok(Self {
+            client,
+            api_url: api_url.into(),
+        })
+    }
+
+    pub async fn latest_release(&self) -> Result<Release> {
+        let url = format!("{}/repos/{}/releases/latest", self.api_url, REPOSITORY);
+        let release = self
+            .client
+            .get(&url)
+            .header("Accept", "application/vnd.github+json")
+            .send()
+            .await?
+            .error_for_status()
+            .context("failed to query the latest release")?
+            .json::<Release>()
+            .await?;
+
+        Ok(release)
+    }
+
+    /// Check for a newer release and, unless `check_only` is set, install it to `target`.
+    pub async fn run(&self, target: &Path, check_only: bool, force: bool) -> Result<UpdateStatus> {
+        let release = self.latest_release().await?;
+        let version = release.version().to_string();
+
+        if !force && !is_newer(&version, env!("CARGO_PKG_VERSION")) {
+            return Ok(UpdateStatus::UpToDate { version });
+        }
+        if check_only {
+            return Ok(UpdateStatus::Available { version });
+        }
+
+        let archive_name = archive_name()?;
+        let archive = release
+            .asset(&archive_name)
+            .with_context(|| format!("release {} has no {}", release.tag_name, archive_name))?;
+        let checksum = release
+            .asset(&format!("{archive_name}.sha256"))
+            .with_context(|| {
+                format!(
+                    "release {} has no checksum for {}; refusing to install an unverified binary",
+                    release.tag_name, archive_name
+                )
+            })?;
+
+        let archive_bytes = self.download(&archive.browser_download_url).await?;
+        let checksum_text = self.download(&checksum.browser_download_url).await?;
+        verify_checksum(&archive_bytes, &String::from_utf8_lossy(&checksum_text))?;
+
+        let binary = extract_binary(&archive_bytes)?;
+        replace_binary(target, &binary)?;
+
+        Ok(UpdateStatus::Updated { version })
+    }
+
+    async fn download(&self, url: &str) -> Result<Vec<u8>> {
+        let bytes = self
+            .client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()
+            .with_context(|| format!("failed to download {url}"))?
+            .bytes()
+            .await?;
+        Ok(bytes.to_vec())
+    }
+}
+
+/// Name of the release archive for the platform this binary was built for.
+pub fn archive_name() -> Result<String> {
+    if std::env::consts::OS != "linux" {
+        bail!(
+            "self-update is only supported on Linux; download releases manually from https://github.com/{REPOSITORY}/releases"
+        );
+    }
+    Ok(format!(
+        "{BINARY_NAME}-{}-linux.tar.gz",
+        std::env::consts::ARCH
+    ))
+}
+
+/// Compare dotted numeric versions, ignoring any pre-release suffix.
+pub fn is_newer(candidate: &str, current: &str) -> bool {
+    fn parts(version: &str) -> Vec<u64> {
+        version
+            .split(['-', '+'])
+            .next()
+            .unwrap_or_default()
+            .split('.')
+            .map(|part| part.parse().unwrap_or(0))
+            .collect()
+    }
+    parts(candidate) > parts(current)
+}
+
+/// Check `data` against a `sha256sum`-style checksum file.
+pub fn verify_checksum(data: &[u8], checksum_file: &str) -> Result<()> {
+    let expected = checksum_file
+        .split_whitespace()
+        .next()
+        .context("checksum file is empty")?
+        .to_lowercase();
+    let actual = hex::encode(Sha256::digest(data));
+
+    if actual != expected {
+        bail!("checksum mismatch: expected {expected}, got {actual}");
+    }
+    Ok(())
+}
+
+fn extract_binary(archive: &[u8]) -> Result<Vec<u8>> {
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(archive));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.file_name().and_then(|name| name.to_str()) == Some(BINARY_NAME) {
+            let mut binary = Vec::new();
+            entry.read_to_end(&mut binary)?;
+            return Ok(binary);
+        }
+    }
+    bail!("release archive does not contain {BINARY_NAME}")
+}
+
+/// Atomically replace `target` by writing next to it and renaming over it.
+fn replace_binary(target: &Path, binary: &[u8]) -> Result<()> {
+    let staging = target.with_extension("update");
+    std::fs::write(&staging, binary)
+        .with_context(|| format!("failed to write {}", staging.display()))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&staging, std::fs::Permissions::from_mode(0o755))?;
+    }
+
+    std::fs::rename(&staging, target)
+        .with_context(|| format!("failed to replace {}", target.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn tarball(binary: &[u8]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        ));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(binary.len() as u64);
+        header.set_mode(0o755);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, BINARY_NAME, binary)
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    async fn mock_release(server: &MockServer, tag: &str, archive: &[u8], checksum: &str) {
+        let name = archive_name().unwrap();
+        Mock::given(method("GET"))
+            .and(path(format!("/repos/{REPOSITORY}/releases/latest")))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "tag_name": tag,
+                "assets": [
+                    { "name": name, "browser_download_url": format!("{}/download/archive", server.uri()) },
+                    { "name": format!("{name}.sha256"), "browser_download_url": format!("{}/download/checksum", server.uri()) },
+                ]
+            })))
+            .mount(server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/download/archive"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(archive.to_vec()))
+            .mount(server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/download/checksum"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(checksum.to_string()))
+            .mount(server)
+            .await;
+    }
+
+    #[test]
+    fn test_is_newer() {
+        assert!(is_newer("0.2.0", "0.1.5"));
+        assert!(is_newer("1.0.0", "0.9.9"));
+        assert!(is_newer("0.1.10", "0.1.9"));
+        assert!(!is_newer("0.1.5", "0.1.5"));
+        assert!(!is_newer("0.1.4", "0.1.5"));
+        assert!(!is_newer("0.1.5-rc1", "0.1.5"));
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        let data = b"hello";
+        let checksum = format!("{}  archive.tar.gz\n", hex::encode(Sha256::digest(data)));
+
+        assert!(verify_checksum(data, &checksum).is_ok());
+        assert!(verify_checksum(b"tampered", &checksum).is_err());
+        assert!(verify_checksum(data, "").is_err());
+    }
+
+    #[test]
+    fn test_extract_binary() {
+        let archive = tarball(b"binary contents");
+        assert_eq!(extract_binary(&archive).unwrap(), b"binary contents");
+    }
+
+    #[tokio::test]
+    async fn test_run_up_to_date() {
+        let server = MockServer::start().await;
+        mock_release(&server, &format!("v{}", env!("CARGO_PKG_VERSION")), b"", "").await;
+        let dir = tempfile::tempdir().unwrap();
+
+        let status = SelfUpdater::new(server.uri())
+            .unwrap()
+            .run(&dir.path().join(BINARY_NAME), false, false)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            status,
+            UpdateStatus::UpToDate {
+                version: env!("CARGO_PKG_VERSION").to_string()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_check_only() {
+        let server = MockServer::start().await;
+        mock_release(&server, "v99.0.0", b"", "").await;
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join(BINARY_NAME);
+
+        let status = SelfUpdater::new(server.uri())
+            .unwrap()
+            .run(&target, true, false)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            status,
+            UpdateStatus::Available {
+                version: "99.0.0".to_string()
+            }
+        );
+        assert!(!target.exists());
+    }
+
+    #[tokio::test]
+    async fn test_run_installs_verified_binary() {
+        let server = MockServer::start().await;
+        let archive = tarball(b"new binary");
+        let checksum = hex::encode(Sha256::digest(&archive));
+        mock_release(&server, "v99.0.0", &archive, &checksum).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join(BINARY_NAME);
+        std::fs::write(&target, b"old binary").unwrap();
+
+        let status = SelfUpdater::new(server.uri())
+            .unwrap()
+            .run(&target, false, false)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            status,
+            UpdateStatus::Updated {
+                version: "99.0.0".to_string()
+            }
+        );
+        assert_eq!(std::fs::read(&target).unwrap(), b"new binary");
+    }
+
+    #[tokio::test]
+    async fn test_run_rejects_checksum_mismatch() {
+        let server = MockServer::start().await;
+        let archive = tarball(b"new binary");
+        let checksum = hex::encode(Sha256::digest(b"something else"));
+        mock_release(&server, "v99.0.0", &archive, &checksum).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join(BINARY_NAME);
+        std::fs::write(&target, b"old binary").unwrap();
+
+        let result = SelfUpdater::new(server.uri())
+            .unwrap()
+            .run(&target, false, false)
+            .await;
+
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("checksum mismatch")
+        );
+        assert_eq!(std::fs::read(&target).unwrap(), b"old binary");
+    }
+}
@@ -0,0 +1,1954 @@
+use crate::access::{Allowlist, require_allowed};
+use crate::auth::{ScrapeAuth, require_auth};
+use crate::build_info::{BUILD_INFO, BuildInfo};
+use crate::dashboard;
+use crate::devices::{DeviceStatus, Devices};
+use crate::probe::Prober;
+use crate::readings::{Readings, TimedReading};
+use crate::websocket;
+use arc_swap::ArcSwap;
+use axum::extract::{FromRef, OriginalUri, Query, Request, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::middleware;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{any, get, post};
+use axum::{Json, Router};
+use bytes::Bytes;
+use futures_util::Stream;
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tracing::debug;
+
+/// The rendered `/metrics` payload. The poller swaps in a new one after every poll;
+/// handlers take a reference to the current one without locking or copying it.
+pub type SharedMetrics = Arc<ArcSwap<Bytes>>;
+
+/// A payload holding `text`, for the poller to publish into.
+pub fn shared_metrics(text: impl Into<Bytes>) -> SharedMetrics {
+    Arc::new(ArcSwap::from_pointee(text.into()))
+}
+
+/// Asks the on-demand poller for fresh readings; it replies once they are published.
+pub type ScrapeTrigger = mpsc::Sender<oneshot::Sender<()>>;
+
+/// Asks the pollers to poll now, for `POST /admin/poll`.
+///
+/// Every poller holds a clone of `done` until it has polled, so the sender learns
+/// the poll finished when the channel closes.
+#[derive(Debug, Clone)]
+pub struct PollRequest {
+    /// Only this device, or all of them
+    pub device: Option<String>,
+    pub done: mpsc::Sender<()>,
+}
+
+impl PollRequest {
+    pub fn includes(&self, device: &str) -> bool {
+        self.device.as_ref().is_none_or(|name| name == device)
+    }
+}
+
+pub type PollTrigger = broadcast::Sender<PollRequest>;
+
+/// Something to make a device do, from the admin endpoints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceAction {
+    /// Blink the status LED (`POST /admin/identify`)
+    Identify,
+    /// Turn communication with the HomeWizard cloud on or off (`POST /admin/cloud`)
+    SetCloud(bool),
+}
+
+/// Asks the poller of `device` to carry out `action`.
+#[derive(Debug, Clone)]
+pub struct ActionRequest {
+    pub device: String,
+    pub action: DeviceAction,
+    /// Receives the outcome from the poller of the device
+    pub done: mpsc::Sender<Result<(), String>>,
+}
+
+pub type ActionTrigger = broadcast::Sender<ActionRequest>;
+
+/// State shared by all handlers; each handler extracts only the part it needs.
+#[derive(Clone)]
+pub struct AppState {
+    pub metrics: SharedMetrics,
+    pub devices: Devices,
+    pub readings: Readings,
+    /// Set in on-demand scrape mode, where `/metrics` polls the devices first.
+    pub scrape: Option<ScrapeTrigger>,
+    /// Set with `--probe`, which enables `/probe`.
+    pub probe: Option<Arc<Prober>>,
+    /// Credentials required on everything but the health checks and `/version`, if any.
+    pub auth: Option<Arc<ScrapeAuth>>,
+    /// Networks allowed to reach everything but the health checks, if restricted.
+    pub allowlist: Option<Arc<Allowlist>>,
+    /// Reaches the pollers, for `POST /admin/poll`; set with `--enable-admin-api`,
+    /// which registers that route.
+    pub poll: Option<PollTrigger>,
+    /// Reaches the pollers, for `POST /admin/identify` and `POST /admin/cloud`;
+    /// set with `--enable-admin-api`, which registers those routes.
+    pub actions: Option<ActionTrigger>,
+}
+
+impl FromRef<AppState> for SharedMetrics {
+    fn from_ref(state: &AppState) -> Self {
+        state.metrics.clone()
+    }
+}
+
+impl FromRef<AppState> for Option<ScrapeTrigger> {
+    fn from_ref(state: &AppState) -> Self {
+        state.scrape.clone()
+    }
+}
+
+impl FromRef<AppState> for Option<PollTrigger> {
+    fn from_ref(state: &AppState) -> Self {
+        state.poll.clone()
+    }
+}
+
+impl FromRef<AppState> for Option<ActionTrigger> {
+    fn from_ref(state: &AppState) -> Self {
+        state.actions.clone()
+    }
+}
+
+impl FromRef<AppState> for Option<Arc<Prober>> {
+    fn from_ref(state: &AppState) -> Self {
+        state.probe.clone()
+    }
+}
+
+impl FromRef<AppState> for Devices {
+    fn from_ref(state: &AppState) -> Self {
+        state.devices.clone()
+    }
+}
+
+impl FromRef<AppState> for Readings {
+    fn from_ref(state: &AppState) -> Self {
+        state.readings.clone()
+    }
+}
+
+/// Error returned by the JSON endpoints (`/api/*`, history and control endpoints).
+///
+/// Rendered as `{"code": ..., "message": ..., "hint": ...}` with a matching HTTP
+/// status, so scripts can branch on `code` instead of parsing messages.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ApiError {
+    #[serde(skip)]
+    pub status: StatusCode,
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+            hint: None,
+        }
+    }
+
+    pub fn with_hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    pub fn unavailable(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::SERVICE_UNAVAILABLE, "unavailable", message)
+    }
+
+    pub fn bad_gateway(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_GATEWAY, "device_error", message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", message)
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(error: anyhow::Error) -> Self {
+        Self::internal(error.to_string())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self)).into_response()
+    }
+}
+
+/// Build the HTTP router serving metrics, health and API endpoints.
+pub fn router(state: AppState) -> Router {
+    let metrics = if state.scrape.is_some() {
+        get(on_demand_metrics_handler)
+    } else {
+        get(metrics_handler)
+    };
+
+    // Every route serving readings or device details, or acting on devices
+    let mut router = Router::new()
+        .route("/metrics", metrics)
+        .route("/metrics/{device}", get(device_metrics_handler))
+        .route("/devices", get(devices_handler))
+        .route("/health/details", get(health_details_handler))
+        .route("/json", get(json_handler))
+        .route("/history", get(history_handler))
+        .route("/events", get(events_handler))
+        .route("/ws", get(ws_handler))
+        .route("/", get(root_handler));
+    if state.probe.is_some() {
+        router = router.route("/probe", get(probe_handler));
+    }
+    if state.poll.is_some() {
+        router = router.route("/admin/poll", post(admin_poll_handler));
+    }
+    if state.actions.is_some() {
+        router = router
+            .route("/admin/identify", post(admin_identify_handler))
+            .route("/admin/cloud", post(admin_cloud_handler));
+    }
+    if let Some(auth) = &state.auth {
+        router = router.route_layer(middleware::from_fn_with_state(auth.clone(), require_auth));
+    }
+    router = router
+        .route("/version", get(version_handler))
+        .route("/api/{*path}", any(api_not_found_handler));
+    if let Some(allowlist) = &state.allowlist {
+        router = router.route_layer(middleware::from_fn_with_state(
+            allowlist.clone(),
+            require_allowed,
+        ));
+    }
+    // Added after the allowlist, so container and load balancer health checks keep working
+    router
+        .route("/health", get(health_handler))
+        .route("/livez", get(health_handler))
+        .route("/ready", get(ready_handler))
+        .with_state(state)
+}
+
+/// Bind a Unix domain socket at `path` with permissions `mode`, for `--listen unix:`.
+///
+/// A socket file left behind by a previous run is replaced; any other file is not.
+#[cfg(unix)]
+pub fn bind_unix(path: &Path, mode: u32) -> anyhow::Result<UnixListener> {
+    use anyhow::{Context, bail};
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            bail!("{} exists and is not a socket", path.display());
+        }
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind Unix socket {}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .with_context(|| format!("Failed to set permissions of {}", path.display()))?;
+    Ok(listener)
+}
+
+async fn metrics_handler(State(metrics): State<SharedMetrics>) -> impl IntoResponse {
+    // Cloning `Bytes` only bumps a reference count
+    let payload = Bytes::clone(&metrics.load());
+    (
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        payload,
+    )
+}
+
+/// Poll the devices, then serve what the poll published.
+async fn on_demand_metrics_handler(
+    State(metrics): State<SharedMetrics>,
+    State(scrape): State<Option<ScrapeTrigger>>,
+) -> impl IntoResponse {
+    if let Some(scrape) = scrape {
+        let (reply, polled) = oneshot::channel();
+        if scrape.send(reply).await.is_ok() {
+            let _ = polled.await;
+        }
+    }
+    metrics_handler(State(metrics)).await
+}
+
+/// The series of one device, for a scrape job (and interval) per device.
+///
+/// Series without a `device` label, such as the exporter's own counters, are
+/// only on `/metrics`. In on-demand scrape mode all devices are polled first.
+async fn device_metrics_handler(
+    State(metrics): State<SharedMetrics>,
+    State(scrape): State<Option<ScrapeTrigger>>,
+    State(devices): State<Devices>,
+    axum::extract::Path(device): axum::extract::Path<String>,
+) -> Result<Response, ApiError> {
+    if !devices
+        .snapshot()
+        .iter()
+        .any(|status| status.name == device)
+    {
+        return Err(ApiError::not_found(format!("unknown device '{device}'"))
+            .with_hint("see /devices for the configured devices"));
+    }
+    if let Some(scrape) = scrape {
+        let (reply, polled) = oneshot::channel();
+        if scrape.send(reply).await.is_ok() {
+            let _ = polled.await;
+        }
+    }
+
+    let payload = metrics.load();
+    let body = device_series(&String::from_utf8_lossy(&payload), &device);
+    Ok(([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], body).into_response())
+}
+
+/// The samples of a text exposition labelled `device="<device>"`, with the
+/// HELP and TYPE lines of their families.
+fn device_series(body: &str, device: &str) -> String {
+    let matcher = format!(
+        "device=\"{}\"",
+        device
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    );
+    let mut output = String::new();
+    // HELP and TYPE lines of the current family, until a sample of the device shows up
+    let mut header = String::new();
+    let mut family = "";
+
+    for line in body.lines() {
+        if let Some(rest) = line
+            .strip_prefix("# HELP ")
+            .or(line.strip_prefix("# TYPE "))
+        {
+            let name = rest.split(' ').next().unwrap_or_default();
+            if name != family {
+                header.clear();
+                family = name;
+            }
+            header.push_str(line);
+            header.push('\n');
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+        let labels = line
+            .split_once('{')
+            .map(|(_, labels)| labels)
+            .unwrap_or_default();
+        if labels.starts_with(&matcher) || labels.contains(&format!(",{matcher}")) {
+            output.push_str(&header);
+            header.clear();
+            output.push_str(line);
+            output.push('\n');
+        }
+    }
+    output
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeParams {
+    target: Option<String>,
+}
+
+async fn probe_handler(
+    State(prober): State<Option<Arc<Prober>>>,
+    Query(params): Query<ProbeParams>,
+) -> Result<String, ApiError> {
+    let prober = prober.ok_or_else(|| ApiError::not_found("probing is disabled"))?;
+    let target = params
+        .target
+        .filter(|target| !target.trim().is_empty())
+        .ok_or_else(|| {
+            ApiError::bad_request("missing `target` parameter")
+                .with_hint("use /probe?target=<address>, e.g. /probe?target=192.168.1.50")
+        })?;
+
+    Ok(prober.probe(target.trim()).await?)
+}
+
+async fn devices_handler(State(devices): State<Devices>) -> Json<Vec<DeviceStatus>> {
+    Json(devices.snapshot())
+}
+
+/// Poll all devices, or `?device=` alone, right away instead of at the next
+/// interval, and return their status once the poll is done.
+async fn admin_poll_handler(
+    State(devices): State<Devices>,
+    State(poll): State<Option<PollTrigger>>,
+    Query(params): Query<JsonParams>,
+) -> Result<Json<Vec<DeviceStatus>>, ApiError> {
+    let poll = poll.ok_or_else(|| ApiError::unavailable("polling is not running"))?;
+    if let Some(device) = &params.device
+        && !devices
+            .snapshot()
+            .iter()
+            .any(|status| status.name == *device)
+    {
+        return Err(ApiError::not_found(format!("unknown device '{device}'"))
+            .with_hint("see /devices for the configured devices"));
+    }
+
+    let (done, mut polled) = mpsc::channel(1);
+    poll.send(PollRequest {
+        device: params.device.clone(),
+        done,
+    })
+    .map_err(|_| ApiError::unavailable("polling is not running"))?;
+    // Closed once every poller has dropped its copy of the request
+    while polled.recv().await.is_some() {}
+
+    let mut statuses = devices.snapshot();
+    if let Some(device) = &params.device {
+        statuses.retain(|status| status.name == *device);
+    }
+    Ok(Json(statuses))
+}
+
+/// Make `?device=` blink its status LED, to find out which meter is which.
+async fn admin_identify_handler(
+    State(devices): State<Devices>,
+    State(actions): State<Option<ActionTrigger>>,
+    Query(params): Query<JsonParams>,
+) -> Result<StatusCode, ApiError> {
+    run_action(&devices, actions, params.device, DeviceAction::Identify).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct CloudParams {
+    device: Option<String>,
+    enabled: Option<bool>,
+}
+
+/// Turn communication of `?device=` with the HomeWizard cloud on or off (`?enabled=`).
+async fn admin_cloud_handler(
+    State(devices): State<Devices>,
+    State(actions): State<Option<ActionTrigger>>,
+    Query(params): Query<CloudParams>,
+) -> Result<StatusCode, ApiError> {
+    let Some(enabled) = params.enabled else {
+        return Err(ApiError::bad_request("missing ?enabled=")
+            .with_hint("pass ?enabled=false to keep the device off the cloud"));
+    };
+    run_action(
+        &devices,
+        actions,
+        params.device,
+        DeviceAction::SetCloud(enabled),
+    )
+    .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Have the poller of `device` carry out `action`, and wait for the outcome.
+async fn run_action(
+    devices: &Devices,
+    actions: Option<ActionTrigger>,
+    device: Option<String>,
+    action: DeviceAction,
+) -> Result<(), ApiError> {
+    let actions = actions.ok_or_else(|| ApiError::unavailable("polling is not running"))?;
+    let Some(device) = device else {
+        return Err(ApiError::bad_request("missing ?device=")
+            .with_hint("see /devices for the configured devices"));
+    };
+    if !devices
+        .snapshot()
+        .iter()
+        .any(|status| status.name == device)
+    {
+        return Err(ApiError::not_found(format!("unknown device '{device}'"))
+            .with_hint("see /devices for the configured devices"));
+    }
+
+    let (done, mut outcome) = mpsc::channel(1);
+    actions
+        .send(ActionRequest {
+            device: device.clone(),
+            action,
+            done,
+        })
+        .map_err(|_| ApiError::unavailable("polling is not running"))?;
+    match outcome.recv().await {
+        Some(Ok(())) => Ok(()),
+        Some(Err(e)) => Err(ApiError::bad_gateway(format!("{device} refused: {e}"))),
+        // Every poller dropped the request, so none polls the device
+        None => Err(ApiError::unavailable(format!(
+            "{device} is not being polled"
+        ))),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonParams {
+    device: Option<String>,
+}
+
+/// Latest readings of all devices, or of `?device=` alone.
+async fn json_handler(
+    State(readings): State<Readings>,
+    Query(params): Query<JsonParams>,
+) -> Result<Response, ApiError> {
+    let Some(device) = params.device else {
+        return Ok(Json(readings.latest()).into_response());
+    };
+    let reading: TimedReading = readings.get(&device).ok_or_else(|| {
+        ApiError::not_found(format!("no reading of device '{device}' yet"))
+            .with_hint("see /devices for the configured devices and their state")
+    })?;
+    Ok(Json(reading).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryParams {
+    minutes: Option<u64>,
+    device: Option<String>,
+}
+
+/// Readings of the last `?minutes=` (60 by default), oldest first.
+async fn history_handler(
+    State(readings): State<Readings>,
+    Query(params): Query<HistoryParams>,
+) -> Result<Json<Vec<TimedReading>>, ApiError> {
+    if !readings.keeps_history() {
+        return Err(ApiError::not_found("history is disabled")
+            .with_hint("set --history-retention to keep readings in memory"));
+    }
+    let minutes = params.minutes.unwrap_or(60);
+    if minutes == 0 {
+        return Err(ApiError::bad_request("`minutes` must be at least 1"));
+    }
+    let since = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        .saturating_sub(minutes.saturating_mul(60));
+    Ok(Json(readings.history(since, params.device.as_deref())))
+}
+
+/// Every new reading, or those of `?device=`, as Server-Sent `reading` events.
+async fn events_handler(
+    State(readings): State<Readings>,
+    Query(params): Query<JsonParams>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let events = readings.subscribe();
+    let device = params.device;
+    let stream = futures_util::stream::unfold(events, move |mut events| {
+        let device = device.clone();
+        async move {
+            loop {
+                match events.recv().await {
+                    Ok(reading) if device.as_ref().is_none_or(|d| reading.device == *d) => {
+                        let event = Event::default().event("reading").json_data(&reading);
+                        return Some((event, events));
+                    }
+                    // A client that cannot keep up skips to the newest readings
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// Whether the comma separated `name` header lists `token`.
+fn has_token(headers: &HeaderMap, name: header::HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+/// Every new reading, or those of `?device=`, pushed over a WebSocket as JSON text frames.
+async fn ws_handler(
+    State(readings): State<Readings>,
+    Query(params): Query<JsonParams>,
+    mut request: Request,
+) -> Result<Response, ApiError> {
+    let headers = request.headers();
+    let key = headers
+        .get(header::SEC_WEBSOCKET_KEY)
+        .and_then(|key| key.to_str().ok())
+        .filter(|_| {
+            has_token(headers, header::CONNECTION, "upgrade")
+                && has_token(headers, header::UPGRADE, "websocket")
+        })
+        .ok_or_else(|| {
+            ApiError::bad_request("not a WebSocket handshake").with_hint(
+                "connect with a WebSocket client, or use /events to stream over plain HTTP",
+            )
+        })?;
+    if headers
+        .get(header::SEC_WEBSOCKET_VERSION)
+        .is_none_or(|version| version != "13")
+    {
+        return Err(ApiError::bad_request("unsupported WebSocket version")
+            .with_hint("only version 13 (RFC 6455) is supported"));
+    }
+    let accept = websocket::accept_key(key);
+
+    // Subscribed before answering, so no reading is missed during the handshake
+    let events = readings.subscribe();
+    let upgrade = hyper::upgrade::on(&mut request);
+    tokio::spawn(async move {
+        let result = match upgrade.await {
+            Ok(upgraded) => {
+                websocket::serve(
+                    TokioIo::new(upgraded),
+                    events,
+                    params.device,
+                    websocket::PING_INTERVAL,
+                )
+                .await
+            }
+            Err(e) => Err(std::io::Error::other(e)),
+        };
+        if let Err(e) = result {
+            debug!("WebSocket connection ended: {}", e);
+        }
+    });
+
+    Ok((
+        StatusCode::SWITCHING_PROTOCOLS,
+        [
+            (header::UPGRADE, "websocket".to_string()),
+            (header::CONNECTION, "upgrade".to_string()),
+            (header::SEC_WEBSOCKET_ACCEPT, accept),
+        ],
+    )
+        .into_response())
+}
+
+#[derive(Debug, Serialize)]
+struct HealthDetails {
+    /// `ready` or `not_ready`, as `/ready` reports it
+    status: &'static str,
+    reason: Option<String>,
+    version: &'static str,
+    devices: Vec<DeviceStatus>,
+}
+
+/// Readiness and the poll status of every device, for debugging without the logs.
+async fn health_details_handler(
+    State(devices): State<Devices>,
+    State(scrape): State<Option<ScrapeTrigger>>,
+) -> Json<HealthDetails> {
+    let readiness = readiness(&devices, &scrape);
+    Json(HealthDetails {
+        status: if readiness.is_ok() {
+            "ready"
+        } else {
+            "not_ready"
+        },
+        reason: readiness.err(),
+        version: BUILD_INFO.version,
+        devices: devices.snapshot(),
+    })
+}
+
+async fn version_handler() -> Json<BuildInfo> {
+    Json(BUILD_INFO)
+}
+
+async fn health_handler() -> &'static str {
+    "OK"
+}
+
+/// Whether the exporter has readings worth scraping. In on-demand scrape mode
+/// devices are only polled on scrapes, so the exporter is always ready to take them.
+fn readiness(devices: &Devices, scrape: &Option<ScrapeTrigger>) -> Result<(), String> {
+    match scrape {
+        Some(_) => Ok(()),
+        None => devices.readiness(),
+    }
+}
+
+/// 503 until a device has been polled successfully, and again once none has been for
+/// `--not-ready-after`.
+async fn ready_handler(
+    State(devices): State<Devices>,
+    State(scrape): State<Option<ScrapeTrigger>>,
+) -> (StatusCode, String) {
+    match readiness(&devices, &scrape) {
+        Ok(()) => (StatusCode::OK, "OK".to_string()),
+        Err(reason) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("Not ready: {reason}"),
+        ),
+    }
+}
+
+/// Status page with the devices, their latest readings and the last hour of history.
+async fn root_handler(
+    State(devices): State<Devices>,
+    State(readings): State<Readings>,
+) -> Html<String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let history = readings
+        .keeps_history()
+        .then(|| readings.history(now.saturating_sub(3600), None));
+    Html(dashboard::render(
+        &devices.snapshot(),
+        &readings.latest(),
+        history.as_deref(),
+        now,
+    ))
+}
+
+async fn api_not_found_handler(OriginalUri(uri): OriginalUri) -> ApiError {
+    ApiError::not_found(format!("no API endpoint at {}", uri.path()))
+        .with_hint("see / for the list of available endpoints")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{Device, DeviceState};
+    use axum::body::Body;
+    use axum::extract::connect_info::MockConnectInfo;
+    use axum::http::Request;
+    use clap::Parser;
+    use std::net::SocketAddr;
+    use tower::ServiceExt;
+
+    fn create_test_app() -> Router {
+        let shared_metrics = shared_metrics(
+            "# HELP test_metric A test metric\n# TYPE test_metric counter\ntest_metric 42\n",
+        );
+
+        router(AppState {
+            metrics: shared_metrics,
+            devices: Devices::new(&[Device::parse("kitchen=192.168.1.100")]),
+            readings: Readings::new(),
+            scrape: None,
+            probe: None,
+            auth: None,
+            allowlist: None,
+            poll: None,
+            actions: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_ready_handler() {
+        let devices = Devices::new(&[Device::parse("kitchen=10.0.0.1")]);
+        let app = |scrape: Option<ScrapeTrigger>| {
+            router(AppState {
+                metrics: shared_metrics(""),
+                devices: devices.clone(),
+                readings: Readings::new(),
+                scrape,
+                probe: None,
+                auth: None,
+                allowlist: None,
+                poll: None,
+                actions: None,
+            })
+        };
+        let get = |app: Router| async move {
+            let response = app
+                .oneshot(
+                    Request::builder()
+                        .uri("/ready")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        };
+
+        assert_eq!(
+            get(app(None)).await,
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Not ready: no successful poll yet".to_string()
+            )
+        );
+        // Scrapes are what polls the devices in on-demand mode
+        let (trigger, _requests) = mpsc::channel(1);
+        assert_eq!(get(app(Some(trigger))).await.0, StatusCode::OK);
+
+        devices.record_success("kitchen");
+        assert_eq!(get(app(None)).await, (StatusCode::OK, "OK".to_string()));
+    }
+
+    #[test]
+    fn test_device_series() {
+        let body = "# HELP homewizard_water_total_m3 Total\n\
+# TYPE homewizard_water_total_m3 counter\n\
+homewizard_water_total_m3{device=\"garden\"} 12.5\n\
+homewizard_water_total_m3{device=\"kitchen\"} 100\n\
+# HELP homewizard_water_flow_avg_lpm Average\n\
+# TYPE homewizard_water_flow_avg_lpm gauge\n\
+homewizard_water_flow_avg_lpm{device=\"kitchen\",window=\"5m\"} 1.5\n\
+# HELP homewizard_exporter_scrapes_total Scrapes\n\
+# TYPE homewizard_exporter_scrapes_total counter\n\
+homewizard_exporter_scrapes_total 3\n\
+# HELP homewizard_water_offset_m3 Offset\n\
+# TYPE homewizard_water_offset_m3 gauge\n\
+homewizard_water_offset_m3{device=\"garden\",parent_device=\"kitchen\"} 1\n";
+
+        assert_eq!(
+            device_series(body, "kitchen"),
+            "# HELP homewizard_water_total_m3 Total\n\
+# TYPE homewizard_water_total_m3 counter\n\
+homewizard_water_total_m3{device=\"kitchen\"} 100\n\
+# HELP homewizard_water_flow_avg_lpm Average\n\
+# TYPE homewizard_water_flow_avg_lpm gauge\n\
+homewizard_water_flow_avg_lpm{device=\"kitchen\",window=\"5m\"} 1.5\n"
+        );
+        assert!(device_series(body, "garden").contains("homewizard_water_offset_m3"));
+        assert_eq!(device_series(body, "cellar"), "");
+    }
+
+    #[tokio::test]
+    async fn test_device_metrics_handler() {
+        let app = router(AppState {
+            metrics: shared_metrics(
+                "# TYPE homewizard_up gauge\nhomewizard_up{device=\"kitchen\"} 1\nhomewizard_up{device=\"garden\"} 0\n",
+            ),
+            devices: Devices::new(&[
+                Device::parse("kitchen=10.0.0.1"),
+                Device::parse("garden=10.0.0.2"),
+            ]),
+            readings: Readings::new(),
+            scrape: None,
+            probe: None,
+            auth: None,
+            allowlist: None,
+            poll: None,
+            actions: None,
+        });
+        let get = |uri: &str| {
+            app.clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        };
+
+        let response = get("/metrics/garden").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            body,
+            "# TYPE homewizard_up gauge\nhomewizard_up{device=\"garden\"} 0\n"
+        );
+
+        let response = get("/metrics/cellar").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_admin_poll_handler() {
+        let devices = Devices::new(&[
+            Device::parse("kitchen=10.0.0.1"),
+            Device::parse("garden=10.0.0.2"),
+        ]);
+        let (poll, _) = broadcast::channel::<PollRequest>(4);
+        // Stands in for a device poller
+        let mut requests = poll.subscribe();
+        let poller_devices = devices.clone();
+        tokio::spawn(async move {
+            while let Ok(request) = requests.recv().await {
+                for name in ["kitchen", "garden"] {
+                    if request.includes(name) {
+                        poller_devices.record_success(name);
+                    }
+                }
+            }
+        });
+        let app = |poll: Option<PollTrigger>| {
+            router(AppState {
+                metrics: shared_metrics(""),
+                devices: devices.clone(),
+                readings: Readings::new(),
+                scrape: None,
+                probe: None,
+                auth: None,
+                allowlist: None,
+                poll,
+                actions: None,
+            })
+        };
+        let post = |app: Router, uri: &str| {
+            app.oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri(uri)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = post(app(Some(poll.clone())), "/admin/poll?device=kitchen")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json.as_array().unwrap().len(), 1);
+        assert_eq!(json[0]["name"], "kitchen");
+        assert_eq!(json[0]["state"], "up");
+        assert_eq!(devices.snapshot()[1].state, DeviceState::Unknown);
+
+        let response = post(app(Some(poll.clone())), "/admin/poll").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(devices.snapshot()[1].state, DeviceState::Up);
+
+        let response = post(app(Some(poll.clone())), "/admin/poll?device=attic")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Not served without --enable-admin-api
+        let response = post(app(None), "/admin/poll").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app(Some(poll))
+            .oneshot(
+                Request::builder()
+                    .uri("/admin/poll")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_admin_action_handlers() {
+        let devices = Devices::new(&[
+            Device::parse("kitchen=10.0.0.1"),
+            Device::parse("garden=10.0.0.2"),
+        ]);
+        let actions: ActionTrigger = broadcast::channel(4).0;
+        let mut requests = actions.subscribe();
+        let done = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let poller_done = done.clone();
+        tokio::spawn(async move {
+            while let Ok(request) = requests.recv().await {
+                let outcome = match request.device.as_str() {
+                    "kitchen" => Ok(()),
+                    _ => Err("HTTP status error: 404 Not Found".to_string()),
+                };
+                poller_done.lock().unwrap().push(request.action);
+                let _ = request.done.send(outcome).await;
+            }
+        });
+        let app = |actions: Option<ActionTrigger>| {
+            router(AppState {
+                metrics: shared_metrics(""),
+                devices: devices.clone(),
+                readings: Readings::new(),
+                scrape: None,
+                probe: None,
+                auth: None,
+                allowlist: None,
+                poll: None,
+                actions,
+            })
+        };
+        let post = |uri: &str| {
+            app(Some(actions.clone())).oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri(uri)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+        let status = |uri: &'static str| {
+            let response = post(uri);
+            async move { response.await.unwrap().status() }
+        };
+
+        assert_eq!(
+            status("/admin/identify?device=kitchen").await,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            status("/admin/cloud?device=kitchen&enabled=false").await,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            *done.lock().unwrap(),
+            [DeviceAction::Identify, DeviceAction::SetCloud(false)]
+        );
+
+        assert_eq!(
+            status("/admin/identify?device=garden").await,
+            StatusCode::BAD_GATEWAY
+        );
+        assert_eq!(status("/admin/identify").await, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            status("/admin/cloud?device=kitchen").await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status("/admin/identify?device=attic").await,
+            StatusCode::NOT_FOUND
+        );
+
+        let response = app(None)
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/admin/identify?device=kitchen")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        // Without --enable-admin-api the routes do not exist
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = app(None)
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/admin/cloud?device=kitchen&enabled=false")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_health_details_handler() {
+        let devices = Devices::new(&[
+            Device::parse("kitchen=10.0.0.1"),
+            Device::parse("garden=10.0.0.2"),
+        ]);
+        let app = || {
+            router(AppState {
+                metrics: shared_metrics(""),
+                devices: devices.clone(),
+                readings: Readings::new(),
+                scrape: None,
+                probe: None,
+                auth: None,
+                allowlist: None,
+                poll: None,
+                actions: None,
+            })
+        };
+        let get = |app: Router| async move {
+            let response = app
+                .oneshot(
+                    Request::builder()
+                        .uri("/health/details")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        let json = get(app()).await;
+        assert_eq!(json["status"], "not_ready");
+        assert_eq!(json["reason"], "no successful poll yet");
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+
+        devices.record_success("kitchen");
+        devices.record_failure("garden", "connection refused".to_string());
+        devices.record_failure("garden", "connection refused".to_string());
+        devices.set_breaker_open("garden", true);
+        let json = get(app()).await;
+        assert_eq!(json["status"], "ready");
+        assert!(json["reason"].is_null());
+        let garden = &json["devices"][1];
+        assert_eq!(garden["name"], "garden");
+        assert_eq!(garden["state"], "down");
+        assert_eq!(garden["last_error"], "connection refused");
+        assert_eq!(garden["consecutive_failures"], 2);
+        assert_eq!(garden["circuit_breaker_open"], true);
+        assert!(garden["last_poll"].is_u64());
+    }
+
+    #[tokio::test]
+    async fn test_livez_handler() {
+        let app = create_test_app();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/livez")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "OK");
+    }
+
+    #[tokio::test]
+    async fn test_health_handler() {
+        let app = create_test_app();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/health")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "OK");
+    }
+
+    #[tokio::test]
+    async fn test_root_handler() {
+        let app = create_test_app();
+
+        let response = app
+            .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/html; charset=utf-8"
+        );
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body_str.contains("HomeWizard Water Prometheus Exporter"));
+        assert!(body_str.contains("/metrics"));
+        assert!(body_str.contains("/health"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_handler() {
+        let app = create_test_app();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body_str.contains("test_metric"));
+        assert!(body_str.contains("42"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_handler_with_empty_metrics() {
+        let shared_metrics = shared_metrics("");
+        let app = Router::new()
+            .route("/metrics", get(metrics_handler))
+            .with_state(shared_metrics);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "");
+    }
+
+    #[tokio::test]
+    async fn test_not_found_route() {
+        let app = create_test_app();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/nonexistent")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_handler_concurrent_access() {
+        let shared_metrics = shared_metrics(
+            "# HELP test_metric A test metric\n# TYPE test_metric counter\ntest_metric 42\n",
+        );
+
+        // Make multiple concurrent requests
+        let mut handles = Vec::new();
+        for _ in 0..10 {
+            let app = Router::new()
+                .route("/metrics", get(metrics_handler))
+                .with_state(shared_metrics.clone());
+
+            let handle = tokio::spawn(async move {
+                let response = app
+                    .oneshot(
+                        Request::builder()
+                            .uri("/metrics")
+                            .body(Body::empty())
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                String::from_utf8(body.to_vec()).unwrap()
+            });
+            handles.push(handle);
+        }
+
+        // Wait for all requests to complete
+        for handle in handles {
+            let body = handle.await.unwrap();
+            assert!(body.contains("test_metric"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health_handler_method_not_allowed() {
+        let app = create_test_app();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/health")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_update_during_request() {
+        let shared_metrics = shared_metrics("initial_metric 1\n");
+
+        let app = Router::new()
+            .route("/metrics", get(metrics_handler))
+            .with_state(shared_metrics.clone());
+
+        // Get initial metrics
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body_str.contains("initial_metric 1"));
+
+        // Update metrics
+        shared_metrics.store(Arc::new(Bytes::from("updated_metric 2\n")));
+
+        // Get updated metrics
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body_str.contains("updated_metric 2"));
+    }
+
+    #[test]
+    fn test_shared_metrics_type_alias() {
+        let shared_metrics: SharedMetrics = shared_metrics("test");
+        assert_eq!(**shared_metrics.load(), "test");
+    }
+
+    #[tokio::test]
+    async fn test_metrics_handler_content_type() {
+        let response = create_test_app()
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/plain; charset=utf-8"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_api_unknown_endpoint_returns_json_error() {
+        let app = create_test_app();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/unknown")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()["content-type"], "application/json");
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["code"], "not_found");
+        assert_eq!(json["message"], "no API endpoint at /api/unknown");
+        assert!(json["hint"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_api_error_into_response() {
+        let response = ApiError::unavailable("no reading yet").into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["code"], "unavailable");
+        assert_eq!(json["message"], "no reading yet");
+        assert!(json.get("hint").is_none());
+    }
+
+    #[test]
+    fn test_api_error_from_anyhow() {
+        let error = ApiError::from(anyhow::anyhow!("boom"));
+        assert_eq!(error.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(error.code, "internal_error");
+        assert_eq!(error.to_string(), "internal_error: boom");
+    }
+
+    #[tokio::test]
+    async fn test_devices_handler() {
+        let app = create_test_app();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/devices")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "application/json");
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json[0]["name"], "kitchen");
+        assert_eq!(json[0]["alias"], "kitchen");
+        assert_eq!(json[0]["address"], "192.168.1.100");
+        assert_eq!(json[0]["state"], "unknown");
+        assert!(json[0]["product_type"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_json_handler() {
+        let readings = Readings::new();
+        let app = router(AppState {
+            metrics: shared_metrics(""),
+            devices: Devices::new(&[Device::parse("kitchen=192.168.1.100")]),
+            readings: readings.clone(),
+            scrape: None,
+            probe: None,
+            auth: None,
+            allowlist: None,
+            poll: None,
+            actions: None,
+        });
+        let get = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let response = app
+                    .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                (
+                    status,
+                    serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+                )
+            }
+        };
+
+        assert_eq!(get("/json").await, (StatusCode::OK, serde_json::json!([])));
+        let (status, json) = get("/json?device=kitchen").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(json["code"], "not_found");
+
+        readings.record(
+            "kitchen",
+            &crate::homewizard::Reading::Water(crate::homewizard::HomeWizardWaterData {
+                total_liter_m3: 42.5,
+                ..Default::default()
+            }),
+            crate::cloud::DataSource::Local,
+        );
+        let (status, json) = get("/json").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json[0]["device"], "kitchen");
+        assert_eq!(json[0]["reading"]["total_liter_m3"], 42.5);
+        let (status, json) = get("/json?device=kitchen").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["type"], "water");
+    }
+
+    #[tokio::test]
+    async fn test_history_handler() {
+        let app = |readings: Readings| {
+            router(AppState {
+                metrics: shared_metrics(""),
+                devices: Devices::default(),
+                readings,
+                scrape: None,
+                probe: None,
+                auth: None,
+                allowlist: None,
+                poll: None,
+                actions: None,
+            })
+        };
+        let get = |app: Router, uri: &'static str| async move {
+            let response = app
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (
+                status,
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            )
+        };
+
+        let (status, json) = get(app(Readings::new()), "/history").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(
+            json["hint"]
+                .as_str()
+                .unwrap()
+                .contains("--history-retention")
+        );
+
+        let readings = Readings::new().with_history(std::time::Duration::from_secs(3600));
+        for device in ["kitchen", "garden"] {
+            readings.record(
+                device,
+                &crate::homewizard::Reading::Water(Default::default()),
+                crate::cloud::DataSource::Local,
+            );
+        }
+        let (status, json) = get(app(readings.clone()), "/history?minutes=5").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json.as_array().unwrap().len(), 2);
+
+        let (_, json) = get(app(readings.clone()), "/history?device=garden").await;
+        assert_eq!(json.as_array().unwrap().len(), 1);
+        assert_eq!(json[0]["device"], "garden");
+
+        let (status, _) = get(app(readings), "/history?minutes=0").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_events_handler() {
+        use futures_util::StreamExt;
+
+        let readings = Readings::new();
+        let app = router(AppState {
+            metrics: shared_metrics(""),
+            devices: Devices::default(),
+            readings: readings.clone(),
+            scrape: None,
+            probe: None,
+            auth: None,
+            allowlist: None,
+            poll: None,
+            actions: None,
+        });
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/events?device=kitchen")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+
+        for (device, total) in [("garden", 5.0), ("kitchen", 42.5)] {
+            readings.record(
+                device,
+                &crate::homewizard::Reading::Water(crate::homewizard::HomeWizardWaterData {
+                    total_liter_m3: total,
+                    ..Default::default()
+                }),
+                crate::cloud::DataSource::Local,
+            );
+        }
+        let mut body = response.into_body().into_data_stream();
+        let frame = body.next().await.unwrap().unwrap();
+        let frame = std::str::from_utf8(&frame).unwrap();
+
+        let data = frame
+            .strip_prefix("event: reading\ndata: ")
+            .and_then(|rest| rest.strip_suffix("\n\n"))
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_str(data).unwrap();
+        assert_eq!(json["device"], "kitchen");
+        assert_eq!(json["reading"]["total_liter_m3"], 42.5);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_require_auth() {
+        let mut token = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut token, b"s3cr3t\n").unwrap();
+        let auth = ScrapeAuth::new(&[], Some(token.path())).unwrap();
+        let app = router(AppState {
+            metrics: shared_metrics("test_metric 1\n"),
+            devices: Devices::default(),
+            readings: Readings::new(),
+            scrape: None,
+            probe: None,
+            auth: auth.map(Arc::new),
+            allowlist: None,
+            poll: None,
+            actions: None,
+        });
+        let get = |uri: &str, token: Option<&str>| {
+            let mut request = Request::builder().uri(uri);
+            if let Some(token) = token {
+                request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let response = get("/metrics", None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+
+        let response = get("/metrics", Some("wrong")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = get("/metrics", Some("s3cr3t")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Every route exposing readings or devices is protected as well
+        for uri in [
+            "/",
+            "/devices",
+            "/health/details",
+            "/json",
+            "/history",
+            "/events",
+            "/ws",
+        ] {
+            let response = get(uri, None).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{uri}");
+        }
+        let response = get("/json", Some("s3cr3t")).await.unwrap();
+        assert_ne!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // Health checks stay open
+        for uri in ["/health", "/livez", "/version"] {
+            let response = get(uri, None).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{uri}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket() {
+        use std::os::unix::fs::PermissionsExt;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("exporter.sock");
+        // A stale socket from a previous run is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let listener = bind_unix(&path, 0o600).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        tokio::spawn(async move { axum::serve(listener, create_test_app()).await });
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.contains("test_metric 42"));
+    }
+
+    #[tokio::test]
+    async fn test_websocket() {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+        let readings = Readings::new();
+        let app = router(AppState {
+            metrics: shared_metrics(""),
+            devices: Devices::default(),
+            readings: readings.clone(),
+            scrape: None,
+            probe: None,
+            auth: None,
+            allowlist: None,
+            poll: None,
+            actions: None,
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let mut stream = BufReader::new(stream);
+        stream
+            .write_all(
+                b"GET /ws HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+                  Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            stream.read_line(&mut head).await.unwrap();
+        }
+        assert!(head.starts_with("HTTP/1.1 101"), "{head}");
+        assert!(head.contains("sec-websocket-accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+        readings.record(
+            "kitchen",
+            &crate::homewizard::Reading::Water(Default::default()),
+            crate::cloud::DataSource::Local,
+        );
+        let mut header = [0; 2];
+        stream.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[0], 0x81);
+        // A reading does not fit the 7-bit length
+        assert_eq!(header[1], 126);
+        let mut payload = vec![0; usize::from(stream.read_u16().await.unwrap())];
+        stream.read_exact(&mut payload).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(json["device"], "kitchen");
+    }
+
+    #[tokio::test]
+    async fn test_version_handler() {
+        let response = create_test_app()
+            .oneshot(
+                Request::builder()
+                    .uri("/version")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(json["git_sha"], BUILD_INFO.git_sha);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_requires_handshake() {
+        let app = create_test_app();
+        let response = app
+            .oneshot(Request::builder().uri("/ws").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket_keeps_other_files() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let error = bind_unix(file.path(), 0o660).unwrap_err();
+        assert!(error.to_string().contains("not a socket"));
+        assert!(file.path().exists());
+    }
+
+    #[tokio::test]
+    async fn test_allowlist() {
+        let networks = ["192.168.1.0/24".parse().unwrap()];
+        let app = |peer: &str| {
+            router(AppState {
+                metrics: shared_metrics("test_metric 1\n"),
+                devices: Devices::default(),
+                readings: Readings::new(),
+                scrape: None,
+                probe: None,
+                auth: None,
+                allowlist: Allowlist::new(&networks).map(Arc::new),
+                poll: None,
+                actions: None,
+            })
+            .layer(MockConnectInfo(peer.parse::<SocketAddr>().unwrap()))
+        };
+        let get = |app: Router, uri: &str| {
+            app.oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        };
+
+        let response = get(app("192.168.1.20:50000"), "/metrics").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        for uri in ["/metrics", "/devices", "/"] {
+            let response = get(app("10.0.0.5:50000"), uri).await.unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{uri}");
+        }
+
+        // Health checks stay open
+        for uri in ["/livez", "/ready"] {
+            let response = get(app("10.0.0.5:50000"), uri).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_on_demand_metrics_waits_for_poll() {
+        let shared_metrics = shared_metrics("stale 1\n");
+        let (trigger, mut requests) = mpsc::channel::<oneshot::Sender<()>>(1);
+
+        // Stand-in for the on-demand poller: publish fresh metrics, then reply
+        let published = shared_metrics.clone();
+        tokio::spawn(async move {
+            while let Some(reply) = requests.recv().await {
+                published.store(Arc::new(Bytes::from("fresh 1\n")));
+                let _ = reply.send(());
+            }
+        });
+
+        let app = router(AppState {
+            metrics: shared_metrics,
+            devices: Devices::default(),
+            readings: Readings::new(),
+            scrape: Some(trigger),
+            probe: None,
+            auth: None,
+            allowlist: None,
+            poll: None,
+            actions: None,
+        });
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "fresh 1\n");
+    }
+
+    fn probe_app() -> Router {
+        let config =
+            crate::config::Config::try_parse_from(["homewizard-water-exporter", "--probe"])
+                .unwrap();
+        router(AppState {
+            metrics: shared_metrics(""),
+            devices: Devices::default(),
+            readings: Readings::new(),
+            scrape: None,
+            probe: Some(Arc::new(Prober::new(config).unwrap())),
+            auth: None,
+            allowlist: None,
+            poll: None,
+            actions: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_probe_requires_target() {
+        let response = probe_app()
+            .oneshot(
+                Request::builder()
+                    .uri("/probe")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["code"], "bad_request");
+    }
+
+    #[tokio::test]
+    async fn test_probe_unreachable_target() {
+        let response = probe_app()
+            .oneshot(
+                Request::builder()
+                    .uri("/probe?target=127.0.0.1:1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("homewizard_up{device=\"127.0.0.1:1\"} 0"));
+    }
+
+    #[tokio::test]
+    async fn test_probe_disabled_by_default() {
+        let response = create_test_app()
+            .oneshot(
+                Request::builder()
+                    .uri("/probe?target=127.0.0.1:1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
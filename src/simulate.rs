@@ -0,0 +1,389 @@
+use crate::homewizard::{
+    DeviceType, HomeWizardDeviceInfo, HomeWizardEnergySocketData, HomeWizardKwhData,
+    HomeWizardP1Data, HomeWizardWaterData, Reading,
+};
+use chrono::{Local, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+use serde_json::Map;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Mutex;
+
+/// Device simulated with `--simulate` when no `--host` is given.
+pub const SIMULATED_HOST: &str = "simulated";
+
+/// Longest stretch simulated at once; a longer gap between readings is skipped.
+const MAX_CATCH_UP_SECONDS: i64 = 24 * 3600;
+/// Average water use per day, which sets the starting total.
+const DAILY_USE_M3: f64 = 0.35;
+/// Water draws started per hour at a weight of 1.
+const DRAWS_PER_HOUR: f64 = 6.0;
+/// Chance per second that a leak starts: about one every two days.
+const LEAK_CHANCE: f64 = 1.0 / (2.0 * 24.0 * 3600.0);
+
+/// Kinds of water draws: flow in L/min, duration in seconds, relative frequency.
+const DRAWS: [(std::ops::Range<f64>, std::ops::Range<u64>, u32); 4] = [
+    // Tap
+    (3.0..7.0, 10..90, 6),
+    // Toilet
+    (5.0..8.0, 40..70, 3),
+    // Shower
+    (7.0..10.0, 300..720, 1),
+    // Washing machine or dishwasher fill
+    (6.0..10.0, 60..180, 1),
+];
+
+/// Appliances switched on now and then: power in W, duration in seconds, relative frequency.
+const APPLIANCES: [(std::ops::Range<f64>, std::ops::Range<u64>, u32); 3] = [
+    // Kettle
+    (1800.0..2200.0, 120..240, 4),
+    // Oven
+    (2000.0..2800.0, 1200..3600, 1),
+    // Washing machine
+    (300.0..600.0, 3600..7200, 1),
+];
+
+/// Something running at a fixed rate until `until` (seconds since the simulation started).
+#[derive(Debug, Clone, Copy)]
+struct Activity {
+    rate: f64,
+    until: i64,
+}
+
+#[derive(Debug)]
+struct State {
+    rng: fastrand::Rng,
+    /// Time simulated up to
+    at: Option<NaiveDateTime>,
+    /// Seconds simulated so far
+    elapsed: i64,
+    total_m3: f64,
+    draw: Option<Activity>,
+    leak: Option<Activity>,
+    import_kwh: f64,
+    export_kwh: f64,
+    gas_m3: f64,
+    base_load_w: f64,
+    appliance: Option<Activity>,
+}
+
+/// Generates realistic readings in place of a device (`--simulate`).
+///
+/// Water is drawn in short bursts (taps, toilets, showers) that are more
+/// frequent in the morning and evening and rare at night, and now and then a
+/// small leak runs for hours. Power devices follow a base load with
+/// appliances switching on and, for P1 meters, solar export during the day.
+#[derive(Debug)]
+pub struct Simulator {
+    name: String,
+    state: Mutex<State>,
+}
+
+impl Simulator {
+    /// A simulator for the device `name`, which also seeds the random numbers.
+    pub fn new(name: &str) -> Self {
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        Self::with_seed(name, hasher.finish())
+    }
+
+    pub fn with_seed(name: &str, seed: u64) -> Self {
+        let mut rng = fastrand::Rng::with_seed(seed);
+        // Start from a total that grows with time, so restarts rarely go backwards
+        let epoch = NaiveDate::from_ymd_opt(2020, 1, 1)
+            .unwrap()
+            .and_time(NaiveTime::MIN);
+        let days = (Local::now().naive_local() - epoch).num_seconds() as f64 / 86400.0;
+        let total_m3 = 100.0 + rng.f64() * 400.0 + days * DAILY_USE_M3;
+        Self {
+            name: name.to_string(),
+            state: Mutex::new(State {
+                at: None,
+                elapsed: 0,
+                total_m3,
+                draw: None,
+                leak: None,
+                import_kwh: total_m3 * 20.0,
+                export_kwh: total_m3 * 5.0,
+                gas_m3: total_m3 * 2.0,
+                base_load_w: 150.0 + rng.f64() * 200.0,
+                appliance: None,
+                rng,
+            }),
+        }
+    }
+
+    /// The reading of a `device_type` device now.
+    pub fn reading(&self, device_type: DeviceType) -> Reading {
+        self.reading_at(device_type, Local::now().naive_local())
+    }
+
+    /// The reading at `now`, after simulating the time since the previous one.
+    pub fn reading_at(&self, device_type: DeviceType, now: NaiveDateTime) -> Reading {
+        let mut state = self.state.lock().unwrap();
+        state.advance(now);
+        state.reading(device_type, now)
+    }
+
+    pub fn device_info(&self, device_type: DeviceType) -> HomeWizardDeviceInfo {
+        let (product_type, product_name) = match device_type {
+            DeviceType::Water => ("HWE-WTR", "Watermeter"),
+            DeviceType::P1 => ("HWE-P1", "P1 Meter"),
+            DeviceType::EnergySocket => ("HWE-SKT", "Energy Socket"),
+            DeviceType::Kwh => ("HWE-KWH1", "kWh meter"),
+        };
+        let mut hasher = DefaultHasher::new();
+        self.name.hash(&mut hasher);
+        HomeWizardDeviceInfo {
+            product_type: product_type.to_string(),
+            product_name: format!("{product_name} (simulated)"),
+            serial: format!("5c2fafe{:05x}", hasher.finish() & 0xfffff),
+            firmware_version: "simulated".to_string(),
+            api_version: "v1".to_string(),
+        }
+    }
+}
+
+/// How busy the household is at `hour`, relative to an average hour.
+fn activity_weight(hour: u32) -> f64 {
+    match hour {
+        0..=5 => 0.05,
+        6..=8 => 2.0,
+        9..=16 => 0.7,
+        17..=21 => 1.5,
+        _ => 0.5,
+    }
+}
+
+impl State {
+    fn advance(&mut self, now: NaiveDateTime) {
+        let Some(at) = self.at else {
+            self.at = Some(now);
+            return;
+        };
+        let seconds = (now - at).num_seconds();
+        if seconds <= 0 {
+            return;
+        }
+        let skip = (seconds - MAX_CATCH_UP_SECONDS).max(0);
+        for second in skip..seconds {
+            let time = at + chrono::Duration::seconds(second);
+            self.step(time.hour());
+        }
+        self.at = Some(now);
+    }
+
+    /// Simulate one second.
+    fn step(&mut self, hour: u32) {
+        self.elapsed += 1;
+        let weight = activity_weight(hour);
+        let now = self.elapsed;
+
+        let draw_chance = DRAWS_PER_HOUR * weight / 3600.0;
+        if self.draw.is_none_or(|draw| draw.until <= now) && self.rng.f64() < draw_chance {
+            self.draw = Some(self.pick(&DRAWS));
+        }
+        if self.leak.is_none_or(|leak| leak.until <= now) && self.rng.f64() < LEAK_CHANCE {
+            let rate = 0.2 + self.rng.f64() * 1.3;
+            let until = now + self.rng.i64(1800..4 * 3600);
+            self.leak = Some(Activity { rate, until });
+        }
+        if self
+            .appliance
+            .is_none_or(|appliance| appliance.until <= now)
+            && self.rng.f64() < draw_chance / 3.0
+        {
+            self.appliance = Some(self.pick(&APPLIANCES));
+        }
+
+        self.total_m3 += self.flow_lpm() / 60.0 / 1000.0;
+        let net_w = self.household_w() - solar_w(hour);
+        if net_w >= 0.0 {
+            self.import_kwh += net_w / 3_600_000.0;
+        } else {
+            self.export_kwh += -net_w / 3_600_000.0;
+        }
+        // Heating and cooking gas, mostly while people are around
+        self.gas_m3 += 0.1 * weight / 3600.0;
+    }
+
+    fn pick(&mut self, kinds: &[(std::ops::Range<f64>, std::ops::Range<u64>, u32)]) -> Activity {
+        let total: u32 = kinds.iter().map(|kind| kind.2).sum();
+        let mut choice = self.rng.u32(0..total);
+        let (rates, durations, _) = kinds
+            .iter()
+            .find(|kind| {
+                let found = choice < kind.2;
+                choice = choice.saturating_sub(kind.2);
+                found
+            })
+            .unwrap_or(&kinds[0]);
+        let rate = rates.start + self.rng.f64() * (rates.end - rates.start);
+        let duration = self.rng.u64(durations.clone()) as i64;
+        Activity {
+            rate,
+            until: self.elapsed + duration,
+        }
+    }
+
+    fn running(activity: Option<Activity>, now: i64) -> f64 {
+        activity
+            .filter(|activity| activity.until > now)
+            .map_or(0.0, |activity| activity.rate)
+    }
+
+    fn flow_lpm(&self) -> f64 {
+        Self::running(self.draw, self.elapsed) + Self::running(self.leak, self.elapsed)
+    }
+
+    fn household_w(&self) -> f64 {
+        self.base_load_w + Self::running(self.appliance, self.elapsed)
+    }
+
+    fn reading(&mut self, device_type: DeviceType, now: NaiveDateTime) -> Reading {
+        let wifi_strength = Some((70.0 + self.rng.f64() * 10.0).round());
+        match device_type {
+            DeviceType::Water => Reading::Water(HomeWizardWaterData {
+                wifi_ssid: "Simulated".to_string(),
+                wifi_strength,
+                wifi_rssi_db: None,
+                total_liter_m3: round(self.total_m3, 3),
+                active_liter_lpm: Some(round(self.flow_lpm(), 1)),
+                total_liter_offset_m3: 0.0,
+                extra: Map::new(),
+            }),
+            DeviceType::P1 => {
+                let voltage = round(228.0 + self.rng.f64() * 6.0, 1);
+                Reading::P1(HomeWizardP1Data {
+                    wifi_ssid: Some("Simulated".to_string()),
+                    wifi_strength,
+                    total_power_import_kwh: round(self.import_kwh, 3),
+                    total_power_export_kwh: Some(round(self.export_kwh, 3)),
+                    active_power_w: Some(round(self.household_w() - solar_w(now.hour()), 0)),
+                    active_voltage_l1_v: Some(voltage),
+                    active_voltage_l2_v: None,
+                    active_voltage_l3_v: None,
+                    total_gas_m3: Some(round(self.gas_m3, 3)),
+                    extra: Map::new(),
+                })
+            }
+            DeviceType::EnergySocket => {
+                let power = Self::running(self.appliance, self.elapsed);
+                Reading::EnergySocket(HomeWizardEnergySocketData {
+                    wifi_ssid: Some("Simulated".to_string()),
+                    wifi_strength,
+                    total_power_import_kwh: round(self.import_kwh / 10.0, 3),
+                    total_power_export_kwh: Some(0.0),
+                    active_power_w: Some(round(power, 0)),
+                    power_on: true,
+                    extra: Map::new(),
+                })
+            }
+            DeviceType::Kwh => {
+                let power = round(self.household_w(), 0);
+                let voltage = round(228.0 + self.rng.f64() * 6.0, 1);
+                Reading::Kwh(HomeWizardKwhData {
+                    wifi_ssid: Some("Simulated".to_string()),
+                    wifi_strength,
+                    total_power_import_kwh: round(self.import_kwh, 3),
+                    total_power_export_kwh: Some(0.0),
+                    active_power_w: Some(power),
+                    active_power_l1_w: Some(power),
+                    active_voltage_v: Some(voltage),
+                    active_current_a: Some(round(power / voltage, 2)),
+                    ..Default::default()
+                })
+            }
+        }
+    }
+}
+
+/// Solar power in W at `hour`, peaking at noon.
+fn solar_w(hour: u32) -> f64 {
+    let hour = f64::from(hour);
+    if (7.0..=19.0).contains(&hour) {
+        2500.0 * (std::f64::consts::PI * (hour - 7.0) / 12.0).sin()
+    } else {
+        0.0
+    }
+}
+
+fn round(value: f64, decimals: i32) -> f64 {
+    let factor = 10f64.powi(decimals);
+    (value * factor).round() / factor
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2026, 3, 2)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    fn water(reading: Reading) -> HomeWizardWaterData {
+        match reading {
+            Reading::Water(data) => data,
+            other => panic!("unexpected reading {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_water_day() {
+        let simulator = Simulator::with_seed("kitchen", 7);
+        let first = water(simulator.reading_at(DeviceType::Water, at(0, 0)));
+        assert_eq!(first.active_liter_lpm, Some(0.0));
+
+        let mut previous = first.total_liter_m3;
+        let mut flowing_minutes = 0;
+        for minute in 1..24 * 60 {
+            let data = water(simulator.reading_at(DeviceType::Water, at(minute / 60, minute % 60)));
+            assert!(data.total_liter_m3 >= previous);
+            let flow = data.active_liter_lpm.unwrap();
+            assert!((0.0..20.0).contains(&flow));
+            if flow > 0.0 {
+                flowing_minutes += 1;
+            }
+            previous = data.total_liter_m3;
+        }
+        let used = previous - first.total_liter_m3;
+        assert!((0.05..2.0).contains(&used), "used {used} m³");
+        // Mostly idle, with draws now and then
+        assert!((10..600).contains(&flowing_minutes), "{flowing_minutes}");
+    }
+
+    #[test]
+    fn test_power_devices() {
+        let simulator = Simulator::with_seed("meter", 3);
+        let Reading::P1(start) = simulator.reading_at(DeviceType::P1, at(6, 0)) else {
+            panic!("expected a P1 reading");
+        };
+        let Reading::P1(noon) = simulator.reading_at(DeviceType::P1, at(13, 0)) else {
+            panic!("expected a P1 reading");
+        };
+        assert!(noon.total_power_import_kwh > start.total_power_import_kwh);
+        // The panels outproduce the base load around noon
+        assert!(noon.total_power_export_kwh > start.total_power_export_kwh);
+        assert!(noon.total_gas_m3 > start.total_gas_m3);
+
+        let Reading::Kwh(kwh) = simulator.reading_at(DeviceType::Kwh, at(13, 1)) else {
+            panic!("expected a kWh reading");
+        };
+        assert!(kwh.active_power_w.unwrap() > 0.0);
+        assert_eq!(kwh.phases().len(), 1);
+
+        assert_eq!(
+            simulator.device_info(DeviceType::Water).product_type,
+            "HWE-WTR"
+        );
+    }
+
+    #[test]
+    fn test_seeded_by_name() {
+        let total =
+            |name: &str| water(Simulator::new(name).reading(DeviceType::Water)).total_liter_m3;
+        assert_eq!(total("kitchen"), total("kitchen"));
+        assert_ne!(total("kitchen"), total("garden"));
+    }
+}
@@ -0,0 +1,408 @@
+use crate::config::SmtpSecurity;
+use crate::tls::{self, ClientStream};
+use anyhow::{Context, Result, bail};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use std::fmt;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio_rustls::TlsConnector;
+use tracing::debug;
+
+/// A reply the server refused the message with.
+#[derive(Debug)]
+pub struct Reply {
+    pub code: u16,
+    pub text: String,
+}
+
+impl Reply {
+    /// Whether sending again later cannot help (5xx).
+    pub fn is_permanent(&self) -> bool {
+        self.code >= 500
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.code, self.text)
+    }
+}
+
+impl std::error::Error for Reply {}
+
+/// Sends plain text mail through an SMTP server, one connection per message.
+#[derive(Clone)]
+pub struct Mailer {
+    pub host: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    tls: TlsConnector,
+    pub credentials: Option<(String, String)>,
+    pub from: String,
+    pub to: Vec<String>,
+    pub timeout: Duration,
+}
+
+impl fmt::Debug for Mailer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mailer")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("security", &self.security)
+            .field("from", &self.from)
+            .field("to", &self.to)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Mailer {
+    pub fn new(
+        host: &str,
+        port: u16,
+        security: SmtpSecurity,
+        from: &str,
+        to: &[String],
+        timeout: Duration,
+    ) -> Result<Self> {
+        for address in to.iter().map(String::as_str).chain([from]) {
+            validate_address(address)?;
+        }
+        if to.is_empty() {
+            bail!("no email recipients");
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            security,
+            tls: tls::connector(None)?,
+            credentials: None,
+            from: from.to_string(),
+            to: to.to_vec(),
+            timeout,
+        })
+    }
+
+    /// Verify the server against these CAs instead of the public ones.
+    pub fn with_ca_file(mut self, path: Option<&std::path::Path>) -> Result<Self> {
+        self.tls = tls::connector(path)?;
+        Ok(self)
+    }
+
+    pub fn with_credentials(mut self, credentials: Option<(String, String)>) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// Send a message to every recipient.
+    pub async fn send(&self, subject: &str, body: &str) -> Result<()> {
+        let message = message(&self.from, &self.to, subject, body, chrono::Local::now());
+        tokio::time::timeout(self.timeout * 4, self.transaction(&message))
+            .await
+            .context("timed out")?
+    }
+
+    async fn transaction(&self, message: &str) -> Result<()> {
+        let implicit_tls = (self.security == SmtpSecurity::Tls).then_some(&self.tls);
+        let stream = tls::connect(&self.host, self.port, implicit_tls, self.timeout).await?;
+        let mut session = Session::new(stream);
+        session.expect(220).await?;
+        let mut capabilities = session.ehlo().await?;
+
+        if self.security == SmtpSecurity::Starttls {
+            if !capabilities.iter().any(|line| line == "STARTTLS") {
+                bail!("{} does not offer STARTTLS", self.host);
+            }
+            session.command("STARTTLS", 220).await?;
+            let stream = session.stream.into_inner();
+            session =
+                Session::new(tls::upgrade(stream, &self.host, &self.tls, self.timeout).await?);
+            capabilities = session.ehlo().await?;
+        }
+
+        if let Some((username, password)) = &self.credentials {
+            let mechanisms: Vec<&str> = capabilities
+                .iter()
+                .filter_map(|line| line.strip_prefix("AUTH"))
+                .flat_map(|rest| rest.trim_start_matches('=').split_whitespace())
+                .collect();
+            if mechanisms.contains(&"PLAIN") || !mechanisms.contains(&"LOGIN") {
+                let token = STANDARD.encode(format!("\0{username}\0{password}"));
+                session.command(&format!("AUTH PLAIN {token}"), 235).await?;
+            } else {
+                session.command("AUTH LOGIN", 334).await?;
+                session.command(&STANDARD.encode(username), 334).await?;
+                session.command(&STANDARD.encode(password), 235).await?;
+            }
+        }
+
+        session
+            .command(&format!("MAIL FROM:<{}>", self.from), 250)
+            .await?;
+        for to in &self.to {
+            session.command(&format!("RCPT TO:<{to}>"), 250).await?;
+        }
+        session.command("DATA", 354).await?;
+        session.stream.write_all(message.as_bytes()).await?;
+        session.command(".", 250).await?;
+        // The message is accepted; a failed goodbye does not matter
+        let _ = session.command("QUIT", 221).await;
+        debug!("Sent mail to {}", self.to.join(", "));
+        Ok(())
+    }
+}
+
+fn validate_address(address: &str) -> Result<()> {
+    let valid = address
+        .split_once('@')
+        .is_some_and(|(local, domain)| !local.is_empty() && !domain.is_empty())
+        && !address.contains(|c: char| c.is_whitespace() || matches!(c, '<' | '>'));
+    if !valid {
+        bail!("invalid email address `{address}`");
+    }
+    Ok(())
+}
+
+struct Session {
+    stream: BufReader<Box<dyn ClientStream>>,
+}
+
+impl Session {
+    fn new(stream: Box<dyn ClientStream>) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    /// Read a reply, which may span several `250-` lines.
+    async fn reply(&mut self) -> Result<Reply> {
+        let mut text = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                bail!("connection closed");
+            }
+            let line = line.trim_end();
+            let code = line
+                .get(..3)
+                .and_then(|code| code.parse().ok())
+                .with_context(|| format!("invalid SMTP reply {line:?}"))?;
+            text.push(line.get(4..).unwrap_or_default().to_string());
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(Reply {
+                    code,
+                    text: text.join("\n"),
+                });
+            }
+        }
+    }
+
+    async fn expect(&mut self, code: u16) -> Result<Reply> {
+        let reply = self.reply().await?;
+        if reply.code != code {
+            return Err(reply.into());
+        }
+        Ok(reply)
+    }
+
+    async fn command(&mut self, command: &str, code: u16) -> Result<Reply> {
+        self.stream
+            .write_all(format!("{command}\r\n").as_bytes())
+            .await?;
+        self.expect(code).await
+    }
+
+    /// Greet the server and return the extensions it offers, in upper case.
+    async fn ehlo(&mut self) -> Result<Vec<String>> {
+        let reply = self.command("EHLO localhost", 250).await?;
+        Ok(reply
+            .text
+            .lines()
+            .skip(1)
+            .map(|line| line.to_ascii_uppercase())
+            .collect())
+    }
+}
+
+/// The message as sent after `DATA`, up to but not including the final `.`.
+fn message(
+    from: &str,
+    to: &[String],
+    subject: &str,
+    body: &str,
+    date: chrono::DateTime<chrono::Local>,
+) -> String {
+    let subject = if subject.is_ascii() {
+        subject.to_string()
+    } else {
+        format!("=?UTF-8?B?{}?=", STANDARD.encode(subject))
+    };
+    let domain = from
+        .split_once('@')
+        .map_or("localhost", |(_, domain)| domain);
+    let mut message = format!(
+        "From: {from}\r\nTo: {}\r\nSubject: {subject}\r\nDate: {}\r\nMessage-ID: <{}.{:08x}@{domain}>\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: base64\r\n\r\n",
+        to.join(", "),
+        date.to_rfc2822(),
+        date.timestamp_millis(),
+        fastrand::u32(..),
+    );
+    // Base64 lines never start with a dot, so no dot-stuffing is needed
+    let encoded = STANDARD.encode(body);
+    for line in encoded.as_bytes().chunks(76) {
+        message.push_str(std::str::from_utf8(line).unwrap_or_default());
+        message.push_str("\r\n");
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    fn mailer(port: u16) -> Mailer {
+        Mailer::new(
+            "127.0.0.1",
+            port,
+            SmtpSecurity::None,
+            "exporter@example.com",
+            &["me@example.com".to_string(), "you@example.com".to_string()],
+            Duration::from_secs(5),
+        )
+        .unwrap()
+    }
+
+    /// A server that answers every command from `script` in turn, returning what it received.
+    async fn server(
+        script: &'static [(&'static str, &'static str)],
+    ) -> (u16, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let task = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            stream
+                .write_all(b"220 mail.example.com ESMTP\r\n")
+                .await
+                .unwrap();
+            let mut received = String::new();
+            for (expected, answer) in script {
+                loop {
+                    let mut line = String::new();
+                    stream.read_line(&mut line).await.unwrap();
+                    received.push_str(&line);
+                    // Message lines are not answered
+                    if line.starts_with(expected) {
+                        break;
+                    }
+                }
+                stream.write_all(answer.as_bytes()).await.unwrap();
+            }
+            let mut rest = String::new();
+            let _ = stream.read_to_string(&mut rest).await;
+            received
+        });
+        (port, task)
+    }
+
+    #[test]
+    fn test_new() {
+        let timeout = Duration::from_secs(5);
+        let to = ["me@example.com".to_string()];
+        assert!(Mailer::new("smtp", 587, SmtpSecurity::Starttls, "a@b", &to, timeout).is_ok());
+        assert!(
+            Mailer::new(
+                "smtp",
+                587,
+                SmtpSecurity::Starttls,
+                "exporter",
+                &to,
+                timeout
+            )
+            .is_err()
+        );
+        assert!(Mailer::new("smtp", 587, SmtpSecurity::Starttls, "a@b", &[], timeout).is_err());
+        let bad = ["<me@example.com>".to_string()];
+        assert!(Mailer::new("smtp", 587, SmtpSecurity::Starttls, "a@b", &bad, timeout).is_err());
+    }
+
+    #[test]
+    fn test_message() {
+        let date = chrono::Local
+            .with_ymd_and_hms(2024, 5, 1, 12, 0, 0)
+            .unwrap();
+        let message = message(
+            "exporter@example.com",
+            &["me@example.com".to_string()],
+            "kitchen: possible leak",
+            "The meter total went back to 0.25 m³",
+            date,
+        );
+        let (headers, body) = message.split_once("\r\n\r\n").unwrap();
+        assert!(headers.starts_with(
+            "From: exporter@example.com\r\nTo: me@example.com\r\nSubject: kitchen: possible leak\r\nDate: "
+        ));
+        assert!(headers.contains("@example.com>\r\nMIME-Version: 1.0\r\n"));
+        assert_eq!(
+            STANDARD.decode(body.replace("\r\n", "")).unwrap(),
+            "The meter total went back to 0.25 m³".as_bytes()
+        );
+
+        let message = super::message("a@b", &[], "m³", "", date);
+        assert!(message.contains("Subject: =?UTF-8?B?bcKz?=\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let (port, server) = server(&[
+            (
+                "EHLO",
+                "250-mail.example.com\r\n250-AUTH LOGIN XOAUTH2\r\n250 8BITMIME\r\n",
+            ),
+            ("AUTH LOGIN", "334 VXNlcm5hbWU6\r\n"),
+            ("ZXhwb3J0ZXI=", "334 UGFzc3dvcmQ6\r\n"),
+            ("c2VjcmV0", "235 Authenticated\r\n"),
+            ("MAIL FROM:<exporter@example.com>", "250 OK\r\n"),
+            ("RCPT TO:<me@example.com>", "250 OK\r\n"),
+            ("RCPT TO:<you@example.com>", "250 OK\r\n"),
+            ("DATA", "354 Go ahead\r\n"),
+            (".\r\n", "250 Queued\r\n"),
+            ("QUIT", "221 Bye\r\n"),
+        ])
+        .await;
+        mailer(port)
+            .with_credentials(Some(("exporter".to_string(), "secret".to_string())))
+            .send("kitchen: offline", "kitchen is offline")
+            .await
+            .unwrap();
+        let received = server.await.unwrap();
+        assert!(
+            received.contains("Subject: kitchen: offline\r\n"),
+            "{received}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_refused() {
+        let (port, _server) = server(&[
+            ("EHLO", "250 mail.example.com\r\n"),
+            ("MAIL FROM", "250 OK\r\n"),
+            ("RCPT TO", "550 No such user\r\n"),
+        ])
+        .await;
+        let error = mailer(port).send("subject", "body").await.unwrap_err();
+        let reply = error.downcast_ref::<Reply>().unwrap();
+        assert!(reply.is_permanent());
+        assert_eq!(reply.to_string(), "550 No such user");
+
+        // STARTTLS is required unless turned off
+        let (port, _server) = server(&[("EHLO", "250 mail.example.com\r\n")]).await;
+        let mut mailer = mailer(port);
+        mailer.security = SmtpSecurity::Starttls;
+        let error = mailer.send("subject", "body").await.unwrap_err();
+        assert_eq!(error.to_string(), "127.0.0.1 does not offer STARTTLS");
+    }
+}
@@ -0,0 +1,229 @@
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Exporter-side state that must survive a restart, stored as JSON on disk.
+///
+/// Values read from the meter are fetched again on the next poll; only what the
+/// exporter derives from them (such as the start-of-day total behind "used
+/// today") would otherwise be lost.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Start-of-day baseline per device.
+    #[serde(default)]
+    pub daily_usage: BTreeMap<String, DailyBaseline>,
+    /// Highest flow seen today per device.
+    #[serde(default)]
+    pub peak_flow: BTreeMap<String, DailyPeak>,
+    /// Water used in the current billing cycle per device.
+    #[serde(default)]
+    pub billing_cycle: BTreeMap<String, BillingCycle>,
+    /// Water used during the latest quiet hours per device.
+    #[serde(default)]
+    pub night_usage: BTreeMap<String, NightUsage>,
+    /// Water used by all water meters, behind `--aggregate`.
+    #[serde(default)]
+    pub aggregate_usage: AggregateUsage,
+    /// Last value of every counter series: device totals and the exporter's own
+    /// counters, so they continue where they left off instead of resetting.
+    #[serde(default)]
+    pub counters: Vec<CounterSample>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CounterSample {
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+    pub value: f64,
+}
+
+impl Snapshot {
+    /// Forget devices that are no longer configured, so their series do not come back.
+    pub fn retain_devices(&mut self, devices: &[&str]) {
+        self.daily_usage
+            .retain(|device, _| devices.contains(&device.as_str()));
+        self.peak_flow
+            .retain(|device, _| devices.contains(&device.as_str()));
+        self.billing_cycle
+            .retain(|device, _| devices.contains(&device.as_str()));
+        self.night_usage
+            .retain(|device, _| devices.contains(&device.as_str()));
+        // The water of a removed meter stays in the sum
+        self.aggregate_usage
+            .totals
+            .retain(|device, _| devices.contains(&device.as_str()));
+        self.counters.retain(|sample| {
+            sample
+                .labels
+                .get("device")
+                .is_none_or(|device| devices.contains(&device.as_str()))
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DailyBaseline {
+    pub date: NaiveDate,
+    pub total_m3: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DailyPeak {
+    pub date: NaiveDate,
+    pub flow_lpm: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BillingCycle {
+    pub start: NaiveDate,
+    pub used_m3: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct NightUsage {
+    /// Date the quiet hours started on
+    pub night: NaiveDate,
+    pub used_m3: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AggregateUsage {
+    /// Last total of every meter counted, including meters whose readings were dropped
+    pub totals: BTreeMap<String, f64>,
+    pub used_m3: f64,
+}
+
+/// Read a snapshot; a missing file is not an error and yields `None`.
+pub fn load(path: &Path) -> Result<Option<Snapshot>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e).with_context(|| format!("failed to read state file {}", path.display()));
+        }
+    };
+
+    let snapshot = serde_json::from_str(&content)
+        .with_context(|| format!("failed to parse state file {}", path.display()))?;
+    Ok(Some(snapshot))
+}
+
+/// Write a snapshot atomically, so a crash mid-write never leaves a torn file.
+pub fn save(path: &Path, snapshot: &Snapshot) -> Result<()> {
+    if let Some(parent) = path.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let staging = path.with_extension("tmp");
+    std::fs::write(&staging, serde_json::to_vec_pretty(snapshot)?)
+        .with_context(|| format!("failed to write {}", staging.display()))?;
+    std::fs::rename(&staging, path)
+        .with_context(|| format!("failed to replace state file {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        snapshot.daily_usage.insert(
+            "kitchen".to_string(),
+            DailyBaseline {
+                date: NaiveDate::from_ymd_opt(2025, 1, 23).unwrap(),
+                total_m3: 1234.5,
+            },
+        );
+        snapshot.peak_flow.insert(
+            "kitchen".to_string(),
+            DailyPeak {
+                date: NaiveDate::from_ymd_opt(2025, 1, 23).unwrap(),
+                flow_lpm: 12.5,
+            },
+        );
+        snapshot.billing_cycle.insert(
+            "kitchen".to_string(),
+            BillingCycle {
+                start: NaiveDate::from_ymd_opt(2025, 1, 15).unwrap(),
+                used_m3: 3.25,
+            },
+        );
+        snapshot.night_usage.insert(
+            "kitchen".to_string(),
+            NightUsage {
+                night: NaiveDate::from_ymd_opt(2025, 1, 22).unwrap(),
+                used_m3: 0.01,
+            },
+        );
+        snapshot.aggregate_usage = AggregateUsage {
+            totals: BTreeMap::from([("kitchen".to_string(), 1234.5)]),
+            used_m3: 1240.0,
+        };
+        snapshot.counters.push(CounterSample {
+            name: "homewizard_water_total_m3".to_string(),
+            labels: BTreeMap::from([("device".to_string(), "kitchen".to_string())]),
+            value: 1234.5,
+        });
+        snapshot
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("state.json");
+
+        save(&path, &snapshot()).unwrap();
+        assert_eq!(load(&path).unwrap(), Some(snapshot()));
+        assert!(!path.with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(load(&dir.path().join("missing.json")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_load_corrupt_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        std::fs::write(&path, "{not json").unwrap();
+
+        assert!(
+            load(&path)
+                .unwrap_err()
+                .to_string()
+                .contains("failed to parse state file")
+        );
+    }
+
+    #[test]
+    fn test_missing_sections_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        std::fs::write(&path, "{}").unwrap();
+
+        assert_eq!(load(&path).unwrap(), Some(Snapshot::default()));
+    }
+
+    #[test]
+    fn test_retain_devices() {
+        let mut snapshot = snapshot();
+        snapshot.retain_devices(&["kitchen"]);
+        assert_eq!(snapshot, self::snapshot());
+
+        snapshot.retain_devices(&["garden"]);
+        assert!(snapshot.daily_usage.is_empty());
+        assert!(snapshot.peak_flow.is_empty());
+        assert!(snapshot.billing_cycle.is_empty());
+        assert!(snapshot.night_usage.is_empty());
+        assert!(snapshot.aggregate_usage.totals.is_empty());
+        assert_eq!(snapshot.aggregate_usage.used_m3, 1240.0);
+        assert!(snapshot.counters.is_empty());
+    }
+}
@@ -0,0 +1,251 @@
+use crate::homewizard::Reading;
+use crate::readings::TimedReading;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fmt::Write;
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+
+/// Largest datagram sent, to stay below the MTU of a typical network.
+const MAX_DATAGRAM: usize = 1432;
+
+/// How readings become StatsD metrics: a gauge per value, and for water meters a
+/// counter of the liters used since the previous reading.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatsdFormat {
+    /// Prepended to every metric name, with a dot
+    pub prefix: String,
+    /// Send the device as DogStatsD tags instead of in the metric name
+    pub dogstatsd: bool,
+    /// Added to the `device` and `type` tags with `dogstatsd`
+    pub tags: Vec<(String, String)>,
+}
+
+impl StatsdFormat {
+    /// The lines of a reading; `used_liters` is the counter increment of a water meter.
+    pub fn lines(&self, device: &str, reading: &Reading, used_liters: Option<f64>) -> Vec<String> {
+        let device_type = reading.device_type().as_str();
+        let (name, tags) = if self.dogstatsd {
+            let mut tags = format!("|#device:{},type:{device_type}", tag(device));
+            for (name, value) in &self.tags {
+                let _ = write!(tags, ",{}:{}", tag(name), tag(value));
+            }
+            (self.prefix.clone(), tags)
+        } else {
+            (
+                format!("{}.{}", self.prefix, component(device)),
+                String::new(),
+            )
+        };
+
+        let mut lines: Vec<String> = reading
+            .fields()
+            .into_iter()
+            .filter(|(_, value)| value.is_finite())
+            .map(|(field, value)| format!("{name}.{field}:{value}|g{tags}"))
+            .collect();
+        // Nothing is sent for a meter that was reset
+        if let Some(liters) = used_liters.filter(|liters| liters.is_finite() && *liters > 0.0) {
+            lines.push(format!("{name}.water_used_liters:{liters}|c{tags}"));
+        }
+        lines
+    }
+}
+
+/// Characters with a meaning in the StatsD protocol, replaced in metric name parts.
+fn component(text: &str) -> String {
+    text.chars()
+        .map(|c| {
+            if matches!(c, '.' | ':' | '|' | '@' | '#' | ' ') {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+/// A DogStatsD tag key or value, which cannot contain `,`, `|` or `#`.
+fn tag(text: &str) -> String {
+    text.chars()
+        .map(|c| {
+            if matches!(c, ',' | '|' | '#' | ' ') {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+/// Sends readings to a StatsD or DogStatsD server over UDP (`--statsd-address`).
+pub struct StatsdSink {
+    socket: UdpSocket,
+    format: StatsdFormat,
+    /// Last total of each water meter, for the used liters counter
+    totals: HashMap<String, f64>,
+}
+
+impl StatsdSink {
+    /// `address` is a `host:port`, resolved once.
+    pub async fn connect(address: &str, format: StatsdFormat) -> Result<Self> {
+        let target = tokio::net::lookup_host(address)
+            .await
+            .with_context(|| format!("failed to resolve StatsD address {address}"))?
+            .next()
+            .with_context(|| format!("StatsD address {address} did not resolve"))?;
+        let local = if target.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(target).await?;
+        Ok(Self {
+            socket,
+            format,
+            totals: HashMap::new(),
+        })
+    }
+
+    pub async fn send(&mut self, device: &str, reading: &Reading) -> Result<()> {
+        let used_liters = match reading {
+            Reading::Water(data) => self
+                .totals
+                .insert(device.to_string(), data.total_liter_m3)
+                .map(|previous| (data.total_liter_m3 - previous) * 1000.0),
+            _ => None,
+        };
+        let lines = self.format.lines(device, reading, used_liters);
+        for datagram in datagrams(&lines) {
+            self.socket.send(datagram.as_bytes()).await?;
+        }
+        debug!("Sent {} StatsD metrics for {}", lines.len(), device);
+        Ok(())
+    }
+
+    /// Send every recorded reading from a background task.
+    pub fn start(mut self, mut readings: broadcast::Receiver<TimedReading>) {
+        tokio::spawn(async move {
+            loop {
+                match readings.recv().await {
+                    Ok(reading) => {
+                        if let Err(e) = self.send(&reading.device, &reading.reading).await {
+                            warn!("Failed to send StatsD metrics: {:#}", e);
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("StatsD sink fell behind, skipped {} readings", skipped)
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+}
+
+/// Lines joined by newlines into as few datagrams as fit.
+fn datagrams(lines: &[String]) -> Vec<String> {
+    let mut datagrams = Vec::new();
+    let mut current = String::new();
+    for line in lines {
+        if !current.is_empty() && current.len() + 1 + line.len() > MAX_DATAGRAM {
+            datagrams.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(line);
+    }
+    if !current.is_empty() {
+        datagrams.push(current);
+    }
+    datagrams
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::homewizard::{HomeWizardP1Data, HomeWizardWaterData};
+
+    fn water(total: f64) -> Reading {
+        Reading::Water(HomeWizardWaterData {
+            total_liter_m3: total,
+            active_liter_lpm: Some(3.0),
+            ..Default::default()
+        })
+    }
+
+    fn format(dogstatsd: bool) -> StatsdFormat {
+        StatsdFormat {
+            prefix: "homewizard".to_string(),
+            dogstatsd,
+            tags: vec![("site".to_string(), "home".to_string())],
+        }
+    }
+
+    #[test]
+    fn test_lines() {
+        assert_eq!(
+            format(false).lines("kitchen.sink", &water(42.5), Some(12.0)),
+            [
+                "homewizard.kitchen_sink.total_liter_m3:42.5|g",
+                "homewizard.kitchen_sink.active_liter_lpm:3|g",
+                "homewizard.kitchen_sink.water_used_liters:12|c",
+            ]
+        );
+        assert_eq!(
+            format(true).lines("kitchen, left", &water(42.5), None),
+            [
+                "homewizard.total_liter_m3:42.5|g|#device:kitchen__left,type:water,site:home",
+                "homewizard.active_liter_lpm:3|g|#device:kitchen__left,type:water,site:home",
+            ]
+        );
+
+        let p1 = Reading::P1(HomeWizardP1Data {
+            active_power_w: Some(-250.0),
+            ..Default::default()
+        });
+        assert_eq!(
+            format(false).lines("meter", &p1, None)[1],
+            "homewizard.meter.active_power_w:-250|g"
+        );
+    }
+
+    #[test]
+    fn test_datagrams() {
+        assert!(datagrams(&[]).is_empty());
+        let line = "x".repeat(600);
+        let lines = vec![line.clone(), line.clone(), line.clone()];
+        assert_eq!(datagrams(&lines), [format!("{line}\n{line}"), line.clone()]);
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        let mut sink = StatsdSink::connect(&address, format(false)).await.unwrap();
+
+        let mut buf = [0; MAX_DATAGRAM];
+        sink.send("kitchen", &water(42.5)).await.unwrap();
+        let len = server.recv(&mut buf).await.unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf[..len]).unwrap(),
+            "homewizard.kitchen.total_liter_m3:42.5|g\nhomewizard.kitchen.active_liter_lpm:3|g"
+        );
+
+        // 5 liters used since the previous reading
+        sink.send("kitchen", &water(42.505)).await.unwrap();
+        let len = server.recv(&mut buf).await.unwrap();
+        let datagram = std::str::from_utf8(&buf[..len]).unwrap();
+        let counter = datagram.lines().last().unwrap();
+        let liters: f64 = counter
+            .strip_prefix("homewizard.kitchen.water_used_liters:")
+            .and_then(|rest| rest.strip_suffix("|c"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((liters - 5.0).abs() < 1e-6, "{counter}");
+    }
+}
@@ -0,0 +1,166 @@
+use std::ffi::OsString;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// Readiness and watchdog notifications for `Type=notify` systemd units.
+///
+/// systemd passes the notification socket in `NOTIFY_SOCKET` and, with
+/// `WatchdogSec=`, the watchdog timeout in `WATCHDOG_USEC`. Outside systemd
+/// both are unset and every notification is a no-op.
+#[derive(Debug, Clone, Default)]
+pub struct Notifier {
+    socket: Option<OsString>,
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    pub fn from_env() -> Self {
+        Self::new(
+            std::env::var_os("NOTIFY_SOCKET"),
+            std::env::var("WATCHDOG_USEC").ok().as_deref(),
+            std::env::var("WATCHDOG_PID").ok().as_deref(),
+        )
+    }
+
+    fn new(
+        socket: Option<OsString>,
+        watchdog_usec: Option<&str>,
+        watchdog_pid: Option<&str>,
+    ) -> Self {
+        // The watchdog is meant for the main process, not for anything it spawned
+        let for_us = watchdog_pid
+            .and_then(|pid| pid.parse::<u32>().ok())
+            .is_none_or(|pid| pid == std::process::id());
+        let watchdog = watchdog_usec
+            .and_then(|usec| usec.parse::<u64>().ok())
+            .filter(|usec| *usec > 0 && for_us)
+            .map(Duration::from_micros);
+        let socket = socket.filter(|socket| !socket.is_empty());
+        Self {
+            watchdog: watchdog.filter(|_| socket.is_some()),
+            socket,
+        }
+    }
+
+    /// Timeout after which systemd restarts the service without a watchdog ping.
+    pub fn watchdog_timeout(&self) -> Option<Duration> {
+        self.watchdog
+    }
+
+    /// The metrics server is up.
+    pub fn ready(&self) {
+        self.notify("READY=1");
+    }
+
+    /// Shutdown has started.
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    /// Tell systemd the poller is still making progress.
+    pub fn watchdog(&self) {
+        if self.watchdog.is_some() {
+            self.notify("WATCHDOG=1");
+        }
+    }
+
+    fn notify(&self, state: &str) {
+        let Some(socket) = &self.socket else {
+            return;
+        };
+        match send(socket, state) {
+            Ok(()) => debug!("Notified systemd: {}", state),
+            Err(e) => warn!("Failed to notify systemd ({}): {}", state, e),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn send(socket: &OsString, state: &str) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let path = socket.as_bytes();
+    let addr = match path.strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name)?
+        }
+        _ => SocketAddr::from_pathname(std::path::Path::new(socket))?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_socket: &OsString, _state: &str) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    fn receiver() -> (tempfile::TempDir, UnixDatagram, OsString) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        (dir, socket, path.into_os_string())
+    }
+
+    fn recv(socket: &UnixDatagram) -> String {
+        let mut buf = [0; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    #[test]
+    fn test_disabled_outside_systemd() {
+        let notifier = Notifier::new(None, Some("30000000"), None);
+        assert_eq!(notifier.watchdog_timeout(), None);
+        // Nothing to send to, nothing to fail
+        notifier.ready();
+        notifier.watchdog();
+    }
+
+    #[test]
+    fn test_notifications() {
+        let (_dir, socket, path) = receiver();
+        let notifier = Notifier::new(Some(path), Some("30000000"), None);
+        assert_eq!(notifier.watchdog_timeout(), Some(Duration::from_secs(30)));
+
+        notifier.ready();
+        assert_eq!(recv(&socket), "READY=1");
+        notifier.watchdog();
+        assert_eq!(recv(&socket), "WATCHDOG=1");
+        notifier.stopping();
+        assert_eq!(recv(&socket), "STOPPING=1");
+    }
+
+    #[test]
+    fn test_no_watchdog_pings_without_watchdog() {
+        let (_dir, socket, path) = receiver();
+        let notifier = Notifier::new(Some(path), None, None);
+
+        notifier.watchdog();
+        notifier.ready();
+        assert_eq!(recv(&socket), "READY=1");
+    }
+
+    #[test]
+    fn test_watchdog_of_another_process() {
+        let (_dir, _socket, path) = receiver();
+        let own_pid = std::process::id().to_string();
+
+        let notifier = Notifier::new(Some(path.clone()), Some("30000000"), Some(&own_pid));
+        assert!(notifier.watchdog_timeout().is_some());
+
+        let notifier = Notifier::new(Some(path), Some("30000000"), Some("1"));
+        assert_eq!(notifier.watchdog_timeout(), None);
+    }
+}
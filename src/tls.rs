@@ -0,0 +1,78 @@
+use anyhow::{Context, Result};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, ServerName};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+pub(crate) fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read certificates from {}", path.display()))
+}
+
+/// A TLS client for the sinks that open their own connections, trusting the
+/// certificates in `ca_file`, or the public web PKI without one.
+pub fn connector(ca_file: Option<&Path>) -> Result<TlsConnector> {
+    Ok(TlsConnector::from(client_config(ca_file)?))
+}
+
+/// The client configuration of [`connector`], for clients that take one directly.
+pub fn client_config(ca_file: Option<&Path>) -> Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    match ca_file {
+        Some(path) => {
+            for cert in read_certs(path)? {
+                roots
+                    .add(cert)
+                    .with_context(|| format!("Invalid CA certificate in {}", path.display()))?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// A connection opened by [`connect`], over TLS or not.
+pub trait ClientStream: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> ClientStream for S {}
+
+/// Open a TCP connection to `host:port`, through `tls` if set.
+pub async fn connect(
+    host: &str,
+    port: u16,
+    tls: Option<&TlsConnector>,
+    timeout: Duration,
+) -> Result<Box<dyn ClientStream>> {
+    let tcp = tokio::time::timeout(timeout, TcpStream::connect((host, port)))
+        .await
+        .context("timed out")??;
+    let _ = tcp.set_nodelay(true);
+    match tls {
+        Some(connector) => upgrade(Box::new(tcp), host, connector, timeout).await,
+        None => Ok(Box::new(tcp)),
+    }
+}
+
+/// Start TLS on a connection that was opened without it, as after `STARTTLS`.
+pub async fn upgrade(
+    stream: Box<dyn ClientStream>,
+    host: &str,
+    connector: &TlsConnector,
+    timeout: Duration,
+) -> Result<Box<dyn ClientStream>> {
+    let name = ServerName::try_from(host.to_string())?;
+    let stream = tokio::time::timeout(timeout, connector.connect(name, stream))
+        .await
+        .context("TLS handshake timed out")??;
+    Ok(Box::new(stream))
+}
@@ -0,0 +1,174 @@
+use anyhow::{Context, Result, bail};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+/// Pushes waiting to be sent; newer ones are dropped while it is full.
+const QUEUE_CAPACITY: usize = 16;
+
+/// Pushes the exporter's metrics to VictoriaMetrics' `/api/v1/import/prometheus`
+/// (`--vm-import-url`), in the text format served by `/metrics`.
+pub struct VmImporter {
+    client: reqwest::Client,
+    url: reqwest::Url,
+    basic_auth: Option<(String, String)>,
+}
+
+impl VmImporter {
+    /// `url` is the server's base URL, such as `http://victoriametrics:8428`; a URL that
+    /// already ends in `/api/v1/import/prometheus` is used as is.
+    pub fn new(url: &str, basic_auth: Option<(String, String)>, timeout: Duration) -> Result<Self> {
+        let url = url.trim_end_matches('/');
+        let url = if url.ends_with("/api/v1/import/prometheus") {
+            reqwest::Url::parse(url)
+        } else {
+            reqwest::Url::parse(&format!("{url}/api/v1/import/prometheus"))
+        }
+        .context("invalid VictoriaMetrics URL")?;
+        if !matches!(url.scheme(), "http" | "https") {
+            bail!("VictoriaMetrics URL must use http or https");
+        }
+        Ok(Self {
+            client: reqwest::Client::builder().timeout(timeout).build()?,
+            url,
+            basic_auth,
+        })
+    }
+
+    pub fn url(&self) -> &reqwest::Url {
+        &self.url
+    }
+
+    pub async fn push(&self, metrics_text: String) -> Result<()> {
+        let mut request = self
+            .client
+            .post(self.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "text/plain")
+            .body(metrics_text);
+        if let Some((username, password)) = &self.basic_auth {
+            request = request.basic_auth(username, Some(password));
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            bail!("VictoriaMetrics returned {status}: {}", body.trim());
+        }
+        debug!("Pushed metrics to {}", self.url);
+        Ok(())
+    }
+
+    /// Run the importer on a background task, pushing in the order they are queued.
+    pub fn start(self) -> VmImport {
+        let (tx, mut rx) = mpsc::channel::<String>(QUEUE_CAPACITY);
+        tokio::spawn(async move {
+            while let Some(metrics_text) = rx.recv().await {
+                if let Err(e) = self.push(metrics_text).await {
+                    warn!("Failed to push metrics to VictoriaMetrics: {:#}", e);
+                }
+            }
+        });
+        VmImport { queue: tx }
+    }
+}
+
+/// Queue of the VictoriaMetrics importer.
+#[derive(Clone)]
+pub struct VmImport {
+    queue: mpsc::Sender<String>,
+}
+
+impl VmImport {
+    pub fn record(&self, metrics_text: String) {
+        if self.queue.try_send(metrics_text).is_err() {
+            warn!("VictoriaMetrics queue is full, dropping samples");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_string, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const TEXT: &str = "homewizard_water_total_liter_m3{device=\"kitchen\"} 42.5\n";
+
+    #[test]
+    fn test_url() {
+        let timeout = Duration::from_secs(5);
+        for url in [
+            "http://victoriametrics:8428",
+            "http://victoriametrics:8428/",
+            "http://victoriametrics:8428/api/v1/import/prometheus",
+        ] {
+            assert_eq!(
+                VmImporter::new(url, None, timeout).unwrap().url().as_str(),
+                "http://victoriametrics:8428/api/v1/import/prometheus"
+            );
+        }
+        // vminsert of a cluster
+        assert_eq!(
+            VmImporter::new("https://vminsert:8480/insert/0/prometheus", None, timeout)
+                .unwrap()
+                .url()
+                .as_str(),
+            "https://vminsert:8480/insert/0/prometheus/api/v1/import/prometheus"
+        );
+        assert!(VmImporter::new("victoriametrics:8428", None, timeout).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_push() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/import/prometheus"))
+            // vm:secret
+            .and(header("Authorization", "Basic dm06c2VjcmV0"))
+            .and(body_string(TEXT))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(401).set_body_string("unauthorized"))
+            .mount(&server)
+            .await;
+
+        let timeout = Duration::from_secs(5);
+        let auth = Some(("vm".to_string(), "secret".to_string()));
+        let importer = VmImporter::new(&server.uri(), auth, timeout).unwrap();
+        importer.push(TEXT.to_string()).await.unwrap();
+
+        let importer = VmImporter::new(&server.uri(), None, timeout).unwrap();
+        let error = importer.push(TEXT.to_string()).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "VictoriaMetrics returned 401 Unauthorized: unauthorized"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&server)
+            .await;
+        let import = VmImporter::new(&server.uri(), None, Duration::from_secs(5))
+            .unwrap()
+            .start();
+        import.record(TEXT.to_string());
+
+        for _ in 0..50 {
+            if let Some(requests) = server.received_requests().await
+                && let Some(request) = requests.first()
+            {
+                assert_eq!(request.body, TEXT.as_bytes());
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("nothing was pushed");
+    }
+}
@@ -0,0 +1,333 @@
+use crate::readings::TimedReading;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc};
+
+/// How often `/ws` clients are pinged; one that has not answered by the next ping is dropped.
+pub const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Largest frame accepted from a client, which only sends control frames anyway.
+const MAX_FRAME_LEN: u64 = 64 * 1024;
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_TEXT: u8 = 0x1;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// Close code for a server going away, sent on shutdown.
+const GOING_AWAY: u16 = 1001;
+
+/// The `Sec-WebSocket-Accept` value answering a client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    BASE64.encode(sha1(format!("{key}{GUID}").as_bytes()))
+}
+
+/// SHA-1 as needed for the handshake; not for anything that must be secure.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut words = [0u32; 80];
+        for (word, bytes) in words.iter_mut().zip(block.chunks(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in words.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (total, value) in state.iter_mut().zip([a, b, c, d, e]) {
+            *total = total.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (bytes, word) in digest.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+/// A frame as received from a client, unmasked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Frame {
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// An unfragmented, unmasked frame, as servers send them.
+fn encode(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Frame> {
+    let mut header = [0; 2];
+    reader.read_exact(&mut header).await?;
+    let opcode = header[0] & 0x0f;
+    let masked = header[1] & 0x80 != 0;
+    let len = match header[1] & 0x7f {
+        126 => u64::from(reader.read_u16().await?),
+        127 => reader.read_u64().await?,
+        len => u64::from(len),
+    };
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {len} bytes is too large"),
+        ));
+    }
+
+    let mut mask = [0; 4];
+    if masked {
+        reader.read_exact(&mut mask).await?;
+    }
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Frame { opcode, payload })
+}
+
+/// Send each reading (of `device` only, if given) as a JSON text frame until
+/// the client closes the connection or stops answering pings.
+pub async fn serve<S>(
+    stream: S,
+    mut readings: broadcast::Receiver<TimedReading>,
+    device: Option<String>,
+    ping_interval: Duration,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    // Reading a frame is not cancel safe, so it gets its own task
+    let (frames_tx, mut frames) = mpsc::channel(8);
+    let reader_task = tokio::spawn(async move {
+        while let Ok(frame) = read_frame(&mut reader).await {
+            if frames_tx.send(frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut pings =
+        tokio::time::interval_at(tokio::time::Instant::now() + ping_interval, ping_interval);
+    let mut answered = true;
+    let result = loop {
+        tokio::select! {
+            reading = readings.recv() => match reading {
+                Ok(reading) if device.as_ref().is_none_or(|d| reading.device == *d) => {
+                    let json = serde_json::to_vec(&reading)?;
+                    writer.write_all(&encode(OP_TEXT, &json)).await?;
+                }
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => {
+                    let _ = writer.write_all(&encode(OP_CLOSE, &GOING_AWAY.to_be_bytes())).await;
+                    break Ok(());
+                }
+            },
+            frame = frames.recv() => match frame {
+                Some(Frame { opcode: OP_CLOSE, payload }) => {
+                    // Echo the status code, as the protocol asks
+                    let code = payload.get(..2).unwrap_or_default();
+                    let _ = writer.write_all(&encode(OP_CLOSE, code)).await;
+                    break Ok(());
+                }
+                Some(Frame { opcode: OP_PING, payload }) => {
+                    answered = true;
+                    writer.write_all(&encode(OP_PONG, &payload)).await?;
+                }
+                // A pong, or any other sign of life
+                Some(_) => answered = true,
+                None => break Ok(()),
+            },
+            _ = pings.tick() => {
+                if !answered {
+                    break Err(io::Error::new(io::ErrorKind::TimedOut, "client stopped answering pings"));
+                }
+                answered = false;
+                writer.write_all(&encode(OP_PING, b"")).await?;
+            }
+        }
+    };
+    reader_task.abort();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cloud::DataSource;
+    use crate::homewizard::{HomeWizardWaterData, Reading};
+    use crate::readings::Readings;
+
+    /// A frame as a client sends it: masked.
+    fn client_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    fn water(total: f64) -> Reading {
+        Reading::Water(HomeWizardWaterData {
+            total_liter_m3: total,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_sha1() {
+        assert_eq!(
+            hex::encode(sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(
+            hex::encode(sha1(b"")),
+            "da39a3ee5e6b4b0d3255bfef95601890afd80709"
+        );
+        // Spans two blocks
+        assert_eq!(
+            hex::encode(sha1(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // The example from RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(OP_TEXT, b"hi"), b"\x81\x02hi");
+        assert_eq!(&encode(OP_TEXT, &[0; 200])[..4], &[0x81, 126, 0, 200]);
+        assert_eq!(
+            &encode(OP_TEXT, &[0; 70_000])[..10],
+            &[0x81, 127, 0, 0, 0, 0, 0, 1, 0x11, 0x70]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_frame() {
+        let frame = client_frame(OP_PING, b"hello");
+        let read = read_frame(&mut frame.as_slice()).await.unwrap();
+        assert_eq!(
+            read,
+            Frame {
+                opcode: OP_PING,
+                payload: b"hello".to_vec()
+            }
+        );
+
+        let huge = [0x82, 127, 0, 0, 0, 0, 0, 0x10, 0, 0];
+        assert!(read_frame(&mut huge.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let readings = Readings::new();
+        let (client, server) = tokio::io::duplex(4096);
+        let task = tokio::spawn(serve(
+            server,
+            readings.subscribe(),
+            Some("kitchen".to_string()),
+            Duration::from_secs(3600),
+        ));
+        let (mut client_reader, mut client_writer) = tokio::io::split(client);
+
+        readings.record("garden", &water(5.0), DataSource::Local);
+        readings.record("kitchen", &water(42.5), DataSource::Local);
+        let frame = read_frame(&mut client_reader).await.unwrap();
+        assert_eq!(frame.opcode, OP_TEXT);
+        let json: serde_json::Value = serde_json::from_slice(&frame.payload).unwrap();
+        assert_eq!(json["device"], "kitchen");
+        assert_eq!(json["reading"]["total_liter_m3"], 42.5);
+
+        client_writer
+            .write_all(&client_frame(OP_PING, b"are you there"))
+            .await
+            .unwrap();
+        let frame = read_frame(&mut client_reader).await.unwrap();
+        assert_eq!(frame.opcode, OP_PONG);
+        assert_eq!(frame.payload, b"are you there");
+
+        client_writer
+            .write_all(&client_frame(OP_CLOSE, &1000u16.to_be_bytes()))
+            .await
+            .unwrap();
+        let frame = read_frame(&mut client_reader).await.unwrap();
+        assert_eq!(frame.opcode, OP_CLOSE);
+        assert_eq!(frame.payload, 1000u16.to_be_bytes());
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_ping() {
+        let readings = Readings::new();
+        let (client, server) = tokio::io::duplex(4096);
+        let task = tokio::spawn(serve(
+            server,
+            readings.subscribe(),
+            None,
+            Duration::from_millis(20),
+        ));
+        let (mut client_reader, _client_writer) = tokio::io::split(client);
+
+        let frame = read_frame(&mut client_reader).await.unwrap();
+        assert_eq!(frame.opcode, OP_PING);
+        let error = task.await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+}